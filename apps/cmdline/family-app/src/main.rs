@@ -7,7 +7,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::sync::Arc;
 use tank::client::TankClient;
-use tank::data::ScubaData;
+use tank::data::{ScubaData, ValidationError};
 use uuid::Uuid;
 
 /*
@@ -225,24 +225,38 @@ impl FamilyApp {
         // register callback that validates char limit for posts
         data_store_guard.validator().set_validate_callback_for_type(
             POST_PREFIX.to_string(),
-            |_, val| {
-                let post: Post = serde_json::from_str(val.data_val()).unwrap();
+            |_, _, val| {
+                let post: Post = serde_json::from_str(val.data_val()).map_err(|err| {
+                    ValidationError::Invalid(POST_PREFIX.to_string(), err.to_string())
+                })?;
                 if post.contents.len() > 200 {
-                    return false;
+                    return Err(ValidationError::Invalid(
+                        POST_PREFIX.to_string(),
+                        String::from("post is longer than 200 characters"),
+                    ));
                 }
-                true
+                Ok(())
             },
         );
 
         // register callback that validates char limit for comments
         data_store_guard.validator().set_validate_callback_for_type(
             COMMENT_PREFIX.to_string(),
-            |_, val| {
-                let comment: Comment = serde_json::from_str(val.data_val()).unwrap();
+            |_, _, val| {
+                let comment: Comment =
+                    serde_json::from_str(val.data_val()).map_err(|err| {
+                        ValidationError::Invalid(
+                            COMMENT_PREFIX.to_string(),
+                            err.to_string(),
+                        )
+                    })?;
                 if comment.contents.len() > 100 {
-                    return false;
+                    return Err(ValidationError::Invalid(
+                        COMMENT_PREFIX.to_string(),
+                        String::from("comment is longer than 100 characters"),
+                    ));
                 }
-                true
+                Ok(())
             },
         );
 
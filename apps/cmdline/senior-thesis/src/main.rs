@@ -9,7 +9,7 @@
 
 // scuba related imports
 use tank::client::TankClient;
-use tank::data::ScubaData;
+use tank::data::{ScubaData, ValidationError};
 // command line imports
 use reedline_repl_rs::clap::{Arg, ArgMatches, Command};
 use reedline_repl_rs::Repl;
@@ -246,6 +246,19 @@ enum Vote {
     No,
 }
 
+// helpers for client side write checks
+fn invalid(data_type: &str, reason: &str) -> ValidationError {
+    ValidationError::Invalid(data_type.to_string(), reason.to_string())
+}
+
+fn parse<'a, T: Deserialize<'a>>(
+    data_type: &str,
+    data_val: &'a String,
+) -> Result<T, ValidationError> {
+    serde_json::from_str::<T>(data_val)
+        .map_err(|err| invalid(data_type, &err.to_string()))
+}
+
 // application instance
 struct ProtestApp {
     client: TankClient,
@@ -418,121 +431,118 @@ impl ProtestApp {
         // write check for agent_list (no alias repeats in agent list)
         data_store_guard.validator().set_validate_callback_for_type(
             String::from("agent_list"),
-            |_, val| {
-                let agent_list = match serde_json::from_str::<AgentList>(val.data_val()) {
-                    Ok(agent_list) => agent_list,
-                    Err(_) => return false,
-                };
+            |_, _, val| {
+                let agent_list = parse::<AgentList>("agent_list", val.data_val())?;
                 let follower_aliases: Vec<String> =
                     agent_list.follower_list.keys().cloned().collect();
                 if follower_aliases.contains(&agent_list.coordinator.alias) {
-                    return false;
+                    return Err(invalid("agent_list", "coordinator alias repeated"));
                 }
-                return true;
+                return Ok(());
             },
         );
         // write check for join team request (nothing in particular necessary)
         data_store_guard.validator().set_validate_callback_for_type(
             String::from("join_team_request"),
-            |_, val| {
-                match serde_json::from_str::<JoinTeamRequest>(val.data_val()) {
-                    Ok(_) => return true,
-                    Err(_) => return false,
-                };
+            |_, _, val| {
+                parse::<JoinTeamRequest>("join_team_request", val.data_val())?;
+                return Ok(());
             },
         );
         // write check for committed operations list (make sure dates are monotonic)
         data_store_guard.validator().set_validate_callback_for_type(
             String::from("committed_operations_list"),
-            |_, val| {
-                let committed_operations_list = match serde_json::from_str::<
-                    Vec<OperationProposal>,
-                >(val.data_val())
-                {
-                    Ok(committed_operations_list) => committed_operations_list,
-                    Err(_) => return false,
-                };
+            |_, _, val| {
+                let committed_operations_list = parse::<Vec<OperationProposal>>(
+                    "committed_operations_list",
+                    val.data_val(),
+                )?;
                 for operation in &committed_operations_list {
                     if !(operation.start_time <= operation.end_time) {
-                        return false;
+                        return Err(invalid(
+                            "committed_operations_list",
+                            "operation ends before it starts",
+                        ));
                     }
                 }
                 for i in 1..committed_operations_list.len() {
                     if !(committed_operations_list[i - 1].end_time
                         <= committed_operations_list[i].start_time)
                     {
-                        return false;
+                        return Err(invalid(
+                            "committed_operations_list",
+                            "operations overlap",
+                        ));
                     }
                 }
 
-                return true;
+                return Ok(());
             },
         );
         // write check for private messages (dates are monotonic + correct timestamp)
         data_store_guard.validator().set_validate_callback_for_type(
             String::from("private_messages"),
-            |_, val| {
+            |_, _, val| {
                 let private_message_chain =
-                    match serde_json::from_str::<PrivateMessageChain>(val.data_val()) {
-                        Ok(private_message_chain) => private_message_chain,
-                        Err(_) => return false,
-                    };
+                    parse::<PrivateMessageChain>("private_messages", val.data_val())?;
                 if !private_message_chain.message_chain.is_empty() {
                     if private_message_chain.message_chain
                         [private_message_chain.message_chain.len() - 1]
                         .time_stamp
                         != private_message_chain.last_message_time_stamp
                     {
-                        return false;
+                        return Err(invalid(
+                            "private_messages",
+                            "last message timestamp is out of date",
+                        ));
                     }
                 }
                 for i in 1..private_message_chain.message_chain.len() {
                     if !(private_message_chain.message_chain[i - 1].time_stamp
                         <= private_message_chain.message_chain[i].time_stamp)
                     {
-                        return false;
+                        return Err(invalid(
+                            "private_messages",
+                            "timestamps are not monotonic",
+                        ));
                     }
                 }
-                return true;
+                return Ok(());
             },
         );
         // write check for public messages (dates are monotonic + correct timestamp)
         data_store_guard.validator().set_validate_callback_for_type(
             String::from("public_messages"),
-            |_, val| {
+            |_, _, val| {
                 let public_message_chain =
-                    match serde_json::from_str::<PublicMessageChain>(val.data_val()) {
-                        Ok(public_message_chain) => public_message_chain,
-                        Err(_) => return false,
-                    };
+                    parse::<PublicMessageChain>("public_messages", val.data_val())?;
                 for i in 1..public_message_chain.message_chain.len() {
                     if !(public_message_chain.message_chain[i - 1].time_stamp
                         <= public_message_chain.message_chain[i].time_stamp)
                     {
-                        return false;
+                        return Err(invalid(
+                            "public_messages",
+                            "timestamps are not monotonic",
+                        ));
                     }
                 }
-                return true;
+                return Ok(());
             },
         );
         // write check for location database (nothing in particular necessary)
         data_store_guard.validator().set_validate_callback_for_type(
             String::from("location_database"),
-            |_, val| {
-                match serde_json::from_str::<Vec<Location>>(val.data_val()) {
-                    Ok(_) => return true,
-                    Err(_) => return false,
-                };
+            |_, _, val| {
+                parse::<Vec<Location>>("location_database", val.data_val())?;
+                return Ok(());
             },
         );
         // write check for operation proposal (nothing in particular necessary)
         data_store_guard.validator().set_validate_callback_for_type(
             String::from("operation_proposal"),
-            |_, val| {
-                match serde_json::from_str::<OperationProposal>(val.data_val()) {
-                    Ok(_) => return true,
-                    Err(_) => return false,
-                };
+            |_, _, val| {
+                parse::<OperationProposal>("operation_proposal", val.data_val())?;
+                return Ok(());
             },
         );
     }
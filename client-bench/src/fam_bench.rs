@@ -11,7 +11,7 @@ use std::sync::Arc;
 use std::time::Instant;
 use tank::client::Error;
 use tank::client::TankClient;
-use tank::data::ScubaData;
+use tank::data::{ScubaData, ValidationError};
 use tokio::sync::{Mutex, RwLock};
 use uuid::Uuid;
 
@@ -137,12 +137,15 @@ impl FamilyApp {
         let mut data_store_guard = device_guard.as_ref().unwrap().data_store.write();
         data_store_guard.validator().set_validate_callback_for_type(
             POST_PREFIX.to_string(),
-            |_, val| {
+            |_, _, val| {
                 let post: Post = serde_json::from_str(val.data_val()).unwrap();
                 if post.contents.len() > MAX_CHAR {
-                    return false;
+                    return Err(ValidationError::Invalid(
+                        POST_PREFIX.to_string(),
+                        format!("post is longer than {} characters", MAX_CHAR),
+                    ));
                 }
-                true
+                Ok(())
             },
         );
     }
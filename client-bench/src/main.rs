@@ -1,6 +1,7 @@
 use std::env;
 
 mod fam_bench;
+mod membership_bench;
 mod pass_bench;
 
 #[tokio::main]
@@ -13,6 +14,9 @@ async fn main() {
     } else if args.len() > 1 && args[1].eq("fam") {
         println!("Running fam_bench");
         fam_bench::run().await;
+    } else if args.len() > 1 && args[1].eq("membership") {
+        println!("Running membership_bench");
+        membership_bench::run().await;
     } else {
         println!("Running nothing");
     }
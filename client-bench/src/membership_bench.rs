@@ -0,0 +1,98 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use tank::metadata::{Group, MetadataStore};
+
+// Org of NUM_TEAMS teams nested NUM_LEVELS deep below a root group, with
+// DEVICES_PER_TEAM devices at each leaf team
+const NUM_TEAMS: usize = 8;
+const NUM_LEVELS: usize = 3;
+const DEVICES_PER_TEAM: usize = 16;
+const NUM_QUERIES: usize = 1000;
+
+fn add_group(meta_store: &mut MetadataStore, group_id: &String, is_device: bool) {
+    let children = if is_device { None } else { Some(None) };
+    meta_store.set_group(
+        group_id.clone(),
+        Group::new(Some(group_id.clone()), None, false, children),
+    );
+}
+
+fn add_teams(
+    meta_store: &mut MetadataStore,
+    parent_id: &String,
+    level: usize,
+    devices: &mut Vec<String>,
+) {
+    for team in 0..NUM_TEAMS {
+        let team_id = format!("{}/{}", parent_id, team);
+        add_group(meta_store, &team_id, false);
+        meta_store.link_groups(parent_id, &team_id).unwrap();
+        if level + 1 < NUM_LEVELS {
+            add_teams(meta_store, &team_id, level + 1, devices);
+        } else {
+            for device in 0..DEVICES_PER_TEAM {
+                let device_id = format!("{}/device{}", team_id, device);
+                add_group(meta_store, &device_id, true);
+                meta_store.link_groups(&team_id, &device_id).unwrap();
+                devices.push(device_id);
+            }
+        }
+    }
+}
+
+// Times NUM_QUERIES permission-style lookups against the root group, with a
+// group change every change_every queries (0 for none)
+fn time_queries(
+    meta_store: &mut MetadataStore,
+    root_id: &String,
+    devices: &Vec<String>,
+    change_every: usize,
+) -> Duration {
+    let start = Instant::now();
+    let mut resolved = HashSet::new();
+    for i in 0..NUM_QUERIES {
+        if change_every != 0 && i % change_every == 0 {
+            let team_id = format!("{}/{}", root_id, i % NUM_TEAMS);
+            let extra_id = format!("extra{}", i);
+            add_group(meta_store, &extra_id, true);
+            meta_store.link_groups(&team_id, &extra_id).unwrap();
+        }
+        let device_id = &devices[i * 7919 % devices.len()];
+        assert!(meta_store.is_group_member(device_id, root_id, &None));
+        resolved = meta_store.resolve_group_ids(vec![root_id]);
+    }
+    assert!(resolved.len() >= devices.len());
+    start.elapsed()
+}
+
+pub async fn run() {
+    let root_id = String::from("org");
+    let mut meta_store = MetadataStore::new();
+    let mut devices = Vec::new();
+    add_group(&mut meta_store, &root_id, false);
+    add_teams(&mut meta_store, &root_id, 0, &mut devices);
+    println!("{} devices, {} queries per run", devices.len(), NUM_QUERIES);
+
+    for change_every in [0, 100, 10] {
+        let mut uncached = meta_store.clone();
+        uncached.disable_membership_cache();
+        let uncached_time = time_queries(&mut uncached, &root_id, &devices, change_every);
+
+        let mut cached = meta_store.clone();
+        cached.enable_membership_cache();
+        let cached_time = time_queries(&mut cached, &root_id, &devices, change_every);
+
+        let label = if change_every == 0 {
+            String::from("no group changes")
+        } else {
+            format!("a group change every {} queries", change_every)
+        };
+        println!(
+            "{}: uncached {:?}, cached {:?}, speedup {:.1}x",
+            label,
+            uncached_time,
+            cached_time,
+            uncached_time.as_secs_f64() / cached_time.as_secs_f64()
+        );
+    }
+}
@@ -134,6 +134,14 @@ impl<C: CoreClient> Core<C> {
         self.crypto.get_idkey()
     }
 
+    pub fn signing_key(&self) -> String {
+        self.crypto.get_signing_key()
+    }
+
+    pub fn sign(&self, message: &str) -> String {
+        self.crypto.sign(message)
+    }
+
     pub async fn send_message(
         &self,
         series: Vec<(Vec<std::string::String>, std::string::String, bool)>,
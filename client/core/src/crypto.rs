@@ -2,6 +2,7 @@ use crate::server_comm::ServerComm;
 use async_condvar_fair::Condvar;
 use olm_rs::account::{IdentityKeys, OlmAccount, OneTimeKeys};
 use olm_rs::session::{OlmMessage, OlmSession, PreKeyMessage};
+use olm_rs::utility::OlmUtility;
 use parking_lot::Mutex;
 use rand::RngCore;
 use std::collections::{HashMap, VecDeque};
@@ -115,6 +116,15 @@ impl Crypto {
         self.idkeys.curve25519().to_string()
     }
 
+    // ed25519 key that verifies this device's signatures
+    pub fn get_signing_key(&self) -> String {
+        self.idkeys.ed25519().to_string()
+    }
+
+    pub fn sign(&self, message: &str) -> String {
+        self.account.lock().sign(message)
+    }
+
     async fn new_outbound_session<S: ServerComm>(
         &self,
         server_comm: &S,
@@ -340,9 +350,15 @@ impl Crypto {
     }
 }
 
+pub fn verify_signature(signing_key: &str, message: &str, signature: &str) -> bool {
+    OlmUtility::new()
+        .ed25519_verify(signing_key, message, signature.to_string())
+        .unwrap_or(false)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Crypto, NUM_OTKEYS};
+    use super::{verify_signature, Crypto, NUM_OTKEYS};
     use crate::core::stream_client::StreamClient;
     use std::sync::Arc;
 
@@ -358,6 +374,22 @@ mod tests {
         println!("idkey: {:?}", crypto.get_idkey());
     }
 
+    #[test]
+    fn test_sign() {
+        let crypto = Crypto::new(false);
+        let signature = crypto.sign("hello");
+        assert!(verify_signature(
+            &crypto.get_signing_key(),
+            "hello",
+            &signature
+        ));
+        assert!(!verify_signature(
+            &crypto.get_signing_key(),
+            "hell0",
+            &signature
+        ));
+    }
+
     #[test]
     fn test_gen_otkeys() {
         let crypto = Crypto::new(false);
@@ -0,0 +1,147 @@
+use crate::metadata::MetadataStore;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use thiserror::Error;
+
+/*
+ * Devices whose operations TankClient drops on arrival, before any
+ * permission checks. Besides single devices, whole groups (e.g. a contact's
+ * linked group) can be blocked, which also covers devices that join the
+ * group later. The list can be persisted to a JSON file so it survives
+ * restarts.
+ */
+
+#[derive(Debug, PartialEq, Error)]
+pub enum Error {
+    #[error("Cannot read or write block list file: {0}")]
+    Io(String),
+    #[error("Cannot convert block list to or from string: {0}")]
+    Serialization(String),
+}
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct BlockList {
+    idkeys: HashSet<String>,
+    groups: HashSet<String>,
+}
+
+impl BlockList {
+    pub fn new() -> BlockList {
+        Self::default()
+    }
+
+    // a missing file is treated as an empty block list
+    pub fn load(path: &String) -> Result<BlockList, Error> {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| Error::Serialization(e.to_string())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(BlockList::new()),
+            Err(err) => Err(Error::Io(err.to_string())),
+        }
+    }
+
+    pub fn save(&self, path: &String) -> Result<(), Error> {
+        let contents = serde_json::to_string(self)
+            .map_err(|e| Error::Serialization(e.to_string()))?;
+        fs::write(path, contents).map_err(|e| Error::Io(e.to_string()))
+    }
+
+    pub fn blocked_devices(&self) -> &HashSet<String> {
+        &self.idkeys
+    }
+
+    pub fn blocked_groups(&self) -> &HashSet<String> {
+        &self.groups
+    }
+
+    // each of these returns whether the block list changed
+    pub fn block_device(&mut self, idkey: String) -> bool {
+        self.idkeys.insert(idkey)
+    }
+
+    pub fn unblock_device(&mut self, idkey: &String) -> bool {
+        self.idkeys.remove(idkey)
+    }
+
+    pub fn block_group(&mut self, group_id: String) -> bool {
+        self.groups.insert(group_id)
+    }
+
+    pub fn unblock_group(&mut self, group_id: &String) -> bool {
+        self.groups.remove(group_id)
+    }
+
+    pub fn is_blocked(&self, idkey: &String, meta_store: Option<&MetadataStore>) -> bool {
+        if self.idkeys.contains(idkey) {
+            return true;
+        }
+        match meta_store {
+            Some(meta_store) => self
+                .groups
+                .iter()
+                .any(|group_id| meta_store.is_group_member(idkey, group_id, &None)),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blocklist::BlockList;
+    use crate::metadata::{Group, MetadataStore};
+
+    #[test]
+    fn test_block_devices_and_groups() {
+        let mut meta_store = MetadataStore::new();
+        let group_id = String::from("contact");
+        let idkey_0 = String::from("0");
+        let idkey_1 = String::from("1");
+        meta_store.set_group(
+            group_id.clone(),
+            Group::new(Some(group_id.clone()), None, true, Some(None)),
+        );
+        meta_store.set_group(
+            idkey_1.clone(),
+            Group::new(Some(idkey_1.clone()), None, false, None),
+        );
+        meta_store.link_groups(&group_id, &idkey_1).unwrap();
+
+        let mut block_list = BlockList::new();
+        assert!(block_list.block_device(idkey_0.clone()));
+        assert!(!block_list.block_device(idkey_0.clone()));
+        assert!(block_list.is_blocked(&idkey_0, None));
+        assert!(!block_list.is_blocked(&idkey_1, Some(&meta_store)));
+
+        block_list.block_group(group_id.clone());
+        assert!(block_list.is_blocked(&idkey_1, Some(&meta_store)));
+        // group membership is only known with a metadata store
+        assert!(!block_list.is_blocked(&idkey_1, None));
+
+        assert!(block_list.unblock_device(&idkey_0));
+        assert!(block_list.unblock_group(&group_id));
+        assert!(!block_list.is_blocked(&idkey_0, Some(&meta_store)));
+        assert!(!block_list.is_blocked(&idkey_1, Some(&meta_store)));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir()
+            .join(format!(
+                "blocklist-{}.json",
+                crate::metadata::generate_uuid()
+            ))
+            .to_string_lossy()
+            .to_string();
+        assert_eq!(BlockList::load(&path), Ok(BlockList::new()));
+
+        let mut block_list = BlockList::new();
+        block_list.block_device(String::from("0"));
+        block_list.block_group(String::from("contact"));
+        block_list.save(&path).unwrap();
+        assert_eq!(BlockList::load(&path), Ok(block_list));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bincode::Options;
+use scuba_core::crypto::verify_signature;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::invite::now_secs;
+
+/*
+ * Capability tokens let whoever holds them become a reader or writer of a
+ * data object without first exchanging contacts. A token names the device
+ * that issued it, the data object and role it grants, and when it expires,
+ * and is signed with the issuing device's signing key so that none of these
+ * can be changed. Only the issuing device can check the signature: the
+ * token does not carry the signing key, since a key taken from the token
+ * itself would prove nothing about who made it. Redeeming a token sends it
+ * back to the issuing device, which checks the signature against its own key
+ * and that it issued the token and has not revoked it, then adds the holder
+ * as a contact and shares the data object with them. Unlike invites, tokens
+ * can be redeemed any number of times until they expire. The tokens a device
+ * has issued can be persisted to a JSON file so they survive restarts.
+ */
+
+pub const DEFAULT_CAPABILITY_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+const CODE_PREFIX: &str = "scubacap1.";
+
+#[derive(Debug, PartialEq, Error)]
+pub enum Error {
+    #[error("Capability token is malformed")]
+    Malformed,
+    #[error("Capability token signature is invalid")]
+    BadSignature,
+    #[error("Capability token expired at {0}")]
+    Expired(u64),
+    #[error("Cannot read or write issued capabilities file: {0}")]
+    Io(String),
+    #[error("Cannot convert issued capabilities to or from string: {0}")]
+    Serialization(String),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum CapabilityRole {
+    Reader,
+    Writer,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Capability {
+    pub token_id: String,
+    // idkey of the issuing device
+    pub issuer: String,
+    pub data_id: String,
+    pub role: CapabilityRole,
+    // seconds since the Unix epoch
+    pub expires_at: u64,
+    pub signature: String,
+}
+
+impl Capability {
+    pub fn new(
+        issuer: String,
+        data_id: String,
+        role: CapabilityRole,
+        ttl_secs: u64,
+        sign: impl Fn(&str) -> String,
+    ) -> Capability {
+        let mut capability = Capability {
+            token_id: Uuid::new_v4().simple().to_string(),
+            issuer,
+            data_id,
+            role,
+            expires_at: now_secs().saturating_add(ttl_secs),
+            signature: String::new(),
+        };
+        capability.signature = sign(&capability.signed_message());
+        capability
+    }
+
+    // everything but the signature itself
+    fn signed_message(&self) -> String {
+        serde_json::to_string(&(
+            &self.token_id,
+            &self.issuer,
+            &self.data_id,
+            &self.role,
+            self.expires_at,
+        ))
+        .unwrap()
+    }
+
+    pub fn code(&self) -> String {
+        let bytes = bincode::DefaultOptions::new().serialize(self).unwrap();
+        format!("{}{}", CODE_PREFIX, URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    // parses code() output and checks the expiry; the signature is checked
+    // by the issuer on redemption (see verify())
+    pub fn parse(text: &str) -> Result<Capability, Error> {
+        let code = text
+            .trim()
+            .strip_prefix(CODE_PREFIX)
+            .ok_or(Error::Malformed)?;
+        let bytes = URL_SAFE_NO_PAD.decode(code).map_err(|_| Error::Malformed)?;
+        let capability: Capability = bincode::DefaultOptions::new()
+            .deserialize(&bytes)
+            .map_err(|_| Error::Malformed)?;
+        capability.check_expiry(now_secs())?;
+        Ok(capability)
+    }
+
+    // signing_key must be the issuer's own key, not one the holder supplied
+    pub fn verify(&self, signing_key: &str, now: u64) -> Result<(), Error> {
+        if !verify_signature(signing_key, &self.signed_message(), &self.signature) {
+            return Err(Error::BadSignature);
+        }
+        self.check_expiry(now)
+    }
+
+    fn check_expiry(&self, now: u64) -> Result<(), Error> {
+        if now > self.expires_at {
+            return Err(Error::Expired(self.expires_at));
+        }
+        Ok(())
+    }
+}
+
+// Unrevoked tokens issued by a device, by token_id, with their expiry
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct IssuedCapabilities {
+    expiries: HashMap<String, u64>,
+}
+
+impl IssuedCapabilities {
+    pub fn new() -> IssuedCapabilities {
+        Self::default()
+    }
+
+    // a missing file is treated as no issued tokens
+    pub fn load(path: &String) -> Result<IssuedCapabilities, Error> {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| Error::Serialization(e.to_string())),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                Ok(IssuedCapabilities::new())
+            }
+            Err(err) => Err(Error::Io(err.to_string())),
+        }
+    }
+
+    pub fn save(&self, path: &String) -> Result<(), Error> {
+        let contents = serde_json::to_string(self)
+            .map_err(|e| Error::Serialization(e.to_string()))?;
+        fs::write(path, contents).map_err(|e| Error::Io(e.to_string()))
+    }
+
+    // also drops tokens that expired before now
+    pub fn issue(&mut self, token_id: String, expires_at: u64, now: u64) {
+        self.expiries.retain(|_, expires_at| *expires_at >= now);
+        self.expiries.insert(token_id, expires_at);
+    }
+
+    pub fn revoke(&mut self, token_id: &String) -> bool {
+        self.expiries.remove(token_id).is_some()
+    }
+
+    pub fn is_issued(&self, token_id: &String) -> bool {
+        self.expiries.contains_key(token_id)
+    }
+
+    pub fn merge(&mut self, other: IssuedCapabilities) {
+        self.expiries.extend(other.expiries);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::capability::{Capability, CapabilityRole, Error, IssuedCapabilities};
+    use crate::invite::now_secs;
+    use scuba_core::crypto::Crypto;
+
+    fn test_capability(crypto: &Crypto, ttl_secs: u64) -> Capability {
+        Capability::new(
+            crypto.get_idkey(),
+            "data".to_string(),
+            CapabilityRole::Reader,
+            ttl_secs,
+            |message| crypto.sign(message),
+        )
+    }
+
+    #[test]
+    fn test_code_round_trip() {
+        let crypto = Crypto::new(false);
+        let capability = test_capability(&crypto, 60);
+        assert_eq!(Capability::parse(&capability.code()), Ok(capability));
+    }
+
+    #[test]
+    fn test_bad_capabilities() {
+        let crypto = Crypto::new(false);
+        assert_eq!(Capability::parse("scubacap1.!!!"), Err(Error::Malformed));
+
+        // escalating the role invalidates the signature
+        let signing_key = crypto.get_signing_key();
+        let mut capability = test_capability(&crypto, 60);
+        assert_eq!(capability.verify(&signing_key, now_secs()), Ok(()));
+        capability.role = CapabilityRole::Writer;
+        assert_eq!(
+            capability.verify(&signing_key, now_secs()),
+            Err(Error::BadSignature)
+        );
+
+        // a token signed by anyone but the issuer is rejected by the issuer
+        let forger = Crypto::new(false);
+        let mut forged = test_capability(&forger, 60);
+        forged.issuer = crypto.get_idkey();
+        assert_eq!(
+            forged.verify(&signing_key, now_secs()),
+            Err(Error::BadSignature)
+        );
+
+        let capability = test_capability(&crypto, 0);
+        assert_eq!(
+            capability.verify(&signing_key, now_secs() + 1),
+            Err(Error::Expired(capability.expires_at))
+        );
+    }
+
+    #[test]
+    fn test_issued_capabilities_file() {
+        let path = std::env::temp_dir()
+            .join(format!("tank-capabilities-{}.json", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(
+            IssuedCapabilities::load(&path),
+            Ok(IssuedCapabilities::new())
+        );
+
+        let mut issued = IssuedCapabilities::new();
+        issued.issue("expired".to_string(), 5, 0);
+        issued.issue("revoked".to_string(), 20, 0);
+        issued.issue("token".to_string(), 20, 10);
+        assert!(!issued.is_issued(&"expired".to_string()));
+        assert!(issued.revoke(&"revoked".to_string()));
+        assert!(!issued.revoke(&"revoked".to_string()));
+        issued.save(&path).unwrap();
+
+        let loaded = IssuedCapabilities::load(&path).unwrap();
+        assert!(loaded.is_issued(&"token".to_string()));
+        assert!(!loaded.is_issued(&"revoked".to_string()));
+        assert_eq!(loaded, issued);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,382 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+ * Dependency tracking for causally consistent data updates. Every causal
+ * update is tagged with a dot (writing device, per-device counter), the
+ * devices it is sent to, and the dots of the updates the writer had applied
+ * that all of those devices were also sent. A recipient holds an update back
+ * until it has applied all of those, so e.g. a reply never shows up before
+ * the message it answers, while reads stay local. Updates that some
+ * recipient never got (e.g. ones from before it was added as a reader, which
+ * it only gets as a plain UpdateData) are not dependencies, since waiting on
+ * them would hold the update back forever.
+ *
+ * Dots must never be reused, including after the writing device restarts,
+ * or recipients would take the dependency on a new update as met by an old
+ * one. Counters therefore start at the current time in microseconds rather
+ * than at 0, which stays ahead of the last run's counter unless that run
+ * wrote more than one update per microsecond or the clock is set back.
+ *
+ * Since a device only gets the updates sent to it, the counters it sees from
+ * one origin have gaps. Every update therefore also names, for each
+ * recipient, the counter of the origin's previous update to that recipient.
+ * Following that chain, a recipient keeps only the highest counter up to
+ * which it applied every update sent to it, plus the (few) updates that
+ * arrived ahead of an earlier one, rather than every dot it ever applied.
+ */
+
+// updates held back at most; past this, the oldest is applied without
+// waiting for the rest of its dependencies
+pub const MAX_PENDING: usize = 10000;
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub struct Dot {
+    pub origin: String,
+    pub ctr: u64,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CausalMeta {
+    pub dot: Dot,
+    pub deps: Vec<Dot>,
+    // devices the update was sent to
+    #[serde(default)]
+    pub recipients: Vec<String>,
+    // recipient -> ctr of the origin's previous update to it, if any since
+    // the origin started
+    #[serde(default)]
+    pub prev: HashMap<String, u64>,
+}
+
+// The updates from one origin a device has applied (or given up on)
+#[derive(Debug, Default, PartialEq, Clone)]
+struct Applied {
+    // every update up to this ctr
+    floor: u64,
+    // updates that arrived before the one sent to this device before them,
+    // by the ctr of that earlier update
+    ahead: HashMap<u64, u64>,
+}
+
+impl Applied {
+    fn contains(&self, ctr: u64) -> bool {
+        ctr <= self.floor || self.ahead.values().any(|&other| other == ctr)
+    }
+
+    fn insert(&mut self, ctr: u64, prev: Option<u64>) -> bool {
+        if self.contains(ctr) {
+            return false;
+        }
+        match prev {
+            Some(prev) if prev != self.floor => {
+                self.ahead.insert(prev, ctr);
+                return true;
+            }
+            // the next one, or the first since the origin restarted
+            _ => self.floor = ctr,
+        }
+        while let Some(next) = self.ahead.remove(&self.floor) {
+            self.floor = next;
+        }
+        let floor = self.floor;
+        self.ahead.retain(|_, ctr| *ctr > floor);
+        true
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CausalState<T> {
+    next_ctr: u64,
+    // recipient -> ctr of the last update this device sent to it
+    last_sent: HashMap<String, u64>,
+    // origin -> updates from it this device has applied (or given up on)
+    applied: HashMap<String, Applied>,
+    // data id -> dot of the latest update to it this device knows of, and
+    // the devices that update was sent to
+    latest: HashMap<String, (Dot, HashSet<String>)>,
+    // updates waiting on dependencies, in arrival order
+    pending: Vec<(CausalMeta, T)>,
+}
+
+impl<T> CausalState<T> {
+    pub fn new() -> CausalState<T> {
+        let now_micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_micros() as u64);
+        CausalState {
+            next_ctr: now_micros,
+            last_sent: HashMap::new(),
+            applied: HashMap::new(),
+            latest: HashMap::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    // Tags a new update to data_id by origin that is sent to recipients.
+    // Dependencies are the latest updates to each data id that were sent to
+    // all of recipients.
+    pub fn new_update(
+        &mut self,
+        origin: &String,
+        data_id: &String,
+        recipients: &Vec<String>,
+    ) -> CausalMeta {
+        let dot = Dot {
+            origin: origin.to_string(),
+            ctr: self.next_ctr,
+        };
+        self.next_ctr += 1;
+        let deps = self
+            .latest
+            .values()
+            .filter(|(_, received_by)| {
+                recipients.iter().all(|idkey| received_by.contains(idkey))
+            })
+            .map(|(dep, _)| dep.clone())
+            .collect();
+        let prev = recipients
+            .iter()
+            .filter_map(|idkey| {
+                let prev = self.last_sent.insert(idkey.clone(), dot.ctr)?;
+                Some((idkey.clone(), prev))
+            })
+            .collect();
+        let meta = CausalMeta {
+            dot,
+            deps,
+            recipients: recipients.clone(),
+            prev,
+        };
+        // later updates by this device depend on this one even before it has
+        // come back from the server
+        self.set_latest(data_id, &meta);
+        meta
+    }
+
+    fn set_latest(&mut self, data_id: &String, meta: &CausalMeta) {
+        self.latest.insert(
+            data_id.to_string(),
+            (meta.dot.clone(), meta.recipients.iter().cloned().collect()),
+        );
+    }
+
+    fn is_applied(&self, dot: &Dot) -> bool {
+        self.applied
+            .get(&dot.origin)
+            .map_or(false, |applied| applied.contains(dot.ctr))
+    }
+
+    pub fn is_ready(&self, meta: &CausalMeta) -> bool {
+        meta.deps.iter().all(|dep| self.is_applied(dep))
+    }
+
+    // Holds an update back until its dependencies are applied. If MAX_PENDING
+    // updates are already held, removes and returns the oldest, which the
+    // caller should apply (and mark applied) as is.
+    pub fn buffer(&mut self, meta: CausalMeta, item: T) -> Option<(CausalMeta, T)> {
+        self.pending.push((meta, item));
+        if self.pending.len() > MAX_PENDING {
+            return Some(self.pending.remove(0));
+        }
+        None
+    }
+
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    // idkey is that of the device applying the update
+    pub fn mark_applied(&mut self, idkey: &String, data_id: &String, meta: &CausalMeta) {
+        let dot = &meta.dot;
+        let prev = meta.prev.get(idkey).copied();
+        let newly_applied = match self.applied.get_mut(&dot.origin) {
+            Some(applied) => applied.insert(dot.ctr, prev),
+            // updates from before this device started tracking the origin
+            // (e.g. before it restarted) cannot be waited on
+            None => {
+                let mut applied = Applied {
+                    floor: prev.unwrap_or(0),
+                    ..Applied::default()
+                };
+                applied.insert(dot.ctr, prev);
+                self.applied.insert(dot.origin.clone(), applied);
+                true
+            }
+        };
+        if newly_applied {
+            match self.latest.get(data_id) {
+                // do not replace a newer local update that is still on its
+                // way back from the server
+                Some((latest, _))
+                    if latest.origin == dot.origin && latest.ctr > dot.ctr => {}
+                _ => self.set_latest(data_id, meta),
+            }
+        }
+    }
+
+    // Removes and returns the buffered updates whose dependencies have all
+    // been applied, in arrival order. Applying them may make more updates
+    // ready, so callers should repeat until this returns nothing.
+    pub fn take_ready(&mut self) -> Vec<(CausalMeta, T)> {
+        let (ready, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|(meta, _)| self.is_ready(meta));
+        self.pending = pending;
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::causal::{CausalState, Dot, MAX_PENDING};
+
+    #[test]
+    fn test_reply_waits_for_message() {
+        let alice = String::from("alice");
+        let bob = String::from("bob");
+        let carol = String::from("carol");
+        let everyone = vec![alice.clone(), bob.clone(), carol.clone()];
+        let msg_id = String::from("msg");
+        let reply_id = String::from("reply");
+
+        let mut alice_state = CausalState::<()>::new();
+        let mut bob_state = CausalState::<()>::new();
+        let mut carol_state = CausalState::<String>::new();
+
+        let msg = alice_state.new_update(&alice, &msg_id, &everyone);
+        assert!(msg.deps.is_empty());
+        bob_state.mark_applied(&bob, &msg_id, &msg);
+        let reply = bob_state.new_update(&bob, &reply_id, &everyone);
+        assert_eq!(reply.deps, vec![msg.dot.clone()]);
+
+        // carol receives the reply first
+        assert!(!carol_state.is_ready(&reply));
+        carol_state.buffer(reply.clone(), reply_id.clone());
+        assert!(carol_state.take_ready().is_empty());
+
+        assert!(carol_state.is_ready(&msg));
+        carol_state.mark_applied(&carol, &msg_id, &msg);
+        let ready = carol_state.take_ready();
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].1, reply_id);
+        assert_eq!(carol_state.num_pending(), 0);
+    }
+
+    #[test]
+    fn test_unshared_data_is_not_a_dependency() {
+        let alice = String::from("alice");
+        let bob = String::from("bob");
+        let private_id = String::from("private");
+        let shared_id = String::from("shared");
+
+        let mut state = CausalState::<()>::new();
+        let private = state.new_update(&alice, &private_id, &vec![alice.clone()]);
+        state.mark_applied(&alice, &private_id, &private);
+        let shared =
+            state.new_update(&alice, &shared_id, &vec![alice.clone(), bob.clone()]);
+        assert!(shared.deps.is_empty());
+
+        // but earlier updates to the same data are
+        let shared_2 =
+            state.new_update(&alice, &shared_id, &vec![alice.clone(), bob.clone()]);
+        assert_eq!(
+            shared_2.deps,
+            vec![Dot {
+                origin: alice.clone(),
+                ctr: shared.dot.ctr
+            }]
+        );
+    }
+
+    #[test]
+    fn test_new_reader_does_not_wait_for_old_updates() {
+        let alice = String::from("alice");
+        let bob = String::from("bob");
+        let data_id = String::from("data");
+
+        let mut state = CausalState::<()>::new();
+        // bob is only added as a reader after the first update
+        let before = state.new_update(&alice, &data_id, &vec![alice.clone()]);
+        let after = state.new_update(&alice, &data_id, &vec![alice.clone(), bob.clone()]);
+        assert!(after.deps.is_empty());
+        assert!(CausalState::<()>::new().is_ready(&after));
+
+        // updates bob did get are still dependencies
+        let later = state.new_update(&alice, &data_id, &vec![alice.clone(), bob.clone()]);
+        assert_eq!(later.deps, vec![after.dot.clone()]);
+        assert!(before.dot.ctr < after.dot.ctr);
+    }
+
+    #[test]
+    fn test_dots_are_not_reused_after_restart() {
+        let alice = String::from("alice");
+        let data_id = String::from("data");
+        let recipients = vec![alice.clone()];
+
+        let mut first_run = CausalState::<()>::new();
+        let mut last = first_run.new_update(&alice, &data_id, &recipients);
+        for _ in 0..10 {
+            last = first_run.new_update(&alice, &data_id, &recipients);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let restarted =
+            CausalState::<()>::new().new_update(&alice, &data_id, &recipients);
+        assert!(restarted.dot.ctr > last.dot.ctr);
+    }
+
+    #[test]
+    fn test_applied_is_pruned() {
+        let alice = String::from("alice");
+        let bob = String::from("bob");
+        let carol = String::from("carol");
+        let data_id = String::from("data");
+
+        let mut alice_state = CausalState::<()>::new();
+        let mut bob_state = CausalState::<()>::new();
+        let to_bob = vec![alice.clone(), bob.clone()];
+        let to_carol = vec![alice.clone(), carol.clone()];
+
+        // bob does not see the updates alice sends carol alone
+        let first = alice_state.new_update(&alice, &data_id, &to_bob);
+        let skipped = alice_state.new_update(&alice, &data_id, &to_carol);
+        let second = alice_state.new_update(&alice, &data_id, &to_bob);
+        let third = alice_state.new_update(&alice, &data_id, &to_bob);
+        assert_eq!(second.prev.get(&bob), Some(&first.dot.ctr));
+        assert_eq!(second.prev.get(&alice), Some(&skipped.dot.ctr));
+
+        // out of order: third arrives before second
+        bob_state.mark_applied(&bob, &data_id, &first);
+        bob_state.mark_applied(&bob, &data_id, &third);
+        assert!(bob_state.is_applied(&third.dot));
+        assert!(!bob_state.is_applied(&second.dot));
+        assert_eq!(bob_state.applied[&alice].ahead.len(), 1);
+
+        bob_state.mark_applied(&bob, &data_id, &second);
+        assert!(bob_state.is_applied(&second.dot));
+        assert_eq!(bob_state.applied[&alice].floor, third.dot.ctr);
+        assert!(bob_state.applied[&alice].ahead.is_empty());
+    }
+
+    #[test]
+    fn test_pending_is_capped() {
+        let alice = String::from("alice");
+        let data_id = String::from("data");
+        let recipients = vec![alice.clone()];
+
+        let mut writer = CausalState::<()>::new();
+        let missing = writer.new_update(&alice, &data_id, &recipients);
+        let waiting = writer.new_update(&alice, &data_id, &recipients);
+        assert_eq!(waiting.deps, vec![missing.dot.clone()]);
+
+        let mut state = CausalState::<usize>::new();
+        for i in 0..MAX_PENDING {
+            assert!(state.buffer(waiting.clone(), i).is_none());
+        }
+        // the oldest is given back once the limit is reached
+        let (_, evicted) = state.buffer(waiting.clone(), MAX_PENDING).unwrap();
+        assert_eq!(evicted, 0);
+        assert_eq!(state.num_pending(), MAX_PENDING);
+    }
+}
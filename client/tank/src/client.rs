@@ -4,9 +4,11 @@ use async_trait::async_trait;
 use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Values;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
+use std::future::Future;
 use std::io::Write;
+use std::ops::Bound::{Excluded, Unbounded};
 use std::sync::Arc;
 use std::time::Instant;
 use std::{thread, time};
@@ -14,9 +16,22 @@ use thiserror::Error;
 
 use scuba_core::core::{Core, CoreClient, SequenceNumber};
 
-use crate::data::{BasicData, ScubaData};
-use crate::devices::Device;
-use crate::metadata::{Group, PermType, PermissionSet};
+use crate::blocklist::BlockList;
+use crate::capability::{self, Capability, CapabilityRole, IssuedCapabilities};
+use crate::causal::{CausalMeta, CausalState};
+use crate::crdt::{self, Crdt};
+use crate::data::DataVersion;
+use crate::data::{BasicData, BinaryVal, Expiry, ScubaData, ValidationError};
+use crate::devices::{Device, PendingContact, PendingShare};
+use crate::export::StateExport;
+use crate::invite::{self, Invite, IssuedInvites};
+use crate::metadata::{
+    Collected, Group, OwnerChange, OwnerProposal, PermChange, PermChangeEntry, PermType,
+    PermissionSet,
+};
+use crate::migration::{self, MigrationMode, Migrations};
+use crate::snapshot::{self, IncomingSnapshot, OutgoingSnapshot, SnapshotChunk};
+use crate::wire::{self, WireFormat};
 
 /*
  * Existing set_*() functions whose writes should abide by consistency
@@ -28,6 +43,8 @@ use crate::metadata::{Group, PermType, PermissionSet};
  * - [x] add_permissions()
  * - [ ] delete_data() (not impl)
  * - [ ] remove_permissions() (not impl)
+ * - [x] remove_contact()
+ * - [x] transfer_ownership()/set_owner_quorum()/approve_owner_change()
  */
 
 /*
@@ -40,9 +57,9 @@ use crate::metadata::{Group, PermType, PermissionSet};
  * - [x] get_group()
  * - [x] get_all_groups()
  *
- * TODO should Operations should be extended to include Get* calls for strict
- * serializability? Otherwise, read-only transactions will not be sent to the
- * server for ordering, which violates strict serializability
+ * NOTE the above Get* calls are not sent to the server for ordering, so they
+ * do not provide strict serializability; use read_only_transaction() for
+ * reads that must be ordered with respect to all other operations
  *
  * TODO also need to modify TankClient configuration to only allow one transaction
  * at a time (for any transactional consistency models)
@@ -79,6 +96,67 @@ pub enum Error {
         #[from]
         source: crate::devices::Error,
     },
+    #[error(transparent)]
+    ValidationErr {
+        #[from]
+        source: crate::data::ValidationError,
+    },
+    #[error(transparent)]
+    CrdtErr {
+        #[from]
+        source: crate::crdt::Error,
+    },
+    #[error(transparent)]
+    SnapshotErr {
+        #[from]
+        source: crate::snapshot::Error,
+    },
+    #[error(transparent)]
+    BlockListErr {
+        #[from]
+        source: crate::blocklist::Error,
+    },
+    #[error(transparent)]
+    ExportErr {
+        #[from]
+        source: crate::export::Error,
+    },
+    #[error(transparent)]
+    InviteErr {
+        #[from]
+        source: crate::invite::Error,
+    },
+    #[error(transparent)]
+    CapabilityErr {
+        #[from]
+        source: crate::capability::Error,
+    },
+    #[error(transparent)]
+    MigrationErr {
+        #[from]
+        source: crate::migration::Error,
+    },
+    #[error(transparent)]
+    WireErr {
+        #[from]
+        source: crate::wire::Error,
+    },
+    #[error("Binary value of {0} cannot be decompressed: {1}")]
+    CorruptBinaryData(String, String),
+    #[error(
+        "{0} is or includes one of this client's own devices; it cannot be blocked."
+    )]
+    CannotBlockOwnDevice(String),
+    #[error("Owner changes to {0} need approval; use transfer_ownership()")]
+    OwnerApprovalRequired(String),
+    #[error("Namespaces cannot be opened from within a namespace.")]
+    NestedNamespace,
+    #[error("Namespace {0} has not been opened.")]
+    NamespaceNotOpen(String),
+    #[error("No device link is in progress.")]
+    NoLinkInProgress,
+    #[error("Over {0} operations arrived before the link snapshot; link again.")]
+    LinkBufferFull(usize),
     #[error("Received error while sending message: {0}.")]
     SendFailed(String),
     #[error("Invalid transaction status")]
@@ -98,11 +176,43 @@ pub enum Error {
 #[derive(Debug, Serialize, Deserialize, Clone)]
 enum Operation {
     UpdateLinked(String, String, HashMap<String, Group>),
+    // the whole state in one message, as sent by devices that predate link
+    // snapshots
     ConfirmUpdateLinked(String, HashMap<String, Group>, HashMap<String, BasicData>),
+    // (linked_name, number of chunks, snapshot hash)
+    LinkSnapshotStart(String, usize, String),
+    LinkSnapshotChunk(SnapshotChunk),
+    // chunks the new device is missing, or None if it has not even received
+    // LinkSnapshotStart
+    LinkSnapshotResume(Option<Vec<usize>>),
+    LinkSnapshotDone,
     AddContact(String, String, HashMap<String, Group>),
     ConfirmAddContact(String, HashMap<String, Group>),
+    // AddContact that uses an invite created by the recipient, so that the
+    // recipient adds the sender without further confirmation; (invite
+    // secret, sender, contact_name, contact_devices)
+    AcceptInvite(String, String, String, HashMap<String, Group>),
+    // AddContact that also redeems a capability token issued by the
+    // recipient; (token, sender, contact_name, contact_devices)
+    RedeemCapability(Capability, String, String, HashMap<String, Group>),
+    // sent to own linked devices; (contact_name)
+    RemoveContact(String),
+    // sent to own linked devices; (perm_id of an incoming share)
+    AcceptShare(String),
+    DeclineShare(String),
+    // sent to the removed contact's devices; (remover's linked_name)
+    ContactRemoved(String),
+    // sent to own linked devices; (idkey or group_id, is_group)
+    Block(String, bool),
+    // (perm_id, members whose grant expires, expiry)
+    SetGrantExpiry(String, PermType, Expiry),
+    Unblock(String, bool),
     SetPerm(String, PermissionSet),
     AddPermMembers(String, Option<String>, PermType),
+    // (proposal_id, perm_id, change); counts as the sender's approval
+    ProposeOwnerChange(String, String, OwnerChange),
+    // (proposal_id)
+    ApproveOwnerChange(String),
     // TODO RemovePermMember
     SetGroup(String, Group),
     SetGroups(HashMap<String, Group>),
@@ -113,34 +223,67 @@ enum Operation {
     //AddChild(String, String),
     //RemoveChild(String, String),
     UpdateData(String, BasicData),
+    // UpdateData that recipients apply only after the updates it depends on
+    CausalUpdateData(String, BasicData, CausalMeta),
     DeleteData(String),
     DeleteSelfDevice,
     DeleteOtherDevice(String),
     Test(String),
     Dummy(u64),
+    ReadSnapshot(u64, Vec<String>),
+    // (data_id, reason); the rejecting device is the sender
+    DataRejected(String, ValidationError),
+    // (token_id, data_id, reason)
+    CapabilityRejected(String, String, String),
     //need to make sure these dont recurse
     TxStart(String, Transaction),
+    // (voter, tx_id, last tx_id the voter committed)
+    TxAccept(String, SequenceNumber, SequenceNumber),
     TxCommit(String, SequenceNumber),
     TxAbort(String, SequenceNumber),
+    // any other operation, sent by and to the app namespace with this id
+    Namespaced(String, Box<Operation>),
 }
 
 impl Operation {
-    fn to_string(msg: &Operation) -> Result<String, serde_json::Error> {
-        serde_json::to_string(msg)
+    fn to_string(msg: &Operation) -> Result<String, wire::Error> {
+        wire::encode(msg, WireFormat::Binary)
     }
 
-    fn from_string(msg: String) -> Result<Operation, serde_json::Error> {
-        serde_json::from_str(msg.as_str())
+    // accepts any wire format, including JSON from older devices
+    fn from_string(msg: String) -> Result<Operation, wire::Error> {
+        wire::decode(msg.as_str())
     }
 
     fn get_data_id(operation: &Operation) -> String {
         match operation {
             Operation::UpdateData(data_id, data_val) => data_id.to_string(),
+            Operation::CausalUpdateData(data_id, ..) => data_id.to_string(),
             Operation::DeleteData(data_id) => data_id.to_string(),
             // TODO confirm this is never used
             _ => "".to_string(),
         }
     }
+
+    // whether a newly linked device should hold this operation back until
+    // it has applied the snapshot from the device it is linking to; only
+    // the snapshot itself and messages to self are processed right away
+    fn buffer_during_link(operation: &Operation) -> bool {
+        match operation {
+            Operation::ConfirmUpdateLinked(..)
+            | Operation::LinkSnapshotStart(..)
+            | Operation::LinkSnapshotChunk(..)
+            | Operation::Dummy(..)
+            | Operation::ReadSnapshot(..) => false,
+            _ => true,
+        }
+    }
+}
+
+// what lets a contact request skip the recipient's confirmation
+enum ContactCredential {
+    Invite(String),
+    Capability(Capability),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -152,8 +295,14 @@ struct Transaction {
     prepare_sequence_number: Option<SequenceNumber>,
     commit_sequence_number: Option<SequenceNumber>,
     prev_seq_number: SequenceNumber,
-    timeout: SequenceNumber,
+    // number of sequencer epochs after the prepare (TxStart) epoch within
+    // which the transaction must commit
+    timeout: u64,
     coord_loopback: bool,
+    // ids of data read (but not necessarily written) by this transaction
+    read_ids: Vec<String>,
+    // only meaningful to the coordinator: the op_id the caller is waiting on
+    op_id: Option<u64>,
 }
 
 impl Transaction {
@@ -162,7 +311,10 @@ impl Transaction {
         recipients: Vec<String>,
         ops: Vec<Operation>,
         prev_seq_number: SequenceNumber,
+        timeout: u64,
         coord_loopback: bool,
+        read_ids: Vec<String>,
+        op_id: Option<u64>,
     ) -> Transaction {
         Transaction {
             coordinator: device_id,
@@ -172,12 +324,47 @@ impl Transaction {
             prepare_sequence_number: None,
             commit_sequence_number: None,
             prev_seq_number,
-            // TODO: make this dynamic? system dependent?
-            timeout: 100000000000000000000000,
+            timeout,
             coord_loopback,
+            read_ids,
+            op_id,
+        }
+    }
+
+    // Since the sequencer totally orders all messages, every device sees the
+    // commit message at the same sequence number and so agrees on whether it
+    // arrived in time. It also means that once a device has seen _any_
+    // message past the deadline, a commit can no longer arrive in time, so
+    // participants can unilaterally abort.
+    fn timed_out(&self, seq: SequenceNumber) -> bool {
+        match self.prepare_sequence_number {
+            Some(prepare_seq) => {
+                epoch_of(seq) > epoch_of(prepare_seq).saturating_add(self.timeout)
+            }
+            None => false,
         }
     }
 
+    fn write_ids(&self) -> HashSet<String> {
+        self.ops
+            .iter()
+            .map(|op| Operation::get_data_id(op))
+            // non-data ops (e.g. SetPerm) have an empty data id
+            .filter(|data_id| !data_id.is_empty())
+            .collect::<HashSet<String>>()
+    }
+
+    // two transactions conflict if either one writes data that the other
+    // reads or writes
+    fn conflicts_with(&self, other: &Transaction) -> bool {
+        let self_writes = self.write_ids();
+        let other_writes = other.write_ids();
+        self_writes
+            .iter()
+            .any(|id| other_writes.contains(id) || other.read_ids.contains(id))
+            || self.read_ids.iter().any(|id| other_writes.contains(id))
+    }
+
     fn to_string(msg: &Transaction) -> Result<String, serde_json::Error> {
         serde_json::to_string(msg)
     }
@@ -187,26 +374,79 @@ impl Transaction {
     }
 }
 
+// sequence numbers are assigned by the server as
+// epoch_id << 64 | shard_id << 56 | shard_instance << 48 | ctr
+fn epoch_of(seq: SequenceNumber) -> u64 {
+    (seq >> 64) as u64
+}
+
+/*
+ * Commit protocol
+ *
+ * The coordinator sends TxStart to every recipient (itself included). Each
+ * recipient checks the transaction for conflicts when TxStart arrives and
+ * votes with TxAccept or TxAbort. The coordinator sends TxCommit once every
+ * recipient has accepted. If anyone aborts, the abort goes to all
+ * recipients. Whichever of the commit or the abort the sequencer orders
+ * first wins on every device.
+ *
+ * Originally the coordinator committed as soon as its own TxStart came
+ * back, and a conflicting recipient's abort could arrive after the commit.
+ * transact() has to tell its caller whether the transaction committed, so
+ * the coordinator now waits for every vote. Recipients send aborts to all
+ * recipients, not just the coordinator, so that a transaction whose
+ * coordinator is gone can still be aborted.
+ */
+
 pub struct TxCoordinator {
+    // last message applied; every commit sequenced before it that touched
+    // data this device holds has been applied too, so a tx started now only
+    // has to be checked against commits sequenced after it
     seq_number: SequenceNumber,
+    timeout: u64,
     local_pending_tx: HashMap<SequenceNumber, (Vec<String>, Transaction)>,
     remote_pending_tx: HashMap<SequenceNumber, Transaction>,
-    committed_tx: Vec<((SequenceNumber, SequenceNumber), Transaction)>,
+    // prepare seq -> (commit seq, tx)
+    committed_tx: BTreeMap<SequenceNumber, (SequenceNumber, Transaction)>,
+    // data id -> prepare seqs of committed txs that read or wrote it
+    committed_index: HashMap<String, BTreeSet<SequenceNumber>>,
+    // device id -> seq_number that transaction participant last reported; a
+    // committed tx can be dropped once every participant has moved past it,
+    // since none of them can then start a tx that needs to be checked
+    // against it
+    acked_seq_numbers: HashMap<String, SequenceNumber>,
+    // highest tx_id dropped by trim_committed(); a device that was not yet
+    // counted in the watermark when it was trimmed may still start a tx
+    // sequenced before it, which can then no longer be checked for conflicts
+    trimmed_up_to: SequenceNumber,
     /* true if currently within transaction */
     tx_state: bool,
+    // seq_number when the current transaction was entered, i.e. before any
+    // of its reads
+    tx_prev_seq_number: SequenceNumber,
     temp_tx_ops: Vec<(Operation, Vec<String>)>,
+    temp_tx_reads: Vec<String>,
+    // op_id -> (number of unresolved shards, outcome so far) for transactions
+    // this device is coordinating on behalf of a waiting caller
+    tx_outcomes: HashMap<u64, (usize, Result<(), Error>)>,
 }
 
-// TODO: trim committed histories
 impl TxCoordinator {
     fn new() -> TxCoordinator {
         TxCoordinator {
             seq_number: 0,
+            timeout: DEFAULT_TX_TIMEOUT_EPOCHS,
             tx_state: false,
+            tx_prev_seq_number: 0,
             local_pending_tx: HashMap::new(),
             remote_pending_tx: HashMap::new(),
-            committed_tx: Vec::new(),
+            committed_tx: BTreeMap::new(),
+            committed_index: HashMap::new(),
+            acked_seq_numbers: HashMap::new(),
+            trimmed_up_to: 0,
             temp_tx_ops: Vec::new(),
+            temp_tx_reads: Vec::new(),
+            tx_outcomes: HashMap::new(),
         }
     }
 
@@ -221,20 +461,60 @@ impl TxCoordinator {
             return false;
         }
         self.tx_state = true;
+        self.tx_prev_seq_number = self.seq_number;
         return self.tx_state;
     }
 
-    fn exit_tx(&mut self) -> (Vec<(Operation, Vec<String>)>, SequenceNumber) {
+    fn exit_tx(
+        &mut self,
+    ) -> (Vec<(Operation, Vec<String>)>, Vec<String>, SequenceNumber) {
         let ops = self.temp_tx_ops.clone();
+        let reads = self.temp_tx_reads.clone();
         self.temp_tx_ops = Vec::new();
+        self.temp_tx_reads = Vec::new();
         self.tx_state = false;
-        return (ops, self.seq_number);
+        return (ops, reads, self.tx_prev_seq_number);
     }
 
     fn add_op_to_cur_tx(&mut self, msg: Operation, dst_idkeys: Vec<String>) {
         self.temp_tx_ops.push((msg, dst_idkeys));
     }
 
+    fn add_read_to_cur_tx(&mut self, data_id: String) {
+        if !self.temp_tx_reads.contains(&data_id) {
+            self.temp_tx_reads.push(data_id);
+        }
+    }
+
+    fn register_outcome(&mut self, op_id: u64, num_shards: usize) {
+        self.tx_outcomes.insert(op_id, (num_shards, Ok(())));
+    }
+
+    // records the outcome of one shard of a transaction this device is
+    // coordinating; returns the op_id the caller is waiting on once the
+    // outcome of the whole transaction is known
+    fn resolve_shard(&mut self, tx: &Transaction, res: Result<(), Error>) -> Option<u64> {
+        let op_id = tx.op_id?;
+        let (remaining, outcome) = self.tx_outcomes.get_mut(&op_id)?;
+        if outcome.is_err() {
+            // already resolved by an earlier shard
+            return None;
+        }
+        *remaining -= 1;
+        if res.is_err() {
+            *outcome = res;
+            return Some(op_id);
+        }
+        if *remaining == 0 {
+            return Some(op_id);
+        }
+        None
+    }
+
+    fn take_outcome(&mut self, op_id: u64) -> Option<Result<(), Error>> {
+        self.tx_outcomes.remove(&op_id).map(|(_, outcome)| outcome)
+    }
+
     fn get_transaction(&self, tx_id: SequenceNumber) -> Result<&Transaction, Error> {
         if self.local_pending_tx.contains_key(&tx_id) {
             return Ok(&self.local_pending_tx.get(&tx_id).unwrap().1);
@@ -244,16 +524,23 @@ impl TxCoordinator {
         return Err(Error::TxNotFound);
     }
 
-    fn get_committed_transaction(
-        &self,
-        tx_id: SequenceNumber,
-    ) -> Result<&Transaction, Error> {
-        for ((start_seq, _), tx) in self.committed_tx.iter() {
-            if *start_seq == tx_id {
-                return Ok(&tx);
-            }
-        }
-        return Err(Error::TxNotFound);
+    // drops all pending transactions whose deadline has passed as of seq;
+    // returns the ones this device was coordinating so that waiting callers
+    // can be woken up
+    fn expire_transactions(&mut self, seq: SequenceNumber) -> Vec<Transaction> {
+        self.remote_pending_tx.retain(|_, tx| !tx.timed_out(seq));
+
+        let expired_ids: Vec<SequenceNumber> = self
+            .local_pending_tx
+            .iter()
+            .filter(|(_, (_, tx))| tx.timed_out(seq))
+            .map(|(tx_id, _)| *tx_id)
+            .collect();
+        expired_ids
+            .iter()
+            .filter_map(|tx_id| self.local_pending_tx.remove(tx_id))
+            .map(|(_, tx)| tx)
+            .collect()
     }
 
     fn start_message(
@@ -265,6 +552,12 @@ impl TxCoordinator {
     ) -> Result<(), Error> {
         let mut tx = unsequenced_tx.clone();
         tx.prepare_sequence_number = Some(tx_id);
+        self.track_participants(&tx);
+        self.ack(&tx.coordinator, tx.prev_seq_number);
+
+        // stale transactions (e.g. from a coordinator that went offline)
+        // must not block this one
+        self.expire_transactions(tx_id);
 
         if sender == my_device_id {
             self.local_pending_tx
@@ -273,12 +566,31 @@ impl TxCoordinator {
             self.remote_pending_tx.insert(tx_id, tx.clone());
         }
 
-        let res = self.detect_conflict(&tx);
-        if res {
-            return Ok(());
-        } else {
+        if self.detect_conflict(&tx) {
             return Err(Error::TransactionConflictsError);
         }
+        Ok(())
+    }
+
+    // records a recipient's vote to commit a transaction this device is
+    // coordinating; returns true once every recipient has voted
+    fn accept_message(
+        &mut self,
+        sender: String,
+        tx_id: &SequenceNumber,
+    ) -> Result<bool, Error> {
+        match self.local_pending_tx.get_mut(tx_id) {
+            Some((accepted, tx)) => {
+                if !tx.recipients.contains(&sender) {
+                    return Err(Error::BadTransactionError);
+                }
+                if !accepted.contains(&sender) {
+                    accepted.push(sender);
+                }
+                Ok(accepted.len() == tx.recipients.len())
+            }
+            None => Err(Error::TxNotFound),
+        }
     }
 
     fn commit_message(
@@ -291,60 +603,122 @@ impl TxCoordinator {
     ) -> Result<(), Error> {
         // committing a tx i coordinated
         if sender == my_device_id {
-            let q = &mut self.local_pending_tx;
-            let mut tx = q[tx_id].1.clone();
+            let mut tx = match self.local_pending_tx.remove(tx_id) {
+                Some((_, tx)) => tx,
+                None => return Err(Error::TxNotFound),
+            };
             tx.commit_sequence_number = Some(seq);
-            q.remove(tx_id);
-            if seq > tx.prepare_sequence_number.unwrap_or_default() + tx.timeout {
-                println!("TIMEOUT (coord = self)");
-                println!("cur_seq: {}", &seq);
-                println!(
-                    "prep_seq: {}",
-                    &tx.prepare_sequence_number.unwrap_or_default()
-                );
-                println!("to_offset: {}", &tx.timeout);
-                println!(
-                    "to_seq: {}",
-                    tx.prepare_sequence_number.unwrap_or_default() + tx.timeout
-                );
-                println!(
-                    "diff: {}",
-                    tx.prepare_sequence_number.unwrap_or_default() + tx.timeout - seq
-                );
+            if tx.timed_out(seq) {
                 return Err(Error::Timeout);
-            } else {
-                self.committed_tx.push(((*tx_id, seq), tx));
             }
+            self.add_committed(*tx_id, seq, tx);
         // commit message for a remote transaction
-        } else if self.remote_pending_tx.get(tx_id).unwrap().coordinator == sender {
+        } else {
+            match self.remote_pending_tx.get(tx_id) {
+                Some(tx) if tx.coordinator == sender => {}
+                Some(_) => return Err(Error::BadTransactionError),
+                // already expired (or aborted) locally
+                None => return Err(Error::Timeout),
+            }
             let mut tx = self.remote_pending_tx.remove(tx_id).unwrap();
             tx.commit_sequence_number = Some(seq);
-            if seq > tx.prepare_sequence_number.unwrap_or_default() + tx.timeout {
-                println!("TIMEOUT (coord = other)");
-                println!("cur_seq: {}", &seq);
-                println!(
-                    "prep_seq: {}",
-                    &tx.prepare_sequence_number.unwrap_or_default()
-                );
-                println!("to_offset: {}", &tx.timeout);
-                println!(
-                    "to_seq: {}",
-                    tx.prepare_sequence_number.unwrap_or_default() + tx.timeout
-                );
-                println!(
-                    "diff: {}",
-                    tx.prepare_sequence_number.unwrap_or_default() - seq + tx.timeout
-                );
+            if tx.timed_out(seq) {
                 return Err(Error::Timeout);
-            } else {
-                self.committed_tx.push(((*tx_id, seq), tx));
             }
+            self.add_committed(*tx_id, seq, tx);
         }
 
-        self.seq_number = *tx_id;
+        self.seq_number = self.seq_number.max(*tx_id);
+        self.ack(&my_device_id, self.seq_number);
         Ok(())
     }
 
+    fn add_committed(
+        &mut self,
+        tx_id: SequenceNumber,
+        seq: SequenceNumber,
+        tx: Transaction,
+    ) {
+        for data_id in tx
+            .write_ids()
+            .into_iter()
+            .chain(tx.read_ids.iter().cloned())
+        {
+            self.committed_index
+                .entry(data_id)
+                .or_insert_with(BTreeSet::new)
+                .insert(tx_id);
+        }
+        self.committed_tx.insert(tx_id, (seq, tx));
+    }
+
+    // makes sure every device involved in tx holds back the watermark until
+    // it has been heard from
+    fn track_participants(&mut self, tx: &Transaction) {
+        for device_id in tx.recipients.iter().chain(std::iter::once(&tx.coordinator)) {
+            self.track_device(device_id);
+        }
+    }
+
+    // records that everything up to seq has been applied
+    fn observe(&mut self, seq: SequenceNumber) {
+        self.seq_number = self.seq_number.max(seq);
+    }
+
+    // stops counting devices that are gone in the watermark
+    fn untrack_devices(&mut self, device_ids: &HashSet<String>) {
+        self.acked_seq_numbers
+            .retain(|device_id, _| !device_ids.contains(device_id));
+        self.trim_committed();
+    }
+
+    // counts device_id in the watermark at 0 until it acknowledges something
+    fn track_device(&mut self, device_id: &String) {
+        self.acked_seq_numbers
+            .entry(device_id.to_string())
+            .or_insert(0);
+    }
+
+    // records that device_id has committed everything up to seq_number, and
+    // drops committed transactions that every device has moved past
+    fn ack(&mut self, device_id: &String, seq_number: SequenceNumber) {
+        let acked = self
+            .acked_seq_numbers
+            .entry(device_id.to_string())
+            .or_insert(0);
+        *acked = (*acked).max(seq_number);
+        self.trim_committed();
+    }
+
+    fn watermark(&self) -> SequenceNumber {
+        self.acked_seq_numbers
+            .values()
+            .min()
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn trim_committed(&mut self) {
+        let watermark = self.watermark();
+        // detect_conflict() only checks committed txs sequenced after the
+        // tx's prev_seq_number, which is never below the watermark
+        let keep = self.committed_tx.split_off(&watermark.saturating_add(1));
+        let trimmed = std::mem::replace(&mut self.committed_tx, keep);
+        if let Some(tx_id) = trimmed.keys().next_back() {
+            self.trimmed_up_to = self.trimmed_up_to.max(*tx_id);
+        }
+        for (tx_id, (_, tx)) in trimmed {
+            for data_id in tx.write_ids().iter().chain(tx.read_ids.iter()) {
+                if let Some(tx_ids) = self.committed_index.get_mut(data_id) {
+                    tx_ids.remove(&tx_id);
+                    if tx_ids.is_empty() {
+                        self.committed_index.remove(data_id);
+                    }
+                }
+            }
+        }
+    }
+
     fn abort_message(
         &mut self,
         my_device_id: String,
@@ -356,61 +730,74 @@ impl TxCoordinator {
             self.local_pending_tx.remove(tx_id);
         // this client got an abort message from a recipient
         // for a tx this client is coordinating
-        } else if self.local_pending_tx.contains_key(tx_id) {
-            assert!(self
-                .remote_pending_tx
-                .get(tx_id)
-                .unwrap()
-                .recipients
-                .contains(&sender));
+        } else if let Some((_, tx)) = self.local_pending_tx.get(tx_id) {
+            if !tx.recipients.contains(&sender) {
+                return Err(Error::BadTransactionError);
+            }
+            // the abort may have been sent to all recipients (see
+            // coordinated_by()), in which case it is sequenced before any
+            // commit this device sends from now on, and every recipient
+            // drops the tx on seeing it
+            self.local_pending_tx.remove(tx_id);
             return Err(Error::SendToAll);
-        // a coordinator for a remote transaction is telling this client to
-        // abort
-        } else if self.remote_pending_tx.contains_key(tx_id) {
-            assert_eq!(
-                self.remote_pending_tx.get(tx_id).unwrap().coordinator,
-                sender
-            );
+        // the coordinator of a remote transaction, or another recipient
+        // presuming abort, is telling this client to abort
+        } else if let Some(tx) = self.remote_pending_tx.get(tx_id) {
+            if tx.coordinator != sender && !tx.recipients.contains(&sender) {
+                return Err(Error::BadTransactionError);
+            }
             self.remote_pending_tx.remove(tx_id);
         }
         Ok(())
     }
 
-    fn detect_conflict(&self, msg: &Transaction) -> bool {
-        let mut tx_keys = Vec::new();
+    // Pending transactions coordinated by any of coordinators. A recipient
+    // can abort these without waiting for their deadline by sending TxAbort
+    // to every recipient: since everyone sees messages in the same order,
+    // either the coordinator's commit comes first and the abort is ignored,
+    // or the abort comes first and the commit is, everywhere.
+    fn coordinated_by(
+        &self,
+        coordinators: &HashSet<String>,
+    ) -> Vec<(SequenceNumber, Transaction)> {
+        self.remote_pending_tx
+            .iter()
+            .filter(|(_, tx)| coordinators.contains(&tx.coordinator))
+            .map(|(tx_id, tx)| (*tx_id, tx.clone()))
+            .collect()
+    }
 
-        for op in msg.ops.clone().into_iter() {
-            tx_keys.push(Operation::get_data_id(&op));
+    fn detect_conflict(&self, msg: &Transaction) -> bool {
+        // msg has already been added to the pending set, so skip it
+        for (tx_id, (_, tx)) in self.local_pending_tx.iter() {
+            if Some(*tx_id) != msg.prepare_sequence_number && msg.conflicts_with(tx) {
+                return true;
+            }
         }
 
-        //impl for all enums -> move to func over two txs
-        for tx in self.local_pending_tx.clone().into_iter() {
-            for op in tx.1 .1.ops.clone().into_iter() {
-                let data_id = Operation::get_data_id(&op);
-                if tx_keys.contains(&data_id) {
-                    return true;
-                }
+        for (tx_id, tx) in self.remote_pending_tx.iter() {
+            if Some(*tx_id) != msg.prepare_sequence_number && msg.conflicts_with(tx) {
+                return true;
             }
         }
 
-        for tx in self.remote_pending_tx.clone().into_iter() {
-            for op in tx.1.ops.clone().into_iter() {
-                let data_id = Operation::get_data_id(&op);
-                if tx_keys.contains(&data_id) {
-                    return true;
-                }
-            }
+        // committed transactions this tx may conflict with have already been
+        // trimmed, so it cannot be checked; abort it rather than risk
+        // committing a conflict
+        if msg.prev_seq_number < self.trimmed_up_to {
+            return true;
         }
 
-        for tx in self.committed_tx.clone().into_iter() {
-            //only iterate through committed transactions that were sequenced
-            // after the prev txn accepted by the original client
-            if tx.1.prepare_sequence_number.unwrap() > msg.prev_seq_number {
-                for op in tx.1.ops.clone().into_iter() {
-                    let data_id = Operation::get_data_id(&op);
-                    if tx_keys.contains(&data_id) {
-                        return true;
-                    }
+        //only check committed transactions that were sequenced after the prev
+        // txn accepted by the original client, and that touched the same data
+        for data_id in msg.write_ids().iter().chain(msg.read_ids.iter()) {
+            let tx_ids = match self.committed_index.get(data_id) {
+                Some(tx_ids) => tx_ids,
+                None => continue,
+            };
+            for tx_id in tx_ids.range((Excluded(msg.prev_seq_number), Unbounded)) {
+                if msg.conflicts_with(&self.committed_tx[tx_id].1) {
+                    return true;
                 }
             }
         }
@@ -418,6 +805,142 @@ impl TxCoordinator {
     }
 }
 
+const DEFAULT_TX_RETRY_LIMIT: u32 = 5;
+// TODO: make this dynamic? system dependent?
+const DEFAULT_TX_TIMEOUT_EPOCHS: u64 = 100;
+const TX_BACKOFF_BASE_MS: u64 = 20;
+const TX_BACKOFF_MAX_SHIFT: u32 = 8;
+// messages held for app namespaces that have not been opened yet; any
+// contact can send them, so later ones are dropped once this many are held
+const MAX_NAMESPACE_BUFFER_LEN: usize = 10000;
+// operations a newly linked device holds back while its snapshot arrives;
+// past this many the link fails and has to be redone (see fail_link())
+const MAX_LINK_BUFFER_LEN: usize = 10000;
+
+// Handle passed to the closure given to TankClient::transact(); all reads
+// and writes made through it belong to the same transaction
+#[derive(Clone)]
+pub struct TxHandle {
+    client: TankClient,
+    // writes buffered so far, so that later reads in the same transaction
+    // observe them
+    writes: Arc<Mutex<HashMap<String, BasicData>>>,
+}
+
+impl TxHandle {
+    fn new(client: TankClient) -> TxHandle {
+        TxHandle {
+            client,
+            writes: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn get_data(&self, data_id: &String) -> Result<Option<BasicData>, Error> {
+        if let Some(data_val) = self.writes.lock().get(data_id) {
+            return Ok(Some(data_val.clone()));
+        }
+
+        // record the read so the transaction aborts if the value changes
+        // before it commits
+        self.client
+            .tx_coordinator
+            .write()
+            .as_mut()
+            .unwrap()
+            .add_read_to_cur_tx(data_id.to_string());
+
+        let device_guard = self.client.device.read();
+        let data_store_guard = device_guard.as_ref().unwrap().data_store.read();
+        Ok(data_store_guard.get_data(data_id).map(|x| x.clone()))
+    }
+
+    pub async fn set_data(
+        &self,
+        data_id: String,
+        data_type: String,
+        data_val: String,
+    ) -> Result<(), Error> {
+        let (device_ids, basic_data) = self
+            .client
+            .prepare_data_update(&data_id, data_type, data_val, None)
+            .await?;
+        self.client
+            .send_or_add_to_txn(
+                device_ids,
+                &Operation::UpdateData(data_id.clone(), basic_data.clone()),
+                false,
+            )
+            .await?;
+        self.writes.lock().insert(data_id, basic_data);
+        Ok(())
+    }
+}
+
+// An update this device wrote that a recipient (possibly this device itself)
+// refused to apply
+#[derive(Debug, PartialEq, Clone)]
+pub struct DataRejection {
+    pub rejected_by: String,
+    pub data_id: String,
+    pub reason: ValidationError,
+}
+
+// A capability token this device redeemed that its issuer refused or failed
+// to grant
+#[derive(Debug, PartialEq, Clone)]
+pub struct CapabilityRejection {
+    pub rejected_by: String,
+    pub token_id: String,
+    pub data_id: String,
+    pub reason: String,
+}
+
+// Consistency guarantees for a single operation; see TankClient::new() for
+// what each flag means. Defaults to the client-wide flags, which can be
+// overridden per data_type (set_consistency_for_type()) or per call (e.g.
+// set_data_with_consistency()).
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Consistency {
+    pub block_writes: bool,
+    pub sync_reads: bool,
+    pub mult_outstanding: bool,
+    // tag data updates with their causal dependencies so that recipients
+    // apply them in causal order
+    pub causal: bool,
+}
+
+impl Consistency {
+    // waits for every operation to be ordered by the server before
+    // returning, and only has one outstanding at a time
+    pub fn linearizable() -> Consistency {
+        Consistency {
+            block_writes: true,
+            sync_reads: true,
+            mult_outstanding: false,
+            causal: false,
+        }
+    }
+
+    // returns immediately and never waits on other operations
+    pub fn eventual() -> Consistency {
+        Consistency {
+            block_writes: false,
+            sync_reads: false,
+            mult_outstanding: true,
+            causal: false,
+        }
+    }
+
+    // like eventual(), but recipients never apply an update before the
+    // updates its writer had seen
+    pub fn causal() -> Consistency {
+        Consistency {
+            causal: true,
+            ..Consistency::eventual()
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct TankClient {
     core: Option<Arc<Core<TankClient>>>,
@@ -431,8 +954,64 @@ pub struct TankClient {
     mult_outstanding: bool,
     //multikey: bool,
     tx_coordinator: Arc<RwLock<Option<TxCoordinator>>>,
+    tx_retry_limit: Arc<RwLock<u32>>,
+    // set while transact() runs a body; this device builds one transaction
+    // at a time, so others wait for it
+    tx_running: Arc<Mutex<bool>>,
+    tx_running_cv: Arc<Condvar>,
     op_id_ctr: Arc<Mutex<(u64, HashSet<u64>)>>,
     op_id_ctr_cv: Arc<Condvar>,
+    read_snapshots:
+        Arc<Mutex<HashMap<u64, (SequenceNumber, HashMap<String, BasicData>)>>>,
+    data_rejections: Arc<Mutex<Vec<DataRejection>>>,
+    capability_rejections: Arc<Mutex<Vec<CapabilityRejection>>>,
+    // issuers of the tokens this device redeemed, by token_id; only the
+    // issuer can reject a token
+    redeemed_capabilities: Arc<Mutex<HashMap<String, String>>>,
+    // snapshots being sent to newly linked devices, by idkey
+    link_snapshots: Arc<Mutex<HashMap<String, OutgoingSnapshot>>>,
+    link_chunk_bytes: Arc<RwLock<usize>>,
+    // snapshot being received while this device is being linked, plus the
+    // operations that arrived in the meantime
+    incoming_link: Arc<Mutex<Option<IncomingSnapshot>>>,
+    link_buffer: Arc<Mutex<Vec<(SequenceNumber, String, Operation)>>>,
+    // set once linking this device failed, until it is linked again
+    link_failed: Arc<RwLock<bool>>,
+    // unused invites created by this device
+    invites: Arc<Mutex<IssuedInvites>>,
+    // where invites are persisted, if anywhere
+    invite_file: Arc<RwLock<Option<String>>>,
+    invite_ttl_secs: Arc<RwLock<u64>>,
+    // unrevoked capability tokens issued by this device
+    capabilities: Arc<Mutex<IssuedCapabilities>>,
+    // where capabilities are persisted, if anywhere
+    capability_file: Arc<RwLock<Option<String>>>,
+    capability_ttl_secs: Arc<RwLock<u64>>,
+    block_list: Arc<RwLock<BlockList>>,
+    // where block_list is persisted, if anywhere
+    block_list_file: Arc<RwLock<Option<String>>>,
+    consistency_policies: Arc<RwLock<HashMap<String, Consistency>>>,
+    // hold back contact requests and shares from other users until accepted
+    require_acceptance: Arc<RwLock<bool>>,
+    // collect orphaned metadata whenever data is deleted
+    auto_compact: Arc<RwLock<bool>>,
+    // causal dependencies seen so far, plus causal updates waiting on them
+    causal_state: Arc<Mutex<CausalState<(SequenceNumber, String, Operation)>>>,
+    // latest sequencer epoch seen, against which expiries are checked
+    last_epoch: Arc<RwLock<u64>>,
+    // app id if this is the view of one app namespace, None for the client
+    // the namespaces were opened from
+    namespace: Option<String>,
+    // open namespaces, and messages received for namespaces that are not
+    // open yet, by app id
+    namespaces: Arc<RwLock<HashMap<String, TankClient>>>,
+    namespace_buffer:
+        Arc<Mutex<HashMap<String, Vec<(SequenceNumber, String, Operation)>>>>,
+    // messages dropped because namespace_buffer was full, by app id
+    namespace_drops: Arc<Mutex<HashMap<String, usize>>>,
+    // format of sent messages; received ones may be in any format
+    wire_format: Arc<RwLock<WireFormat>>,
+    migrations: Arc<RwLock<Migrations>>,
     // benchmarking fields
     benchmark_send: Arc<RwLock<Option<usize>>>,
     benchmark_recv_update: Arc<RwLock<Option<usize>>>,
@@ -457,10 +1036,12 @@ impl CoreClient for TankClient {
         message: String,
         bench: bool,
     ) {
-        let mut dummy = true;
-        if message.contains("UpdateData") {
-            dummy = false;
-        }
+        // messages are binary, so only decode them here when benchmarking
+        let dummy = !bench
+            || !matches!(
+                Operation::from_string(message.clone()),
+                Ok(Operation::UpdateData(..)) | Ok(Operation::CausalUpdateData(..))
+            );
 
         if bench && !dummy && self.benchmark_recv_update.read().is_some() {
             self.recv_update_timestamp_vec.lock().push((
@@ -481,33 +1062,7 @@ impl CoreClient for TankClient {
             thread::sleep(time::Duration::from_secs(self.sec_wait_to_apply.unwrap()));
         }
 
-        match Operation::from_string(message.clone()) {
-            Ok(operation) => {
-                //if bench { println!("operation: {:?}", operation.clone()); }
-                match self.check_permissions(&sender, &operation) {
-                    Ok(_) => {
-                        if self.validate_data_invariants(&operation) {
-                            match self.demux(seq, operation).await {
-                                Ok(_) => {}
-                                Err(err) => {
-                                    println!("Error in demux: {:?}", err)
-                                }
-                            }
-                        } else {
-                            println!(
-                                "Error in validation: {:?}",
-                                Error::DataInvariantViolated
-                            );
-                        }
-                    }
-                    Err(err) => println!("Error in permissions: {:?}", err),
-                }
-            }
-            Err(_) => println!(
-                "Error getting operation: {:?}",
-                Error::StringConversionErr(message)
-            ),
-        };
+        self.receive_message(seq, sender, message).await;
 
         if bench && !dummy && self.benchmark_recv_update.read().is_some() {
             self.recv_update_timestamp_vec.lock().push((
@@ -609,8 +1164,41 @@ impl TankClient {
             sync_reads,
             mult_outstanding,
             tx_coordinator,
+            tx_retry_limit: Arc::new(RwLock::new(DEFAULT_TX_RETRY_LIMIT)),
+            tx_running: Arc::new(Mutex::new(false)),
+            tx_running_cv: Arc::new(Condvar::new()),
             op_id_ctr: Arc::new(Mutex::new((0, HashSet::new()))),
             op_id_ctr_cv: Arc::new(Condvar::new()),
+            read_snapshots: Arc::new(Mutex::new(HashMap::new())),
+            data_rejections: Arc::new(Mutex::new(Vec::new())),
+            capability_rejections: Arc::new(Mutex::new(Vec::new())),
+            redeemed_capabilities: Arc::new(Mutex::new(HashMap::new())),
+            link_snapshots: Arc::new(Mutex::new(HashMap::new())),
+            link_chunk_bytes: Arc::new(RwLock::new(snapshot::DEFAULT_CHUNK_BYTES)),
+            incoming_link: Arc::new(Mutex::new(None)),
+            link_buffer: Arc::new(Mutex::new(Vec::new())),
+            link_failed: Arc::new(RwLock::new(false)),
+            invites: Arc::new(Mutex::new(IssuedInvites::new())),
+            invite_file: Arc::new(RwLock::new(None)),
+            invite_ttl_secs: Arc::new(RwLock::new(invite::DEFAULT_INVITE_TTL_SECS)),
+            capabilities: Arc::new(Mutex::new(IssuedCapabilities::new())),
+            capability_file: Arc::new(RwLock::new(None)),
+            capability_ttl_secs: Arc::new(RwLock::new(
+                capability::DEFAULT_CAPABILITY_TTL_SECS,
+            )),
+            block_list: Arc::new(RwLock::new(BlockList::new())),
+            block_list_file: Arc::new(RwLock::new(None)),
+            consistency_policies: Arc::new(RwLock::new(HashMap::new())),
+            require_acceptance: Arc::new(RwLock::new(false)),
+            auto_compact: Arc::new(RwLock::new(true)),
+            causal_state: Arc::new(Mutex::new(CausalState::new())),
+            last_epoch: Arc::new(RwLock::new(0)),
+            namespace: None,
+            namespaces: Arc::new(RwLock::new(HashMap::new())),
+            namespace_buffer: Arc::new(Mutex::new(HashMap::new())),
+            namespace_drops: Arc::new(Mutex::new(HashMap::new())),
+            wire_format: Arc::new(RwLock::new(WireFormat::Json)),
+            migrations: Arc::new(RwLock::new(Migrations::new())),
             benchmark_send: Arc::new(RwLock::new(benchmark_runs)),
             benchmark_recv_update: Arc::new(RwLock::new(benchmark_runs)),
             benchmark_recv_dummy: Arc::new(RwLock::new(benchmark_runs)),
@@ -664,6 +1252,74 @@ impl TankClient {
         client
     }
 
+    // Handles one message from the server
+    async fn receive_message(
+        &self,
+        seq: SequenceNumber,
+        sender: String,
+        message: String,
+    ) {
+        match Operation::from_string(message.clone()) {
+            Ok(operation) => self.receive_operation(seq, sender, operation).await,
+            Err(_) => println!(
+                "Error getting operation: {:?}",
+                Error::StringConversionErr(message)
+            ),
+        };
+    }
+
+    // Handles one decoded operation, either from the server or passed on
+    // to this namespace by the client it was opened from
+    #[async_recursion]
+    async fn receive_operation(
+        &self,
+        seq: SequenceNumber,
+        sender: String,
+        operation: Operation,
+    ) {
+        // drop anything from blocked devices before it gets anywhere near
+        // permission checks or demux
+        match operation {
+            _ if self.is_blocked(&sender) => {
+                println!("Dropping operation from blocked device {}", sender)
+            }
+            Operation::Namespaced(app_id, operation) => {
+                self.receive_namespaced(seq, sender, app_id, *operation)
+                    .await
+            }
+            operation => {
+                // every sequenced message advances this device's view of
+                // time, which may push pending transactions past their
+                // deadline
+                self.expire_transactions(seq);
+                self.purge_expired(seq);
+                //if bench { println!("operation: {:?}", operation.clone()); }
+                // while this device is still receiving its initial state,
+                // hold on to updates so they are applied on top of it
+                // rather than overwritten by it
+                if !Operation::buffer_during_link(&operation) {
+                    self.process_operation(seq, sender.clone(), operation).await;
+                } else if let Err(err) = self.check_link() {
+                    // without the state it was linked to, this device can
+                    // only apply updates wrongly
+                    println!("Dropping operation: {:?}", err);
+                } else if self.incoming_link.lock().is_some() {
+                    let mut link_buffer = self.link_buffer.lock();
+                    if link_buffer.len() < MAX_LINK_BUFFER_LEN {
+                        link_buffer.push((seq, sender.clone(), operation));
+                    } else {
+                        core::mem::drop(link_buffer);
+                        if let Err(err) = self.fail_link().await {
+                            println!("Error failing link: {:?}", err);
+                        }
+                    }
+                } else {
+                    self.process_operation(seq, sender.clone(), operation).await;
+                }
+            }
+        };
+    }
+
     /* Transactions */
 
     fn discern_shards(
@@ -699,31 +1355,43 @@ impl TankClient {
         return txn_shards;
     }
 
-    async fn apply_locally(&self, tx_id: SequenceNumber) -> Result<(), Error> {
-        let coord_guard = self.tx_coordinator.read();
-        let tx = coord_guard
-            .as_ref()
-            .unwrap()
-            .get_committed_transaction(tx_id)
-            .unwrap();
+    // takes the transaction itself rather than looking it up, since committing
+    // may have already trimmed it from the committed history
+    async fn apply_locally(
+        &self,
+        tx_id: SequenceNumber,
+        tx: &Transaction,
+    ) -> Result<(), Error> {
         if tx.coord_loopback && self.idkey() == tx.coordinator {
             return Ok(());
         }
         for op in tx.ops.clone().into_iter() {
             //call into data store to apply
-            // TODO: handle errors
-            self.demux(tx_id.clone(), op).await;
+            if let Err(err) = self.demux(tx_id.clone(), tx.coordinator.clone(), op).await
+            {
+                println!("Error applying transaction: {:?}", err);
+            }
         }
         Ok(())
     }
 
+    // returns the number of shards the transaction was split into
     async fn initiate_transaction(
         &self,
         device_id: String,
         ops: Vec<(Operation, Vec<String>)>,
+        read_ids: Vec<String>,
         prev_seq_number: SequenceNumber,
-    ) {
-        let txn_shards = self.discern_shards(ops.clone());
+        op_id: Option<u64>,
+    ) -> Result<usize, Error> {
+        let mut txn_shards = self.discern_shards(ops.clone());
+        // what was read must still be checked for conflicts even if nothing
+        // was written; the reads were local, so this device can do so alone
+        if txn_shards.is_empty() && !read_ids.is_empty() {
+            txn_shards.insert(vec![device_id.clone()], (Vec::new(), false));
+        }
+        let num_shards = txn_shards.len();
+        let timeout = self.tx_coordinator.read().as_ref().unwrap().timeout;
         let mut txn_series = Vec::new();
         for (recipients, (ops, coord_loopback)) in txn_shards {
             let transaction = Transaction::new(
@@ -731,47 +1399,129 @@ impl TankClient {
                 recipients.clone(),
                 ops,
                 prev_seq_number,
+                timeout,
                 coord_loopback,
+                read_ids.clone(),
+                op_id,
             );
             txn_series.push((
                 recipients,
-                Operation::to_string(&Operation::TxStart(device_id.clone(), transaction))
-                    .unwrap(),
+                Operation::TxStart(device_id.clone(), transaction),
                 false,
             ));
         }
-        self.send_message(txn_series).await;
-    }
+        if num_shards == 0 {
+            return Ok(0);
+        }
 
-    async fn send_abort_to_coordinator(&self, sender: String, tx_id: SequenceNumber) {
-        let recipients = vec![self.idkey(), sender];
-        self.send_message(vec![(
-            recipients,
-            Operation::to_string(&Operation::TxAbort(self.idkey(), tx_id)).unwrap(),
-            false,
-        )])
-        .await;
+        // register before sending so the outcome cannot race the registration
+        if let Some(op_id) = op_id {
+            self.tx_coordinator
+                .write()
+                .as_mut()
+                .unwrap()
+                .register_outcome(op_id, num_shards);
+        }
+        match self.send_message(txn_series).await {
+            Ok(_) => Ok(num_shards),
+            Err(err) => Err(Error::SendFailed(err.to_string())),
+        }
     }
 
-    async fn send_abort_as_coordinator(&self, tx_id: SequenceNumber) {
-        let binding = self.tx_coordinator.read();
-        let tx = binding
-            .as_ref()
-            .unwrap()
-            .get_transaction(tx_id)
-            .unwrap()
-            .clone();
+    async fn send_accept_to_coordinator(&self, sender: String, tx_id: SequenceNumber) {
+        let seq_number = self.tx_coordinator.read().as_ref().unwrap().seq_number;
+        let _ = self
+            .send_message(vec![(
+                vec![sender],
+                Operation::TxAccept(self.idkey(), tx_id, seq_number),
+                false,
+            )])
+            .await;
+    }
+
+    async fn record_accept_as_coordinator(
+        &self,
+        voter: String,
+        tx_id: SequenceNumber,
+        voter_seq_number: SequenceNumber,
+    ) {
+        let all_accepted = {
+            let mut tx_coordinator_guard = self.tx_coordinator.write();
+            let tx_coordinator = tx_coordinator_guard.as_mut().unwrap();
+            tx_coordinator.ack(&voter, voter_seq_number);
+            tx_coordinator.accept_message(voter.clone(), &tx_id)
+        };
+        match all_accepted {
+            Ok(true) => self.send_commit_as_coordinator(tx_id).await,
+            // This device has no record of the transaction, e.g. because it
+            // was restarted (losing its in-memory state) or the transaction
+            // already expired. Nothing was committed, so presume abort and
+            // let the participant release it.
+            Err(Error::TxNotFound) if voter != self.idkey() => {
+                let _ = self
+                    .send_message(vec![(
+                        vec![voter],
+                        Operation::TxAbort(self.idkey(), tx_id),
+                        false,
+                    )])
+                    .await;
+            }
+            _ => {}
+        }
+    }
+
+    // aborts any transactions that can no longer commit as of seq, waking up
+    // callers waiting on the ones coordinated by this device
+    fn expire_transactions(&self, seq: SequenceNumber) {
+        let expired = match self.tx_coordinator.write().as_mut() {
+            Some(tx_coordinator) => tx_coordinator.expire_transactions(seq),
+            None => return,
+        };
+        for tx in expired {
+            self.resolve_transaction_shard(&tx, Err(Error::Timeout));
+        }
+    }
+
+    // wakes up the caller waiting on a transaction once its outcome is known
+    fn resolve_transaction_shard(&self, tx: &Transaction, res: Result<(), Error>) {
+        let resolved = self
+            .tx_coordinator
+            .write()
+            .as_mut()
+            .unwrap()
+            .resolve_shard(tx, res);
+        if let Some(op_id) = resolved {
+            let mut op_id_ctr = self.op_id_ctr.lock();
+            op_id_ctr.1.remove(&op_id);
+            self.op_id_ctr_cv.notify_all();
+        }
+    }
+
+    async fn send_abort_to_coordinator(&self, sender: String, tx_id: SequenceNumber) {
+        // the coordinator relays the abort to all recipients (including this
+        // device)
+        let recipients = vec![sender];
         self.send_message(vec![(
-            tx.recipients,
-            Operation::to_string(&Operation::TxAbort(self.idkey(), tx_id)).unwrap(),
+            recipients,
+            Operation::TxAbort(self.idkey(), tx_id),
+            false,
+        )])
+        .await;
+    }
+
+    async fn send_abort_as_coordinator(&self, tx_id: SequenceNumber, tx: &Transaction) {
+        self.send_message(vec![(
+            tx.recipients.clone(),
+            Operation::TxAbort(self.idkey(), tx_id),
             false,
         )])
         .await;
     }
 
     async fn send_commit_as_coordinator(&self, tx_id: SequenceNumber) {
-        let binding = self.tx_coordinator.read();
-        let tx = binding
+        let tx = self
+            .tx_coordinator
+            .read()
             .as_ref()
             .unwrap()
             .get_transaction(tx_id)
@@ -779,7 +1529,7 @@ impl TankClient {
             .clone();
         self.send_message(vec![(
             tx.recipients,
-            Operation::to_string(&Operation::TxCommit(self.idkey(), tx_id)).unwrap(),
+            Operation::TxCommit(self.idkey(), tx_id),
             false,
         )])
         .await;
@@ -799,6 +1549,16 @@ impl TankClient {
             Operation::Test(msg) => Ok(()),
             /* Dummy op */
             Operation::Dummy(num) => Ok(()),
+            /* Read markers are only ever sent to self */
+            Operation::ReadSnapshot(op_id, _) => {
+                if *sender != self.idkey() {
+                    return Err(Error::InsufficientPermissions(
+                        sender.to_string(),
+                        op_id.to_string(),
+                    ));
+                }
+                Ok(())
+            }
             // TODO need manual checks
             //Operation::UpdateLinked(
             //    sender,
@@ -812,8 +1572,88 @@ impl TankClient {
             // TODO and need manual checks
             //Operation::AddContact => Ok(()),
             //Operation::ConfirmAddContact => Ok(()),
+            /* Only linked devices can remove a contact, answer a share or
+             * change the block list */
+            Operation::RemoveContact(_)
+            | Operation::AcceptShare(_)
+            | Operation::DeclineShare(_)
+            | Operation::Block(..)
+            | Operation::Unblock(..) => {
+                if !self
+                    .device
+                    .read()
+                    .as_ref()
+                    .unwrap()
+                    .linked_devices()
+                    .contains(sender)
+                {
+                    return Err(Error::InsufficientPermissions(
+                        sender.to_string(),
+                        "linked devices".to_string(),
+                    ));
+                }
+                Ok(())
+            }
+            /* Only a contact can remove itself */
+            Operation::ContactRemoved(contact_name) => {
+                if self
+                    .device
+                    .read()
+                    .as_ref()
+                    .unwrap()
+                    .get_contact_name(sender)
+                    .as_ref()
+                    != Some(contact_name)
+                {
+                    return Err(Error::InsufficientPermissions(
+                        sender.to_string(),
+                        contact_name.to_string(),
+                    ));
+                }
+                Ok(())
+            }
+            /* Invites can only be used once and before they expire */
+            Operation::AcceptInvite(secret, invitee, ..) => {
+                if invitee == sender
+                    && self.invites.lock().is_valid(secret, invite::now_secs())
+                {
+                    Ok(())
+                } else {
+                    Err(Error::InsufficientPermissions(
+                        sender.to_string(),
+                        "invite".to_string(),
+                    ))
+                }
+            }
+            /* Capabilities must have been issued by this device, and not
+             * revoked or tampered with */
+            Operation::RedeemCapability(capability, redeemer, ..) => {
+                let issued = self.capabilities.lock().is_issued(&capability.token_id);
+                if redeemer != sender || capability.issuer != self.idkey() || !issued {
+                    return Err(Error::InsufficientPermissions(
+                        sender.to_string(),
+                        "capability".to_string(),
+                    ));
+                }
+                let signing_key = self.core.as_ref().unwrap().signing_key();
+                capability
+                    .verify(&signing_key, invite::now_secs())
+                    .map_err(Error::from)
+            }
+            /* Only the issuer of a token this device redeemed can reject it */
+            Operation::CapabilityRejected(token_id, ..) => {
+                match self.redeemed_capabilities.lock().get(token_id) {
+                    Some(issuer) if issuer == sender => Ok(()),
+                    _ => Err(Error::InsufficientPermissions(
+                        sender.to_string(),
+                        "capability".to_string(),
+                    )),
+                }
+            }
             /* Special case: use pending idkey */
-            Operation::ConfirmUpdateLinked(new_linked_name, new_groups, new_data) => {
+            Operation::ConfirmUpdateLinked(..)
+            | Operation::LinkSnapshotStart(..)
+            | Operation::LinkSnapshotChunk(..) => {
                 let pending_idkey_opt = self
                     .device
                     .read()
@@ -835,12 +1675,23 @@ impl TankClient {
                 }
                 Ok(())
             }
+            /* Only the device a snapshot is being sent to can ask for it */
+            Operation::LinkSnapshotResume(_) | Operation::LinkSnapshotDone => {
+                if !self.link_snapshots.lock().contains_key(sender) {
+                    return Err(Error::InsufficientPermissions(
+                        sender.to_string(),
+                        "link snapshot".to_string(),
+                    ));
+                }
+                Ok(())
+            }
             /* Need metadata-mod permissions */
             Operation::SetPerm(perm_id, perm_val) => {
                 // if permissions set with this id already exists, return error
                 // this prevents malicious clients from replacing an existing
                 // permissions object with one of their choosing, giving them
-                // access to whatever data was pointed to
+                // access to whatever data was pointed to (or dropping the
+                // owner quorum); an unchanged set can be resent
                 if self
                     .device
                     .read()
@@ -848,15 +1699,14 @@ impl TankClient {
                     .unwrap()
                     .meta_store
                     .read()
-                    .get_perm(perm_id)
-                    .is_some()
+                    .is_perm_overwrite(perm_id, perm_val)
                 {
-                    return Ok(()); //return Err(Error::PermAlreadyExists(perm_id.
-                                   // to_string()));
+                    return Err(Error::PermAlreadyExists(perm_id.to_string()));
                 }
                 Ok(())
             }
-            Operation::AddPermMembers(perm_id, group_id_opt, new_members) => {
+            Operation::AddPermMembers(perm_id, _, _)
+            | Operation::SetGrantExpiry(perm_id, _, _) => {
                 // must have valid permissions given existing permissions
                 // object
                 if !self
@@ -873,6 +1723,61 @@ impl TankClient {
                         perm_id.to_string(),
                     ));
                 }
+                // once several owners must agree, owners can only be added
+                // through ProposeOwnerChange
+                if let Operation::AddPermMembers(_, _, PermType::Owners(_)) = operation {
+                    if self
+                        .device
+                        .read()
+                        .as_ref()
+                        .unwrap()
+                        .meta_store
+                        .read()
+                        .required_owner_approvals(perm_id)
+                        > 1
+                    {
+                        return Err(Error::OwnerApprovalRequired(perm_id.to_string()));
+                    }
+                }
+                Ok(())
+            }
+            /* Need owner permissions */
+            Operation::ProposeOwnerChange(_, perm_id, _) => {
+                if !self
+                    .device
+                    .read()
+                    .as_ref()
+                    .unwrap()
+                    .meta_store
+                    .read()
+                    .has_owner_mod_permissions(sender, perm_id)
+                {
+                    return Err(Error::InsufficientPermissions(
+                        sender.to_string(),
+                        perm_id.to_string(),
+                    ));
+                }
+                Ok(())
+            }
+            Operation::ApproveOwnerChange(proposal_id) => {
+                let device_guard = self.device.read();
+                let meta_store_guard = device_guard.as_ref().unwrap().meta_store.read();
+                let perm_id = match meta_store_guard.get_owner_proposal(proposal_id) {
+                    Some(proposal) => &proposal.perm_id,
+                    None => {
+                        return Err(Error::from(
+                            crate::metadata::Error::ProposalDoesNotExist(
+                                proposal_id.to_string(),
+                            ),
+                        ))
+                    }
+                };
+                if !meta_store_guard.has_owner_mod_permissions(sender, perm_id) {
+                    return Err(Error::InsufficientPermissions(
+                        sender.to_string(),
+                        perm_id.to_string(),
+                    ));
+                }
                 Ok(())
             }
             /* Need metadata-mod permissions (via perm-backpointer) */
@@ -896,11 +1801,33 @@ impl TankClient {
                         group_id.to_string(),
                     ));
                 }
-                Ok(())
+                // owners of a perm with a quorum change only through
+                // ProposeOwnerChange
+                match self
+                    .device
+                    .read()
+                    .as_ref()
+                    .unwrap()
+                    .meta_store
+                    .read()
+                    .bypasses_owner_quorum(group_id, group_val)
+                {
+                    Some(perm_id) => Err(Error::OwnerApprovalRequired(perm_id)),
+                    None => Ok(()),
+                }
             }
             // FIXME groups may need to be sent in a particular order in order
             // for the above check to work in a loop
-            Operation::SetGroups(groups) => Ok(()),
+            Operation::SetGroups(groups) => {
+                let device_guard = self.device.read();
+                let meta_store_guard = device_guard.as_ref().unwrap().meta_store.read();
+                match groups.iter().find_map(|(group_id, group_val)| {
+                    meta_store_guard.bypasses_owner_quorum(group_id, group_val)
+                }) {
+                    Some(perm_id) => Err(Error::OwnerApprovalRequired(perm_id)),
+                    None => Ok(()),
+                }
+            }
             //Operation::LinkGroups(parent_id, child_id) => Ok(()),
             //Operation::DeleteGroup(group_id) => Ok(()),
             // TODO do permission check
@@ -962,24 +1889,268 @@ impl TankClient {
         }
     }
 
-    fn validate_data_invariants(&self, operation: &Operation) -> bool {
+    fn validate_data_invariants(
+        &self,
+        sender: &String,
+        operation: &Operation,
+    ) -> Result<(), ValidationError> {
         match operation {
-            Operation::UpdateData(data_id, data_val) => self
-                .device
-                .read()
-                .as_ref()
-                .unwrap()
-                .data_store
-                .read()
-                .validate(&data_id, &data_val),
-            _ => true,
+            Operation::UpdateData(data_id, data_val)
+            | Operation::CausalUpdateData(data_id, data_val, _) => {
+                // validators and schemas are written for the current version
+                // of a type, so updates that cannot be brought to it (newer
+                // versions, or failed upgrades) cannot be checked and are
+                // rejected rather than let through
+                let upgraded =
+                    self.migrations.read().upgrade(data_val).map_err(|err| {
+                        ValidationError::UnsupportedVersion(
+                            data_val.data_type().to_string(),
+                            err.to_string(),
+                        )
+                    })?;
+                let device_guard = self.device.read();
+                let data_store_guard = device_guard.as_ref().unwrap().data_store.read();
+                // merging would fail (or drop the CRDT) on every device
+                if let Some(old_val) = data_store_guard.get_data(data_id) {
+                    crdt::check_write(old_val, &upgraded).map_err(|err| {
+                        ValidationError::CrdtMismatch(
+                            data_id.to_string(),
+                            err.to_string(),
+                        )
+                    })?;
+                }
+                data_store_guard.validate(sender, &data_id, &upgraded)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // lets the writer of a rejected update know why it was rejected
+    async fn report_rejection(
+        &self,
+        writer: String,
+        data_id: String,
+        reason: ValidationError,
+    ) {
+        if writer == self.idkey() {
+            self.data_rejections.lock().push(DataRejection {
+                rejected_by: self.idkey(),
+                data_id,
+                reason,
+            });
+            return;
+        }
+        let _ = self
+            .send_message(vec![(
+                vec![writer],
+                Operation::DataRejected(data_id, reason),
+                false,
+            )])
+            .await;
+    }
+
+    async fn report_capability_rejection(
+        &self,
+        redeemer: String,
+        capability: &Capability,
+        reason: Error,
+    ) {
+        let _ = self
+            .send_message(vec![(
+                vec![redeemer],
+                Operation::CapabilityRejected(
+                    capability.token_id.clone(),
+                    capability.data_id.clone(),
+                    reason.to_string(),
+                ),
+                false,
+            )])
+            .await;
+    }
+
+    #[async_recursion]
+    async fn process_operation(
+        &self,
+        seq: SequenceNumber,
+        sender: String,
+        operation: Operation,
+    ) {
+        if self.hold_for_acceptance(seq, &sender, &operation) {
+            return;
+        }
+        self.apply_operation(seq, sender, operation).await;
+    }
+
+    // Keeps contact requests, and anything that would add another user's
+    // permission sets or data to this device, out of the stores until this
+    // user accepts them. Returns true if operation was held back (or dropped
+    // because its share was declined).
+    fn hold_for_acceptance(
+        &self,
+        seq: SequenceNumber,
+        sender: &String,
+        operation: &Operation,
+    ) -> bool {
+        if !*self.require_acceptance.read() {
+            return false;
+        }
+        let device_guard = self.device.read();
+        let device = match device_guard.as_ref() {
+            Some(device) => device,
+            None => return false,
+        };
+        if *sender == self.idkey() || device.linked_devices().contains(sender) {
+            return false;
+        }
+
+        // groups only belong to a share that is already being held back
+        let held_perm_id = |group: &Group| {
+            group
+                .perm_ids()
+                .iter()
+                .find(|perm_id| {
+                    device.is_share_pending(perm_id) || device.is_share_declined(perm_id)
+                })
+                .cloned()
+        };
+        let (perm_id, data_id) = match operation {
+            Operation::AddContact(idkey, contact_name, contact_devices) => {
+                device.add_pending_contact(PendingContact {
+                    idkey: idkey.to_string(),
+                    contact_name: contact_name.to_string(),
+                    contact_devices: contact_devices.clone(),
+                });
+                return true;
+            }
+            Operation::SetPerm(perm_id, _)
+            | Operation::AddPermMembers(perm_id, ..)
+            | Operation::SetGrantExpiry(perm_id, ..) => (perm_id.to_string(), None),
+            Operation::UpdateData(data_id, data_val)
+            | Operation::CausalUpdateData(data_id, data_val, _) => {
+                (data_val.perm_id().to_string(), Some(data_id.to_string()))
+            }
+            Operation::SetGroup(_, group) => match held_perm_id(group) {
+                Some(perm_id) => (perm_id, None),
+                None => return false,
+            },
+            Operation::SetGroups(groups) => {
+                match groups.values().find_map(held_perm_id) {
+                    Some(perm_id) => (perm_id, None),
+                    None => return false,
+                }
+            }
+            _ => return false,
+        };
+        if device.is_share_accepted(&perm_id)
+            || device.meta_store.read().get_perm(&perm_id).is_some()
+        {
+            return false;
+        }
+        if !device.is_share_declined(&perm_id) {
+            device.hold_share_op(
+                perm_id,
+                sender.to_string(),
+                data_id,
+                seq,
+                Operation::to_string(operation).unwrap(),
+            );
+        }
+        true
+    }
+
+    #[async_recursion]
+    async fn apply_operation(
+        &self,
+        seq: SequenceNumber,
+        sender: String,
+        operation: Operation,
+    ) {
+        match self.check_permissions(&sender, &operation) {
+            Ok(_) => match self.validate_data_invariants(&sender, &operation) {
+                Ok(_) => match self.demux(seq, sender.clone(), operation).await {
+                    Ok(_) => {}
+                    Err(err) => {
+                        println!("Error in demux: {:?}", err)
+                    }
+                },
+                Err(err) => {
+                    println!("Error in validation: {:?}", err);
+                    self.skip_causal(&operation).await;
+                    self.report_rejection(
+                        sender,
+                        Operation::get_data_id(&operation),
+                        err,
+                    )
+                    .await;
+                }
+            },
+            Err(err) => {
+                println!("Error in permissions: {:?}", err);
+                self.skip_causal(&operation).await;
+                if let Operation::RedeemCapability(capability, ..) = &operation {
+                    self.report_capability_rejection(sender, capability, err)
+                        .await;
+                }
+            }
+        }
+        // a device that never took part in a transaction is not counted in
+        // the watermark; its first tx is started after the last message it
+        // applied, so it is only aborted if that is before a trimmed tx, and
+        // a retry then starts after the abort
+        if let Some(tx_coordinator) = self.tx_coordinator.write().as_mut() {
+            tx_coordinator.observe(seq);
+        }
+    }
+
+    // a causal update that is dropped still counts as delivered, so that
+    // updates depending on it are not held back forever
+    async fn skip_causal(&self, operation: &Operation) {
+        if let Operation::CausalUpdateData(data_id, _, meta) = operation {
+            self.causal_applied(data_id, meta).await;
+        }
+    }
+
+    // records that the update tagged with dot has been applied and applies
+    // any buffered causal updates that were only waiting on it
+    async fn causal_applied(&self, data_id: &String, meta: &CausalMeta) {
+        self.causal_state
+            .lock()
+            .mark_applied(&self.idkey(), data_id, meta);
+        loop {
+            let ready = self.causal_state.lock().take_ready();
+            if ready.is_empty() {
+                break;
+            }
+            for (_, (seq, sender, operation)) in ready {
+                self.process_operation(seq, sender, operation).await;
+            }
         }
     }
 
+    // Tags an update to data_id that will be sent to device_ids. It depends
+    // on the latest known update to each data object that was also sent to
+    // all of device_ids, since those are the only ones they are sure to have.
+    fn new_causal_update(
+        &self,
+        data_id: &String,
+        device_ids: &Vec<String>,
+    ) -> CausalMeta {
+        self.causal_state
+            .lock()
+            .new_update(&self.idkey(), data_id, device_ids)
+    }
+
+    // Number of causal updates received but not yet applied because some of
+    // their dependencies have not arrived
+    pub fn num_causally_pending(&self) -> usize {
+        self.causal_state.lock().num_pending()
+    }
+
     #[async_recursion]
     async fn demux(
         &self,
         seq: SequenceNumber,
+        sender: String,
         operation: Operation,
     ) -> Result<(), Error> {
         match operation {
@@ -987,43 +2158,275 @@ impl TankClient {
                 .update_linked_group(sender, temp_linked_name, members_to_add)
                 .await
                 .map_err(Error::from),
-            Operation::ConfirmUpdateLinked(new_linked_name, new_groups, new_data) => self
-                .device
-                .read()
-                .as_ref()
-                .unwrap()
-                .confirm_update_linked_group(new_linked_name, new_groups, new_data)
-                .map_err(Error::from),
+            Operation::ConfirmUpdateLinked(linked_name, groups, data) => {
+                if self.incoming_link.lock().take().is_none() {
+                    return Ok(());
+                }
+                self.install_link_state(linked_name, groups, data).await
+            }
+            Operation::LinkSnapshotStart(linked_name, num_chunks, hash) => {
+                match self.incoming_link.lock().as_mut() {
+                    Some(incoming) => incoming.set_header(linked_name, num_chunks, hash),
+                    None => return Ok(()),
+                }
+                self.finish_link_if_complete(sender).await
+            }
+            Operation::LinkSnapshotChunk(chunk) => {
+                let index = chunk.index;
+                let res = match self.incoming_link.lock().as_mut() {
+                    Some(incoming) => incoming.add_chunk(chunk),
+                    None => return Ok(()),
+                };
+                if let Err(err) = res {
+                    // ask for a fresh copy of the chunk
+                    self.send_link_message(
+                        sender,
+                        &Operation::LinkSnapshotResume(Some(vec![index])),
+                    )
+                    .await?;
+                    return Err(Error::from(err));
+                }
+                self.finish_link_if_complete(sender).await
+            }
+            Operation::LinkSnapshotResume(missing) => {
+                // starting over: send the current state rather than the
+                // state at the time of the first attempt
+                if missing.is_none() {
+                    self.prepare_link_snapshot(sender.clone())?;
+                }
+                self.send_link_snapshot(sender, missing).await
+            }
+            Operation::LinkSnapshotDone => {
+                self.link_snapshots.lock().remove(&sender);
+                Ok(())
+            }
             Operation::AddContact(sender, contact_name, contact_devices) => self
                 .add_contact_response(sender, contact_name, contact_devices)
                 .await
                 .map_err(Error::from),
-            Operation::ConfirmAddContact(contact_name, contact_devices) => self
-                .device
-                .read()
-                .as_ref()
-                .unwrap()
-                .add_contact(contact_name, contact_devices)
-                .map_err(Error::from),
-            Operation::SetPerm(perm_id, perm_val) => {
-                self.device
+            Operation::AcceptInvite(secret, sender, contact_name, contact_devices) => {
+                {
+                    let mut invites = self.invites.lock();
+                    invites.take(&secret);
+                    self.save_invites(&invites)?;
+                }
+                self.add_contact_response(sender, contact_name, contact_devices)
+                    .await
+            }
+            Operation::RedeemCapability(
+                capability,
+                sender,
+                contact_name,
+                contact_devices,
+            ) => {
+                self.add_contact_response(
+                    sender.clone(),
+                    contact_name.clone(),
+                    contact_devices,
+                )
+                .await?;
+                // add_permissions() waits on its own messages coming back,
+                // which can only happen once this callback returns
+                let client = self.clone();
+                tokio::spawn(async move {
+                    let res = match capability.role {
+                        CapabilityRole::Reader => {
+                            client
+                                .add_readers(
+                                    capability.data_id.clone(),
+                                    vec![&contact_name],
+                                )
+                                .await
+                        }
+                        CapabilityRole::Writer => {
+                            client
+                                .add_writers(
+                                    capability.data_id.clone(),
+                                    vec![&contact_name],
+                                )
+                                .await
+                        }
+                    };
+                    if let Err(err) = res {
+                        println!("Error granting capability: {:?}", err);
+                        client
+                            .report_capability_rejection(sender, &capability, err)
+                            .await;
+                    }
+                });
+                Ok(())
+            }
+            Operation::AcceptShare(perm_id) => {
+                let share =
+                    match self.device.read().as_ref().unwrap().accept_share(&perm_id) {
+                        Some(share) => share,
+                        None => return Ok(()),
+                    };
+                for (op_seq, op_sender, op_string) in share.ops.into_iter() {
+                    match Operation::from_string(op_string.clone()) {
+                        Ok(op) => self.apply_operation(op_seq, op_sender, op).await,
+                        Err(_) => println!(
+                            "Error getting operation: {:?}",
+                            Error::StringConversionErr(op_string)
+                        ),
+                    }
+                }
+                Ok(())
+            }
+            Operation::DeclineShare(perm_id) => {
+                let share = self.device.read().as_ref().unwrap().decline_share(&perm_id);
+                // dropped causal updates must not hold up others
+                for (_, _, op_string) in share.map_or(Vec::new(), |share| share.ops) {
+                    if let Ok(op) = Operation::from_string(op_string) {
+                        self.skip_causal(&op).await;
+                    }
+                }
+                Ok(())
+            }
+            Operation::RemoveContact(contact_name) => {
+                let contact_devices = self
+                    .device
                     .read()
                     .as_ref()
                     .unwrap()
-                    .meta_store
-                    .write()
-                    .set_perm(perm_id, perm_val);
-                Ok(())
+                    .remove_contact(&contact_name)?;
+                {
+                    let own_devices = self.own_devices();
+                    let mut block_list = self.block_list.write();
+                    for idkey in contact_devices.difference(&own_devices) {
+                        block_list.block_device(idkey.clone());
+                    }
+                    self.save_block_list(&block_list)?;
+                }
+                self.forget_devices(&contact_devices).await
             }
-            Operation::AddPermMembers(perm_id, group_id_opt, new_members) => self
+            Operation::Block(id, is_group) => {
+                if self.blocks_own_device(&id, is_group) {
+                    return Err(Error::CannotBlockOwnDevice(id));
+                }
+                let mut block_list = self.block_list.write();
+                match is_group {
+                    true => block_list.block_group(id),
+                    false => block_list.block_device(id),
+                };
+                self.save_block_list(&block_list)
+            }
+            Operation::Unblock(id, is_group) => {
+                let mut block_list = self.block_list.write();
+                match is_group {
+                    true => block_list.unblock_group(&id),
+                    false => block_list.unblock_device(&id),
+                };
+                self.save_block_list(&block_list)
+            }
+            Operation::ContactRemoved(contact_name) => {
+                let contact_devices = self
+                    .device
+                    .read()
+                    .as_ref()
+                    .unwrap()
+                    .remove_contact(&contact_name)?;
+                self.forget_devices(&contact_devices).await
+            }
+            Operation::ConfirmAddContact(contact_name, contact_devices) => self
                 .device
                 .read()
                 .as_ref()
                 .unwrap()
-                .meta_store
-                .write()
-                .add_permissions(&perm_id, group_id_opt, new_members)
+                .add_contact(contact_name, contact_devices)
                 .map_err(Error::from),
+            Operation::SetPerm(perm_id, perm_val) => {
+                let device_guard = self.device.read();
+                let mut meta_store_guard =
+                    device_guard.as_ref().unwrap().meta_store.write();
+                meta_store_guard.log_perm_change(PermChangeEntry {
+                    sender,
+                    seq,
+                    perm_id: perm_id.clone(),
+                    change: PermChange::Set(perm_val.clone()),
+                });
+                meta_store_guard.set_perm(perm_id, perm_val);
+                Ok(())
+            }
+            Operation::AddPermMembers(perm_id, group_id_opt, new_members) => {
+                let device_guard = self.device.read();
+                let mut meta_store_guard =
+                    device_guard.as_ref().unwrap().meta_store.write();
+                meta_store_guard.add_permissions(
+                    &perm_id,
+                    group_id_opt.clone(),
+                    new_members.clone(),
+                )?;
+                meta_store_guard.log_perm_change(PermChangeEntry {
+                    sender,
+                    seq,
+                    perm_id,
+                    change: PermChange::AddMembers(group_id_opt, new_members),
+                });
+                Ok(())
+            }
+            Operation::ProposeOwnerChange(proposal_id, perm_id, change) => {
+                let device_guard = self.device.read();
+                let mut meta_store_guard =
+                    device_guard.as_ref().unwrap().meta_store.write();
+                if meta_store_guard.propose_owner_change(
+                    proposal_id,
+                    perm_id.clone(),
+                    change.clone(),
+                    sender.clone(),
+                )? {
+                    meta_store_guard.log_perm_change(PermChangeEntry {
+                        sender,
+                        seq,
+                        perm_id,
+                        change: PermChange::Owners(change),
+                    });
+                }
+                Ok(())
+            }
+            Operation::ApproveOwnerChange(proposal_id) => {
+                let device_guard = self.device.read();
+                let mut meta_store_guard =
+                    device_guard.as_ref().unwrap().meta_store.write();
+                let proposal = meta_store_guard.get_owner_proposal(&proposal_id).cloned();
+                if meta_store_guard.approve_owner_change(&proposal_id, sender.clone())? {
+                    let proposal = proposal.unwrap();
+                    meta_store_guard.log_perm_change(PermChangeEntry {
+                        sender,
+                        seq,
+                        perm_id: proposal.perm_id,
+                        change: PermChange::Owners(proposal.change),
+                    });
+                }
+                Ok(())
+            }
+            Operation::SetGrantExpiry(perm_id, members, expiry) => {
+                let device_guard = self.device.read();
+                let mut meta_store_guard =
+                    device_guard.as_ref().unwrap().meta_store.write();
+                let mut perm_set = match meta_store_guard.get_perm(&perm_id) {
+                    Some(perm_set) => perm_set.clone(),
+                    None => {
+                        return Err(Error::from(
+                            crate::metadata::Error::PermSetDoesNotExist(perm_id),
+                        ))
+                    }
+                };
+                match members {
+                    PermType::Readers(members) => members
+                        .into_iter()
+                        .for_each(|member| perm_set.set_reader_expiry(member, expiry)),
+                    PermType::Writers(members) => members
+                        .into_iter()
+                        .for_each(|member| perm_set.set_writer_expiry(member, expiry)),
+                    // only reader and writer grants can expire
+                    PermType::Owners(_) | PermType::DOReaders(_) => {
+                        return Err(Error::InsufficientPermissions(sender, perm_id))
+                    }
+                }
+                meta_store_guard.set_perm(perm_id, perm_set);
+                Ok(())
+            }
             Operation::SetGroup(group_id, group_val) => {
                 self.device
                     .read()
@@ -1100,23 +2503,80 @@ impl TankClient {
             //    .remove_child(&group_id, &child_id)
             //    .map_err(Error::from),
             Operation::UpdateData(data_id, data_val) => {
-                self.device
-                    .read()
-                    .as_ref()
-                    .unwrap()
-                    .data_store
-                    .write()
-                    .set_data(data_id, data_val);
+                // updates that cannot be upgraded were already rejected by
+                // validate_data_invariants
+                let data_val = {
+                    let migrations = self.migrations.read();
+                    match migrations.mode() {
+                        MigrationMode::Eager => {
+                            migrations.upgrade(&data_val).unwrap_or(data_val)
+                        }
+                        MigrationMode::Lazy => data_val,
+                    }
+                };
+                let device_guard = self.device.read();
+                let mut data_store_guard =
+                    device_guard.as_ref().unwrap().data_store.write();
+                // CRDT values are merged into the local value rather than
+                // overwriting it
+                let data_val = match data_store_guard.get_data(&data_id) {
+                    Some(old_val) => crdt::merge_data(old_val, data_val)?,
+                    None => data_val,
+                };
+                data_store_guard.set_data_from(data_id, data_val, sender, seq);
                 Ok(())
             }
+            Operation::CausalUpdateData(data_id, data_val, meta) => {
+                let (seq, sender, data_id, data_val, meta) = {
+                    let mut causal_state = self.causal_state.lock();
+                    if causal_state.is_ready(&meta) {
+                        (seq, sender, data_id, data_val, meta)
+                    } else {
+                        let operation =
+                            Operation::CausalUpdateData(data_id, data_val, meta.clone());
+                        match causal_state.buffer(meta, (seq, sender, operation)) {
+                            // too many updates are held back: stop waiting on
+                            // the dependencies of the oldest
+                            Some((
+                                _,
+                                (
+                                    seq,
+                                    sender,
+                                    Operation::CausalUpdateData(data_id, data_val, meta),
+                                ),
+                            )) => {
+                                println!("Applying causal update {:?} early", meta.dot);
+                                (seq, sender, data_id, data_val, meta)
+                            }
+                            _ => return Ok(()),
+                        }
+                    }
+                };
+                let res = self
+                    .demux(
+                        seq,
+                        sender,
+                        Operation::UpdateData(data_id.clone(), data_val),
+                    )
+                    .await;
+                self.causal_applied(&data_id, &meta).await;
+                res
+            }
             Operation::DeleteData(data_id) => {
-                self.device
-                    .read()
-                    .as_ref()
-                    .unwrap()
-                    .data_store
-                    .write()
-                    .delete_data(&data_id);
+                {
+                    let device_guard = self.device.read();
+                    let device = device_guard.as_ref().unwrap();
+                    let deleted = device.data_store.write().delete_data(&data_id);
+                    if let Some(data_val) = deleted {
+                        device
+                            .meta_store
+                            .write()
+                            .mark_perm_orphaned(data_val.perm_id());
+                    }
+                }
+                if *self.auto_compact.read() {
+                    self.compact()?;
+                }
                 Ok(())
             }
             Operation::DeleteSelfDevice => {
@@ -1131,13 +2591,14 @@ impl TankClient {
                     })
                     .map_err(Error::from)
             }
-            Operation::DeleteOtherDevice(idkey_to_delete) => self
-                .device
-                .read()
-                .as_ref()
-                .unwrap()
-                .delete_device(idkey_to_delete)
-                .map_err(Error::from),
+            Operation::DeleteOtherDevice(idkey_to_delete) => {
+                self.device
+                    .read()
+                    .as_ref()
+                    .unwrap()
+                    .delete_device(idkey_to_delete.clone())?;
+                self.forget_devices(&HashSet::from([idkey_to_delete])).await
+            }
             Operation::Test(_) => Ok(()),
             Operation::Dummy(op_id) => {
                 let mut op_id_ctr = self.op_id_ctr.lock();
@@ -1147,23 +2608,88 @@ impl TankClient {
                 core::mem::drop(op_id_ctr);
                 Ok(())
             }
+            Operation::ReadSnapshot(op_id, data_ids) => {
+                // all prior operations in the total order have already been
+                // applied, so this is a consistent snapshot as of seq
+                let mut snapshot = HashMap::<String, BasicData>::new();
+                let device_guard = self.device.read();
+                let data_store_guard = device_guard.as_ref().unwrap().data_store.read();
+                for data_id in data_ids {
+                    if let Some(data_val) = data_store_guard.get_data(&data_id) {
+                        snapshot.insert(data_id, data_val.clone());
+                    }
+                }
+                core::mem::drop(data_store_guard);
+                core::mem::drop(device_guard);
+                self.read_snapshots.lock().insert(op_id, (seq, snapshot));
+
+                let mut op_id_ctr = self.op_id_ctr.lock();
+                // remove op_id from hashset
+                op_id_ctr.1.remove(&op_id);
+                self.op_id_ctr_cv.notify_all();
+                core::mem::drop(op_id_ctr);
+                Ok(())
+            }
+            Operation::DataRejected(data_id, reason) => {
+                self.data_rejections.lock().push(DataRejection {
+                    rejected_by: sender,
+                    data_id,
+                    reason,
+                });
+                Ok(())
+            }
+            Operation::CapabilityRejected(token_id, data_id, reason) => {
+                self.redeemed_capabilities.lock().remove(&token_id);
+                self.capability_rejections.lock().push(CapabilityRejection {
+                    rejected_by: sender,
+                    token_id,
+                    data_id,
+                    reason,
+                });
+                Ok(())
+            }
             Operation::TxStart(sender, tx) => {
+                let tx_copy = tx.clone();
                 let res = self.tx_coordinator.write().as_mut().unwrap().start_message(
                     self.idkey(),
                     sender.clone(),
                     seq,
                     tx,
                 );
-                if res == Err(Error::TransactionConflictsError) {
+                // the coordinator only sends a commit once every recipient
+                // (including itself) has voted to accept
+                if sender == self.idkey() {
+                    if res == Err(Error::TransactionConflictsError) {
+                        self.send_abort_as_coordinator(seq, &tx_copy).await;
+                    } else {
+                        let seq_number =
+                            self.tx_coordinator.read().as_ref().unwrap().seq_number;
+                        self.record_accept_as_coordinator(sender, seq, seq_number)
+                            .await;
+                    }
+                } else if res == Err(Error::TransactionConflictsError) {
                     self.send_abort_to_coordinator(sender, seq).await;
                 } else {
-                    if sender == self.idkey() {
-                        self.send_commit_as_coordinator(seq).await;
-                    }
+                    self.send_accept_to_coordinator(sender, seq).await;
                 }
                 Ok(())
             }
+            Operation::TxAccept(sender, tx_id, sender_seq_number) => {
+                self.record_accept_as_coordinator(sender, tx_id, sender_seq_number)
+                    .await;
+                Ok(())
+            }
             Operation::TxCommit(sender, tx_id) => {
+                // copy out before commit_message() moves the tx out of the
+                // pending set
+                let tx_opt = self
+                    .tx_coordinator
+                    .read()
+                    .as_ref()
+                    .unwrap()
+                    .get_transaction(tx_id)
+                    .ok()
+                    .cloned();
                 let resp = self
                     .tx_coordinator
                     .write()
@@ -1171,21 +2697,62 @@ impl TankClient {
                     .unwrap()
                     .commit_message(self.idkey(), sender, &tx_id, seq);
                 if resp == Ok(()) {
-                    self.apply_locally(tx_id).await;
+                    if let Some(tx) = tx_opt.as_ref() {
+                        let _ = self.apply_locally(tx_id, tx).await;
+                    }
+                }
+                match tx_opt {
+                    Some(tx) if tx.coordinator == self.idkey() => {
+                        self.resolve_transaction_shard(&tx, resp);
+                    }
+                    _ => {}
                 }
                 Ok(())
             }
             Operation::TxAbort(sender, tx_id) => {
+                let tx_opt = self
+                    .tx_coordinator
+                    .read()
+                    .as_ref()
+                    .unwrap()
+                    .get_transaction(tx_id)
+                    .ok()
+                    .cloned();
                 let resp = self.tx_coordinator.write().as_mut().unwrap().abort_message(
                     self.idkey(),
-                    sender,
+                    sender.clone(),
                     &tx_id,
                 );
                 if resp == Err(Error::SendToAll) {
-                    self.send_abort_as_coordinator(tx_id).await;
+                    // dropped already, so relay the abort to the recipients
+                    // that voted to commit and wake up the caller now
+                    let tx = tx_opt.unwrap();
+                    self.send_abort_as_coordinator(tx_id, &tx).await;
+                    self.resolve_transaction_shard(
+                        &tx,
+                        Err(Error::TransactionConflictsError),
+                    );
+                } else if sender == self.idkey() {
+                    // this device's own abort broadcast as coordinator
+                    match tx_opt {
+                        Some(tx) if tx.coordinator == self.idkey() => {
+                            self.resolve_transaction_shard(
+                                &tx,
+                                Err(Error::TransactionConflictsError),
+                            );
+                        }
+                        _ => {}
+                    }
                 }
                 Ok(())
             }
+            // only ever the outermost operation of a message, which
+            // receive_operation() routes before it gets here
+            Operation::Namespaced(app_id, operation) => {
+                self.receive_namespaced(seq, sender, app_id, *operation)
+                    .await;
+                Ok(())
+            }
         }
     }
 
@@ -1194,11 +2761,35 @@ impl TankClient {
     // TODO: change message to be a collection of messages
     async fn send_message(
         &self,
-        series: Vec<(Vec<String>, String, bool)>,
+        series: Vec<(Vec<String>, Operation, bool)>,
     ) -> reqwest::Result<reqwest::Response> {
+        let wire_format = *self.wire_format.read();
+        let series = series
+            .into_iter()
+            .map(|(recipients, operation, bench)| {
+                let operation = match &self.namespace {
+                    Some(app_id) => {
+                        Operation::Namespaced(app_id.to_string(), Box::new(operation))
+                    }
+                    None => operation,
+                };
+                (
+                    recipients,
+                    wire::encode(&operation, wire_format).unwrap(),
+                    bench,
+                )
+            })
+            .collect();
         self.core.as_ref().unwrap().send_message(series).await
     }
 
+    // Every device understands WireFormat::Json, so it is the default;
+    // switch to Binary only once all devices that will receive messages
+    // decode the current wire::WIRE_VERSION
+    pub fn set_wire_format(&self, wire_format: WireFormat) {
+        *self.wire_format.write() = wire_format;
+    }
+
     /* Remaining top-level functionality */
 
     // Doesn't make sense to sync this read, since the idkey is needed to send
@@ -1228,51 +2819,7 @@ impl TankClient {
     // TODO if no linked devices, linked_name can just == idkey
     // only create linked_group if link devices
     pub async fn create_standalone_device(&self) -> Result<(), Error> {
-        // check if can have multiple outstanding ops, or if not, check that
-        // no other ops are outstanding
-        let op_id;
-        loop {
-            let mut op_id_ctr = self.op_id_ctr.lock();
-            if !self.mult_outstanding && op_id_ctr.1.len() != 0 {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                // get op_id and inc id ctr
-                op_id = op_id_ctr.0;
-                op_id_ctr.0 += 1;
-                // add op into hashset
-                op_id_ctr.1.insert(op_id);
-                break;
-            }
-        }
-
-        /////////
-
-        let res = self
-            .send_or_add_to_txn(
-                vec![self.idkey()],
-                &Operation::Dummy(op_id.clone()),
-                false,
-            )
-            .await;
-
-        if res.is_err() {
-            return Err(Error::SendFailed(res.err().unwrap().to_string()));
-        }
-
-        /////////
-
-        // check if need to block on reads, and if so, if this read has
-        // returned from the server yet
-        loop {
-            let op_id_ctr = self.op_id_ctr.lock();
-            if self.sync_reads && op_id_ctr.1.contains(&op_id) {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                break;
-            }
-        }
+        self.start_op().await?;
 
         /////////
 
@@ -1283,51 +2830,7 @@ impl TankClient {
     }
 
     pub async fn create_linked_device(&self, idkey: String) -> Result<(), Error> {
-        // check if can have multiple outstanding ops, or if not, check that
-        // no other ops are outstanding
-        let op_id;
-        loop {
-            let mut op_id_ctr = self.op_id_ctr.lock();
-            if !self.mult_outstanding && op_id_ctr.1.len() != 0 {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                // get op_id and inc id ctr
-                op_id = op_id_ctr.0;
-                op_id_ctr.0 += 1;
-                // add op into hashset
-                op_id_ctr.1.insert(op_id);
-                break;
-            }
-        }
-
-        /////////
-
-        let res = self
-            .send_or_add_to_txn(
-                vec![self.idkey()],
-                &Operation::Dummy(op_id.clone()),
-                false,
-            )
-            .await;
-
-        if res.is_err() {
-            return Err(Error::SendFailed(res.err().unwrap().to_string()));
-        }
-
-        /////////
-
-        // check if need to block on reads, and if so, if this read has
-        // returned from the server yet
-        loop {
-            let op_id_ctr = self.op_id_ctr.lock();
-            if self.sync_reads && op_id_ctr.1.contains(&op_id) {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                break;
-            }
-        }
+        self.start_op().await?;
 
         /////////
 
@@ -1336,6 +2839,9 @@ impl TankClient {
             None,
             Some(idkey.clone()),
         ));
+        *self.incoming_link.lock() = Some(IncomingSnapshot::new());
+        self.link_buffer.lock().clear();
+        *self.link_failed.write() = false;
 
         let linked_name = self
             .device
@@ -1384,101 +2890,203 @@ impl TankClient {
             .unwrap()
             .update_linked_group(temp_linked_name.clone(), members_to_add)
             .map_err(Error::from);
-        let perm_linked_name = self
-            .device
-            .read()
-            .as_ref()
-            .unwrap()
-            .linked_name
-            .read()
-            .to_string();
+        self.prepare_link_snapshot(sender.clone())?;
+        // TODO notify contacts of new members
+        self.send_link_snapshot(sender, None).await
+    }
 
-        // send all groups and to new members
-        // FIXME send_or_add_to_txn?
-        match self
-            .send_message(vec![(
-                vec![sender],
-                Operation::to_string(&Operation::ConfirmUpdateLinked(
-                    perm_linked_name,
-                    self.device
-                        .read()
-                        .as_ref()
-                        .unwrap()
-                        .meta_store
-                        .read()
-                        .get_all_groups()
-                        .clone(),
-                    self.device
-                        .read()
-                        .as_ref()
-                        .unwrap()
-                        .data_store
-                        .read()
-                        .get_all_data()
-                        .clone(),
-                ))
-                .unwrap(),
-                false,
-            )])
-            .await
-        {
-            Ok(_) => {
-                // TODO notify contacts of new members
-                // get contacts
+    // snapshots all groups and data for the new device dst_idkey, in chunks
+    // small enough to go through the server
+    fn prepare_link_snapshot(&self, dst_idkey: String) -> Result<(), Error> {
+        let snapshot = {
+            let device_guard = self.device.read();
+            let device = device_guard.as_ref().unwrap();
+            let linked_name = device.linked_name.read().to_string();
+            let groups = device.meta_store.read().get_all_groups().clone();
+            let data = device.data_store.read().get_all_data().clone();
+            OutgoingSnapshot::new(
+                linked_name,
+                groups,
+                data,
+                *self.link_chunk_bytes.read(),
+            )?
+        };
+        self.link_snapshots.lock().insert(dst_idkey, snapshot);
+        Ok(())
+    }
 
-                Ok(())
-            }
-            Err(err) => Err(Error::SendFailed(err.to_string())),
+    // sends the snapshot header and the requested chunks (all of them if
+    // None) to a device being linked
+    async fn send_link_snapshot(
+        &self,
+        dst_idkey: String,
+        chunk_indices: Option<Vec<usize>>,
+    ) -> Result<(), Error> {
+        let (header, chunks) = {
+            let link_snapshots = self.link_snapshots.lock();
+            let snapshot = match link_snapshots.get(&dst_idkey) {
+                Some(snapshot) => snapshot,
+                None => return Err(Error::NoLinkInProgress),
+            };
+            let chunks: Vec<SnapshotChunk> = match chunk_indices {
+                Some(indices) => indices
+                    .into_iter()
+                    .filter_map(|index| snapshot.get_chunk(index).cloned())
+                    .collect(),
+                None => snapshot.chunks.clone(),
+            };
+            (
+                Operation::LinkSnapshotStart(
+                    snapshot.linked_name.clone(),
+                    snapshot.num_chunks(),
+                    snapshot.hash.clone(),
+                ),
+                chunks,
+            )
+        };
+
+        self.send_link_message(dst_idkey.clone(), &header).await?;
+        // one message per chunk so that no single message carries the
+        // whole store
+        for chunk in chunks.into_iter() {
+            self.send_link_message(
+                dst_idkey.clone(),
+                &Operation::LinkSnapshotChunk(chunk),
+            )
+            .await?;
         }
+        Ok(())
     }
 
-    pub async fn get_linked_devices(&self) -> Result<HashSet<String>, Error> {
-        // check if can have multiple outstanding ops, or if not, check that
-        // no other ops are outstanding
-        let op_id;
-        loop {
-            let mut op_id_ctr = self.op_id_ctr.lock();
-            if !self.mult_outstanding && op_id_ctr.1.len() != 0 {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                // get op_id and inc id ctr
-                op_id = op_id_ctr.0;
-                op_id_ctr.0 += 1;
-                // add op into hashset
-                op_id_ctr.1.insert(op_id);
-                break;
-            }
+    async fn send_link_message(
+        &self,
+        dst_idkey: String,
+        operation: &Operation,
+    ) -> Result<(), Error> {
+        match self
+            .send_message(vec![(vec![dst_idkey], operation.clone(), false)])
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Error::SendFailed(err.to_string())),
         }
+    }
 
-        /////////
+    // once every chunk has arrived, installs the snapshot, replays the
+    // operations buffered during the transfer and lets the sender drop its
+    // copy of the snapshot
+    async fn finish_link_if_complete(&self, sender: String) -> Result<(), Error> {
+        let incoming = {
+            let mut incoming_link = self.incoming_link.lock();
+            if !incoming_link.as_ref().map_or(false, |x| x.is_complete()) {
+                return Ok(());
+            }
+            incoming_link.take().unwrap()
+        };
+        let (linked_name, groups, data) = match incoming.clone().assemble() {
+            Ok(res) => res,
+            Err(err) => {
+                // start over from scratch
+                *self.incoming_link.lock() = Some(IncomingSnapshot::new());
+                self.send_link_message(sender, &Operation::LinkSnapshotResume(None))
+                    .await?;
+                return Err(Error::from(err));
+            }
+        };
+        self.install_link_state(linked_name, groups, data).await?;
+        self.send_link_message(sender, &Operation::LinkSnapshotDone)
+            .await
+    }
 
-        // FIXME send_or_add_to_txn?
-        let res = self
-            .send_message(vec![(
-                vec![self.idkey()],
-                Operation::to_string(&Operation::Dummy(op_id.clone())).unwrap(),
-                false,
-            )])
-            .await;
+    // installs the state received from the device this one is linking to and
+    // replays the operations buffered in the meantime
+    async fn install_link_state(
+        &self,
+        linked_name: String,
+        groups: HashMap<String, Group>,
+        data: HashMap<String, BasicData>,
+    ) -> Result<(), Error> {
+        self.device
+            .read()
+            .as_ref()
+            .unwrap()
+            .confirm_update_linked_group(linked_name, groups, data)?;
 
-        if res.is_err() {
-            return Err(Error::SendFailed(res.err().unwrap().to_string()));
+        let buffered = std::mem::take(&mut *self.link_buffer.lock());
+        for (seq, op_sender, operation) in buffered.into_iter() {
+            self.process_operation(seq, op_sender, operation).await;
         }
+        Ok(())
+    }
 
-        /////////
+    // Gives up on the link once more operations arrived than can be held
+    // until the snapshot is applied. A fresh snapshot would not reflect the
+    // held operations sent to this device alone, so rather than lose them,
+    // the device stops applying updates until create_linked_device() is
+    // called again; check_link() says why.
+    async fn fail_link(&self) -> Result<(), Error> {
+        self.link_buffer.lock().clear();
+        *self.incoming_link.lock() = None;
+        *self.link_failed.write() = true;
+        let pending_idkey = match self
+            .device
+            .read()
+            .as_ref()
+            .and_then(|device| device.get_pending_link_idkey())
+        {
+            Some(idkey) => idkey,
+            None => return Err(Error::NoLinkInProgress),
+        };
+        // the device this one was linking to can drop its snapshot
+        self.send_link_message(pending_idkey, &Operation::LinkSnapshotDone)
+            .await
+    }
 
-        // check if need to block on reads, and if so, if this read has
-        // returned from the server yet
-        loop {
-            let op_id_ctr = self.op_id_ctr.lock();
-            if self.sync_reads && op_id_ctr.1.contains(&op_id) {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                break;
-            }
+    // Returns why linking this device failed, if it did; the device then has
+    // to be linked again with create_linked_device()
+    pub fn check_link(&self) -> Result<(), Error> {
+        if *self.link_failed.read() {
+            return Err(Error::LinkBufferFull(MAX_LINK_BUFFER_LEN));
         }
+        Ok(())
+    }
+
+    // Chunk size (in bytes) used for snapshots sent to newly linked devices
+    pub fn set_link_chunk_size(&self, max_chunk_bytes: usize) {
+        *self.link_chunk_bytes.write() = max_chunk_bytes;
+    }
+
+    // Returns the chunks of the initial snapshot this (newly linked) device
+    // is still waiting for, or None if it is not waiting for one
+    pub fn link_transfer_missing_chunks(&self) -> Option<Option<Vec<usize>>> {
+        self.incoming_link
+            .lock()
+            .as_ref()
+            .map(|incoming| incoming.missing_chunks())
+    }
+
+    // Asks the device this one is being linked to for the snapshot chunks
+    // that have not arrived yet, e.g. after a send failure
+    pub async fn resume_link_transfer(&self) -> Result<(), Error> {
+        let missing = match self.link_transfer_missing_chunks() {
+            Some(missing) => missing,
+            None => return Err(Error::NoLinkInProgress),
+        };
+        let pending_idkey = match self
+            .device
+            .read()
+            .as_ref()
+            .and_then(|device| device.get_pending_link_idkey())
+        {
+            Some(idkey) => idkey,
+            None => return Err(Error::NoLinkInProgress),
+        };
+        self.send_link_message(pending_idkey, &Operation::LinkSnapshotResume(missing))
+            .await
+    }
+
+    pub async fn get_linked_devices(&self) -> Result<HashSet<String>, Error> {
+        self.start_op().await?;
 
         /////////
 
@@ -1491,51 +3099,230 @@ impl TankClient {
      */
 
     pub async fn add_contact(&self, contact_idkey: String) -> Result<(), Error> {
-        // check if can have multiple outstanding ops, or if not, check that
-        // no other ops are outstanding
-        let op_id;
-        loop {
-            let mut op_id_ctr = self.op_id_ctr.lock();
-            if !self.mult_outstanding && op_id_ctr.1.len() != 0 {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                // get op_id and inc id ctr
-                op_id = op_id_ctr.0;
-                op_id_ctr.0 += 1;
-                // add op into hashset
-                op_id_ctr.1.insert(op_id);
-                break;
+        self.send_contact_request(contact_idkey, None).await
+    }
+
+    // Creates a single-use invite that another user can pass to
+    // accept_invite() to become a contact of this user
+    pub fn create_invite(&self) -> Result<Invite, Error> {
+        let invite = Invite::new(self.idkey(), *self.invite_ttl_secs.read());
+        let mut invites = self.invites.lock();
+        invites.issue(invite.secret.clone(), invite.expires_at, invite::now_secs());
+        self.save_invites(&invites)?;
+        Ok(invite)
+    }
+
+    pub fn set_invite_ttl(&self, ttl_secs: u64) {
+        *self.invite_ttl_secs.write() = ttl_secs;
+    }
+
+    fn save_invites(&self, invites: &IssuedInvites) -> Result<(), Error> {
+        match self.invite_file.read().as_ref() {
+            Some(path) => invites.save(path).map_err(Error::from),
+            None => Ok(()),
+        }
+    }
+
+    // Loads the invites persisted at path (if any), merges them with the ones
+    // created so far, and persists all further changes there, so that
+    // invites can still be accepted after this device restarts
+    pub fn set_invite_file(&self, path: String) -> Result<(), Error> {
+        let loaded = IssuedInvites::load(&path)?;
+        let mut invites = self.invites.lock();
+        invites.merge(loaded);
+        invites.save(&path)?;
+        *self.invite_file.write() = Some(path);
+        Ok(())
+    }
+
+    // Adds the creator of the invite as a contact; the creator adds this user
+    // back as soon as it checks the invite secret
+    pub async fn accept_invite(&self, invite_text: String) -> Result<(), Error> {
+        let invite = Invite::parse(&invite_text)?;
+        if invite.idkey == self.idkey() {
+            return Err(Error::SelfIsInvalidContact);
+        }
+        self.send_contact_request(
+            invite.idkey,
+            Some(ContactCredential::Invite(invite.secret)),
+        )
+        .await
+    }
+
+    /*
+     * Incoming contact requests and shares
+     */
+
+    // When on, contact requests sent without an invite and anything another
+    // user shares with this user (via add_readers() etc.) are held back until
+    // accepted with accept_contact()/accept_share()
+    pub fn set_require_acceptance(&self, require_acceptance: bool) {
+        *self.require_acceptance.write() = require_acceptance;
+    }
+
+    pub fn get_pending_contacts(&self) -> Result<Vec<PendingContact>, Error> {
+        match self.device.read().as_ref() {
+            Some(device) => Ok(device.get_pending_contacts()),
+            None => Err(Error::UninitializedDevice),
+        }
+    }
+
+    pub async fn accept_contact(&self, contact_name: String) -> Result<(), Error> {
+        let pending_contact = match self.device.read().as_ref() {
+            Some(device) => device.take_pending_contact(&contact_name),
+            None => return Err(Error::UninitializedDevice),
+        };
+        match pending_contact {
+            Some(pending_contact) => {
+                self.add_contact_response(
+                    pending_contact.idkey,
+                    pending_contact.contact_name,
+                    pending_contact.contact_devices,
+                )
+                .await
             }
+            None => Err(Error::InvalidContactName(contact_name)),
         }
+    }
 
-        /////////
+    pub fn decline_contact(&self, contact_name: String) -> Result<(), Error> {
+        match self.device.read().as_ref() {
+            Some(device) => match device.take_pending_contact(&contact_name) {
+                Some(_) => Ok(()),
+                None => Err(Error::InvalidContactName(contact_name)),
+            },
+            None => Err(Error::UninitializedDevice),
+        }
+    }
 
-        let res = self
-            .send_or_add_to_txn(
-                vec![self.idkey()],
-                &Operation::Dummy(op_id.clone()),
-                false,
-            )
-            .await;
+    pub fn get_pending_shares(&self) -> Result<Vec<PendingShare>, Error> {
+        match self.device.read().as_ref() {
+            Some(device) => Ok(device.get_pending_shares()),
+            None => Err(Error::UninitializedDevice),
+        }
+    }
 
-        if res.is_err() {
-            return Err(Error::SendFailed(res.err().unwrap().to_string()));
+    // Applies everything held back for the share on all of this user's
+    // devices
+    pub async fn accept_share(&self, perm_id: String) -> Result<(), Error> {
+        self.send_to_linked_devices(Operation::AcceptShare(perm_id))
+            .await
+    }
+
+    // Drops everything held back for the share, and anything else sent for
+    // it later, on all of this user's devices
+    pub async fn decline_share(&self, perm_id: String) -> Result<(), Error> {
+        self.send_to_linked_devices(Operation::DeclineShare(perm_id))
+            .await
+    }
+
+    /*
+     * Capabilities
+     */
+
+    // Creates a token that makes whoever redeems it (see redeem_capability())
+    // a reader or writer of data_id, and a contact of this user. Anyone
+    // holding the token can use it until it expires or is revoked.
+    pub fn create_capability(
+        &self,
+        data_id: String,
+        role: CapabilityRole,
+    ) -> Result<Capability, Error> {
+        let device_guard = self.device.read();
+        let device = device_guard.as_ref().ok_or(Error::UninitializedDevice)?;
+        let perm_id = match device.data_store.read().get_data(&data_id) {
+            Some(data_val) => data_val.perm_id().to_string(),
+            None => return Err(Error::NonexistentData(data_id)),
+        };
+        // the same check recipients of the eventual AddPermMembers run
+        if !device.meta_store.read().has_metadata_mod_permissions(
+            &self.idkey(),
+            &perm_id,
+            None,
+        ) {
+            return Err(Error::InsufficientPermissions(self.idkey(), perm_id));
+        }
+        let core = self.core.as_ref().unwrap();
+        let capability = Capability::new(
+            self.idkey(),
+            data_id,
+            role,
+            *self.capability_ttl_secs.read(),
+            |message| core.sign(message),
+        );
+        let mut capabilities = self.capabilities.lock();
+        capabilities.issue(
+            capability.token_id.clone(),
+            capability.expires_at,
+            invite::now_secs(),
+        );
+        self.save_capabilities(&capabilities)?;
+        Ok(capability)
+    }
+
+    pub fn set_capability_ttl(&self, ttl_secs: u64) {
+        *self.capability_ttl_secs.write() = ttl_secs;
+    }
+
+    // Stops the token from being redeemed; returns false if it was not issued
+    // by this device or has already expired or been revoked
+    pub fn revoke_capability(&self, token_id: &String) -> Result<bool, Error> {
+        let mut capabilities = self.capabilities.lock();
+        if !capabilities.revoke(token_id) {
+            return Ok(false);
         }
+        self.save_capabilities(&capabilities)?;
+        Ok(true)
+    }
 
-        /////////
+    fn save_capabilities(&self, capabilities: &IssuedCapabilities) -> Result<(), Error> {
+        match self.capability_file.read().as_ref() {
+            Some(path) => capabilities.save(path).map_err(Error::from),
+            None => Ok(()),
+        }
+    }
 
-        // check if need to block on reads, and if so, if this read has
-        // returned from the server yet
-        loop {
-            let op_id_ctr = self.op_id_ctr.lock();
-            if self.sync_reads && op_id_ctr.1.contains(&op_id) {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                break;
-            }
+    // Loads the tokens persisted at path (if any), merges them with the ones
+    // issued so far, and persists all further changes there, so that tokens
+    // can still be redeemed after this device restarts
+    pub fn set_capability_file(&self, path: String) -> Result<(), Error> {
+        let loaded = IssuedCapabilities::load(&path)?;
+        let mut capabilities = self.capabilities.lock();
+        capabilities.merge(loaded);
+        capabilities.save(&path)?;
+        *self.capability_file.write() = Some(path);
+        Ok(())
+    }
+
+    // Returns (and clears) the tokens redeemed by this device that their
+    // issuer refused or failed to grant since the last call
+    pub fn take_capability_rejections(&self) -> Vec<CapabilityRejection> {
+        std::mem::take(&mut *self.capability_rejections.lock())
+    }
+
+    // Sends a token created with create_capability() back to its issuer,
+    // which adds this user as a contact and then shares the data object
+    pub async fn redeem_capability(&self, capability_text: String) -> Result<(), Error> {
+        let capability = Capability::parse(&capability_text)?;
+        if capability.issuer == self.idkey() {
+            return Err(Error::SelfIsInvalidContact);
         }
+        self.redeemed_capabilities
+            .lock()
+            .insert(capability.token_id.clone(), capability.issuer.clone());
+        self.send_contact_request(
+            capability.issuer.clone(),
+            Some(ContactCredential::Capability(capability)),
+        )
+        .await
+    }
+
+    async fn send_contact_request(
+        &self,
+        contact_idkey: String,
+        credential: Option<ContactCredential>,
+    ) -> Result<(), Error> {
+        self.start_op().await?;
 
         /////////
 
@@ -1570,16 +3357,29 @@ impl TankClient {
             .read()
             .get_all_subgroups(&linked_name);
 
-        match self
-            .send_or_add_to_txn(
-                vec![contact_idkey],
-                &Operation::AddContact(
+        let operation = match credential {
+            Some(ContactCredential::Invite(secret)) => Operation::AcceptInvite(
+                secret,
+                self.core.as_ref().unwrap().idkey(),
+                linked_name,
+                linked_device_groups,
+            ),
+            Some(ContactCredential::Capability(capability)) => {
+                Operation::RedeemCapability(
+                    capability,
                     self.core.as_ref().unwrap().idkey(),
                     linked_name,
                     linked_device_groups,
-                ),
-                false,
-            )
+                )
+            }
+            None => Operation::AddContact(
+                self.core.as_ref().unwrap().idkey(),
+                linked_name,
+                linked_device_groups,
+            ),
+        };
+        match self
+            .send_or_add_to_txn(vec![contact_idkey], &operation, false)
             .await
         {
             Ok(_) => Ok(()),
@@ -1622,11 +3422,7 @@ impl TankClient {
         match self
             .send_message(vec![(
                 vec![sender],
-                Operation::to_string(&Operation::ConfirmAddContact(
-                    linked_name,
-                    linked_device_groups,
-                ))
-                .unwrap(),
+                Operation::ConfirmAddContact(linked_name, linked_device_groups),
                 false,
             )])
             .await
@@ -1636,54 +3432,901 @@ impl TankClient {
         }
     }
 
-    /*
-     * Deleting devices
-     */
+    // Revokes every permission the contact that idkey belongs to has been
+    // granted, tells the contact, and drops any further operations from its
+    // devices
+    pub async fn remove_contact(&self, idkey: String) -> Result<(), Error> {
+        self.start_op().await?;
+
+        let (contact_name, contact_devices, linked_name, linked_devices, group_changes) = {
+            let device_guard = self.device.read();
+            let device = device_guard.as_ref().unwrap();
+            let contact_name = match device.get_contact_name(&idkey) {
+                Some(contact_name) => contact_name,
+                None => return Err(Error::InvalidContactName(idkey)),
+            };
+            let meta_store = device.meta_store.read();
+            let contact_devices = meta_store.resolve_group_ids(vec![&contact_name]);
+
+            // every other member of a perm the contact has access to needs
+            // the perm's groups without the contact, or it would go on
+            // sending the contact updates
+            let parents = meta_store.parents_without_child(&contact_name);
+            let mut group_changes = Vec::new();
+            for (perm_id, perm_val) in meta_store.get_all_perms().iter() {
+                if !meta_store.is_perm_member(&contact_name, perm_id) {
+                    continue;
+                }
+                let mut role_groups = self.get_metadata_reader_groups_from_perm(perm_val);
+                role_groups.extend(perm_val.do_readers().iter().cloned());
+                let mut perm_subgroups = HashSet::<String>::new();
+                for group_id in role_groups.iter() {
+                    perm_subgroups
+                        .extend(meta_store.get_all_subgroups(group_id).into_keys());
+                }
+                let changed = parents
+                    .iter()
+                    .filter(|(group_id, _)| perm_subgroups.contains(*group_id))
+                    .map(|(group_id, group_val)| (group_id.clone(), group_val.clone()))
+                    .collect::<HashMap<String, Group>>();
+                if changed.is_empty() {
+                    continue;
+                }
+                let members = meta_store
+                    .resolve_group_ids(role_groups.iter().collect())
+                    .into_iter()
+                    .filter(|device_id| !contact_devices.contains(device_id))
+                    .collect::<Vec<String>>();
+                group_changes.push((members, changed));
+            }
+
+            let linked_name = device.linked_name.read().clone();
+            let linked_devices = device.linked_devices().into_iter().collect();
+            (
+                contact_name,
+                contact_devices.into_iter().collect::<Vec<String>>(),
+                linked_name,
+                linked_devices,
+                group_changes,
+            )
+        };
+
+        let mut messages = group_changes
+            .into_iter()
+            .map(|(members, changed)| (members, Operation::SetGroups(changed), false))
+            .collect::<Vec<(Vec<String>, Operation, bool)>>();
+        // notify the contact before its devices get blocked
+        messages.push((
+            contact_devices,
+            Operation::ContactRemoved(linked_name),
+            false,
+        ));
+        if let Err(err) = self.send_message(messages).await {
+            return Err(Error::SendFailed(err.to_string()));
+        }
 
-    pub async fn delete_self_device(&self) -> Result<(), Error> {
-        // TODO send to contact devices too
         match self
-            .send_message(vec![(
-                self.device
-                    .read()
-                    .as_ref()
-                    .unwrap()
-                    .linked_devices_excluding_self(),
-                Operation::to_string(&Operation::DeleteOtherDevice(
-                    self.core.as_ref().unwrap().idkey(),
-                ))
-                .unwrap(),
+            .send_or_add_to_txn(
+                linked_devices,
+                &Operation::RemoveContact(contact_name),
                 false,
-            )])
+            )
             .await
         {
-            Ok(_) => {
-                // TODO wait for ACK that other devices have indeed received
-                // above operations before deleting current device
-                let idkey = self.core.as_ref().unwrap().idkey().clone();
-                self.device
-                    .read()
-                    .as_ref()
-                    .unwrap()
-                    .delete_device(idkey)
-                    .map(|_| *self.device.write() = None)
-                    .map_err(Error::from)
-            }
+            Ok(_) => Ok(()),
             Err(err) => Err(Error::SendFailed(err.to_string())),
         }
     }
 
-    pub async fn delete_other_device(&self, to_delete: String) -> Result<(), Error> {
-        // TODO send to contact devices too
-        match self
-            .send_message(vec![(
-                self.device
-                    .read()
-                    .as_ref()
-                    .unwrap()
-                    .linked_devices_excluding_self_and_other(&to_delete),
-                Operation::to_string(&Operation::DeleteOtherDevice(to_delete.clone()))
-                    .unwrap(),
+    /*
+     * Ownership
+     */
+
+    // Hands ownership of data_id's permission set to new_owners (e.g. a
+    // contact's name), which must already have access to it (add them with
+    // add_readers()/add_writers() first). If the permission set requires
+    // several owners to approve (see set_owner_quorum()), this only proposes
+    // the change; the other owners then call approve_owner_change() with
+    // the returned proposal id.
+    pub async fn transfer_ownership(
+        &self,
+        data_id: String,
+        new_owners: Vec<&String>,
+    ) -> Result<String, Error> {
+        self.propose_owner_change(
+            data_id,
+            OwnerChange::Transfer(new_owners.into_iter().cloned().collect()),
+        )
+        .await
+    }
+
+    // Sets how many owners must approve changes to data_id's owners
+    // (including this one); None means any single owner can make them
+    pub async fn set_owner_quorum(
+        &self,
+        data_id: String,
+        quorum: Option<usize>,
+    ) -> Result<String, Error> {
+        self.propose_owner_change(data_id, OwnerChange::SetQuorum(quorum))
+            .await
+    }
+
+    pub async fn approve_owner_change(&self, proposal_id: String) -> Result<(), Error> {
+        let perm_id = match self.device.read().as_ref() {
+            Some(device) => {
+                match device.meta_store.read().get_owner_proposal(&proposal_id) {
+                    Some(proposal) => proposal.perm_id.clone(),
+                    None => {
+                        return Err(Error::from(
+                            crate::metadata::Error::ProposalDoesNotExist(proposal_id),
+                        ))
+                    }
+                }
+            }
+            None => return Err(Error::UninitializedDevice),
+        };
+        self.send_owner_change(&perm_id, Operation::ApproveOwnerChange(proposal_id))
+            .await
+    }
+
+    // Owner changes still waiting on approvals, by proposal id
+    pub fn get_owner_proposals(&self) -> Result<HashMap<String, OwnerProposal>, Error> {
+        match self.device.read().as_ref() {
+            Some(device) => Ok(device.meta_store.read().get_owner_proposals().clone()),
+            None => Err(Error::UninitializedDevice),
+        }
+    }
+
+    async fn propose_owner_change(
+        &self,
+        data_id: String,
+        change: OwnerChange,
+    ) -> Result<String, Error> {
+        let proposal_id = crate::metadata::generate_uuid();
+        let perm_id = match self.device.read().as_ref() {
+            Some(device) => match device.data_store.read().get_data(&data_id) {
+                Some(data_val) => {
+                    let perm_id = data_val.perm_id().to_string();
+                    device
+                        .meta_store
+                        .read()
+                        .check_new_owner_proposal(&proposal_id, &perm_id)?;
+                    perm_id
+                }
+                None => return Err(Error::NonexistentData(data_id)),
+            },
+            None => return Err(Error::UninitializedDevice),
+        };
+        self.send_owner_change(
+            &perm_id,
+            Operation::ProposeOwnerChange(proposal_id.clone(), perm_id.clone(), change),
+        )
+        .await?;
+        Ok(proposal_id)
+    }
+
+    // sends an owner change to every device that holds perm_id
+    async fn send_owner_change(
+        &self,
+        perm_id: &String,
+        operation: Operation,
+    ) -> Result<(), Error> {
+        // look up recipients before taking an op_id, so a missing perm
+        // does not leave it outstanding
+        let device_ids = {
+            let device_guard = self.device.read();
+            let meta_store_guard = device_guard.as_ref().unwrap().meta_store.read();
+            let perm_val = match meta_store_guard.get_perm(perm_id) {
+                Some(perm_val) => perm_val,
+                None => {
+                    return Err(Error::from(crate::metadata::Error::PermSetDoesNotExist(
+                        perm_id.to_string(),
+                    )))
+                }
+            };
+            let group_ids = self.get_metadata_reader_groups_from_perm(perm_val);
+            meta_store_guard
+                .resolve_group_ids(group_ids.iter().collect::<Vec<&String>>())
+                .into_iter()
+                .collect::<Vec<String>>()
+        };
+
+        let op_id = self.next_op_id(self.mult_outstanding).await;
+
+        /////////
+
+        if let Err(err) = self.send_or_add_to_txn(device_ids, &operation, false).await {
+            self.finish_op(op_id);
+            return Err(Error::SendFailed(err.to_string()));
+        }
+
+        let res = self
+            .send_or_add_to_txn(
+                vec![self.idkey()],
+                &Operation::Dummy(op_id.clone()),
+                false,
+            )
+            .await;
+        if res.is_err() {
+            self.finish_op(op_id);
+            return Err(Error::SendFailed(res.err().unwrap().to_string()));
+        }
+
+        /////////
+
+        // check if need to block on writes, and if so, wait until this write
+        // has returned from the server
+        if self.block_writes {
+            self.wait_for_op(op_id).await;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Backup and migration
+     */
+
+    // Serializes all data, groups and permissions, plus contacts and linked
+    // devices, into a versioned JSON document
+    pub fn export_state(&self) -> Result<String, Error> {
+        let device_guard = self.device.read();
+        let device = device_guard.as_ref().ok_or(Error::UninitializedDevice)?;
+        StateExport::from_device(self.idkey(), device)
+            .to_json()
+            .map_err(Error::from)
+    }
+
+    // Loads a document produced by export_state(), possibly exported from
+    // another device. Whatever the exporting linked group owned becomes owned
+    // by this device's linked group, which is shared with this device's
+    // other linked devices and with the other members of each permission
+    // set. Groups, permission sets and data this device already has are left
+    // as they are, as is data this device could not read under the imported
+    // permissions or that fails validation. Returns (id, reason) for every
+    // permission set and data object left out.
+    pub async fn import_state(
+        &self,
+        json: String,
+    ) -> Result<Vec<(String, String)>, Error> {
+        let mut export = StateExport::from_json(&json)?;
+        let idkey = self.idkey();
+        let (linked_name, known_groups, known_perms, known_data, other_devices) = {
+            let device_guard = self.device.read();
+            let device = device_guard.as_ref().ok_or(Error::UninitializedDevice)?;
+            let linked_name = device.linked_name.read().clone();
+            let meta_store = device.meta_store.read();
+            let known_groups = meta_store
+                .get_all_groups()
+                .keys()
+                .cloned()
+                .collect::<HashSet<String>>();
+            let known_perms = meta_store
+                .get_all_perms()
+                .keys()
+                .cloned()
+                .collect::<HashSet<String>>();
+            let known_data = device
+                .data_store
+                .read()
+                .get_all_data()
+                .keys()
+                .cloned()
+                .collect::<HashSet<String>>();
+            (
+                linked_name,
+                known_groups,
+                known_perms,
+                known_data,
+                device.linked_devices_excluding_self(),
+            )
+        };
+        export.rebase(&linked_name);
+        let mut skipped = export.skip_existing(&known_groups, &known_perms, &known_data);
+        skipped.extend(export.validate(&known_groups));
+
+        let device_guard = self.device.read();
+        let device = device_guard.as_ref().ok_or(Error::UninitializedDevice)?;
+        {
+            let mut meta_store = device.meta_store.write();
+            for (group_id, group) in export.groups.iter() {
+                meta_store.set_group(group_id.to_string(), group.clone());
+                // groups that took over from the exported linked group need
+                // to be parents of this device's linked group too
+                if group
+                    .children()
+                    .as_ref()
+                    .map_or(false, |children| children.contains(&linked_name))
+                {
+                    if let Some(linked_group) = meta_store.get_group_mut(&linked_name) {
+                        linked_group.add_parent(group_id.to_string());
+                    }
+                }
+            }
+            for (perm_id, perm_val) in export.perms.iter() {
+                meta_store.set_perm(perm_id.to_string(), perm_val.clone());
+            }
+
+            export.data.retain(|data_id, data_val| {
+                let perm_val = meta_store.get_perm(data_val.perm_id()).unwrap();
+                let readable = [
+                    perm_val.owners(),
+                    perm_val.writers(),
+                    perm_val.readers(),
+                    perm_val.do_readers(),
+                ]
+                .into_iter()
+                .flatten()
+                .any(|group_id| meta_store.is_group_member(&idkey, group_id, &None));
+                if !readable {
+                    skipped.push((data_id.to_string(), "not readable".to_string()));
+                }
+                readable
+            });
+        }
+        {
+            let mut data_store = device.data_store.write();
+            export.data.retain(|data_id, data_val| {
+                match data_store.validate(&idkey, data_id, data_val) {
+                    Ok(_) => true,
+                    Err(err) => {
+                        skipped.push((data_id.to_string(), err.to_string()));
+                        false
+                    }
+                }
+            });
+            for (data_id, data_val) in export.data.iter() {
+                data_store.set_data(data_id.to_string(), data_val.clone());
+            }
+        }
+
+        // this user's other devices get everything; the other members of
+        // each imported perm get that perm, its groups (which now name this
+        // device's linked group as owner) and its data
+        let mut sends = vec![(
+            other_devices.clone(),
+            Operation::SetGroups(export.groups.clone().into_iter().collect()),
+        )];
+        {
+            let meta_store = device.meta_store.read();
+            let resolve = |group_ids: Vec<&String>| {
+                meta_store
+                    .resolve_group_ids(group_ids)
+                    .into_iter()
+                    .chain(other_devices.iter().cloned())
+                    .filter(|device_id| *device_id != idkey)
+                    .collect::<HashSet<String>>()
+                    .into_iter()
+                    .collect::<Vec<String>>()
+            };
+            for (perm_id, perm_val) in export.perms.iter() {
+                let metadata_groups = self.get_metadata_reader_groups_from_perm(perm_val);
+                let data_groups = metadata_groups
+                    .iter()
+                    .chain(perm_val.do_readers().iter())
+                    .collect::<Vec<&String>>();
+                let metadata_readers = resolve(metadata_groups.iter().collect());
+                let data_readers = resolve(data_groups.clone());
+                let mut perm_groups = HashMap::<String, Group>::new();
+                for group_id in data_groups {
+                    perm_groups.extend(meta_store.get_all_subgroups(group_id));
+                }
+                sends.push((
+                    metadata_readers.clone(),
+                    Operation::SetPerm(perm_id.to_string(), perm_val.clone()),
+                ));
+                sends.push((metadata_readers, Operation::SetGroups(perm_groups)));
+                for (data_id, data_val) in export.data.iter() {
+                    if data_val.perm_id() == perm_id {
+                        sends.push((
+                            data_readers.clone(),
+                            Operation::UpdateData(data_id.to_string(), data_val.clone()),
+                        ));
+                    }
+                }
+            }
+        }
+        core::mem::drop(device_guard);
+
+        for (device_ids, op) in sends.iter() {
+            if device_ids.is_empty() {
+                continue;
+            }
+            if let Err(err) = self.send_or_add_to_txn(device_ids.clone(), op, false).await
+            {
+                return Err(Error::SendFailed(err.to_string()));
+            }
+        }
+        Ok(skipped)
+    }
+
+    /*
+     * Consistency
+     */
+
+    // Uses consistency for every get_data()/set_data() of data_type that does
+    // not ask for its own
+    pub fn set_consistency_for_type(&self, data_type: String, consistency: Consistency) {
+        self.consistency_policies
+            .write()
+            .insert(data_type, consistency);
+    }
+
+    pub fn remove_consistency_for_type(&self, data_type: &String) -> Option<Consistency> {
+        self.consistency_policies.write().remove(data_type)
+    }
+
+    // explicit choice, then per-type policy, then the client-wide flags
+    fn consistency_for(
+        &self,
+        data_type: Option<&String>,
+        explicit: Option<Consistency>,
+    ) -> Consistency {
+        if let Some(consistency) = explicit {
+            return consistency;
+        }
+        if let Some(consistency) = data_type.and_then(|data_type| {
+            self.consistency_policies.read().get(data_type).copied()
+        }) {
+            return consistency;
+        }
+        Consistency {
+            block_writes: self.block_writes,
+            sync_reads: self.sync_reads,
+            mult_outstanding: self.mult_outstanding,
+            causal: false,
+        }
+    }
+
+    /*
+     * Expiry
+     */
+
+    // The latest sequencer epoch this device has received a message in,
+    // which is what expiries are checked against
+    pub fn current_epoch(&self) -> u64 {
+        *self.last_epoch.read()
+    }
+
+    // An expiry the given number of epochs after current_epoch()
+    pub fn expiry_in_epochs(&self, epochs: u64) -> Expiry {
+        Expiry::Epoch(self.current_epoch().saturating_add(epochs))
+    }
+
+    // Deletes expired data and revokes lapsed grants. Called with the
+    // sequence number of every received message, so every replica does so
+    // at the same point in the sequence. Nothing lapses in between: until
+    // a message from the expiry epoch arrives, reads without sync_reads
+    // still return the value (sync reads wait on a message of their own,
+    // so they do not).
+    fn purge_expired(&self, seq: SequenceNumber) {
+        let epoch = {
+            let mut last_epoch = self.last_epoch.write();
+            *last_epoch = (*last_epoch).max(epoch_of(seq));
+            *last_epoch
+        };
+
+        let device_guard = self.device.read();
+        if let Some(device) = device_guard.as_ref() {
+            let expired = device.data_store.write().purge_expired(epoch);
+            let mut meta_store = device.meta_store.write();
+            for data_val in expired.iter() {
+                meta_store.mark_perm_orphaned(data_val.perm_id());
+            }
+            meta_store.purge_expired_grants(epoch);
+        }
+    }
+
+    /*
+     * Data type versions
+     */
+
+    // Registers the function that upgrades values of data_type from
+    // from_version to from_version + 1. Values written from now on are
+    // marked as being in the version after the highest registered one.
+    pub fn register_upgrade<F>(&self, data_type: String, from_version: u32, upgrade: F)
+    where
+        F: Fn(&String) -> Result<String, String> + Send + Sync + 'static,
+    {
+        self.migrations
+            .write()
+            .register_upgrade(data_type, from_version, upgrade);
+    }
+
+    pub fn set_migration_mode(&self, mode: MigrationMode) -> Result<(), Error> {
+        self.migrations.write().set_mode(mode);
+        if mode == MigrationMode::Eager {
+            self.migrate_all()?;
+        }
+        Ok(())
+    }
+
+    // Upgrades all stored data to the current versions of their types, and
+    // returns the ids of data that could not be upgraded along with why
+    pub fn migrate_all(&self) -> Result<Vec<(String, migration::Error)>, Error> {
+        let data: Vec<BasicData> = match self.device.read().as_ref() {
+            Some(device) => device
+                .data_store
+                .read()
+                .get_all_data()
+                .values()
+                .cloned()
+                .collect(),
+            None => return Err(Error::UninitializedDevice),
+        };
+        let mut failed = Vec::new();
+        for data_val in data {
+            let data_id = data_val.data_id().to_string();
+            if let Err(Error::MigrationErr { source }) = self.upgrade_stored(data_val) {
+                failed.push((data_id, source));
+            }
+        }
+        Ok(failed)
+    }
+
+    // Brings a stored value up to the current version of its type, and
+    // stores the result so each value is only upgraded once
+    fn upgrade_stored(&self, data_val: BasicData) -> Result<BasicData, Error> {
+        let upgraded = {
+            let migrations = self.migrations.read();
+            if !migrations.needs_upgrade(&data_val) {
+                return Ok(data_val);
+            }
+            migrations.upgrade(&data_val)?
+        };
+        let device_guard = self.device.read();
+        let mut data_store_guard = device_guard.as_ref().unwrap().data_store.write();
+        // unless an update arrived in the meantime
+        if data_store_guard.get_data(data_val.data_id()) == Some(&data_val) {
+            data_store_guard.set_data(data_val.data_id().to_string(), upgraded.clone());
+        }
+        Ok(upgraded)
+    }
+
+    /*
+     * App namespaces
+     */
+
+    // Returns the view of this client for the app with id app_id, opening the
+    // namespace if needed. Namespaces share this client's identity, linked
+    // devices and server connection, but have their own data, groups,
+    // permissions, transactions, invites and capability tokens, and their own
+    // contacts (share_contacts() copies them over). Linked devices should be
+    // linked before opening namespaces.
+    pub async fn namespace(&self, app_id: &str) -> Result<TankClient, Error> {
+        if self.namespace.is_some() {
+            return Err(Error::NestedNamespace);
+        }
+        if let Some(client) = self.namespaces.read().get(app_id) {
+            return Ok(client.clone());
+        }
+
+        let device = match self.device.read().as_ref() {
+            Some(device) => device.new_namespace(),
+            None => return Err(Error::UninitializedDevice),
+        };
+        let mut client = self.clone();
+        client.namespace = Some(app_id.to_string());
+        client.device = Arc::new(RwLock::new(Some(device)));
+        client.data_rejections = Arc::new(Mutex::new(Vec::new()));
+        client.capability_rejections = Arc::new(Mutex::new(Vec::new()));
+        client.redeemed_capabilities = Arc::new(Mutex::new(HashMap::new()));
+        client.consistency_policies = Arc::new(RwLock::new(HashMap::new()));
+        client.causal_state = Arc::new(Mutex::new(CausalState::new()));
+        client.migrations = Arc::new(RwLock::new(Migrations::new()));
+        // transactions (and their conflicts) are per app, as data ids are
+        client.tx_coordinator = Arc::new(RwLock::new(
+            self.tx_coordinator.read().as_ref().map(|tx_coordinator| {
+                let mut namespace_coordinator = TxCoordinator::new();
+                namespace_coordinator.timeout = tx_coordinator.timeout;
+                namespace_coordinator
+            }),
+        ));
+        client.tx_running = Arc::new(Mutex::new(false));
+        client.tx_running_cv = Arc::new(Condvar::new());
+        client.read_snapshots = Arc::new(Mutex::new(HashMap::new()));
+        // so are invites and capability tokens: they name data ids of this
+        // namespace, and are redeemed through it. A namespace's invites and
+        // tokens are only persisted if set_invite_file() and
+        // set_capability_file() are called on it.
+        client.invites = Arc::new(Mutex::new(IssuedInvites::new()));
+        client.invite_file = Arc::new(RwLock::new(None));
+        client.capabilities = Arc::new(Mutex::new(IssuedCapabilities::new()));
+        client.capability_file = Arc::new(RwLock::new(None));
+        // devices are linked through the client namespaces are opened from,
+        // so namespaces start with no link in progress
+        client.link_snapshots = Arc::new(Mutex::new(HashMap::new()));
+        client.incoming_link = Arc::new(Mutex::new(None));
+        client.link_buffer = Arc::new(Mutex::new(Vec::new()));
+        client.link_failed = Arc::new(RwLock::new(false));
+        self.namespaces
+            .write()
+            .insert(app_id.to_string(), client.clone());
+
+        // apply whatever other devices sent before the namespace was opened
+        let buffered = self.namespace_buffer.lock().remove(app_id);
+        for (seq, sender, operation) in buffered.unwrap_or_default() {
+            client.receive_operation(seq, sender, operation).await;
+        }
+
+        Ok(client)
+    }
+
+    // app id of this namespace, or None if this is not a namespace view
+    pub fn app_id(&self) -> Option<&String> {
+        self.namespace.as_ref()
+    }
+
+    pub fn get_namespaces(&self) -> Vec<String> {
+        self.namespaces.read().keys().cloned().collect()
+    }
+
+    // Returns (and clears) the number of messages for each unopened
+    // namespace that were dropped since the last call because too many were
+    // already held (see MAX_NAMESPACE_BUFFER_LEN). A namespace that lost
+    // messages is missing updates from other devices once opened.
+    pub fn take_namespace_drops(&self) -> HashMap<String, usize> {
+        std::mem::take(&mut *self.namespace_drops.lock())
+    }
+
+    // Makes contacts of this client (or all of them, if contact_names is
+    // None) contacts in the namespace app_id as well
+    pub fn share_contacts(
+        &self,
+        app_id: &str,
+        contact_names: Option<Vec<String>>,
+    ) -> Result<(), Error> {
+        let namespaces = self.namespaces.read();
+        let namespace = match namespaces.get(app_id) {
+            Some(namespace) => namespace,
+            None => return Err(Error::NamespaceNotOpen(app_id.to_string())),
+        };
+        let device_guard = self.device.read();
+        let device = match device_guard.as_ref() {
+            Some(device) => device,
+            None => return Err(Error::UninitializedDevice),
+        };
+        let contact_names = match contact_names {
+            Some(contact_names) => contact_names,
+            None => device.get_contacts().into_iter().collect(),
+        };
+        let namespace_device_guard = namespace.device.read();
+        for contact_name in contact_names.iter() {
+            namespace_device_guard
+                .as_ref()
+                .unwrap()
+                .copy_contact_from(device, contact_name)?;
+        }
+        Ok(())
+    }
+
+    async fn receive_namespaced(
+        &self,
+        seq: SequenceNumber,
+        sender: String,
+        app_id: String,
+        operation: Operation,
+    ) {
+        if self.namespace.is_some() {
+            println!("Dropping nested namespace message for {}", app_id);
+            return;
+        }
+        let namespace = self.namespaces.read().get(&app_id).cloned();
+        match namespace {
+            Some(client) => client.receive_operation(seq, sender, operation).await,
+            None => {
+                let mut namespace_buffer = self.namespace_buffer.lock();
+                if namespace_buffer.values().map(Vec::len).sum::<usize>()
+                    >= MAX_NAMESPACE_BUFFER_LEN
+                {
+                    println!("Dropping message for unopened namespace {}", app_id);
+                    *self.namespace_drops.lock().entry(app_id).or_insert(0) += 1;
+                    return;
+                }
+                namespace_buffer
+                    .entry(app_id)
+                    .or_default()
+                    .push((seq, sender, operation));
+            }
+        }
+    }
+
+    /*
+     * Metadata garbage collection
+     */
+
+    // Removes the perms of deleted data that no remaining data uses, along
+    // with groups nothing references anymore. Runs after every delete unless
+    // turned off with set_auto_compact(false).
+    pub fn compact(&self) -> Result<Collected, Error> {
+        let device_guard = self.device.read();
+        let device = match device_guard.as_ref() {
+            Some(device) => device,
+            None => return Err(Error::UninitializedDevice),
+        };
+        let live_perm_ids: HashSet<String> = device
+            .data_store
+            .read()
+            .get_all_data()
+            .values()
+            .map(|data_val| data_val.perm_id().to_string())
+            .collect();
+        let linked_name = device.linked_name.read().clone();
+        let idkey = self.idkey();
+        let collected = device
+            .meta_store
+            .write()
+            .collect_garbage(&live_perm_ids, vec![&linked_name, &idkey]);
+        Ok(collected)
+    }
+
+    pub fn set_auto_compact(&self, auto_compact: bool) {
+        *self.auto_compact.write() = auto_compact;
+    }
+
+    /*
+     * Blocking devices
+     */
+
+    // this client's own devices are never blocked, whatever the block list
+    // holds (e.g. a group that a contact claimed one of them belongs to)
+    fn is_blocked(&self, idkey: &String) -> bool {
+        if self.own_devices().contains(idkey) {
+            return false;
+        }
+        let device_guard = self.device.read();
+        match device_guard.as_ref() {
+            Some(device) => self
+                .block_list
+                .read()
+                .is_blocked(idkey, Some(&device.meta_store.read())),
+            None => self.block_list.read().is_blocked(idkey, None),
+        }
+    }
+
+    fn own_devices(&self) -> HashSet<String> {
+        let mut own_devices = match self.device.read().as_ref() {
+            Some(device) => device.linked_devices(),
+            None => HashSet::new(),
+        };
+        own_devices.insert(self.idkey());
+        own_devices
+    }
+
+    // whether blocking id (a group if is_group, else a device) would block
+    // any of this client's own devices
+    fn blocks_own_device(&self, id: &String, is_group: bool) -> bool {
+        let own_devices = self.own_devices();
+        if own_devices.contains(id) || !is_group {
+            return own_devices.contains(id);
+        }
+        let device_guard = self.device.read();
+        match device_guard.as_ref() {
+            Some(device) => {
+                let meta_store = device.meta_store.read();
+                *id == *device.linked_name.read()
+                    || own_devices
+                        .iter()
+                        .any(|idkey| meta_store.is_group_member(idkey, id, &None))
+            }
+            None => false,
+        }
+    }
+
+    async fn send_block(&self, id: String, is_group: bool) -> Result<(), Error> {
+        if self.blocks_own_device(&id, is_group) {
+            return Err(Error::CannotBlockOwnDevice(id));
+        }
+        self.send_to_linked_devices(Operation::Block(id, is_group))
+            .await
+    }
+
+    fn save_block_list(&self, block_list: &BlockList) -> Result<(), Error> {
+        match self.block_list_file.read().as_ref() {
+            Some(path) => block_list.save(path).map_err(Error::from),
+            None => Ok(()),
+        }
+    }
+
+    // Loads the block list persisted at path (if any), merges it with the
+    // current one, and persists all further changes there
+    pub fn set_block_list_file(&self, path: String) -> Result<(), Error> {
+        let loaded = BlockList::load(&path)?;
+        let mut block_list = self.block_list.write();
+        for idkey in loaded.blocked_devices().iter() {
+            block_list.block_device(idkey.to_string());
+        }
+        for group_id in loaded.blocked_groups().iter() {
+            block_list.block_group(group_id.to_string());
+        }
+        block_list.save(&path)?;
+        *self.block_list_file.write() = Some(path);
+        Ok(())
+    }
+
+    pub fn get_block_list(&self) -> BlockList {
+        self.block_list.read().clone()
+    }
+
+    async fn send_to_linked_devices(&self, operation: Operation) -> Result<(), Error> {
+        let linked_devices = match self.device.read().as_ref() {
+            Some(device) => device.linked_devices().into_iter().collect(),
+            None => return Err(Error::UninitializedDevice),
+        };
+        match self
+            .send_or_add_to_txn(linked_devices, &operation, false)
+            .await
+        {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Error::SendFailed(err.to_string())),
+        }
+    }
+
+    // Blocks a single device on all of this user's devices
+    pub async fn block_device(&self, idkey: String) -> Result<(), Error> {
+        self.send_block(idkey, false).await
+    }
+
+    pub async fn unblock_device(&self, idkey: String) -> Result<(), Error> {
+        self.send_to_linked_devices(Operation::Unblock(idkey, false))
+            .await
+    }
+
+    // Blocks every current and future member of a group, e.g. all devices of
+    // a contact (see get_contacts())
+    pub async fn block_group(&self, group_id: String) -> Result<(), Error> {
+        self.send_block(group_id, true).await
+    }
+
+    pub async fn unblock_group(&self, group_id: String) -> Result<(), Error> {
+        self.send_to_linked_devices(Operation::Unblock(group_id, true))
+            .await
+    }
+
+    /*
+     * Deleting devices
+     */
+
+    pub async fn delete_self_device(&self) -> Result<(), Error> {
+        // TODO send to contact devices too
+        match self
+            .send_message(vec![(
+                self.device
+                    .read()
+                    .as_ref()
+                    .unwrap()
+                    .linked_devices_excluding_self(),
+                Operation::DeleteOtherDevice(self.core.as_ref().unwrap().idkey()),
+                false,
+            )])
+            .await
+        {
+            Ok(_) => {
+                // TODO wait for ACK that other devices have indeed received
+                // above operations before deleting current device
+                let idkey = self.core.as_ref().unwrap().idkey().clone();
+                self.device
+                    .read()
+                    .as_ref()
+                    .unwrap()
+                    .delete_device(idkey)
+                    .map(|_| *self.device.write() = None)
+                    .map_err(Error::from)
+            }
+            Err(err) => Err(Error::SendFailed(err.to_string())),
+        }
+    }
+
+    pub async fn delete_other_device(&self, to_delete: String) -> Result<(), Error> {
+        // TODO send to contact devices too
+        match self
+            .send_message(vec![(
+                self.device
+                    .read()
+                    .as_ref()
+                    .unwrap()
+                    .linked_devices_excluding_self_and_other(&to_delete),
+                Operation::DeleteOtherDevice(to_delete.clone()),
                 false,
             )])
             .await
@@ -1699,12 +4342,12 @@ impl TankClient {
                 match self
                     .send_message(vec![(
                         vec![to_delete.clone()],
-                        Operation::to_string(&Operation::DeleteSelfDevice).unwrap(),
+                        Operation::DeleteSelfDevice,
                         false,
                     )])
                     .await
                 {
-                    Ok(_) => Ok(()),
+                    Ok(_) => self.forget_devices(&HashSet::from([to_delete])).await,
                     Err(err) => Err(Error::SendFailed(err.to_string())),
                 }
             }
@@ -1725,7 +4368,7 @@ impl TankClient {
                     .iter()
                     .map(|x| x.clone())
                     .collect::<Vec<String>>(),
-                Operation::to_string(&Operation::DeleteSelfDevice).unwrap(),
+                Operation::DeleteSelfDevice,
                 false,
             )])
             .await
@@ -1762,6 +4405,9 @@ impl TankClient {
     /* TODO MOVE THESE SOMEWHERE ELSE */
 
     pub fn start_transaction(&self) -> Result<(), Error> {
+        if self.tx_coordinator.read().is_none() {
+            return Err(Error::BadTransactionError);
+        }
         let res = self.tx_coordinator.write().as_mut().unwrap().enter_tx();
         if !res {
             return Err(Error::BadTransactionError);
@@ -1772,152 +4418,310 @@ impl TankClient {
     // TODO cancel transaction func?
 
     pub async fn end_transaction(&self) {
-        let (ops, prev_seq_number) =
+        let (ops, read_ids, prev_seq_number) =
             self.tx_coordinator.write().as_mut().unwrap().exit_tx();
-        self.initiate_transaction(self.idkey(), ops, prev_seq_number)
+        let _ = self
+            .initiate_transaction(self.idkey(), ops, read_ids, prev_seq_number, None)
             .await;
     }
 
-    async fn send_or_add_to_txn(
+    // sets how many sequencer epochs a transaction started after this call
+    // has to commit before participants abort it
+    pub fn set_transaction_timeout(&self, timeout_epochs: u64) -> Result<(), Error> {
+        match self.tx_coordinator.write().as_mut() {
+            Some(tx_coordinator) => {
+                tx_coordinator.timeout = timeout_epochs;
+                Ok(())
+            }
+            None => Err(Error::BadTransactionError),
+        }
+    }
+
+    // Aborts the pending transactions this device takes part in that were
+    // started by any of coordinators, without waiting for their deadline.
+    // Called once a device is deleted or a contact removed; a coordinator
+    // that restarted comes back under a new idkey, so apps that learn of
+    // that otherwise can call this with the old one.
+    pub async fn abort_transactions_coordinated_by(
         &self,
-        dst_idkeys: Vec<String>,
-        op: &Operation,
-        bench: bool,
+        coordinators: &HashSet<String>,
     ) -> Result<(), Error> {
-        if self.tx_coordinator.read().as_ref().is_some()
-            && self
-                .tx_coordinator
-                .read()
-                .as_ref()
-                .unwrap()
-                .check_tx_state()
-        {
-            self.tx_coordinator
-                .write()
-                .as_mut()
-                .unwrap()
-                .add_op_to_cur_tx(op.clone(), dst_idkeys.clone());
-            Ok(())
-        } else {
-            match self
-                .send_message(vec![(
-                    dst_idkeys,
-                    Operation::to_string(op).unwrap(),
-                    bench,
-                )])
-                .await
-            {
-                Ok(_) => Ok(()),
-                Err(err) => Err(Error::SendFailed(err.to_string())),
+        let pending = match self.tx_coordinator.read().as_ref() {
+            Some(tx_coordinator) => tx_coordinator.coordinated_by(coordinators),
+            None => return Err(Error::BadTransactionError),
+        };
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let messages = pending
+            .into_iter()
+            .map(|(tx_id, tx)| {
+                (
+                    tx.recipients,
+                    Operation::TxAbort(self.idkey(), tx_id),
+                    false,
+                )
+            })
+            .collect();
+        match self.send_message(messages).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Error::SendFailed(err.to_string())),
+        }
+    }
+
+    // drops device_ids, which were deleted or belonged to a removed contact,
+    // from transaction bookkeeping
+    async fn forget_devices(&self, device_ids: &HashSet<String>) -> Result<(), Error> {
+        if let Some(tx_coordinator) = self.tx_coordinator.write().as_mut() {
+            tx_coordinator.untrack_devices(device_ids);
+        }
+        self.abort_transactions_coordinated_by(device_ids).await
+    }
+
+    pub fn set_transaction_retry_limit(&self, retry_limit: u32) {
+        *self.tx_retry_limit.write() = retry_limit;
+    }
+
+    // Runs f as a single transaction, resolving to f's result if the
+    // transaction commits. If it aborts due to a conflict or times out, f is
+    // re-run (with exponential backoff) up to the configured retry limit. Any
+    // error returned by f itself aborts the transaction without sending
+    // anything. Concurrent calls run one after the other.
+    //
+    // E.g.:
+    //   client.transact(|tx| async move {
+    //       let counter = tx.get_data(&id).await?;
+    //       tx.set_data(id.clone(), type.clone(), incremented(counter)).await
+    //   }).await
+    pub async fn transact<F, Fut, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: Fn(TxHandle) -> Fut,
+        Fut: Future<Output = Result<R, Error>>,
+    {
+        if self.tx_coordinator.read().is_none() {
+            return Err(Error::BadTransactionError);
+        }
+
+        let retry_limit = *self.tx_retry_limit.read();
+        let mut num_retries = 0;
+        loop {
+            self.wait_for_tx_slot().await;
+            let res = self.transact_once(&f).await;
+            *self.tx_running.lock() = false;
+            self.tx_running_cv.notify_all();
+            match res {
+                Err(Error::TransactionConflictsError | Error::Timeout)
+                    if num_retries < retry_limit =>
+                {
+                    num_retries += 1;
+                    tokio::time::sleep(Self::transaction_backoff(num_retries)).await;
+                }
+                res => return res,
             }
         }
     }
 
-    pub async fn get_perm(
-        &self,
-        perm_id: &String,
-    ) -> Result<Option<PermissionSet>, Error> {
-        // check if can have multiple outstanding ops, or if not, check that
-        // no other ops are outstanding
-        let op_id;
+    // waits until no other transact() body is running, then claims the slot
+    async fn wait_for_tx_slot(&self) {
         loop {
-            let mut op_id_ctr = self.op_id_ctr.lock();
-            if !self.mult_outstanding && op_id_ctr.1.len() != 0 {
+            let mut tx_running = self.tx_running.lock();
+            if *tx_running {
                 // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
+                let _ = self.tx_running_cv.wait(tx_running).await;
             } else {
-                // get op_id and inc id ctr
-                op_id = op_id_ctr.0;
-                op_id_ctr.0 += 1;
-                // add op into hashset
-                op_id_ctr.1.insert(op_id);
-                break;
+                *tx_running = true;
+                return;
             }
         }
+    }
 
-        /////////
+    fn transaction_backoff(num_retries: u32) -> time::Duration {
+        let max_ms = TX_BACKOFF_BASE_MS << num_retries.min(TX_BACKOFF_MAX_SHIFT);
+        // cheap jitter so that conflicting clients don't retry in lockstep
+        let jitter_ms = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos() as u64 % max_ms);
+        time::Duration::from_millis(max_ms / 2 + jitter_ms / 2)
+    }
 
-        let res = self
-            .send_or_add_to_txn(
-                vec![self.idkey()],
-                &Operation::Dummy(op_id.clone()),
-                false,
-            )
-            .await;
+    async fn transact_once<F, Fut, R>(&self, f: &F) -> Result<R, Error>
+    where
+        F: Fn(TxHandle) -> Fut,
+        Fut: Future<Output = Result<R, Error>>,
+    {
+        let op_id = self.next_op_id(self.mult_outstanding).await;
 
-        if res.is_err() {
-            return Err(Error::SendFailed(res.err().unwrap().to_string()));
+        /////////
+
+        if let Err(err) = self.start_transaction() {
+            self.finish_op(op_id);
+            return Err(err);
         }
 
-        /////////
+        let ret = match f(TxHandle::new(self.clone())).await {
+            Ok(ret) => ret,
+            Err(err) => {
+                // discard everything buffered so far
+                self.tx_coordinator.write().as_mut().unwrap().exit_tx();
+                self.finish_op(op_id);
+                return Err(err);
+            }
+        };
 
-        // check if need to block on reads, and if so, if this read has
-        // returned from the server yet
-        loop {
-            let op_id_ctr = self.op_id_ctr.lock();
-            if self.sync_reads && op_id_ctr.1.contains(&op_id) {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                break;
+        let (ops, read_ids, prev_seq_number) =
+            self.tx_coordinator.write().as_mut().unwrap().exit_tx();
+        match self
+            .initiate_transaction(
+                self.idkey(),
+                ops,
+                read_ids,
+                prev_seq_number,
+                Some(op_id),
+            )
+            .await
+        {
+            // nothing read or written
+            Ok(0) => {
+                self.finish_op(op_id);
+                return Ok(ret);
+            }
+            Ok(_) => {}
+            Err(err) => {
+                self.tx_coordinator
+                    .write()
+                    .as_mut()
+                    .unwrap()
+                    .take_outcome(op_id);
+                self.finish_op(op_id);
+                return Err(err);
             }
         }
 
         /////////
 
-        // read perm
-        let device_guard = self.device.read();
-        let meta_store_guard = device_guard.as_ref().unwrap().meta_store.read();
-        let perm = meta_store_guard.get_perm(perm_id);
-        Ok(perm.map(|x| x.clone()))
+        // block until every shard has committed or any shard has aborted
+        self.wait_for_op(op_id).await;
+
+        let outcome = self
+            .tx_coordinator
+            .write()
+            .as_mut()
+            .unwrap()
+            .take_outcome(op_id);
+        match outcome {
+            Some(Err(err)) => Err(err),
+            _ => Ok(ret),
+        }
     }
 
-    pub async fn get_all_perms(&self) -> Result<Vec<PermissionSet>, Error> {
-        // check if can have multiple outstanding ops, or if not, check that
-        // no other ops are outstanding
-        let op_id;
+    // Takes the next op_id, waiting for outstanding ops to finish unless
+    // several may be outstanding, and sends it through the server; with
+    // sync_reads, also waits until it comes back, i.e. until everything sent
+    // to this device before it has been applied
+    async fn start_op(&self) -> Result<u64, Error> {
+        self.start_op_with(self.consistency_for(None, None)).await
+    }
+
+    // start_op() with the given consistency instead of the client-wide one
+    async fn start_op_with(&self, consistency: Consistency) -> Result<u64, Error> {
+        let op_id = self.next_op_id(consistency.mult_outstanding).await;
+        if let Err(err) = self
+            .send_or_add_to_txn(vec![self.idkey()], &Operation::Dummy(op_id), false)
+            .await
+        {
+            self.finish_op(op_id);
+            return Err(Error::SendFailed(err.to_string()));
+        }
+        if consistency.sync_reads {
+            self.wait_for_op(op_id).await;
+        }
+        Ok(op_id)
+    }
+
+    // Takes the next op_id, first waiting for outstanding ops to finish
+    // unless several may be outstanding
+    async fn next_op_id(&self, mult_outstanding: bool) -> u64 {
         loop {
             let mut op_id_ctr = self.op_id_ctr.lock();
-            if !self.mult_outstanding && op_id_ctr.1.len() != 0 {
+            if !mult_outstanding && op_id_ctr.1.len() != 0 {
                 // release the lock
                 let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
             } else {
-                // get op_id and inc id ctr
-                op_id = op_id_ctr.0;
+                let op_id = op_id_ctr.0;
                 op_id_ctr.0 += 1;
-                // add op into hashset
                 op_id_ctr.1.insert(op_id);
-                break;
+                return op_id;
             }
         }
+    }
 
-        /////////
+    // Waits until op_id has come back from the server (or been finished)
+    async fn wait_for_op(&self, op_id: u64) {
+        loop {
+            let op_id_ctr = self.op_id_ctr.lock();
+            if op_id_ctr.1.contains(&op_id) {
+                // release the lock
+                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
+            } else {
+                break;
+            }
+        }
+    }
 
-        let res = self
-            .send_or_add_to_txn(
-                vec![self.idkey()],
-                &Operation::Dummy(op_id.clone()),
-                false,
-            )
-            .await;
+    fn finish_op(&self, op_id: u64) {
+        let mut op_id_ctr = self.op_id_ctr.lock();
+        op_id_ctr.1.remove(&op_id);
+        self.op_id_ctr_cv.notify_all();
+    }
 
-        if res.is_err() {
-            return Err(Error::SendFailed(res.err().unwrap().to_string()));
+    async fn send_or_add_to_txn(
+        &self,
+        dst_idkeys: Vec<String>,
+        op: &Operation,
+        bench: bool,
+    ) -> Result<(), Error> {
+        if self.tx_coordinator.read().as_ref().is_some()
+            && self
+                .tx_coordinator
+                .read()
+                .as_ref()
+                .unwrap()
+                .check_tx_state()
+        {
+            self.tx_coordinator
+                .write()
+                .as_mut()
+                .unwrap()
+                .add_op_to_cur_tx(op.clone(), dst_idkeys.clone());
+            Ok(())
+        } else {
+            match self
+                .send_message(vec![(dst_idkeys, op.clone(), bench)])
+                .await
+            {
+                Ok(_) => Ok(()),
+                Err(err) => Err(Error::SendFailed(err.to_string())),
+            }
         }
+    }
+
+    pub async fn get_perm(
+        &self,
+        perm_id: &String,
+    ) -> Result<Option<PermissionSet>, Error> {
+        self.start_op().await?;
 
         /////////
 
-        // check if need to block on reads, and if so, if this read has
-        // returned from the server yet
-        loop {
-            let op_id_ctr = self.op_id_ctr.lock();
-            if self.sync_reads && op_id_ctr.1.contains(&op_id) {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                break;
-            }
-        }
+        // read perm
+        let device_guard = self.device.read();
+        let meta_store_guard = device_guard.as_ref().unwrap().meta_store.read();
+        let perm = meta_store_guard.get_perm(perm_id);
+        Ok(perm.map(|x| x.clone()))
+    }
+
+    pub async fn get_all_perms(&self) -> Result<Vec<PermissionSet>, Error> {
+        self.start_op().await?;
 
         /////////
 
@@ -1933,51 +4737,7 @@ impl TankClient {
     }
 
     pub async fn get_group(&self, group_id: &String) -> Result<Option<Group>, Error> {
-        // check if can have multiple outstanding ops, or if not, check that
-        // no other ops are outstanding
-        let op_id;
-        loop {
-            let mut op_id_ctr = self.op_id_ctr.lock();
-            if !self.mult_outstanding && op_id_ctr.1.len() != 0 {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                // get op_id and inc id ctr
-                op_id = op_id_ctr.0;
-                op_id_ctr.0 += 1;
-                // add op into hashset
-                op_id_ctr.1.insert(op_id);
-                break;
-            }
-        }
-
-        /////////
-
-        let res = self
-            .send_or_add_to_txn(
-                vec![self.idkey()],
-                &Operation::Dummy(op_id.clone()),
-                false,
-            )
-            .await;
-
-        if res.is_err() {
-            return Err(Error::SendFailed(res.err().unwrap().to_string()));
-        }
-
-        /////////
-
-        // check if need to block on reads, and if so, if this read has
-        // returned from the server yet
-        loop {
-            let op_id_ctr = self.op_id_ctr.lock();
-            if self.sync_reads && op_id_ctr.1.contains(&op_id) {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                break;
-            }
-        }
+        self.start_op().await?;
 
         /////////
 
@@ -1989,51 +4749,7 @@ impl TankClient {
     }
 
     pub async fn get_all_groups(&self) -> Result<Vec<Group>, Error> {
-        // check if can have multiple outstanding ops, or if not, check that
-        // no other ops are outstanding
-        let op_id;
-        loop {
-            let mut op_id_ctr = self.op_id_ctr.lock();
-            if !self.mult_outstanding && op_id_ctr.1.len() != 0 {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                // get op_id and inc id ctr
-                op_id = op_id_ctr.0;
-                op_id_ctr.0 += 1;
-                // add op into hashset
-                op_id_ctr.1.insert(op_id);
-                break;
-            }
-        }
-
-        /////////
-
-        let res = self
-            .send_or_add_to_txn(
-                vec![self.idkey()],
-                &Operation::Dummy(op_id.clone()),
-                false,
-            )
-            .await;
-
-        if res.is_err() {
-            return Err(Error::SendFailed(res.err().unwrap().to_string()));
-        }
-
-        /////////
-
-        // check if need to block on reads, and if so, if this read has
-        // returned from the server yet
-        loop {
-            let op_id_ctr = self.op_id_ctr.lock();
-            if self.sync_reads && op_id_ctr.1.contains(&op_id) {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                break;
-            }
-        }
+        self.start_op().await?;
 
         /////////
 
@@ -2049,171 +4765,482 @@ impl TankClient {
     }
 
     pub async fn get_data(&self, data_id: &String) -> Result<Option<BasicData>, Error> {
-        // check if can have multiple outstanding ops, or if not, check that
-        // no other ops are outstanding
-        let op_id;
-        loop {
-            let mut op_id_ctr = self.op_id_ctr.lock();
-            if !self.mult_outstanding && op_id_ctr.1.len() != 0 {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                // get op_id and inc id ctr
-                op_id = op_id_ctr.0;
-                op_id_ctr.0 += 1;
-                // add op into hashset
-                op_id_ctr.1.insert(op_id);
-                break;
+        self.read_data(data_id, None).await
+    }
+
+    // get_data() with the given consistency instead of the one configured for
+    // the data's type or the client
+    pub async fn get_data_with_consistency(
+        &self,
+        data_id: &String,
+        consistency: Consistency,
+    ) -> Result<Option<BasicData>, Error> {
+        self.read_data(data_id, Some(consistency)).await
+    }
+
+    async fn read_data(
+        &self,
+        data_id: &String,
+        consistency: Option<Consistency>,
+    ) -> Result<Option<BasicData>, Error> {
+        // the policy for a data_type applies to reads of existing data of
+        // that type
+        let data_type = self.device.read().as_ref().and_then(|device| {
+            device
+                .data_store
+                .read()
+                .get_data(data_id)
+                .map(|data_val| data_val.data_type().to_string())
+        });
+        let consistency = self.consistency_for(data_type.as_ref(), consistency);
+        self.start_op_with(consistency).await?;
+
+        /////////
+
+        // read data
+        let data = {
+            let device_guard = self.device.read();
+            let data_store_guard = device_guard.as_ref().unwrap().data_store.read();
+            data_store_guard.get_data(data_id).cloned()
+        };
+        match data {
+            Some(data_val) => self.upgrade_stored(data_val).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn get_all_data(&self) -> Result<Vec<BasicData>, Error> {
+        self.start_op().await?;
+
+        /////////
+
+        // read all data
+        let data: Vec<BasicData> = {
+            let device_guard = self.device.read();
+            let data_store_guard = device_guard.as_ref().unwrap().data_store.read();
+            data_store_guard.get_all_data().values().cloned().collect()
+        };
+        // leave out data that cannot be brought up to the current version of
+        // its type (get_data() reports why)
+        let mut values = Vec::<BasicData>::new();
+        for datum in data {
+            if let Ok(datum) = self.upgrade_stored(datum) {
+                values.push(datum);
             }
         }
+        Ok(values)
+    }
+
+    // Strictly serializable read of a set of data objects. Rather than reading
+    // local state directly (as get_data() does), a read marker is ordered
+    // through the sequencer and the requested objects are snapshotted when the
+    // marker is applied. Returns the marker's sequence number along with the
+    // values of all requested data_ids that exist as of that point in the
+    // total order.
+    pub async fn read_only_transaction(
+        &self,
+        data_ids: Vec<String>,
+    ) -> Result<(SequenceNumber, HashMap<String, BasicData>), Error> {
+        let op_id = self.next_op_id(self.mult_outstanding).await;
 
         /////////
 
+        // read markers never modify state, so they are sent directly rather
+        // than being added to any ongoing (write) transaction
         let res = self
-            .send_or_add_to_txn(
+            .send_message(vec![(
                 vec![self.idkey()],
-                &Operation::Dummy(op_id.clone()),
+                Operation::ReadSnapshot(op_id, data_ids),
                 false,
-            )
+            )])
             .await;
 
         if res.is_err() {
+            self.finish_op(op_id);
             return Err(Error::SendFailed(res.err().unwrap().to_string()));
         }
 
         /////////
 
-        // check if need to block on reads, and if so, if this read has
-        // returned from the server yet
-        loop {
-            let op_id_ctr = self.op_id_ctr.lock();
-            if self.sync_reads && op_id_ctr.1.contains(&op_id) {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                break;
-            }
-        }
+        // always block until the marker has returned from the server,
+        // regardless of sync_reads
+        self.wait_for_op(op_id).await;
 
         /////////
 
-        // read data
-        let device_guard = self.device.read();
-        let data_store_guard = device_guard.as_ref().unwrap().data_store.read();
-        let data = data_store_guard.get_data(data_id);
-        Ok(data.map(|x| x.clone()))
+        match self.read_snapshots.lock().remove(&op_id) {
+            Some(snapshot) => Ok(snapshot),
+            None => Err(Error::TxNotFound),
+        }
     }
 
-    pub async fn get_all_data(&self) -> Result<Vec<BasicData>, Error> {
-        // check if can have multiple outstanding ops, or if not, check that
-        // no other ops are outstanding
-        let op_id;
-        loop {
-            let mut op_id_ctr = self.op_id_ctr.lock();
-            if !self.mult_outstanding && op_id_ctr.1.len() != 0 {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                // get op_id and inc id ctr
-                op_id = op_id_ctr.0;
-                op_id_ctr.0 += 1;
-                // add op into hashset
-                op_id_ctr.1.insert(op_id);
-                break;
-            }
+    // TODO add facility for setting and sharing data at the same time
+
+    pub async fn set_data(
+        &self,
+        data_id: String,
+        data_type: String,
+        data_val: String,
+        // FIXME what is the below option for again? is it sharing upon
+        // setting? why just enabled for data readers and, e.g., not
+        // data writers?
+        data_reader_idkeys: Option<Vec<String>>,
+        add_perm_op_id: Option<u64>,
+        bench: bool,
+    ) -> Result<(), Error> {
+        self.write_data(
+            data_id,
+            data_type,
+            data_val,
+            data_reader_idkeys,
+            add_perm_op_id,
+            bench,
+            None,
+            None,
+            None,
+        )
+        .await
+    }
+
+    // set_data() with the given consistency instead of the one configured for
+    // data_type or the client
+    pub async fn set_data_with_consistency(
+        &self,
+        data_id: String,
+        data_type: String,
+        data_val: String,
+        consistency: Consistency,
+    ) -> Result<(), Error> {
+        self.write_data(
+            data_id,
+            data_type,
+            data_val,
+            None,
+            None,
+            false,
+            None,
+            Some(consistency),
+            None,
+        )
+        .await
+    }
+
+    // set_data() for a value that every replica deletes once expiry passes.
+    // The value is deleted when the first message of that epoch arrives;
+    // see purge_expired().
+    pub async fn set_data_with_expiry(
+        &self,
+        data_id: String,
+        data_type: String,
+        data_val: String,
+        expiry: Expiry,
+    ) -> Result<(), Error> {
+        self.write_data(
+            data_id,
+            data_type,
+            data_val,
+            None,
+            None,
+            false,
+            Some(expiry),
+            None,
+            None,
+        )
+        .await
+    }
+
+    // Sets data_id to raw bytes, deflate-compressed first if compress is set
+    // and that makes them smaller. The data's data_val is left empty.
+    pub async fn set_binary_data(
+        &self,
+        data_id: String,
+        data_type: String,
+        bytes: Vec<u8>,
+        compress: bool,
+    ) -> Result<(), Error> {
+        self.write_data(
+            data_id,
+            data_type,
+            String::new(),
+            None,
+            None,
+            false,
+            None,
+            None,
+            Some(BinaryVal::new(bytes, compress)),
+        )
+        .await
+    }
+
+    // Returns the (decompressed) bytes set with set_binary_data(), or None if
+    // data_id does not exist or is not binary
+    pub async fn get_binary_data(
+        &self,
+        data_id: &String,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        let data_val = match self.get_data(data_id).await? {
+            Some(data_val) => data_val,
+            None => return Ok(None),
+        };
+        match data_val.data_bytes() {
+            Some(data_bytes) => data_bytes.to_bytes().map(Some).map_err(|e| {
+                Error::CorruptBinaryData(data_id.to_string(), e.to_string())
+            }),
+            None => Ok(None),
+        }
+    }
+
+    async fn write_data(
+        &self,
+        data_id: String,
+        data_type: String,
+        data_val: String,
+        data_reader_idkeys: Option<Vec<String>>,
+        add_perm_op_id: Option<u64>,
+        bench: bool,
+        expiry: Option<Expiry>,
+        consistency: Option<Consistency>,
+        data_bytes: Option<BinaryVal>,
+    ) -> Result<(), Error> {
+        if bench && self.benchmark_send.read().is_some() {
+            self.send_timestamp_vec.lock().push((
+                self.benchmark_send.read().unwrap(),
+                String::from("enter sendTANK"),
+                Instant::now(),
+            ));
+        }
+        let consistency = self.consistency_for(Some(&data_type), consistency);
+        let op_id = match add_perm_op_id {
+            Some(op_id) => op_id,
+            None => self.next_op_id(consistency.mult_outstanding).await,
+        };
+
+        /////////
+
+        let (device_ids, basic_data) = self
+            .prepare_data_update(&data_id, data_type, data_val, data_reader_idkeys)
+            .await?;
+        let basic_data = basic_data.with_expiry(expiry).with_bytes(data_bytes);
+        let operation = if consistency.causal {
+            let meta = self.new_causal_update(&data_id, &device_ids);
+            Operation::CausalUpdateData(data_id, basic_data, meta)
+        } else {
+            Operation::UpdateData(data_id, basic_data)
+        };
+
+        if bench && self.benchmark_send.read().is_some() {
+            self.send_timestamp_vec.lock().push((
+                self.benchmark_send.read().unwrap(),
+                String::from("enter CORE"),
+                Instant::now(),
+            ));
+        }
+        let res = self
+            .send_or_add_to_txn(
+                // includes idkeys of _all_ permissions
+                // (including data-only readers)
+                // TODO Make separate for read-only members of txn
+                device_ids.clone(),
+                &operation,
+                bench,
+            )
+            .await;
+        if bench && self.benchmark_send.read().is_some() {
+            self.send_timestamp_vec.lock().push((
+                self.benchmark_send.read().unwrap(),
+                String::from("exit CORE"),
+                Instant::now(),
+            ));
+        }
+        if res.is_err() {
+            return Err(Error::SendFailed(res.err().unwrap().to_string()));
         }
 
-        /////////
-
+        if bench && self.benchmark_send.read().is_some() {
+            self.send_timestamp_vec.lock().push((
+                self.benchmark_send.read().unwrap(),
+                String::from("enter CORE"),
+                Instant::now(),
+            ));
+        }
+        // FIXME better way to do this
         let res = self
             .send_or_add_to_txn(
                 vec![self.idkey()],
                 &Operation::Dummy(op_id.clone()),
-                false,
+                bench,
             )
             .await;
+        if bench && self.benchmark_send.read().is_some() {
+            self.send_timestamp_vec.lock().push((
+                self.benchmark_send.read().unwrap(),
+                String::from("exit LASTCORE"),
+                Instant::now(),
+            ));
+        }
 
         if res.is_err() {
             return Err(Error::SendFailed(res.err().unwrap().to_string()));
         }
+        if bench && self.benchmark_send.read().is_some() {
+            self.send_timestamp_vec.lock().push((
+                self.benchmark_send.read().unwrap(),
+                String::from("exit sendTANK"),
+                Instant::now(),
+            ));
+            let mut ctr_check_guard = self.ctr_check_send.lock();
+            *ctr_check_guard += 1;
+            let cur_count = self.benchmark_send.read().unwrap();
+            if cur_count == 1 {
+                // write sends
+                let mut f = File::options()
+                    .append(true)
+                    .create(true)
+                    .open(&self.send_filename.as_ref().unwrap())
+                    .unwrap();
+                let vec = self.send_timestamp_vec.lock();
+                for entry in vec.iter() {
+                    let _ = write!(f, "{:?}\n", entry);
+                }
+            } else if cur_count > 1 {
+                *self.benchmark_send.write() = Some(cur_count - 1);
+            }
+            //println!("dal ctr_check_send: {:?}", ctr_check_guard);
+        }
 
-        /////////
+        ////////
 
-        // check if need to block on reads, and if so, if this read has
-        // returned from the server yet
-        loop {
-            let op_id_ctr = self.op_id_ctr.lock();
-            if self.sync_reads && op_id_ctr.1.contains(&op_id) {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                break;
-            }
+        // check if need to block on writes, and if so, wait until this write
+        // has returned from the server
+        if consistency.block_writes {
+            self.wait_for_op(op_id).await;
         }
 
-        /////////
+        Ok(())
+    }
 
-        // read all data
+    // Turns on version history (keeping the last max_versions versions of
+    // each data object) and the permission-change log for updates received
+    // from now on
+    pub fn enable_history(&self, max_versions: usize) -> Result<(), Error> {
         let device_guard = self.device.read();
-        let data_store_guard = device_guard.as_ref().unwrap().data_store.read();
-        let data = data_store_guard.get_all_data().values();
-        let mut values = Vec::<BasicData>::new();
-        for datum in data {
-            values.push(datum.clone())
+        let device = match device_guard.as_ref() {
+            Some(device) => device,
+            None => return Err(Error::UninitializedDevice),
+        };
+        device.data_store.write().enable_history(max_versions);
+        device.meta_store.write().enable_perm_log();
+        Ok(())
+    }
+
+    // Returns the recorded versions of data_id, oldest first
+    pub fn get_data_history(
+        &self,
+        data_id: &String,
+    ) -> Result<Vec<DataVersion<BasicData>>, Error> {
+        let device_guard = self.device.read();
+        match device_guard.as_ref() {
+            Some(device) => Ok(device
+                .data_store
+                .read()
+                .get_history(data_id)
+                .into_iter()
+                .cloned()
+                .collect()),
+            None => Err(Error::UninitializedDevice),
         }
-        Ok(values)
     }
 
-    // TODO add facility for setting and sharing data at the same time
+    // Returns the logged permission changes (oldest first), optionally only
+    // those to perm_id
+    pub fn get_perm_log(
+        &self,
+        perm_id: Option<&String>,
+    ) -> Result<Vec<PermChangeEntry>, Error> {
+        let device_guard = self.device.read();
+        match device_guard.as_ref() {
+            Some(device) => Ok(device
+                .meta_store
+                .read()
+                .get_perm_log(perm_id)
+                .into_iter()
+                .cloned()
+                .collect()),
+            None => Err(Error::UninitializedDevice),
+        }
+    }
 
-    pub async fn set_data(
+    // Returns (and clears) the updates written by this device that were
+    // rejected by a recipient's validator since the last call
+    pub fn take_data_rejections(&self) -> Vec<DataRejection> {
+        std::mem::take(&mut *self.data_rejections.lock())
+    }
+
+    // Returns the CRDT stored under data_id, or None if there is no such data
+    // or it does not hold a CRDT
+    pub async fn get_crdt(&self, data_id: &String) -> Result<Option<Crdt>, Error> {
+        Ok(self
+            .get_data(data_id)
+            .await?
+            .and_then(|data| Crdt::from_data_val(data.data_val())))
+    }
+
+    // Applies update to the local copy of the CRDT stored under data_id (or
+    // to init if there is none yet) and sends the result. Since recipients
+    // merge CRDTs on receipt, concurrent updates from other devices are not
+    // lost, so no transaction is needed. update is passed this device's
+    // idkey to use as the replica id.
+    pub async fn update_crdt<F>(
         &self,
         data_id: String,
         data_type: String,
+        init: Crdt,
+        update: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Crdt, &String) -> Result<(), crdt::Error>,
+    {
+        let mut crdt = self.get_crdt(&data_id).await?.unwrap_or(init);
+        update(&mut crdt, &self.idkey())?;
+        self.set_data(data_id, data_type, crdt.to_data_val()?, None, None, false)
+            .await
+    }
+
+    // Looks up (or, for new data, creates and sends) the permissions set for
+    // data_id, and returns the new data value along with the idkeys that
+    // should receive the update
+    async fn prepare_data_update(
+        &self,
+        data_id: &String,
+        data_type: String,
         data_val: String,
-        // FIXME what is the below option for again? is it sharing upon
-        // setting? why just enabled for data readers and, e.g., not
-        // data writers?
         data_reader_idkeys: Option<Vec<String>>,
-        add_perm_op_id: Option<u64>,
-        bench: bool,
-    ) -> Result<(), Error> {
-        if bench && self.benchmark_send.read().is_some() {
-            self.send_timestamp_vec.lock().push((
-                self.benchmark_send.read().unwrap(),
-                String::from("enter sendTANK"),
-                Instant::now(),
-            ));
-        }
-        let op_id;
-        if add_perm_op_id.is_none() {
-            // check if can have multiple outstanding ops, or if not, check that
-            // no other ops are outstanding
-            loop {
-                let mut op_id_ctr = self.op_id_ctr.lock();
-                if !self.mult_outstanding && op_id_ctr.1.len() != 0 {
-                    // release the lock
-                    let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-                } else {
-                    // get op_id and inc id ctr
-                    op_id = op_id_ctr.0;
-                    op_id_ctr.0 += 1;
-                    // add op into hashset
-                    op_id_ctr.1.insert(op_id);
-                    break;
-                }
-            }
-        } else {
-            op_id = add_perm_op_id.unwrap();
-        }
-
-        /////////
-
+    ) -> Result<(Vec<String>, BasicData), Error> {
         // FIXME check write permissions
 
         let device_guard = self.device.read();
         let data_store_guard = device_guard.as_ref().unwrap().data_store.read();
-        let existing_val = data_store_guard.get_data(&data_id).clone();
+        let existing_val = data_store_guard.get_data(data_id).cloned();
+
+        // run the same validation recipients will, before sending anything
+        // (for new data, the perm_id has not been created yet)
+        let new_val = BasicData::new(
+            data_id.clone(),
+            data_type.clone(),
+            data_val.clone(),
+            existing_val
+                .as_ref()
+                .map_or(String::new(), |val| val.perm_id().to_string()),
+        );
+        if let Some(old_val) = &existing_val {
+            crdt::check_write(old_val, &new_val).map_err(|err| {
+                ValidationError::CrdtMismatch(data_id.to_string(), err.to_string())
+            })?;
+        }
+        data_store_guard.validate(&self.idkey(), data_id, &new_val)?;
+        // receiving purges expired data, so the store must not stay locked
+        // while sending below
+        core::mem::drop(data_store_guard);
 
         // if data exists, use existing perms; otherwise create new one
         let perm_id;
@@ -2302,7 +5329,7 @@ impl TankClient {
                     .await;
 
                 if res.is_err() {
-                    return res;
+                    return Err(res.err().unwrap());
                 }
 
                 // send group
@@ -2311,156 +5338,62 @@ impl TankClient {
                         idkeys.clone(),
                         &Operation::SetGroup(
                             group_val.group_id().to_string(),
-                            group_val.clone(),
-                        ),
-                        false,
-                    )
-                    .await;
-
-                if res.is_err() {
-                    return res;
-                }
-
-                // add newly created group as a parent of linked_name
-                res = self
-                    .send_or_add_to_txn(
-                        idkeys.clone(),
-                        &Operation::AddParent(
-                            self.linked_name().to_string(),
-                            group_val.group_id().to_string(),
-                        ),
-                        false,
-                    )
-                    .await;
-
-                if res.is_err() {
-                    return res;
-                }
-            }
-        }
-
-        core::mem::drop(data_store_guard);
-
-        let basic_data = BasicData::new(
-            data_id.clone(),
-            data_type.clone(),
-            data_val,
-            perm_id.clone(),
-        );
-
-        // add data-only-readers to device_ids
-        match perm_val.do_readers() {
-            Some(do_reader_group) => {
-                // resolve group to idkeys, then push idkeys
-                let group_ids = vec![do_reader_group.to_string()];
-                let mut do_reader_ids = device_guard
-                    .as_ref()
-                    .unwrap()
-                    .meta_store
-                    .read()
-                    .resolve_group_ids(group_ids.iter().collect::<Vec<&String>>())
-                    .into_iter()
-                    .collect::<Vec<String>>();
-                device_ids.append(&mut do_reader_ids);
-            }
-            None => {}
-        }
-
-        if bench && self.benchmark_send.read().is_some() {
-            self.send_timestamp_vec.lock().push((
-                self.benchmark_send.read().unwrap(),
-                String::from("enter CORE"),
-                Instant::now(),
-            ));
-        }
-        let res = self
-            .send_or_add_to_txn(
-                // includes idkeys of _all_ permissions
-                // (including data-only readers)
-                // TODO Make separate for read-only members of txn
-                device_ids.clone(),
-                &Operation::UpdateData(data_id, basic_data),
-                bench,
-            )
-            .await;
-        if bench && self.benchmark_send.read().is_some() {
-            self.send_timestamp_vec.lock().push((
-                self.benchmark_send.read().unwrap(),
-                String::from("exit CORE"),
-                Instant::now(),
-            ));
-        }
-        if res.is_err() {
-            return Err(Error::SendFailed(res.err().unwrap().to_string()));
-        }
-
-        if bench && self.benchmark_send.read().is_some() {
-            self.send_timestamp_vec.lock().push((
-                self.benchmark_send.read().unwrap(),
-                String::from("enter CORE"),
-                Instant::now(),
-            ));
-        }
-        // FIXME better way to do this
-        let res = self
-            .send_or_add_to_txn(
-                vec![self.idkey()],
-                &Operation::Dummy(op_id.clone()),
-                bench,
-            )
-            .await;
-        if bench && self.benchmark_send.read().is_some() {
-            self.send_timestamp_vec.lock().push((
-                self.benchmark_send.read().unwrap(),
-                String::from("exit LASTCORE"),
-                Instant::now(),
-            ));
-        }
-
-        if res.is_err() {
-            return Err(Error::SendFailed(res.err().unwrap().to_string()));
-        }
-        if bench && self.benchmark_send.read().is_some() {
-            self.send_timestamp_vec.lock().push((
-                self.benchmark_send.read().unwrap(),
-                String::from("exit sendTANK"),
-                Instant::now(),
-            ));
-            let mut ctr_check_guard = self.ctr_check_send.lock();
-            *ctr_check_guard += 1;
-            let cur_count = self.benchmark_send.read().unwrap();
-            if cur_count == 1 {
-                // write sends
-                let mut f = File::options()
-                    .append(true)
-                    .create(true)
-                    .open(&self.send_filename.as_ref().unwrap())
-                    .unwrap();
-                let vec = self.send_timestamp_vec.lock();
-                for entry in vec.iter() {
-                    write!(f, "{:?}\n", entry);
+                            group_val.clone(),
+                        ),
+                        false,
+                    )
+                    .await;
+
+                if res.is_err() {
+                    return Err(res.err().unwrap());
+                }
+
+                // add newly created group as a parent of linked_name
+                res = self
+                    .send_or_add_to_txn(
+                        idkeys.clone(),
+                        &Operation::AddParent(
+                            self.linked_name().to_string(),
+                            group_val.group_id().to_string(),
+                        ),
+                        false,
+                    )
+                    .await;
+
+                if res.is_err() {
+                    return Err(res.err().unwrap());
                 }
-            } else if cur_count > 1 {
-                *self.benchmark_send.write() = Some(cur_count - 1);
             }
-            //println!("dal ctr_check_send: {:?}", ctr_check_guard);
         }
 
-        ////////
+        let type_version = self.migrations.read().current_version(&data_type);
+        let basic_data = BasicData::new(
+            data_id.clone(),
+            data_type.clone(),
+            data_val,
+            perm_id.clone(),
+        )
+        .with_type_version(type_version);
 
-        // check if need to block on writes, and if so, if this write has
-        // returned from the server yet
-        loop {
-            let op_id_ctr = self.op_id_ctr.lock();
-            if self.block_writes && op_id_ctr.1.contains(&op_id) {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                break;
+        // add data-only-readers to device_ids
+        match perm_val.do_readers() {
+            Some(do_reader_group) => {
+                // resolve group to idkeys, then push idkeys
+                let group_ids = vec![do_reader_group.to_string()];
+                let mut do_reader_ids = device_guard
+                    .as_ref()
+                    .unwrap()
+                    .meta_store
+                    .read()
+                    .resolve_group_ids(group_ids.iter().collect::<Vec<&String>>())
+                    .into_iter()
+                    .collect::<Vec<String>>();
+                device_ids.append(&mut do_reader_ids);
             }
+            None => {}
         }
 
-        Ok(())
+        Ok((device_ids, basic_data))
     }
 
     // TODO remove_data
@@ -2550,37 +5483,74 @@ impl TankClient {
         .await
     }
 
+    // add_readers() where the readers lose access once expiry passes (as of
+    // the first message of that epoch; see purge_expired())
+    pub async fn add_readers_until(
+        &self,
+        data_id: String,
+        readers: Vec<&String>,
+        expiry: Expiry,
+    ) -> Result<(), Error> {
+        let members =
+            PermType::Readers(readers.iter().map(|id| id.to_string()).collect());
+        self.add_permissions_expiring(data_id, members, readers, Some(expiry))
+            .await
+    }
+
+    // add_writers() where the writers lose access once expiry passes (as of
+    // the first message of that epoch; see purge_expired())
+    pub async fn add_writers_until(
+        &self,
+        data_id: String,
+        writers: Vec<&String>,
+        expiry: Expiry,
+    ) -> Result<(), Error> {
+        let members =
+            PermType::Writers(writers.iter().map(|id| id.to_string()).collect());
+        self.add_permissions_expiring(data_id, members, writers, Some(expiry))
+            .await
+    }
+
     pub async fn add_permissions(
+        &self,
+        data_id: String,
+        new_members: PermType,
+        new_members_refs: Vec<&String>, // FIXME one or the other
+    ) -> Result<(), Error> {
+        self.add_permissions_expiring(data_id, new_members, new_members_refs, None)
+            .await
+    }
+
+    async fn add_permissions_expiring(
         &self,
         data_id: String,
         new_members: PermType,
         mut new_members_refs: Vec<&String>, // FIXME one or the other
+        expiry: Option<Expiry>,
     ) -> Result<(), Error> {
-        // check if can have multiple outstanding ops, or if not, check that
-        // no other ops are outstanding
-        let op_id;
-        loop {
-            let mut op_id_ctr = self.op_id_ctr.lock();
-            if !self.mult_outstanding && op_id_ctr.1.len() != 0 {
-                // release the lock
-                let _ = self.op_id_ctr_cv.wait(op_id_ctr).await;
-            } else {
-                // get op_id and inc id ctr
-                op_id = op_id_ctr.0;
-                op_id_ctr.0 += 1;
-                // add op into hashset
-                op_id_ctr.1.insert(op_id);
-                break;
-            }
-        }
+        let op_id = self.next_op_id(self.mult_outstanding).await;
 
         let device_guard = self.device.read();
         let mut data_store_guard = device_guard.as_ref().unwrap().data_store.read();
 
         // check that data exists
         match data_store_guard.get_data(&data_id) {
-            None => return Err(Error::NonexistentData(data_id)),
+            None => {
+                self.finish_op(op_id);
+                return Err(Error::NonexistentData(data_id));
+            }
             Some(data_val) => {
+                // the update is marked with the current version of the
+                // data's type, so it has to be in that version; upgrade it
+                // before sending any metadata, so that a failed upgrade does
+                // not leave new members with metadata but no data
+                let data_val = match self.migrations.read().upgrade(data_val) {
+                    Ok(data_val) => data_val,
+                    Err(err) => {
+                        self.finish_op(op_id);
+                        return Err(Error::from(err));
+                    }
+                };
                 let mut meta_store_guard =
                     device_guard.as_ref().unwrap().meta_store.read();
 
@@ -2735,10 +5705,12 @@ impl TankClient {
                 /*
                  * Wait to send messages until done reading from the
                  * meta_store b/c SetPerm/SetGroups/etc will need to take
-                 * exclusive locks on it.
+                 * exclusive locks on it. The same goes for the data_store,
+                 * which receiving locks to purge expired data.
                  */
 
                 core::mem::drop(meta_store_guard);
+                core::mem::drop(data_store_guard);
 
                 // first send SetPerm for existing, unmodified perm_set
                 // TODO remove this device from the idkeys for this op only
@@ -2754,6 +5726,7 @@ impl TankClient {
                     .await;
 
                 if res.is_err() {
+                    self.finish_op(op_id);
                     return res;
                 }
 
@@ -2768,6 +5741,7 @@ impl TankClient {
                     .await;
 
                 if res.is_err() {
+                    self.finish_op(op_id);
                     return res;
                 }
 
@@ -2778,38 +5752,66 @@ impl TankClient {
                         &Operation::AddPermMembers(
                             perm_val.perm_id().to_string(),
                             group_id_opt,
-                            new_members,
+                            new_members.clone(),
                         ),
                         false,
                     )
                     .await;
 
                 if res.is_err() {
+                    self.finish_op(op_id);
                     return res;
                 }
 
+                // time-bounded grants
+                if let Some(expiry) = expiry {
+                    res = self
+                        .send_or_add_to_txn(
+                            metadata_reader_idkeys.clone(),
+                            &Operation::SetGrantExpiry(
+                                perm_val.perm_id().to_string(),
+                                new_members,
+                                expiry,
+                            ),
+                            false,
+                        )
+                        .await;
+
+                    if res.is_err() {
+                        self.finish_op(op_id);
+                        return res;
+                    }
+                }
+
                 // finally, send UpdateData (via set_data) to set the
                 // associated data val:
 
-                // copy out and drop read lock on data_store b/c UpdateData
-                // will need exclusive lock to set data (on receive)
-
                 let data_id = data_val.data_id().clone();
                 let data_type = data_val.data_type().clone();
                 let data_val_interior = data_val.data_val().clone();
+                // resending the value must not drop its expiry or bytes
+                let data_expiry = data_val.expiry().cloned();
+                let data_bytes = data_val.data_bytes().cloned();
 
-                core::mem::drop(data_store_guard);
                 core::mem::drop(device_guard);
 
-                self.set_data(
-                    data_id,
-                    data_type,
-                    data_val_interior,
-                    Some(data_reader_idkeys),
-                    Some(op_id),
-                    false,
-                )
-                .await
+                let res = self
+                    .write_data(
+                        data_id,
+                        data_type,
+                        data_val_interior,
+                        Some(data_reader_idkeys),
+                        Some(op_id),
+                        false,
+                        data_expiry,
+                        None,
+                        data_bytes,
+                    )
+                    .await;
+                if res.is_err() {
+                    self.finish_op(op_id);
+                }
+                res
             }
         }
     }
@@ -2819,28 +5821,182 @@ impl TankClient {
     // TODO metadata_gc
 }
 
+#[cfg(test)]
 mod tests {
-    use crate::client::{Operation, TankClient};
-    use crate::data::ScubaData;
+    use crate::capability::CapabilityRole;
+    use crate::client::{
+        Error, Operation, TankClient, Transaction, TxCoordinator, MAX_LINK_BUFFER_LEN,
+        MAX_NAMESPACE_BUFFER_LEN,
+    };
+    use crate::crdt::{Crdt, GCounter};
+    use crate::data::{BasicData, ScubaData, ValidationError};
+    use crate::invite::IssuedInvites;
+    use crate::metadata::PermissionSet;
+    use std::collections::{HashMap, HashSet};
+
+    // Polls until cond holds; how many callbacks an operation takes depends
+    // on protocol details, so waiting for a number of them is brittle
+    async fn wait_until<F: Fn() -> bool>(cond: F) {
+        for _ in 0..400 {
+            if cond() {
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(25)).await;
+        }
+        panic!("timed out waiting for condition");
+    }
+
+    fn num_contacts(client: &TankClient) -> usize {
+        client.device.read().as_ref().unwrap().get_contacts().len()
+    }
+
+    fn data_on(client: &TankClient, data_id: &String) -> Option<BasicData> {
+        let device_guard = client.device.read();
+        let data_store_guard = device_guard.as_ref().unwrap().data_store.read();
+        data_store_guard.get_data(data_id).cloned()
+    }
+
+    // data_id and its permissions as client sees them
+    fn shared_state(
+        client: &TankClient,
+        data_id: &String,
+    ) -> Option<(BasicData, PermissionSet)> {
+        let data_val = data_on(client, data_id)?;
+        let device_guard = client.device.read();
+        let meta_store_guard = device_guard.as_ref().unwrap().meta_store.read();
+        let perm_val = meta_store_guard.get_perm(data_val.perm_id())?.clone();
+        Some((data_val, perm_val))
+    }
+
+    async fn new_client(test_wait_num_callbacks: Option<u64>) -> TankClient {
+        new_client_with(test_wait_num_callbacks, false).await
+    }
+
+    async fn new_client_with(
+        test_wait_num_callbacks: Option<u64>,
+        mult_outstanding: bool,
+    ) -> TankClient {
+        TankClient::new(
+            None,
+            None,
+            false,
+            test_wait_num_callbacks,
+            None,
+            true,
+            true,
+            mult_outstanding,
+            true,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .await
+    }
+
+    fn test_transaction(coordinator: &str, recipients: Vec<&str>) -> Transaction {
+        Transaction::new(
+            coordinator.to_string(),
+            recipients.iter().map(|id| id.to_string()).collect(),
+            vec![Operation::DeleteData("data".to_string())],
+            0,
+            100,
+            true,
+            Vec::new(),
+            None,
+        )
+    }
+
+    #[test]
+    fn test_presumed_abort_by_recipient() {
+        let tx = test_transaction("coord", vec!["coord", "a", "b"]);
+
+        // a recipient's abort, sequenced before the commit, wins everywhere
+        let mut at_a = TxCoordinator::new();
+        at_a.start_message("a".to_string(), "coord".to_string(), 1, tx.clone())
+            .unwrap();
+        let gone = HashSet::from(["coord".to_string()]);
+        assert_eq!(at_a.coordinated_by(&gone).len(), 1);
+        at_a.abort_message("a".to_string(), "b".to_string(), &1)
+            .unwrap();
+        assert_eq!(
+            at_a.commit_message("a".to_string(), "coord".to_string(), &1, 2),
+            Err(Error::Timeout)
+        );
+
+        let mut at_coord = TxCoordinator::new();
+        at_coord
+            .start_message("coord".to_string(), "coord".to_string(), 1, tx.clone())
+            .unwrap();
+        assert_eq!(
+            at_coord.abort_message("coord".to_string(), "b".to_string(), &1),
+            Err(Error::SendToAll)
+        );
+        assert_eq!(
+            at_coord.commit_message("coord".to_string(), "coord".to_string(), &1, 2),
+            Err(Error::TxNotFound)
+        );
+
+        // devices outside the transaction cannot abort it
+        let mut at_b = TxCoordinator::new();
+        at_b.start_message("b".to_string(), "coord".to_string(), 1, tx)
+            .unwrap();
+        assert_eq!(
+            at_b.abort_message("b".to_string(), "stranger".to_string(), &1),
+            Err(Error::BadTransactionError)
+        );
+        assert!(at_b
+            .coordinated_by(&HashSet::from(["a".to_string()]))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_trim_ignores_bystanders() {
+        let mut at_a = TxCoordinator::new();
+        let tx = test_transaction("coord", vec!["coord", "a", "b"]);
+        at_a.start_message("a".to_string(), "coord".to_string(), 1, tx)
+            .unwrap();
+        at_a.commit_message("a".to_string(), "coord".to_string(), &1, 2)
+            .unwrap();
+
+        // messages from a device that never transacts do not hold back the
+        // watermark
+        at_a.observe(3);
+        let mut tx = test_transaction("coord", vec!["coord", "a"]);
+        tx.prev_seq_number = 3;
+        at_a.start_message("a".to_string(), "coord".to_string(), 4, tx)
+            .unwrap();
+        assert!(at_a.committed_tx.contains_key(&1));
+
+        // b took part but never acknowledged anything; once it is gone, the
+        // transaction everyone else has moved past can be trimmed
+        at_a.untrack_devices(&HashSet::from(["b".to_string()]));
+        assert!(at_a.committed_tx.is_empty());
+    }
 
     #[tokio::test]
     async fn test_send_one_message() {
-        let mut client_0 = TankClient::new(None, None, false, Some(1), None).await;
-        let mut client_1 = TankClient::new(None, None, false, None, None).await;
+        let client_0 = new_client(Some(1)).await;
+        let client_1 = new_client(None).await;
 
-        client_0.create_standalone_device();
-        client_1.create_standalone_device();
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
 
         println!("client_0 idkey = {:?}", client_0.idkey());
         println!("client_1 idkey = {:?}", client_1.idkey());
 
         // send operation
-        let operation =
-            Operation::to_string(&Operation::Test("hello".to_string())).unwrap();
+        let operation = Operation::Test("hello".to_string());
         println!("sending operation to device 0");
         client_1
-            .send_message(vec![client_0.idkey()], &operation)
-            .await;
+            .send_message(vec![(vec![client_0.idkey()], operation.clone(), false)])
+            .await
+            .unwrap();
 
         loop {
             let ctr = client_0.ctr.lock();
@@ -2855,19 +6011,19 @@ mod tests {
 
     #[tokio::test]
     async fn test_send_two_sequential_messages() {
-        let mut client_0 = TankClient::new(None, None, false, Some(1), None).await;
-        let mut client_1 = TankClient::new(None, None, false, Some(1), None).await;
+        let client_0 = new_client(Some(1)).await;
+        let client_1 = new_client(Some(1)).await;
 
-        client_0.create_standalone_device();
-        client_1.create_standalone_device();
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
 
         // send operation 1
-        let operation_1 =
-            Operation::to_string(&Operation::Test("hello".to_string())).unwrap();
+        let operation_1 = Operation::Test("hello".to_string());
         println!("sending operation to device 0");
         client_1
-            .send_message(vec![client_0.idkey()], &operation_1)
-            .await;
+            .send_message(vec![(vec![client_0.idkey()], operation_1.clone(), false)])
+            .await
+            .unwrap();
 
         loop {
             let ctr = client_0.ctr.lock();
@@ -2880,12 +6036,12 @@ mod tests {
         }
 
         // send operation 2
-        let operation_2 =
-            Operation::to_string(&Operation::Test("goodbye".to_string())).unwrap();
+        let operation_2 = Operation::Test("goodbye".to_string());
         println!("sending operation to device 1");
         client_0
-            .send_message(vec![client_1.idkey()], &operation_2)
-            .await;
+            .send_message(vec![(vec![client_1.idkey()], operation_2.clone(), false)])
+            .await
+            .unwrap();
 
         loop {
             let ctr = client_1.ctr.lock();
@@ -2900,27 +6056,27 @@ mod tests {
 
     #[tokio::test]
     async fn test_send_two_concurrent_messages() {
-        let mut client_0 = TankClient::new(None, None, false, Some(1), None).await;
-        let mut client_1 = TankClient::new(None, None, false, Some(1), None).await;
+        let client_0 = new_client(Some(1)).await;
+        let client_1 = new_client(Some(1)).await;
 
-        client_0.create_standalone_device();
-        client_1.create_standalone_device();
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
 
         // send operation 1
-        let operation_1 =
-            Operation::to_string(&Operation::Test("hello".to_string())).unwrap();
+        let operation_1 = Operation::Test("hello".to_string());
         println!("sending operation to device 0");
         client_1
-            .send_message(vec![client_0.idkey()], &operation_1)
-            .await;
+            .send_message(vec![(vec![client_0.idkey()], operation_1.clone(), false)])
+            .await
+            .unwrap();
 
         // send operation 2
-        let operation_2 =
-            Operation::to_string(&Operation::Test("goodbye".to_string())).unwrap();
+        let operation_2 = Operation::Test("goodbye".to_string());
         println!("sending operation to device 1");
         client_0
-            .send_message(vec![client_1.idkey()], &operation_2)
-            .await;
+            .send_message(vec![(vec![client_1.idkey()], operation_2.clone(), false)])
+            .await
+            .unwrap();
 
         loop {
             let ctr = client_0.ctr.lock();
@@ -2945,12 +6101,15 @@ mod tests {
 
     #[tokio::test]
     async fn test_create_linked_device() {
-        let mut client_0 = TankClient::new(None, None, false, Some(1), None).await;
-        let mut client_1 = TankClient::new(None, None, false, Some(1), None).await;
+        let client_0 = new_client(Some(2)).await;
+        let client_1 = new_client(Some(3)).await;
 
-        client_0.create_standalone_device();
+        client_0.create_standalone_device().await.unwrap();
         // sends operation to device 0 to link devices
-        client_1.create_linked_device(client_0.idkey()).await;
+        client_1
+            .create_linked_device(client_0.idkey())
+            .await
+            .unwrap();
 
         println!("client_0 idkey = {:?}", client_0.idkey());
         println!("client_1 idkey = {:?}", client_1.idkey());
@@ -2994,21 +6153,89 @@ mod tests {
         assert_eq!(linked_name_0, linked_name_1);
     }
 
+    #[tokio::test]
+    async fn test_relink_after_failed_link() {
+        let client_0 = new_client(None).await;
+        let client_1 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
+
+        client_1
+            .create_linked_device(client_0.idkey())
+            .await
+            .unwrap();
+        // as if the link buffer had filled up
+        let _ = client_1.fail_link().await;
+        assert_eq!(
+            client_1.check_link(),
+            Err(Error::LinkBufferFull(MAX_LINK_BUFFER_LEN))
+        );
+
+        client_1
+            .create_linked_device(client_0.idkey())
+            .await
+            .unwrap();
+        assert_eq!(client_1.check_link(), Ok(()));
+        wait_until(|| client_1.link_transfer_missing_chunks().is_none()).await;
+
+        let data_id = crate::metadata::generate_uuid();
+        client_0
+            .set_data(
+                data_id.clone(),
+                "type".to_string(),
+                r#"{ data: true }"#.to_string(),
+                None,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+        wait_until(|| data_on(&client_1, &data_id).is_some()).await;
+    }
+
+    #[tokio::test]
+    async fn test_link_snapshot() {
+        let client_0 = new_client(None).await;
+        let client_1 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
+
+        // data that exists before linking is sent in the link snapshot
+        let data_id = crate::metadata::generate_uuid();
+        client_0
+            .set_data(
+                data_id.clone(),
+                "type".to_string(),
+                r#"{ data: true }"#.to_string(),
+                None,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        client_1
+            .create_linked_device(client_0.idkey())
+            .await
+            .unwrap();
+        wait_until(|| client_1.link_transfer_missing_chunks().is_none()).await;
+        wait_until(|| data_on(&client_1, &data_id).is_some()).await;
+        assert_eq!(data_on(&client_1, &data_id), data_on(&client_0, &data_id));
+    }
+
     #[tokio::test]
     async fn test_serialization() {
-        let mut client_0 = TankClient::new(None, None, false, Some(0), None).await;
-        let mut client_1 = TankClient::new(None, None, false, Some(1), None).await;
-        let mut client_2 = TankClient::new(None, None, false, Some(1), None).await;
-        let mut client_3 = TankClient::new(None, None, false, Some(1), None).await;
-        let mut client_4 = TankClient::new(None, None, false, Some(1), None).await;
-        let mut client_5 = TankClient::new(None, None, false, Some(2), None).await;
-
-        client_0.create_standalone_device();
-        client_1.create_standalone_device();
-        client_2.create_standalone_device();
-        client_3.create_standalone_device();
-        client_4.create_standalone_device();
-        client_5.create_standalone_device();
+        let client_0 = new_client(Some(0)).await;
+        let client_1 = new_client(Some(1)).await;
+        let client_2 = new_client(Some(1)).await;
+        let client_3 = new_client(Some(1)).await;
+        let client_4 = new_client(Some(1)).await;
+        let client_5 = new_client(Some(2)).await;
+
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
+        client_2.create_standalone_device().await.unwrap();
+        client_3.create_standalone_device().await.unwrap();
+        client_4.create_standalone_device().await.unwrap();
+        client_5.create_standalone_device().await.unwrap();
 
         println!("client_0 idkey = {:?}", client_0.idkey());
         println!("client_1 idkey = {:?}", client_1.idkey());
@@ -3021,10 +6248,8 @@ mod tests {
         // FIXME message size actually doesn't even matter because we .await
         // on send_message()
         let vec = vec![0x78; 1024];
-        let operation_1 = Operation::to_string(&Operation::Test(
-            std::str::from_utf8(vec.as_slice()).unwrap().to_string(),
-        ))
-        .unwrap();
+        let operation_1 =
+            Operation::Test(std::str::from_utf8(vec.as_slice()).unwrap().to_string());
         let recipients_1 = vec![
             client_1.idkey(),
             client_2.idkey(),
@@ -3034,13 +6259,18 @@ mod tests {
         ];
 
         // construct a small message with a subset of recipients
-        let operation_2 =
-            Operation::to_string(&Operation::Test("small".to_string())).unwrap();
+        let operation_2 = Operation::Test("small".to_string());
         let recipients_2 = vec![client_5.idkey()];
 
         // send the messages
-        client_0.send_message(recipients_1, &operation_1).await;
-        client_0.send_message(recipients_2, &operation_2).await;
+        client_0
+            .send_message(vec![(recipients_1, operation_1, false)])
+            .await
+            .unwrap();
+        client_0
+            .send_message(vec![(recipients_2, operation_2, false)])
+            .await
+            .unwrap();
 
         // client_0 loop is unnecessary
         loop {
@@ -3108,14 +6338,14 @@ mod tests {
     #[tokio::test]
     async fn test_add_contact() {
         let mut client_0 =
-            TankClient::new(None, None, false, Some(1), None).await;
+            new_client(Some(1)).await;
         let mut client_1 =
-            TankClient::new(None, None, false, Some(1), None).await;
+            new_client(Some(1)).await;
 
-        client_0.create_standalone_device();
-        client_1.create_standalone_device();
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
 
-        client_0.add_contact(client_1.idkey()).await;
+        client_0.add_contact(client_1.idkey()).await.unwrap();
 
         loop {
             let ctr = client_0.ctr.lock();
@@ -3233,36 +6463,36 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_all_contacts() {
-        let mut client_0 = TankClient::new(None, None, false, Some(1), None).await;
-        let mut client_1 = TankClient::new(None, None, false, Some(1), None).await;
-
-        client_0.create_standalone_device();
-        client_1.create_standalone_device();
+        let client_0 = new_client(None).await;
+        let client_1 = new_client(None).await;
 
-        client_0.add_contact(client_1.idkey()).await;
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
 
-        loop {
-            let ctr = client_0.ctr.lock();
-            println!("ctr_0 (test): {:?}", *ctr);
-            if *ctr != 0 {
-                let _ = client_0.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
+        client_0.add_contact(client_1.idkey()).await.unwrap();
 
-        loop {
-            let ctr = client_1.ctr.lock();
-            println!("ctr_1 (test): {:?}", *ctr);
-            if *ctr != 0 {
-                let _ = client_1.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
+        wait_until(|| num_contacts(&client_0) == 1 && num_contacts(&client_1) == 1).await;
 
-        assert_eq!(client_0.get_contacts().len(), 1);
-        assert_eq!(client_1.get_contacts().len(), 1);
+        assert_eq!(
+            client_0
+                .device
+                .read()
+                .as_ref()
+                .unwrap()
+                .get_contacts()
+                .len(),
+            1
+        );
+        assert_eq!(
+            client_1
+                .device
+                .read()
+                .as_ref()
+                .unwrap()
+                .get_contacts()
+                .len(),
+            1
+        );
     }
 
     /*
@@ -3271,7 +6501,7 @@ mod tests {
         let mut client_0 = TankClient::new(None, None, false).await;
         // upload otkeys to server
         client_0.core.receive_message().await;
-        client_0.create_standalone_device();
+        client_0.create_standalone_device().await.unwrap();
 
         let mut client_1 = TankClient::new(None, None, false).await;
         // upload otkeys to server
@@ -3311,7 +6541,7 @@ mod tests {
         let mut client_0 = TankClient::new(None, None, false).await;
         // upload otkeys to server
         client_0.core.receive_message().await;
-        client_0.create_standalone_device();
+        client_0.create_standalone_device().await.unwrap();
 
         let mut client_1 = TankClient::new(None, None, false).await;
         // upload otkeys to server
@@ -3351,7 +6581,7 @@ mod tests {
         let mut client_0 = TankClient::new(None, None, false).await;
         // upload otkeys to server
         client_0.core.receive_message().await;
-        client_0.create_standalone_device();
+        client_0.create_standalone_device().await.unwrap();
 
         let mut client_1 = TankClient::new(None, None, false).await;
         // upload otkeys to server
@@ -3377,14 +6607,48 @@ mod tests {
         client_1.receive_operation().await;
         assert_eq!(client_0.device.read(), None);
         assert_eq!(client_1.device.read(), None);
-        client_0.add_contact(client_1.idkey()).await;
+        client_0.add_contact(client_1.idkey()).await.unwrap();
     }
     */
 
+    #[tokio::test]
+    async fn test_cannot_block_own_devices() {
+        let client_0 = new_client(None).await;
+        let client_1 = new_client(None).await;
+
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
+
+        client_0.add_contact(client_1.idkey()).await.unwrap();
+        wait_until(|| num_contacts(&client_0) == 1 && num_contacts(&client_1) == 1).await;
+
+        assert_eq!(
+            client_0.block_device(client_0.idkey()).await,
+            Err(Error::CannotBlockOwnDevice(client_0.idkey()))
+        );
+        assert_eq!(
+            client_0.block_group(client_0.linked_name()).await,
+            Err(Error::CannotBlockOwnDevice(client_0.linked_name()))
+        );
+
+        let contact_name = client_0
+            .device
+            .read()
+            .as_ref()
+            .unwrap()
+            .get_contacts()
+            .into_iter()
+            .next()
+            .unwrap();
+        client_0.block_group(contact_name).await.unwrap();
+        wait_until(|| client_0.is_blocked(&client_1.idkey())).await;
+        assert!(!client_0.is_blocked(&client_0.idkey()));
+    }
+
     #[tokio::test]
     async fn test_set_data() {
-        let mut client_0 = TankClient::new(None, None, false, Some(4), None).await;
-        client_0.create_standalone_device();
+        let client_0 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
 
         let data_type = "type".to_string();
         let data_id = crate::metadata::generate_uuid();
@@ -3437,21 +6701,20 @@ mod tests {
         println!("");
 
         let res = client_0
-            .set_data(data_id.clone(), data_type.clone(), json_val.clone(), None)
+            .set_data(
+                data_id.clone(),
+                data_type.clone(),
+                json_val.clone(),
+                None,
+                None,
+                false,
+            )
             .await;
         if res.is_err() {
             panic!("send failed");
         }
 
-        loop {
-            let ctr = client_0.ctr.lock();
-            println!("ctr_0 (test): {:?}", *ctr);
-            if *ctr != 0 {
-                let _ = client_0.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
+        wait_until(|| data_on(&client_0, &data_id).is_some()).await;
 
         let data_val = client_0
             .device
@@ -3474,105 +6737,562 @@ mod tests {
         println!("CHECKING METADATA");
         println!("");
 
-        println!("client_0.idkey: {:?}", client_0.idkey());
-        println!("client_0.linked_name: {:?}", client_0.linked_name());
-        println!(
-            "client_0.perms: {:?}",
-            client_0
-                .device
-                .read()
-                .as_ref()
-                .unwrap()
-                .meta_store
-                .read()
-                .get_all_perms()
-        );
-        let groups_0 = client_0
+        println!("client_0.idkey: {:?}", client_0.idkey());
+        println!("client_0.linked_name: {:?}", client_0.linked_name());
+        println!(
+            "client_0.perms: {:?}",
+            client_0
+                .device
+                .read()
+                .as_ref()
+                .unwrap()
+                .meta_store
+                .read()
+                .get_all_perms()
+        );
+        let groups_0 = client_0
+            .device
+            .read()
+            .as_ref()
+            .unwrap()
+            .meta_store
+            .read()
+            .get_all_groups()
+            .clone();
+        println!("client_0.groups:");
+        for group in groups_0 {
+            println!("--{:?}", group);
+        }
+        println!(
+            "client_0.data: {:?}",
+            client_0
+                .device
+                .read()
+                .as_ref()
+                .unwrap()
+                .data_store
+                .read()
+                .get_all_data()
+        );
+
+        let new_group_id = client_0
+            .device
+            .read()
+            .as_ref()
+            .unwrap()
+            .meta_store
+            .read()
+            .get_perm(data_val.perm_id())
+            .unwrap()
+            .owners()
+            .as_ref()
+            .unwrap()
+            .clone();
+
+        let linked_parents_list = client_0
+            .device
+            .read()
+            .as_ref()
+            .unwrap()
+            .meta_store
+            .read()
+            .get_group(&client_0.linked_name())
+            .unwrap()
+            .parents()
+            .clone();
+
+        assert!(linked_parents_list.iter().any(|x| x == &new_group_id));
+    }
+
+    #[tokio::test]
+    async fn test_rejection_names_sender() {
+        let client_0 = new_client(None).await;
+        let client_1 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
+
+        client_0.add_contact(client_1.idkey()).await.unwrap();
+        wait_until(|| num_contacts(&client_0) == 1 && num_contacts(&client_1) == 1).await;
+
+        client_1
+            .device
+            .read()
+            .as_ref()
+            .unwrap()
+            .data_store
+            .write()
+            .validator()
+            .set_validate_callback_for_type("locked".to_string(), |_, _, _| {
+                Err(ValidationError::Invalid(
+                    "value".to_string(),
+                    "locked".to_string(),
+                ))
+            });
+
+        let data_id = crate::metadata::generate_uuid();
+        client_0
+            .set_data(
+                data_id.clone(),
+                "locked".to_string(),
+                r#"{ data: true }"#.to_string(),
+                None,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+        client_0
+            .add_readers(data_id.clone(), vec![&client_1.idkey()])
+            .await
+            .unwrap();
+
+        wait_until(|| !client_0.data_rejections.lock().is_empty()).await;
+        let rejections = client_0.take_data_rejections();
+        assert_eq!(rejections[0].rejected_by, client_1.idkey());
+        assert_eq!(rejections[0].data_id, data_id);
+    }
+
+    #[tokio::test]
+    async fn test_crdt_type_mismatch_rejected() {
+        let client_0 = new_client(None).await;
+        let client_1 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
+
+        client_0.add_contact(client_1.idkey()).await.unwrap();
+        wait_until(|| num_contacts(&client_0) == 1 && num_contacts(&client_1) == 1).await;
+
+        let data_id = crate::metadata::generate_uuid();
+        client_0
+            .update_crdt(
+                data_id.clone(),
+                "counter".to_string(),
+                Crdt::GCounter(GCounter::new()),
+                |crdt, replica| {
+                    if let Crdt::GCounter(counter) = crdt {
+                        counter.increment(replica, 1);
+                    }
+                    Ok(())
+                },
+            )
+            .await
+            .unwrap();
+        client_0
+            .add_readers(data_id.clone(), vec![&client_1.idkey()])
+            .await
+            .unwrap();
+        wait_until(|| data_on(&client_1, &data_id).is_some()).await;
+
+        // caught before sending
+        let res = client_0
+            .set_data(
+                data_id.clone(),
+                "counter".to_string(),
+                "plain".to_string(),
+                None,
+                None,
+                false,
+            )
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::ValidationErr {
+                source: ValidationError::CrdtMismatch(_, _)
+            })
+        ));
+
+        // and by recipients, for writers that skip the check
+        let plain = data_on(&client_0, &data_id)
+            .unwrap()
+            .with_data_val("plain".to_string());
+        client_0
+            .send_message(vec![(
+                vec![client_1.idkey()],
+                Operation::UpdateData(data_id.clone(), plain),
+                false,
+            )])
+            .await
+            .unwrap();
+        wait_until(|| !client_0.data_rejections.lock().is_empty()).await;
+        let rejections = client_0.take_data_rejections();
+        assert!(matches!(
+            rejections[0].reason,
+            ValidationError::CrdtMismatch(_, _)
+        ));
+        assert!(client_1.get_crdt(&data_id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_invite_file() {
+        let client_0 = new_client(None).await;
+        let client_1 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
+
+        let path = std::env::temp_dir()
+            .join(format!(
+                "tank-invites-{}.json",
+                crate::metadata::generate_uuid()
+            ))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let _ = std::fs::remove_file(&path);
+        client_0.set_invite_file(path.clone()).unwrap();
+        let invite = client_0.create_invite().unwrap();
+
+        // as if client_0 had restarted
+        *client_0.invites.lock() = IssuedInvites::new();
+        client_0.set_invite_file(path.clone()).unwrap();
+
+        client_1.accept_invite(invite.code()).await.unwrap();
+        wait_until(|| num_contacts(&client_0) == 1 && num_contacts(&client_1) == 1).await;
+        // used invites are gone from the file too
+        let loaded = IssuedInvites::load(&path).unwrap();
+        assert!(!loaded.is_valid(&invite.secret, crate::invite::now_secs()));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_only_issuer_rejects_capability() {
+        let client_0 = new_client(None).await;
+        let client_1 = new_client(None).await;
+        let client_2 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
+        client_2.create_standalone_device().await.unwrap();
+
+        let data_id = crate::metadata::generate_uuid();
+        client_0
+            .set_data(
+                data_id.clone(),
+                "type".to_string(),
+                r#"{ data: true }"#.to_string(),
+                None,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+        wait_until(|| data_on(&client_0, &data_id).is_some()).await;
+        let capability = client_0
+            .create_capability(data_id.clone(), CapabilityRole::Reader)
+            .unwrap();
+        assert!(client_0.revoke_capability(&capability.token_id).unwrap());
+
+        client_1.redeem_capability(capability.code()).await.unwrap();
+        // a device that did not issue the token cannot reject it
+        client_2
+            .send_message(vec![(
+                vec![client_1.idkey()],
+                Operation::CapabilityRejected(
+                    capability.token_id.clone(),
+                    data_id.clone(),
+                    "forged".to_string(),
+                ),
+                false,
+            )])
+            .await
+            .unwrap();
+
+        wait_until(|| !client_1.capability_rejections.lock().is_empty()).await;
+        let rejections = client_1.take_capability_rejections();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].rejected_by, client_0.idkey());
+        assert_eq!(rejections[0].token_id, capability.token_id);
+    }
+
+    // increments the counter stored at data_id in a transaction
+    async fn increment(client: &TankClient, data_id: &String) -> Result<(), Error> {
+        client
+            .transact(|tx| {
+                let data_id = data_id.clone();
+                async move {
+                    let count = match tx.get_data(&data_id).await? {
+                        Some(data_val) => data_val.data_val().parse::<u64>().unwrap(),
+                        None => 0,
+                    };
+                    // let a concurrent body run in between
+                    tokio::task::yield_now().await;
+                    tx.set_data(data_id, "counter".to_string(), (count + 1).to_string())
+                        .await
+                }
+            })
+            .await
+    }
+
+    fn count_on(client: &TankClient, data_id: &String) -> Option<String> {
+        data_on(client, data_id).map(|data_val| data_val.data_val().to_string())
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_transactions() {
+        // several operations may be outstanding, so nothing but transact()
+        // itself keeps the two bodies apart
+        let client_0 = new_client_with(None, true).await;
+        client_0.create_standalone_device().await.unwrap();
+
+        let data_id = crate::metadata::generate_uuid();
+        client_0
+            .set_data(
+                data_id.clone(),
+                "counter".to_string(),
+                "0".to_string(),
+                None,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+        wait_until(|| count_on(&client_0, &data_id).is_some()).await;
+
+        let (res_0, res_1) = futures::join!(
+            increment(&client_0, &data_id),
+            increment(&client_0, &data_id)
+        );
+        assert_eq!(res_0, Ok(()));
+        assert_eq!(res_1, Ok(()));
+        wait_until(|| count_on(&client_0, &data_id) == Some("2".to_string())).await;
+    }
+
+    #[tokio::test]
+    async fn test_read_only_transaction() {
+        let client_0 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
+
+        let data_id = crate::metadata::generate_uuid();
+        client_0
+            .set_data(
+                data_id.clone(),
+                "counter".to_string(),
+                "7".to_string(),
+                None,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+
+        // objects that do not exist are left out
+        let missing_id = crate::metadata::generate_uuid();
+        let (_, snapshot) = client_0
+            .read_only_transaction(vec![data_id.clone(), missing_id.clone()])
+            .await
+            .unwrap();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot.get(&data_id).unwrap().data_val(), "7");
+    }
+
+    #[tokio::test]
+    async fn test_import_state() {
+        let client_0 = new_client(None).await;
+        let client_1 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
+
+        let data_id = crate::metadata::generate_uuid();
+        client_0
+            .set_data(
+                data_id.clone(),
+                "type".to_string(),
+                r#"{ data: true }"#.to_string(),
+                None,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+        wait_until(|| data_on(&client_0, &data_id).is_some()).await;
+
+        let export = client_0.export_state().unwrap();
+        let skipped = client_1.import_state(export).await.unwrap();
+        assert!(skipped.is_empty());
+
+        // the imported data is now owned by client_1
+        let (data_val, perm_set) = shared_state(&client_1, &data_id).unwrap();
+        assert_eq!(data_val, data_on(&client_0, &data_id).unwrap());
+        let owners = client_1
             .device
             .read()
             .as_ref()
             .unwrap()
             .meta_store
             .read()
-            .get_all_groups()
-            .clone();
-        println!("client_0.groups:");
-        for group in groups_0 {
-            println!("--{:?}", group);
-        }
-        println!(
-            "client_0.data: {:?}",
-            client_0
-                .device
-                .read()
-                .as_ref()
-                .unwrap()
-                .data_store
-                .read()
-                .get_all_data()
+            .resolve_group_ids(vec![perm_set.owners().as_ref().unwrap()]);
+        assert!(owners.contains(&client_1.idkey()));
+        assert!(!owners.contains(&client_0.idkey()));
+    }
+
+    #[tokio::test]
+    async fn test_namespaces() {
+        let client_0 = new_client(None).await;
+        let client_1 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
+
+        client_0.add_contact(client_1.idkey()).await.unwrap();
+        wait_until(|| num_contacts(&client_0) == 1 && num_contacts(&client_1) == 1).await;
+
+        let app_0 = client_0.namespace("app").await.unwrap();
+        assert_eq!(app_0.app_id(), Some(&"app".to_string()));
+        assert_eq!(
+            app_0.namespace("other").await.err(),
+            Some(Error::NestedNamespace)
         );
+        client_0.share_contacts("app", None).unwrap();
+        assert_eq!(num_contacts(&app_0), 1);
 
-        let new_group_id = client_0
-            .device
-            .read()
-            .as_ref()
-            .unwrap()
-            .meta_store
-            .read()
-            .get_perm(data_val.perm_id())
-            .unwrap()
-            .owners()
-            .as_ref()
-            .unwrap()
-            .clone();
+        let data_id = crate::metadata::generate_uuid();
+        app_0
+            .set_data(
+                data_id.clone(),
+                "type".to_string(),
+                r#"{ data: true }"#.to_string(),
+                None,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+        app_0
+            .add_readers(data_id.clone(), vec![&client_1.idkey()])
+            .await
+            .unwrap();
+        assert!(data_on(&client_0, &data_id).is_none());
+
+        // sent before client_1 opened the namespace, so held until it does
+        wait_until(|| client_1.namespace_buffer.lock().contains_key("app")).await;
+        let app_1 = client_1.namespace("app").await.unwrap();
+        wait_until(|| data_on(&app_1, &data_id).is_some()).await;
+        assert!(data_on(&client_1, &data_id).is_none());
+        assert_eq!(client_1.get_namespaces(), vec!["app".to_string()]);
+    }
 
-        let linked_parents_list = client_0
-            .device
-            .read()
-            .as_ref()
-            .unwrap()
-            .meta_store
-            .read()
-            .get_group(&client_0.linked_name())
+    #[tokio::test]
+    async fn test_namespace_state_is_separate() {
+        let client_0 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
+        let app_0 = client_0.namespace("app").await.unwrap();
+
+        let invite = app_0.create_invite().unwrap();
+        let now = crate::invite::now_secs();
+        assert!(app_0.invites.lock().is_valid(&invite.secret, now));
+        assert!(!client_0.invites.lock().is_valid(&invite.secret, now));
+
+        // messages for unopened namespaces past the limit are dropped, and
+        // counted
+        client_0.namespace_buffer.lock().insert(
+            "full".to_string(),
+            vec![
+                (0, client_0.idkey(), Operation::LinkSnapshotDone);
+                MAX_NAMESPACE_BUFFER_LEN
+            ],
+        );
+        client_0
+            .receive_namespaced(
+                1,
+                client_0.idkey(),
+                "other".to_string(),
+                Operation::LinkSnapshotDone,
+            )
+            .await;
+        assert!(!client_0.namespace_buffer.lock().contains_key("other"));
+        assert_eq!(
+            client_0.take_namespace_drops(),
+            HashMap::from([("other".to_string(), 1)])
+        );
+        assert!(client_0.take_namespace_drops().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_only_transaction_body() {
+        let client_0 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
+
+        let data_id = crate::metadata::generate_uuid();
+        client_0
+            .set_data(
+                data_id.clone(),
+                "counter".to_string(),
+                "7".to_string(),
+                None,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+        wait_until(|| count_on(&client_0, &data_id).is_some()).await;
+
+        // the read is checked by a transaction to this device alone
+        let read = |client: TankClient| {
+            let data_id = data_id.clone();
+            async move {
+                client
+                    .transact(|tx| {
+                        let data_id = data_id.clone();
+                        async move { tx.get_data(&data_id).await }
+                    })
+                    .await
+            }
+        };
+        let count = read(client_0.clone()).await.unwrap();
+        assert_eq!(count.unwrap().data_val(), "7");
+
+        // a pending transaction that writes the value conflicts with the read
+        let mut writer = test_transaction("other", vec!["other", "self"]);
+        writer.ops = vec![Operation::DeleteData(data_id.clone())];
+        client_0
+            .tx_coordinator
+            .write()
+            .as_mut()
             .unwrap()
-            .parents()
-            .clone();
+            .remote_pending_tx
+            .insert(1, writer);
+        client_0.set_transaction_retry_limit(0);
+        assert_eq!(
+            read(client_0.clone()).await,
+            Err(Error::TransactionConflictsError)
+        );
+    }
 
-        assert!(linked_parents_list.iter().any(|x| x == &new_group_id));
+    #[tokio::test]
+    async fn test_expiry_in_epochs() {
+        let client_0 = new_client(None).await;
+        client_0.create_standalone_device().await.unwrap();
+
+        let data_id = crate::metadata::generate_uuid();
+        // epochs can be a few milliseconds long
+        let expiry = client_0.expiry_in_epochs(1_000_000);
+        assert_eq!(expiry.epoch(), client_0.current_epoch() + 1_000_000);
+
+        // not yet expired, so the value is kept
+        client_0
+            .set_data_with_expiry(
+                data_id.clone(),
+                "type".to_string(),
+                r#"{ data: true }"#.to_string(),
+                expiry,
+            )
+            .await
+            .unwrap();
+        wait_until(|| data_on(&client_0, &data_id).is_some()).await;
+        assert!(client_0.current_epoch() < expiry.epoch());
     }
 
     #[tokio::test]
     async fn test_add_writers() {
-        let mut client_0 = TankClient::new(None, None, false, Some(8), None).await;
-        let mut client_1 = TankClient::new(None, None, false, Some(5), None).await;
+        let client_0 = new_client(None).await;
+        let client_1 = new_client(None).await;
 
-        client_0.create_standalone_device();
-        client_1.create_standalone_device();
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
 
         let mut res = client_0.add_contact(client_1.idkey()).await;
         if res.is_err() {
             panic!("send failed");
         }
 
-        loop {
-            let ctr = client_0.ctr.lock();
-            println!("ctr_0 (test): {:?}", *ctr);
-            if *ctr != 7 {
-                let _ = client_0.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
-
-        loop {
-            let ctr = client_1.ctr.lock();
-            println!("ctr_1 (test): {:?}", *ctr);
-            if *ctr != 4 {
-                let _ = client_1.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
+        wait_until(|| num_contacts(&client_0) == 1 && num_contacts(&client_1) == 1).await;
 
         println!("");
         println!("CONTACTS ADDED");
@@ -3669,21 +7389,20 @@ mod tests {
         println!("");
 
         res = client_0
-            .set_data(data_id.clone(), data_type.clone(), json_val.clone(), None)
+            .set_data(
+                data_id.clone(),
+                data_type.clone(),
+                json_val.clone(),
+                None,
+                None,
+                false,
+            )
             .await;
         if res.is_err() {
             panic!("send failed");
         }
 
-        loop {
-            let ctr = client_0.ctr.lock();
-            println!("ctr_0 (test): {:?}", *ctr);
-            if *ctr != 4 {
-                let _ = client_0.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
+        wait_until(|| data_on(&client_0, &data_id).is_some()).await;
 
         println!("");
         println!("SET DATA");
@@ -3706,8 +7425,6 @@ mod tests {
         assert_eq!(*data_val.data_type(), data_type.clone());
         assert_eq!(*data_val.data_val(), json_val.clone());
 
-        let perm_id = data_val.perm_id();
-
         println!("");
         println!("");
         println!("ADDING WRITERS");
@@ -3719,25 +7436,11 @@ mod tests {
             panic!("send failed");
         }
 
-        loop {
-            let ctr = client_0.ctr.lock();
-            println!("ctr_0 (test): {:?}", *ctr);
-            if *ctr != 0 {
-                let _ = client_0.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
-
-        loop {
-            let ctr = client_1.ctr.lock();
-            println!("ctr_1 (test): {:?}", *ctr);
-            if *ctr != 0 {
-                let _ = client_1.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
+        wait_until(|| {
+            let state_1 = shared_state(&client_1, &data_id);
+            state_1.is_some() && state_1 == shared_state(&client_0, &data_id)
+        })
+        .await;
 
         let data_val_0 = client_0
             .device
@@ -3822,36 +7525,18 @@ mod tests {
 
     #[tokio::test]
     async fn test_add_readers() {
-        let mut client_0 = TankClient::new(None, None, false, Some(10), None).await;
-        let mut client_1 = TankClient::new(None, None, false, Some(7), None).await;
+        let client_0 = new_client(None).await;
+        let client_1 = new_client(None).await;
 
-        client_0.create_standalone_device();
-        client_1.create_standalone_device();
+        client_0.create_standalone_device().await.unwrap();
+        client_1.create_standalone_device().await.unwrap();
 
         let mut res = client_0.add_contact(client_1.idkey()).await;
         if res.is_err() {
             panic!("send failed");
         }
 
-        loop {
-            let ctr = client_0.ctr.lock();
-            println!("ctr_0 (test): {:?}", *ctr);
-            if *ctr != 9 {
-                let _ = client_0.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
-
-        loop {
-            let ctr = client_1.ctr.lock();
-            println!("ctr_1 (test): {:?}", *ctr);
-            if *ctr != 6 {
-                let _ = client_1.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
+        wait_until(|| num_contacts(&client_0) == 1 && num_contacts(&client_1) == 1).await;
 
         let data_true = r#"{ data: true }"#;
         let data_false = r#"{ data: false }"#;
@@ -3860,21 +7545,20 @@ mod tests {
         let data_id = crate::metadata::generate_uuid();
         let json_val = data_true.to_string();
         res = client_0
-            .set_data(data_id.clone(), data_type.clone(), json_val.clone(), None)
+            .set_data(
+                data_id.clone(),
+                data_type.clone(),
+                json_val.clone(),
+                None,
+                None,
+                false,
+            )
             .await;
         if res.is_err() {
             panic!("send failed");
         }
 
-        loop {
-            let ctr = client_0.ctr.lock();
-            println!("ctr_0 (test): {:?}", *ctr);
-            if *ctr != 6 {
-                let _ = client_0.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
+        wait_until(|| data_on(&client_0, &data_id).is_some()).await;
 
         let data_val = client_0
             .device
@@ -3893,8 +7577,6 @@ mod tests {
         assert_eq!(*data_val.data_type(), data_type.clone());
         assert_eq!(*data_val.data_val(), json_val.clone());
 
-        let perm_id = data_val.perm_id();
-
         println!("");
         println!("");
         println!("ADDING READERS");
@@ -3906,25 +7588,11 @@ mod tests {
             panic!("send failed");
         }
 
-        loop {
-            let ctr = client_0.ctr.lock();
-            println!("ctr_0 (test): {:?}", *ctr);
-            if *ctr != 2 {
-                let _ = client_0.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
-
-        loop {
-            let ctr = client_1.ctr.lock();
-            println!("ctr_1 (test): {:?}", *ctr);
-            if *ctr != 2 {
-                let _ = client_1.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
+        wait_until(|| {
+            let state_1 = shared_state(&client_1, &data_id);
+            state_1.is_some() && state_1 == shared_state(&client_0, &data_id)
+        })
+        .await;
 
         let data_val_0 = client_0
             .device
@@ -4012,6 +7680,8 @@ mod tests {
                 data_type.clone(),
                 data_false.to_string(),
                 None,
+                None,
+                false,
             )
             .await;
         if res.is_err() {
@@ -4022,25 +7692,8 @@ mod tests {
         println!("");
         println!("READER MODDING DATA");
 
-        loop {
-            let ctr = client_0.ctr.lock();
-            println!("ctr_0 (test): {:?}", *ctr);
-            if *ctr != 1 {
-                let _ = client_0.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
-
-        loop {
-            let ctr = client_1.ctr.lock();
-            println!("ctr_1 (test): {:?}", *ctr);
-            if *ctr != 1 {
-                let _ = client_1.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
+        // nothing changes, so give the write time to be rejected
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
 
         let data_val_0 = client_0
             .device
@@ -4128,6 +7781,8 @@ mod tests {
                 data_type.clone(),
                 data_false.to_string(),
                 None,
+                None,
+                false,
             )
             .await;
         if res.is_err() {
@@ -4138,25 +7793,13 @@ mod tests {
         println!("");
         println!("OWNER MODDING DATA");
 
-        loop {
-            let ctr = client_0.ctr.lock();
-            println!("ctr_0 (test): {:?}", *ctr);
-            if *ctr != 0 {
-                let _ = client_0.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
-
-        loop {
-            let ctr = client_1.ctr.lock();
-            println!("ctr_1 (test): {:?}", *ctr);
-            if *ctr != 0 {
-                let _ = client_1.ctr_cv.wait(ctr).await;
-            } else {
-                break;
-            }
-        }
+        wait_until(|| {
+            [&client_0, &client_1].iter().all(|client| {
+                data_on(client, &data_id).map(|data| data.data_val().clone())
+                    == Some(data_false.to_string())
+            })
+        })
+        .await;
 
         let data_val_0 = client_0
             .device
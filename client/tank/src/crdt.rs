@@ -0,0 +1,609 @@
+use crate::data::{BasicData, ScubaData};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use thiserror::Error;
+
+/*
+ * Conflict-free replicated data types. A CRDT is stored as the data_val of
+ * a regular BasicData object (see Crdt::to_data_val()), so it is shared,
+ * permissioned and validated like any other data. The difference is that
+ * TankClient merges an incoming CRDT value with the one it already has
+ * (instead of overwriting it), so concurrent updates from different devices
+ * never get lost and do not need transactions or block_writes.
+ *
+ * Every update method takes the id of the replica (device idkey) making the
+ * update.
+ */
+
+#[derive(Debug, PartialEq, Error)]
+pub enum Error {
+    #[error("Cannot merge CRDT of type {0} with CRDT of type {1}")]
+    TypeMismatch(String, String),
+    #[error("Cannot replace CRDT of type {0} with a value that is not a CRDT")]
+    NotACrdt(String),
+    #[error("Index {0} is out of bounds")]
+    IndexOutOfBounds(usize),
+    #[error("Cannot convert CRDT to string: {0}")]
+    Serialization(String),
+}
+
+// Grow-only counter
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct GCounter {
+    counts: BTreeMap<String, u64>,
+}
+
+impl GCounter {
+    pub fn new() -> GCounter {
+        Self::default()
+    }
+
+    pub fn increment(&mut self, replica: &str, amount: u64) {
+        *self.counts.entry(replica.to_string()).or_insert(0) += amount;
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn merge(&mut self, other: &GCounter) {
+        for (replica, count) in other.counts.iter() {
+            let entry = self.counts.entry(replica.to_string()).or_insert(0);
+            *entry = (*entry).max(*count);
+        }
+    }
+}
+
+// uniquely identifies a single update made by a replica
+type Dot = (String, u64);
+
+// Observed-remove set: a remove only affects the adds it has observed, so an
+// add concurrent with a remove wins
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ORSet {
+    entries: BTreeMap<String, BTreeSet<Dot>>,
+    tombstones: BTreeSet<Dot>,
+    clock: BTreeMap<String, u64>,
+}
+
+impl ORSet {
+    pub fn new() -> ORSet {
+        Self::default()
+    }
+
+    pub fn add(&mut self, replica: &str, elem: String) {
+        let ctr = self.clock.entry(replica.to_string()).or_insert(0);
+        *ctr += 1;
+        let dot = (replica.to_string(), *ctr);
+        self.entries
+            .entry(elem)
+            .or_insert_with(BTreeSet::new)
+            .insert(dot);
+    }
+
+    pub fn remove(&mut self, elem: &String) {
+        if let Some(dots) = self.entries.remove(elem) {
+            self.tombstones.extend(dots);
+        }
+    }
+
+    pub fn contains(&self, elem: &String) -> bool {
+        self.entries.contains_key(elem)
+    }
+
+    pub fn elements(&self) -> Vec<&String> {
+        self.entries.keys().collect()
+    }
+
+    pub fn merge(&mut self, other: &ORSet) {
+        for (replica, ctr) in other.clock.iter() {
+            let entry = self.clock.entry(replica.to_string()).or_insert(0);
+            *entry = (*entry).max(*ctr);
+        }
+        self.tombstones.extend(other.tombstones.iter().cloned());
+        for (elem, dots) in other.entries.iter() {
+            self.entries
+                .entry(elem.to_string())
+                .or_insert_with(BTreeSet::new)
+                .extend(dots.iter().cloned());
+        }
+        let tombstones = &self.tombstones;
+        self.entries.retain(|_, dots| {
+            dots.retain(|dot| !tombstones.contains(dot));
+            !dots.is_empty()
+        });
+    }
+}
+
+// (lamport timestamp, replica); totally ordered, ties broken by replica
+type Timestamp = (u64, String);
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct LWWEntry {
+    timestamp: Timestamp,
+    // None if removed
+    value: Option<String>,
+}
+
+// Last-writer-wins map
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct LWWMap {
+    entries: BTreeMap<String, LWWEntry>,
+    clock: u64,
+}
+
+impl LWWMap {
+    pub fn new() -> LWWMap {
+        Self::default()
+    }
+
+    fn write(&mut self, replica: &str, key: String, value: Option<String>) {
+        self.clock += 1;
+        self.entries.insert(
+            key,
+            LWWEntry {
+                timestamp: (self.clock, replica.to_string()),
+                value,
+            },
+        );
+    }
+
+    pub fn insert(&mut self, replica: &str, key: String, value: String) {
+        self.write(replica, key, Some(value));
+    }
+
+    pub fn remove(&mut self, replica: &str, key: String) {
+        self.write(replica, key, None);
+    }
+
+    pub fn get(&self, key: &String) -> Option<&String> {
+        self.entries.get(key).and_then(|entry| entry.value.as_ref())
+    }
+
+    pub fn entries(&self) -> Vec<(&String, &String)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, entry)| entry.value.as_ref().map(|val| (key, val)))
+            .collect()
+    }
+
+    pub fn merge(&mut self, other: &LWWMap) {
+        self.clock = self.clock.max(other.clock);
+        for (key, other_entry) in other.entries.iter() {
+            match self.entries.get(key) {
+                Some(entry) if entry.timestamp >= other_entry.timestamp => {}
+                _ => {
+                    self.entries.insert(key.to_string(), other_entry.clone());
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+struct RGANode {
+    // the element this one was inserted after (None if inserted at the head)
+    origin: Option<Timestamp>,
+    value: String,
+    deleted: bool,
+}
+
+// Replicated growable array (list). Each element is inserted after an
+// existing element (its origin); elements inserted after the same origin are
+// ordered newest-first, which yields the same order on every replica.
+// Removed elements are kept as tombstones since later inserts may refer to
+// them.
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct RGAList {
+    nodes: BTreeMap<Timestamp, RGANode>,
+    clock: u64,
+}
+
+impl RGAList {
+    pub fn new() -> RGAList {
+        Self::default()
+    }
+
+    // ids of all elements (including removed ones) in list order
+    fn ordered_ids(&self) -> Vec<&Timestamp> {
+        let mut children = BTreeMap::<Option<&Timestamp>, Vec<&Timestamp>>::new();
+        for (id, node) in self.nodes.iter() {
+            children
+                .entry(node.origin.as_ref())
+                .or_insert_with(Vec::new)
+                .push(id);
+        }
+
+        let mut ordered = Vec::new();
+        // ids are iterated in ascending order, so pushing each sibling list
+        // onto the stack as-is pops the newest sibling first
+        let mut stack: Vec<&Timestamp> = children.get(&None).cloned().unwrap_or_default();
+        while let Some(id) = stack.pop() {
+            ordered.push(id);
+            if let Some(next) = children.get(&Some(id)) {
+                stack.extend(next.iter());
+            }
+        }
+        ordered
+    }
+
+    fn visible_ids(&self) -> Vec<&Timestamp> {
+        self.ordered_ids()
+            .into_iter()
+            .filter(|id| !self.nodes[*id].deleted)
+            .collect()
+    }
+
+    pub fn insert(
+        &mut self,
+        replica: &str,
+        index: usize,
+        value: String,
+    ) -> Result<(), Error> {
+        let origin = match index {
+            0 => None,
+            _ => match self.visible_ids().get(index - 1) {
+                Some(id) => Some((*id).clone()),
+                None => return Err(Error::IndexOutOfBounds(index)),
+            },
+        };
+        self.clock += 1;
+        self.nodes.insert(
+            (self.clock, replica.to_string()),
+            RGANode {
+                origin,
+                value,
+                deleted: false,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn push(&mut self, replica: &str, value: String) {
+        let len = self.len();
+        self.insert(replica, len, value).unwrap();
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<String, Error> {
+        let id = match self.visible_ids().get(index) {
+            Some(id) => (*id).clone(),
+            None => return Err(Error::IndexOutOfBounds(index)),
+        };
+        let node = self.nodes.get_mut(&id).unwrap();
+        node.deleted = true;
+        Ok(node.value.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.values().filter(|node| !node.deleted).count()
+    }
+
+    pub fn to_vec(&self) -> Vec<&String> {
+        self.visible_ids()
+            .into_iter()
+            .map(|id| &self.nodes[id].value)
+            .collect()
+    }
+
+    pub fn merge(&mut self, other: &RGAList) {
+        self.clock = self.clock.max(other.clock);
+        for (id, other_node) in other.nodes.iter() {
+            match self.nodes.get_mut(id) {
+                Some(node) => node.deleted |= other_node.deleted,
+                None => {
+                    self.nodes.insert(id.clone(), other_node.clone());
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "crdt")]
+pub enum Crdt {
+    GCounter(GCounter),
+    ORSet(ORSet),
+    LWWMap(LWWMap),
+    RGAList(RGAList),
+}
+
+impl Crdt {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Crdt::GCounter(_) => "GCounter",
+            Crdt::ORSet(_) => "ORSet",
+            Crdt::LWWMap(_) => "LWWMap",
+            Crdt::RGAList(_) => "RGAList",
+        }
+    }
+
+    pub fn to_data_val(&self) -> Result<String, Error> {
+        serde_json::to_string(self).map_err(|err| Error::Serialization(err.to_string()))
+    }
+
+    // returns None if data_val does not hold a CRDT
+    pub fn from_data_val(data_val: &String) -> Option<Crdt> {
+        serde_json::from_str(data_val.as_str()).ok()
+    }
+
+    pub fn merge(&mut self, other: &Crdt) -> Result<(), Error> {
+        match (self, other) {
+            (Crdt::GCounter(this), Crdt::GCounter(other)) => this.merge(other),
+            (Crdt::ORSet(this), Crdt::ORSet(other)) => this.merge(other),
+            (Crdt::LWWMap(this), Crdt::LWWMap(other)) => this.merge(other),
+            (Crdt::RGAList(this), Crdt::RGAList(other)) => this.merge(other),
+            (this, other) => {
+                return Err(Error::TypeMismatch(
+                    this.type_name().to_string(),
+                    other.type_name().to_string(),
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+// Once an object holds a CRDT, every write to it has to carry a CRDT of the
+// same type: anything else cannot be merged
+pub fn check_write(old_data: &BasicData, new_data: &BasicData) -> Result<(), Error> {
+    let old = match Crdt::from_data_val(old_data.data_val()) {
+        Some(crdt) => crdt,
+        None => return Ok(()),
+    };
+    match Crdt::from_data_val(new_data.data_val()) {
+        Some(new) if new.type_name() == old.type_name() => Ok(()),
+        Some(new) => Err(Error::TypeMismatch(
+            old.type_name().to_string(),
+            new.type_name().to_string(),
+        )),
+        None => Err(Error::NotACrdt(old.type_name().to_string())),
+    }
+}
+
+// Computes the value to store when new_data arrives for an object that
+// currently holds old_data: if both hold CRDTs they are merged; if old_data
+// does not hold a CRDT, new_data simply replaces it.
+pub fn merge_data(old_data: &BasicData, new_data: BasicData) -> Result<BasicData, Error> {
+    check_write(old_data, &new_data)?;
+    let mut merged = match Crdt::from_data_val(old_data.data_val()) {
+        Some(crdt) => crdt,
+        None => return Ok(new_data),
+    };
+    // checked above
+    let incoming = Crdt::from_data_val(new_data.data_val()).unwrap();
+    merged.merge(&incoming)?;
+    // keep everything else new_data carries (expiry, bytes, type version)
+    Ok(new_data.with_data_val(merged.to_data_val()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crdt::{
+        check_write, merge_data, Crdt, Error, GCounter, LWWMap, ORSet, RGAList,
+    };
+    use crate::data::{BasicData, Expiry, ScubaData};
+
+    #[test]
+    fn test_gcounter_merge() {
+        let mut a = GCounter::new();
+        let mut b = GCounter::new();
+        a.increment("a", 2);
+        b.increment("b", 3);
+        b.increment("a", 1);
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+        assert_eq!(ab, ba);
+        assert_eq!(ab.value(), 5);
+
+        // idempotent
+        ab.merge(&b);
+        assert_eq!(ab.value(), 5);
+    }
+
+    #[test]
+    fn test_orset_add_wins() {
+        let mut a = ORSet::new();
+        a.add("a", "x".to_string());
+        let mut b = a.clone();
+
+        // concurrent remove and re-add
+        a.remove(&"x".to_string());
+        b.add("b", "x".to_string());
+
+        a.merge(&b);
+        b.merge(&a);
+        assert_eq!(a, b);
+        assert!(a.contains(&"x".to_string()));
+    }
+
+    #[test]
+    fn test_orset_remove() {
+        let mut a = ORSet::new();
+        a.add("a", "x".to_string());
+        a.add("a", "y".to_string());
+        let mut b = a.clone();
+        b.remove(&"x".to_string());
+
+        a.merge(&b);
+        assert_eq!(a.elements(), vec![&"y".to_string()]);
+    }
+
+    #[test]
+    fn test_lwwmap_merge() {
+        let mut a = LWWMap::new();
+        a.insert("a", "k".to_string(), "1".to_string());
+        let mut b = a.clone();
+        b.insert("b", "k".to_string(), "2".to_string());
+        a.remove("a", "other".to_string());
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+        assert_eq!(ab, ba);
+        assert_eq!(ab.get(&"k".to_string()), Some(&"2".to_string()));
+
+        // concurrent writes with the same clock are broken by replica
+        let mut c = LWWMap::new();
+        let mut d = LWWMap::new();
+        c.insert("c", "k".to_string(), "c".to_string());
+        d.insert("d", "k".to_string(), "d".to_string());
+        c.merge(&d);
+        d.merge(&c);
+        assert_eq!(c.get(&"k".to_string()), Some(&"d".to_string()));
+        assert_eq!(c, d);
+    }
+
+    #[test]
+    fn test_rga_insert_remove() {
+        let mut list = RGAList::new();
+        list.push("a", "1".to_string());
+        list.push("a", "3".to_string());
+        list.insert("a", 1, "2".to_string()).unwrap();
+        list.insert("a", 0, "0".to_string()).unwrap();
+        assert_eq!(list.to_vec(), vec!["0", "1", "2", "3"]);
+
+        assert_eq!(list.remove(2), Ok("2".to_string()));
+        assert_eq!(list.to_vec(), vec!["0", "1", "3"]);
+        assert_eq!(list.remove(3), Err(Error::IndexOutOfBounds(3)));
+        assert_eq!(
+            list.insert("a", 5, "x".to_string()),
+            Err(Error::IndexOutOfBounds(5))
+        );
+    }
+
+    #[test]
+    fn test_rga_concurrent_merge() {
+        let mut a = RGAList::new();
+        a.push("a", "x".to_string());
+        let mut b = a.clone();
+
+        a.push("a", "from a".to_string());
+        b.push("b", "from b".to_string());
+        b.remove(0).unwrap();
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+        assert_eq!(ab.to_vec(), ba.to_vec());
+        assert_eq!(ab.len(), 2);
+    }
+
+    #[test]
+    fn test_merge_data() {
+        let mut a = GCounter::new();
+        a.increment("a", 1);
+        let mut b = GCounter::new();
+        b.increment("b", 1);
+
+        let old_data = BasicData::new(
+            "id".to_string(),
+            "counter".to_string(),
+            Crdt::GCounter(a).to_data_val().unwrap(),
+            "perm".to_string(),
+        );
+        let new_data = BasicData::new(
+            "id".to_string(),
+            "counter".to_string(),
+            Crdt::GCounter(b).to_data_val().unwrap(),
+            "perm".to_string(),
+        );
+        let merged = merge_data(&old_data, new_data).unwrap();
+        match Crdt::from_data_val(merged.data_val()) {
+            Some(Crdt::GCounter(counter)) => assert_eq!(counter.value(), 2),
+            _ => panic!("expected a GCounter"),
+        }
+
+        // CRDTs cannot be replaced by non-CRDT values, but non-CRDT values
+        // can be replaced by anything
+        let plain = BasicData::new(
+            "id".to_string(),
+            "counter".to_string(),
+            "plain".to_string(),
+            "perm".to_string(),
+        );
+        assert_eq!(
+            merge_data(&old_data, plain.clone()),
+            Err(Error::NotACrdt("GCounter".to_string()))
+        );
+        assert_eq!(merge_data(&plain, old_data.clone()), Ok(old_data.clone()));
+
+        let set = BasicData::new(
+            "id".to_string(),
+            "counter".to_string(),
+            Crdt::ORSet(ORSet::new()).to_data_val().unwrap(),
+            "perm".to_string(),
+        );
+        assert_eq!(
+            merge_data(&old_data, set),
+            Err(Error::TypeMismatch(
+                "GCounter".to_string(),
+                "ORSet".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_check_write() {
+        let counter = BasicData::new(
+            "id".to_string(),
+            "counter".to_string(),
+            Crdt::GCounter(GCounter::new()).to_data_val().unwrap(),
+            "perm".to_string(),
+        );
+        let set = counter
+            .clone()
+            .with_data_val(Crdt::ORSet(ORSet::new()).to_data_val().unwrap());
+        let plain = counter.clone().with_data_val("plain".to_string());
+
+        assert_eq!(check_write(&counter, &counter), Ok(()));
+        assert_eq!(check_write(&plain, &counter), Ok(()));
+        assert_eq!(check_write(&plain, &plain), Ok(()));
+        assert_eq!(
+            check_write(&counter, &set),
+            Err(Error::TypeMismatch(
+                "GCounter".to_string(),
+                "ORSet".to_string()
+            ))
+        );
+        assert_eq!(
+            check_write(&counter, &plain),
+            Err(Error::NotACrdt("GCounter".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_merge_data_keeps_metadata() {
+        let mut a = GCounter::new();
+        a.increment("a", 1);
+        let mut b = GCounter::new();
+        b.increment("b", 1);
+
+        let old_data = BasicData::new(
+            "id".to_string(),
+            "counter".to_string(),
+            Crdt::GCounter(a).to_data_val().unwrap(),
+            "perm".to_string(),
+        )
+        .with_type_version(2);
+        let new_data = BasicData::new(
+            "id".to_string(),
+            "counter".to_string(),
+            Crdt::GCounter(b).to_data_val().unwrap(),
+            "perm".to_string(),
+        )
+        .with_expiry(Some(Expiry::Epoch(5)))
+        .with_type_version(3);
+        let merged = merge_data(&old_data, new_data).unwrap();
+        assert_eq!(merged.expiry(), Some(&Expiry::Epoch(5)));
+        assert_eq!(merged.type_version(), 3);
+        match Crdt::from_data_val(merged.data_val()) {
+            Some(Crdt::GCounter(counter)) => assert_eq!(counter.value(), 2),
+            _ => panic!("expected a GCounter"),
+        }
+    }
+}
@@ -1,20 +1,141 @@
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use jsonschema::JSONSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::fmt;
+use std::io::{Read, Write};
+use thiserror::Error;
 
 pub trait ScubaData {
     fn data_id(&self) -> &String;
     fn data_type(&self) -> &String;
     fn data_val(&self) -> &String;
     fn perm_id(&self) -> &String;
+    fn expiry(&self) -> Option<&Expiry> {
+        None
+    }
+}
+
+// When a data object or permission grant lapses. The writer sets an absolute
+// deadline in sequencer epochs, which is compared against the epoch of the
+// sequence numbers a replica receives; every replica sees those in the same
+// order, so all replicas agree on when it lapses. (There is no wall-clock
+// deadline, since replicas' clocks would not agree.)
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub enum Expiry {
+    Epoch(u64),
+}
+
+impl Expiry {
+    pub fn epoch(&self) -> u64 {
+        match self {
+            Expiry::Epoch(expiry_epoch) => *expiry_epoch,
+        }
+    }
+
+    pub fn has_expired(&self, epoch: u64) -> bool {
+        epoch >= self.epoch()
+    }
+}
+
+// largest value that BinaryVal::to_bytes() inflates compressed bytes to;
+// anything can be sent as compressed bytes, and a few KB of them can
+// inflate to gigabytes
+pub const MAX_INFLATED_LEN: u64 = 64 * 1024 * 1024;
+
+// Binary contents of a data object, as given or deflate-compressed
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum BinaryVal {
+    Raw(#[serde(with = "bytes_or_base64")] Vec<u8>),
+    Deflated(#[serde(with = "bytes_or_base64")] Vec<u8>),
+}
+
+// Byte strings in binary formats, and base64 in JSON, rather than a list of
+// numbers
+mod bytes_or_base64 {
+    use base64::engine::general_purpose::STANDARD_NO_PAD;
+    use base64::Engine;
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        bytes: &Vec<u8>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&STANDARD_NO_PAD.encode(bytes))
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            let encoded = String::deserialize(deserializer)?;
+            STANDARD_NO_PAD.decode(encoded).map_err(D::Error::custom)
+        } else {
+            serde_bytes::ByteBuf::deserialize(deserializer).map(|bytes| bytes.into_vec())
+        }
+    }
+}
+
+impl BinaryVal {
+    // only keeps the compressed bytes if they are actually smaller
+    pub fn new(bytes: Vec<u8>, compress: bool) -> BinaryVal {
+        if !compress {
+            return BinaryVal::Raw(bytes);
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        match encoder.write_all(&bytes).and_then(|_| encoder.finish()) {
+            Ok(deflated) if deflated.len() < bytes.len() => BinaryVal::Deflated(deflated),
+            _ => BinaryVal::Raw(bytes),
+        }
+    }
+
+    pub fn to_bytes(&self) -> std::io::Result<Vec<u8>> {
+        self.to_bytes_within(MAX_INFLATED_LEN)
+    }
+
+    // fails rather than inflate to more than limit bytes
+    fn to_bytes_within(&self, limit: u64) -> std::io::Result<Vec<u8>> {
+        match self {
+            BinaryVal::Raw(bytes) => Ok(bytes.clone()),
+            BinaryVal::Deflated(deflated) => {
+                let mut bytes = Vec::new();
+                DeflateDecoder::new(deflated.as_slice())
+                    .take(limit + 1)
+                    .read_to_end(&mut bytes)?;
+                if bytes.len() as u64 > limit {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("inflates to more than {} bytes", limit),
+                    ));
+                }
+                Ok(bytes)
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct BasicData {
     data_id: String,
     data_type: String,
+    // empty for binary data
     data_val: String,
     perm_id: String,
+    #[serde(default)]
+    expiry: Option<Expiry>,
+    #[serde(default)]
+    data_bytes: Option<BinaryVal>,
+    // version of data_type's format that data_val is in
+    #[serde(default)]
+    type_version: u32,
 }
 
 impl BasicData {
@@ -29,8 +150,39 @@ impl BasicData {
             data_type,
             data_val,
             perm_id,
+            expiry: None,
+            data_bytes: None,
+            type_version: 0,
         }
     }
+
+    pub fn with_expiry(mut self, expiry: Option<Expiry>) -> BasicData {
+        self.expiry = expiry;
+        self
+    }
+
+    pub fn with_bytes(mut self, data_bytes: Option<BinaryVal>) -> BasicData {
+        self.data_bytes = data_bytes;
+        self
+    }
+
+    pub fn data_bytes(&self) -> Option<&BinaryVal> {
+        self.data_bytes.as_ref()
+    }
+
+    pub fn with_data_val(mut self, data_val: String) -> BasicData {
+        self.data_val = data_val;
+        self
+    }
+
+    pub fn with_type_version(mut self, type_version: u32) -> BasicData {
+        self.type_version = type_version;
+        self
+    }
+
+    pub fn type_version(&self) -> u32 {
+        self.type_version
+    }
 }
 
 impl ScubaData for BasicData {
@@ -49,6 +201,10 @@ impl ScubaData for BasicData {
     fn perm_id(&self) -> &String {
         &self.perm_id
     }
+
+    fn expiry(&self) -> Option<&Expiry> {
+        self.expiry.as_ref()
+    }
 }
 
 impl fmt::Display for BasicData {
@@ -61,60 +217,117 @@ impl fmt::Display for BasicData {
     }
 }
 
-#[derive(Clone)]
-pub struct Validator<T: ScubaData> {
-    general_callback: fn(&String, &T) -> bool,
-    per_type_callbacks: HashMap<String, fn(&String, &T) -> bool>,
+// Reason a data update was rejected; sent back to the writer
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Error)]
+pub enum ValidationError {
+    #[error("Data id is empty")]
+    EmptyDataId,
+    #[error("Data id {0} does not match the id of its value ({1})")]
+    DataIdMismatch(String, String),
+    #[error("Invalid {0}: {1}")]
+    Invalid(String, String),
+    #[error("Value of type {0} does not conform to its schema: {1}")]
+    SchemaViolation(String, String),
+    #[error("Schema for type {0} is not a valid JSON Schema: {1}")]
+    InvalidSchema(String, String),
+    #[error("Value of type {0} is not in a version this device can check: {1}")]
+    UnsupportedVersion(String, String),
+    #[error("Invalid write to CRDT object {0}: {1}")]
+    CrdtMismatch(String, String),
 }
 
-fn default_general_callback<T: ScubaData>(data_id: &String, data_val: &T) -> bool {
-    if data_id.is_empty() || data_val.data_id().is_empty() {
-        return false;
-    }
-    if data_id != data_val.data_id() {
-        return false;
-    }
-    true
+// Called with the idkey of the device that sent the update, the current value
+// (if any) and the new value
+pub type ValidateCallback<T> =
+    Box<dyn Fn(&String, Option<&T>, &T) -> Result<(), ValidationError> + Send + Sync>;
+
+pub struct Validator<T: ScubaData> {
+    general_callback: Option<ValidateCallback<T>>,
+    per_type_callbacks: HashMap<String, ValidateCallback<T>>,
 }
 
 impl<T: ScubaData> Validator<T> {
-    pub fn new(general_callback: Option<fn(&String, &T) -> bool>) -> Validator<T> {
+    pub fn new(general_callback: Option<ValidateCallback<T>>) -> Validator<T> {
         Self {
-            general_callback: general_callback.unwrap_or(default_general_callback),
-            per_type_callbacks: HashMap::<String, fn(&String, &T) -> bool>::new(),
+            general_callback,
+            per_type_callbacks: HashMap::<String, ValidateCallback<T>>::new(),
         }
     }
 
-    pub fn validate(&self, data_id: &String, data_val: &T) -> bool {
-        // call general callback
-        if !(self.general_callback)(data_id, data_val) {
-            return false;
+    pub fn validate(
+        &self,
+        sender: &String,
+        data_id: &String,
+        old_val: Option<&T>,
+        new_val: &T,
+    ) -> Result<(), ValidationError> {
+        if data_id.is_empty() || new_val.data_id().is_empty() {
+            return Err(ValidationError::EmptyDataId);
+        }
+        if data_id != new_val.data_id() {
+            return Err(ValidationError::DataIdMismatch(
+                data_id.to_string(),
+                new_val.data_id().to_string(),
+            ));
+        }
+
+        // call general callback, if one exists
+        if let Some(general_callback) = &self.general_callback {
+            (general_callback)(sender, old_val, new_val)?;
         }
 
         // call type-specific callback, if one exists
-        match self.per_type_callbacks.get(data_val.data_type()) {
-            Some(type_callback) => (type_callback)(data_id, data_val),
-            None => true,
+        match self.per_type_callbacks.get(new_val.data_type()) {
+            Some(type_callback) => (type_callback)(sender, old_val, new_val),
+            None => Ok(()),
         }
     }
 
-    pub fn set_general_validate_callback(&mut self, callback: fn(&String, &T) -> bool) {
-        self.general_callback = callback;
+    pub fn set_general_validate_callback<F>(&mut self, callback: F)
+    where
+        F: Fn(&String, Option<&T>, &T) -> Result<(), ValidationError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.general_callback = Some(Box::new(callback));
     }
 
-    pub fn set_validate_callback_for_type(
+    pub fn set_validate_callback_for_type<F>(
         &mut self,
         data_type: String,
-        callback: fn(&String, &T) -> bool,
-    ) -> Option<fn(&String, &T) -> bool> {
-        self.per_type_callbacks.insert(data_type, callback)
+        callback: F,
+    ) -> Option<ValidateCallback<T>>
+    where
+        F: Fn(&String, Option<&T>, &T) -> Result<(), ValidationError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.per_type_callbacks
+            .insert(data_type, Box::new(callback))
     }
 }
 
-#[derive(Clone)]
+// A version of a data object, tagged with the device that wrote it and the
+// sequence number the write was ordered at
+#[derive(Debug, PartialEq, Clone)]
+pub struct DataVersion<T> {
+    pub sender: String,
+    pub seq: u128,
+    pub data_val: T,
+}
+
 pub struct DataStore<T: ScubaData> {
     store: HashMap<String, T>,
     validator: Validator<T>,
+    // number of versions to keep per object; None if history is disabled
+    max_versions: Option<usize>,
+    history: HashMap<String, VecDeque<DataVersion<T>>>,
+    // data_type -> (raw schema, compiled schema)
+    schemas: HashMap<String, (Value, JSONSchema)>,
+    // (expiry epoch, data_id) of every object that expires, soonest first
+    expiries: BTreeSet<(u64, String)>,
 }
 
 //fn get_all_data_with_type
@@ -123,6 +336,10 @@ impl<T: ScubaData> DataStore<T> {
         Self {
             store: HashMap::<String, T>::new(),
             validator: Validator::<T>::new(None),
+            max_versions: None,
+            history: HashMap::new(),
+            schemas: HashMap::new(),
+            expiries: BTreeSet::new(),
         }
     }
 
@@ -130,29 +347,171 @@ impl<T: ScubaData> DataStore<T> {
         &mut self.validator
     }
 
+    // Requires every value of data_type to be JSON that conforms to schema
+    // (a JSON Schema document); returns the previously registered schema
+    pub fn set_schema_for_type(
+        &mut self,
+        data_type: String,
+        schema: Value,
+    ) -> Result<Option<Value>, ValidationError> {
+        let compiled = JSONSchema::compile(&schema).map_err(|err| {
+            ValidationError::InvalidSchema(data_type.clone(), err.to_string())
+        })?;
+        Ok(self
+            .schemas
+            .insert(data_type, (schema, compiled))
+            .map(|(schema, _)| schema))
+    }
+
+    pub fn remove_schema_for_type(&mut self, data_type: &String) -> Option<Value> {
+        self.schemas.remove(data_type).map(|(schema, _)| schema)
+    }
+
+    pub fn get_schema_for_type(&self, data_type: &String) -> Option<&Value> {
+        self.schemas.get(data_type).map(|(schema, _)| schema)
+    }
+
+    fn check_schema(&self, data_val: &T) -> Result<(), ValidationError> {
+        let schema = match self.schemas.get(data_val.data_type()) {
+            Some((_, schema)) => schema,
+            None => return Ok(()),
+        };
+        let violation = |msg: String| {
+            ValidationError::SchemaViolation(data_val.data_type().to_string(), msg)
+        };
+        let instance = serde_json::from_str::<Value>(data_val.data_val())
+            .map_err(|err| violation(err.to_string()))?;
+        // bound so that the errors (which borrow instance) are dropped first
+        let res = schema.validate(&instance).map_err(|errs| {
+            violation(itertools::join(errs.map(|err| err.to_string()), "; "))
+        });
+        res
+    }
+
     pub fn get_data(&self, data_id: &String) -> Option<&T> {
         self.store.get(data_id)
     }
 
     pub fn set_data(&mut self, data_id: String, data_val: T) -> Option<T> {
-        self.store.insert(data_id, data_val)
+        if let Some(expiry) = data_val.expiry() {
+            self.expiries.insert((expiry.epoch(), data_id.clone()));
+        }
+        let old_val = self.store.insert(data_id.clone(), data_val);
+        self.unindex_expiry(&data_id, old_val.as_ref());
+        old_val
     }
 
     pub fn delete_data(&mut self, data_id: &String) -> Option<T> {
-        self.store.remove(data_id)
+        let old_val = self.store.remove(data_id);
+        self.unindex_expiry(data_id, old_val.as_ref());
+        old_val
+    }
+
+    // drops old_val's expiry from the index unless the current value of
+    // data_id has the same one
+    fn unindex_expiry(&mut self, data_id: &String, old_val: Option<&T>) {
+        let old_expiry = match old_val.and_then(|old_val| old_val.expiry()) {
+            Some(expiry) => expiry,
+            None => return,
+        };
+        let cur_expiry = self
+            .store
+            .get(data_id)
+            .and_then(|data_val| data_val.expiry());
+        if cur_expiry != Some(old_expiry) {
+            self.expiries
+                .remove(&(old_expiry.epoch(), data_id.to_string()));
+        }
     }
 
     pub fn get_all_data(&self) -> &HashMap<String, T> {
         &self.store
     }
 
-    pub fn validate(&self, data_id: &String, data_val: &T) -> bool {
-        self.validator.validate(data_id, data_val)
+    // deletes every object whose expiry has passed; returns them
+    pub fn purge_expired(&mut self, epoch: u64) -> Vec<T> {
+        let mut expired = Vec::new();
+        while let Some((expiry_epoch, data_id)) = self.expiries.first().cloned() {
+            if expiry_epoch > epoch {
+                break;
+            }
+            self.expiries.pop_first();
+            if let Some(data_val) = self.store.remove(&data_id) {
+                expired.push(data_val);
+            }
+        }
+        expired
+    }
+
+    // validates an update from sender against the currently stored value
+    pub fn validate(
+        &self,
+        sender: &String,
+        data_id: &String,
+        data_val: &T,
+    ) -> Result<(), ValidationError> {
+        self.check_schema(data_val)?;
+        self.validator
+            .validate(sender, data_id, self.store.get(data_id), data_val)
+    }
+
+    // Keeps the last max_versions versions of each object written via
+    // set_data_from()
+    pub fn enable_history(&mut self, max_versions: usize) {
+        self.max_versions = Some(max_versions);
+        for versions in self.history.values_mut() {
+            while versions.len() > max_versions {
+                versions.pop_front();
+            }
+        }
+    }
+
+    pub fn disable_history(&mut self) {
+        self.max_versions = None;
+        self.history.clear();
+    }
+
+    // returns the recorded versions of data_id, oldest first
+    pub fn get_history(&self, data_id: &String) -> Vec<&DataVersion<T>> {
+        self.history
+            .get(data_id)
+            .map_or(Vec::new(), |versions| versions.iter().collect())
+    }
+}
+
+impl<T: ScubaData + Clone> DataStore<T> {
+    // set_data() that also records the new version if history is enabled
+    pub fn set_data_from(
+        &mut self,
+        data_id: String,
+        data_val: T,
+        sender: String,
+        seq: u128,
+    ) -> Option<T> {
+        if let Some(max_versions) = self.max_versions {
+            let versions = self
+                .history
+                .entry(data_id.clone())
+                .or_insert_with(VecDeque::new);
+            versions.push_back(DataVersion {
+                sender,
+                seq,
+                data_val: data_val.clone(),
+            });
+            while versions.len() > max_versions {
+                versions.pop_front();
+            }
+        }
+        self.set_data(data_id, data_val)
     }
 }
 
+#[cfg(test)]
 mod tests {
-    use crate::data::{BasicData, DataStore};
+    use crate::data::{
+        BasicData, BinaryVal, DataStore, DataVersion, Expiry, ScubaData, ValidationError,
+    };
+    use serde_json::json;
     use std::collections::HashMap;
 
     #[test]
@@ -187,5 +546,341 @@ mod tests {
         assert_eq!(data_store.get_data(&data.data_id), None);
     }
 
-    // TODO test validation
+    #[test]
+    fn test_validate_data_id() {
+        let data_store = DataStore::new();
+        let data = BasicData::new(
+            String::from("0"),
+            String::from("type"),
+            String::from("val"),
+            String::from("group"),
+        );
+        let sender = String::from("sender");
+        assert_eq!(
+            data_store.validate(&sender, &String::from("0"), &data),
+            Ok(())
+        );
+        assert_eq!(
+            data_store.validate(&sender, &String::from("1"), &data),
+            Err(ValidationError::DataIdMismatch(
+                String::from("1"),
+                String::from("0")
+            ))
+        );
+        assert_eq!(
+            data_store.validate(&sender, &String::from(""), &data),
+            Err(ValidationError::EmptyDataId)
+        );
+    }
+
+    #[test]
+    fn test_validate_callback_for_type() {
+        let mut data_store = DataStore::new();
+        let allowed_sender = String::from("allowed");
+        // callbacks can capture state
+        let max_len = 3;
+        data_store.validator().set_validate_callback_for_type(
+            String::from("type"),
+            move |sender, old_val: Option<&BasicData>, new_val| {
+                if *sender != allowed_sender {
+                    return Err(ValidationError::Invalid(
+                        String::from("type"),
+                        String::from("bad sender"),
+                    ));
+                }
+                if new_val.data_val().len() > max_len {
+                    return Err(ValidationError::Invalid(
+                        String::from("type"),
+                        String::from("too long"),
+                    ));
+                }
+                // values can only grow
+                match old_val {
+                    Some(old_val) if old_val.data_val() > new_val.data_val() => {
+                        Err(ValidationError::Invalid(
+                            String::from("type"),
+                            String::from("decreasing"),
+                        ))
+                    }
+                    _ => Ok(()),
+                }
+            },
+        );
+
+        let data_id = String::from("0");
+        let new_data = |val: &str| {
+            BasicData::new(
+                data_id.clone(),
+                String::from("type"),
+                String::from(val),
+                String::from("group"),
+            )
+        };
+        let allowed = String::from("allowed");
+        assert_eq!(
+            data_store.validate(&allowed, &data_id, &new_data("b")),
+            Ok(())
+        );
+        assert_eq!(
+            data_store.validate(&String::from("other"), &data_id, &new_data("b")),
+            Err(ValidationError::Invalid(
+                String::from("type"),
+                String::from("bad sender")
+            ))
+        );
+        assert_eq!(
+            data_store.validate(&allowed, &data_id, &new_data("bbbb")),
+            Err(ValidationError::Invalid(
+                String::from("type"),
+                String::from("too long")
+            ))
+        );
+
+        data_store.set_data(data_id.clone(), new_data("b"));
+        assert_eq!(
+            data_store.validate(&allowed, &data_id, &new_data("a")),
+            Err(ValidationError::Invalid(
+                String::from("type"),
+                String::from("decreasing")
+            ))
+        );
+
+        // other types are unaffected
+        let other_type = BasicData::new(
+            data_id.clone(),
+            String::from("other_type"),
+            String::from("aaaa"),
+            String::from("group"),
+        );
+        assert_eq!(
+            data_store.validate(&String::from("other"), &data_id, &other_type),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_validate_schema() {
+        let mut data_store = DataStore::new();
+        data_store
+            .set_schema_for_type(
+                String::from("post"),
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "author": { "type": "string" },
+                        "contents": { "type": "string", "maxLength": 5 }
+                    },
+                    "required": ["author", "contents"]
+                }),
+            )
+            .unwrap();
+
+        let sender = String::from("sender");
+        let data_id = String::from("0");
+        let post = |val: &str| {
+            BasicData::new(
+                data_id.clone(),
+                String::from("post"),
+                String::from(val),
+                String::from("group"),
+            )
+        };
+        assert_eq!(
+            data_store.validate(
+                &sender,
+                &data_id,
+                &post(r#"{"author": "a", "contents": "hi"}"#)
+            ),
+            Ok(())
+        );
+        for bad_val in [
+            r#"{"author": "a", "contents": "too long"}"#,
+            r#"{"author": "a"}"#,
+            r#"["a", "hi"]"#,
+            "not json",
+        ] {
+            match data_store.validate(&sender, &data_id, &post(bad_val)) {
+                Err(ValidationError::SchemaViolation(data_type, _)) => {
+                    assert_eq!(data_type, String::from("post"))
+                }
+                res => panic!("unexpected result for {}: {:?}", bad_val, res),
+            }
+        }
+
+        // other types are unaffected
+        let other_type = BasicData::new(
+            data_id.clone(),
+            String::from("other_type"),
+            String::from("not json"),
+            String::from("group"),
+        );
+        assert_eq!(data_store.validate(&sender, &data_id, &other_type), Ok(()));
+
+        data_store.remove_schema_for_type(&String::from("post"));
+        assert_eq!(
+            data_store.validate(&sender, &data_id, &post("not json")),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_invalid_schema() {
+        let mut data_store = DataStore::<BasicData>::new();
+        match data_store.set_schema_for_type(String::from("post"), json!({ "type": 5 })) {
+            Err(ValidationError::InvalidSchema(data_type, _)) => {
+                assert_eq!(data_type, String::from("post"))
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(data_store.get_schema_for_type(&String::from("post")), None);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut data_store = DataStore::new();
+        let data_id = String::from("0");
+        let data = |val: &str| {
+            BasicData::new(
+                data_id.clone(),
+                String::from("type"),
+                String::from(val),
+                String::from("group"),
+            )
+        };
+
+        // nothing is recorded until enabled
+        data_store.set_data_from(data_id.clone(), data("a"), String::from("s0"), 0);
+        assert!(data_store.get_history(&data_id).is_empty());
+
+        data_store.enable_history(2);
+        for (seq, val) in ["b", "c", "d"].iter().enumerate() {
+            data_store.set_data_from(
+                data_id.clone(),
+                data(val),
+                format!("s{}", seq + 1),
+                (seq + 1) as u128,
+            );
+        }
+        assert_eq!(*data_store.get_data(&data_id).unwrap(), data("d"));
+        assert_eq!(
+            data_store.get_history(&data_id),
+            vec![
+                &DataVersion {
+                    sender: String::from("s2"),
+                    seq: 2,
+                    data_val: data("c"),
+                },
+                &DataVersion {
+                    sender: String::from("s3"),
+                    seq: 3,
+                    data_val: data("d"),
+                },
+            ]
+        );
+
+        // shrinking the limit trims existing history
+        data_store.enable_history(1);
+        assert_eq!(data_store.get_history(&data_id).len(), 1);
+        assert_eq!(data_store.get_history(&data_id)[0].data_val, data("d"));
+
+        data_store.disable_history();
+        assert!(data_store.get_history(&data_id).is_empty());
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let mut data_store = DataStore::new();
+        let new_data = |data_id: &str, expiry| {
+            BasicData::new(
+                data_id.to_string(),
+                "type".to_string(),
+                "val".to_string(),
+                "perm".to_string(),
+            )
+            .with_expiry(expiry)
+        };
+        data_store.set_data("forever".to_string(), new_data("forever", None));
+        data_store.set_data(
+            "epoch".to_string(),
+            new_data("epoch", Some(Expiry::Epoch(5))),
+        );
+        data_store.set_data(
+            "later".to_string(),
+            new_data("later", Some(Expiry::Epoch(7))),
+        );
+        // replacing a value replaces its expiry
+        data_store.set_data(
+            "extended".to_string(),
+            new_data("extended", Some(Expiry::Epoch(5))),
+        );
+        data_store.set_data(
+            "extended".to_string(),
+            new_data("extended", Some(Expiry::Epoch(9))),
+        );
+        data_store.set_data(
+            "deleted".to_string(),
+            new_data("deleted", Some(Expiry::Epoch(5))),
+        );
+        data_store.delete_data(&"deleted".to_string());
+
+        assert!(data_store.purge_expired(4).is_empty());
+        assert_eq!(
+            data_store.purge_expired(5),
+            vec![new_data("epoch", Some(Expiry::Epoch(5)))]
+        );
+        assert_eq!(
+            data_store.purge_expired(8),
+            vec![new_data("later", Some(Expiry::Epoch(7)))]
+        );
+        let mut remaining = data_store.get_all_data().keys().collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec![&"extended".to_string(), &"forever".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_expiry_defaults_to_none() {
+        // data serialized before expiries existed still deserializes
+        let data_val: BasicData = serde_json::from_str(
+            r#"{"data_id":"id","data_type":"type","data_val":"val","perm_id":"perm"}"#,
+        )
+        .unwrap();
+        assert_eq!(data_val.expiry(), None);
+    }
+
+    #[test]
+    fn test_binary_val() {
+        let text = "abc".repeat(100).into_bytes();
+        let deflated = BinaryVal::new(text.clone(), true);
+        assert!(
+            matches!(deflated, BinaryVal::Deflated(ref bytes) if bytes.len() < text.len())
+        );
+        assert_eq!(deflated.to_bytes().unwrap(), text);
+
+        // compressing would only make this bigger
+        let short = vec![0u8, 1, 2];
+        assert_eq!(
+            BinaryVal::new(short.clone(), true),
+            BinaryVal::Raw(short.clone())
+        );
+        assert_eq!(
+            BinaryVal::new(short.clone(), false).to_bytes().unwrap(),
+            short
+        );
+
+        assert!(BinaryVal::Deflated(vec![0xff; 4]).to_bytes().is_err());
+
+        // inflating stops at the limit
+        assert_eq!(deflated.to_bytes_within(text.len() as u64).unwrap(), text);
+        assert!(deflated.to_bytes_within(text.len() as u64 - 1).is_err());
+
+        // and data from before binary values has none
+        let data_val: BasicData = serde_json::from_str(
+            r#"{"data_id":"id","data_type":"type","data_val":"val","perm_id":"perm"}"#,
+        )
+        .unwrap();
+        assert_eq!(data_val.data_bytes(), None);
+    }
 }
@@ -1,4 +1,5 @@
 use parking_lot::RwLock;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::Arc;
@@ -11,6 +12,30 @@ use crate::metadata::{Group, MetadataStore, PermType, PermissionSet};
 pub enum Error {
     #[error("Attempted to delete group instead of device.")]
     DeviceHasChildren,
+    #[error("{0} is not a contact.")]
+    NotAContact(String),
+}
+
+// A contact request from a user who had no invite, waiting for this user to
+// accept it
+#[derive(Debug, PartialEq, Clone)]
+pub struct PendingContact {
+    pub idkey: String,
+    pub contact_name: String,
+    pub contact_devices: HashMap<String, Group>,
+}
+
+// Permissions and data another user is sharing with this user, held back
+// until this user accepts them
+#[derive(Debug, PartialEq, Clone)]
+pub struct PendingShare {
+    // the first device that sent anything for this share
+    pub sender: String,
+    pub perm_id: String,
+    // data objects the share would add
+    pub data_ids: BTreeSet<String>,
+    // (sequence number, sender, serialized operation) in arrival order
+    pub ops: Vec<(u128, String, String)>,
 }
 
 #[derive(Clone)]
@@ -20,6 +45,13 @@ pub struct Device<T: ScubaData> {
     pub data_store: Arc<RwLock<DataStore<T>>>,
     pub linked_name: Arc<RwLock<String>>,
     pending_link_idkey: Arc<RwLock<Option<String>>>,
+    // by contact_name
+    pending_contacts: Arc<RwLock<HashMap<String, PendingContact>>>,
+    // by perm_id
+    pending_shares: Arc<RwLock<HashMap<String, PendingShare>>>,
+    // perm_ids of shares that have been accepted or declined
+    accepted_shares: Arc<RwLock<HashSet<String>>>,
+    declined_shares: Arc<RwLock<HashSet<String>>>,
 }
 
 // TODO linked_name => root or smthg
@@ -75,7 +107,52 @@ impl<T: ScubaData> Device<T> {
             data_store: Arc::new(RwLock::new(DataStore::new())),
             linked_name: Arc::new(RwLock::new(linked_name)),
             pending_link_idkey: Arc::new(RwLock::new(pending_link_idkey)),
+            pending_contacts: Arc::new(RwLock::new(HashMap::new())),
+            pending_shares: Arc::new(RwLock::new(HashMap::new())),
+            accepted_shares: Arc::new(RwLock::new(HashSet::new())),
+            declined_shares: Arc::new(RwLock::new(HashSet::new())),
+        }
+    }
+
+    // A device for another app namespace on this same device: it has the
+    // same idkey and linked devices, but its own (empty) data and no contacts
+    pub fn new_namespace(&self) -> Device<T> {
+        let linked_name = self.linked_name.read().clone();
+        let mut meta_store = MetadataStore::new();
+        copy_groups(&self.meta_store.read(), &mut meta_store, &linked_name, true);
+
+        Self {
+            idkey: Arc::new(RwLock::new(self.idkey.read().clone())),
+            meta_store: Arc::new(RwLock::new(meta_store)),
+            data_store: Arc::new(RwLock::new(DataStore::new())),
+            linked_name: Arc::new(RwLock::new(linked_name)),
+            pending_link_idkey: Arc::new(RwLock::new(None)),
+            pending_contacts: Arc::new(RwLock::new(HashMap::new())),
+            pending_shares: Arc::new(RwLock::new(HashMap::new())),
+            accepted_shares: Arc::new(RwLock::new(HashSet::new())),
+            declined_shares: Arc::new(RwLock::new(HashSet::new())),
+        }
+    }
+
+    // Adds a contact of other (e.g. the device of another namespace) as a
+    // contact of this device
+    pub fn copy_contact_from(
+        &self,
+        other: &Device<T>,
+        contact_name: &String,
+    ) -> Result<(), Error> {
+        let other_meta_store = other.meta_store.read();
+        match other_meta_store.get_group(contact_name) {
+            Some(group) if group.is_contact_name => {}
+            _ => return Err(Error::NotAContact(contact_name.to_string())),
         }
+        copy_groups(
+            &other_meta_store,
+            &mut self.meta_store.write(),
+            contact_name,
+            false,
+        );
+        Ok(())
     }
 
     pub fn linked_devices_excluding_self(&self) -> Vec<String> {
@@ -201,7 +278,20 @@ impl<T: ScubaData> Device<T> {
         contacts
     }
 
-    // TODO get_pending_contacts
+    pub fn get_pending_contacts(&self) -> Vec<PendingContact> {
+        self.pending_contacts.read().values().cloned().collect()
+    }
+
+    // a newer request from the same contact replaces an older one
+    pub fn add_pending_contact(&self, pending_contact: PendingContact) {
+        self.pending_contacts
+            .write()
+            .insert(pending_contact.contact_name.clone(), pending_contact);
+    }
+
+    pub fn take_pending_contact(&self, contact_name: &String) -> Option<PendingContact> {
+        self.pending_contacts.write().remove(contact_name)
+    }
 
     pub fn add_contact(
         &self,
@@ -225,8 +315,6 @@ impl<T: ScubaData> Device<T> {
         Ok(())
     }
 
-    // TODO remove_contact
-
     // FIXME Currently, this function is unnecessary since none
     // of this data is persistent and will be automatically
     // GC'd when the `device` field of the glue object is
@@ -246,18 +334,148 @@ impl<T: ScubaData> Device<T> {
         // remove child link to this device from
         // every parent (should have no children)
         for parent in device_group.parents().iter() {
-            self.meta_store.write().remove_child(parent, &to_delete);
+            let _ = self.meta_store.write().remove_child(parent, &to_delete);
         }
 
         self.meta_store.write().delete_group(&to_delete);
 
         Ok(())
     }
+
+    // name of the contact that the device idkey belongs to, if any
+    pub fn get_contact_name(&self, idkey: &String) -> Option<String> {
+        let meta_store = self.meta_store.read();
+        meta_store
+            .get_all_groups()
+            .iter()
+            .find(|(id, val)| {
+                val.is_contact_name && meta_store.is_group_member(idkey, id, &None)
+            })
+            .map(|(id, _)| id.to_string())
+    }
+
+    // Deletes the contact's groups, which also unlinks them from every group
+    // that a permission set grants access to. Returns the contact's device
+    // idkeys.
+    pub fn remove_contact(
+        &self,
+        contact_name: &String,
+    ) -> Result<HashSet<String>, Error> {
+        let mut meta_store = self.meta_store.write();
+        match meta_store.get_group(contact_name) {
+            Some(group) if group.is_contact_name => {}
+            _ => return Err(Error::NotAContact(contact_name.to_string())),
+        }
+
+        let contact_devices = meta_store.resolve_group_ids(vec![contact_name]);
+        let contact_groups: Vec<String> = meta_store
+            .get_all_subgroups(contact_name)
+            .into_keys()
+            .collect();
+        for group_id in contact_groups.iter() {
+            meta_store.delete_group(group_id);
+        }
+
+        Ok(contact_devices)
+    }
+
+    /*
+     * Incoming shares
+     */
+
+    pub fn get_pending_shares(&self) -> Vec<PendingShare> {
+        self.pending_shares.read().values().cloned().collect()
+    }
+
+    pub fn is_share_pending(&self, perm_id: &String) -> bool {
+        self.pending_shares.read().contains_key(perm_id)
+    }
+
+    pub fn is_share_accepted(&self, perm_id: &String) -> bool {
+        self.accepted_shares.read().contains(perm_id)
+    }
+
+    pub fn is_share_declined(&self, perm_id: &String) -> bool {
+        self.declined_shares.read().contains(perm_id)
+    }
+
+    // Adds an operation to the pending share for perm_id, creating it if
+    // needed
+    pub fn hold_share_op(
+        &self,
+        perm_id: String,
+        sender: String,
+        data_id: Option<String>,
+        seq: u128,
+        operation: String,
+    ) {
+        let mut pending_shares = self.pending_shares.write();
+        let share =
+            pending_shares
+                .entry(perm_id.clone())
+                .or_insert_with(|| PendingShare {
+                    sender: sender.clone(),
+                    perm_id,
+                    data_ids: BTreeSet::new(),
+                    ops: Vec::new(),
+                });
+        if let Some(data_id) = data_id {
+            share.data_ids.insert(data_id);
+        }
+        share.ops.push((seq, sender, operation));
+    }
+
+    // Marks the share as accepted and returns whatever was held back for it,
+    // to be applied by the caller
+    pub fn accept_share(&self, perm_id: &String) -> Option<PendingShare> {
+        self.accepted_shares.write().insert(perm_id.to_string());
+        self.pending_shares.write().remove(perm_id)
+    }
+
+    // Marks the share as declined, so that further operations for it are
+    // dropped, and returns whatever was held back for it
+    pub fn decline_share(&self, perm_id: &String) -> Option<PendingShare> {
+        self.declined_shares.write().insert(perm_id.to_string());
+        self.pending_shares.write().remove(perm_id)
+    }
+}
+
+// Copies group_id and its subgroups (optionally with the perms they point
+// to) from one store to another, dropping parents that are not copied along,
+// since those belong to data of the source store
+fn copy_groups(
+    from: &MetadataStore,
+    to: &mut MetadataStore,
+    group_id: &String,
+    with_perms: bool,
+) {
+    let subgroups = from.get_all_subgroups(group_id);
+    for (id, mut group_val) in subgroups.clone().into_iter() {
+        let outside_parents: Vec<String> = group_val
+            .parents()
+            .iter()
+            .filter(|parent_id| !subgroups.contains_key(*parent_id))
+            .cloned()
+            .collect();
+        for parent_id in outside_parents.iter() {
+            group_val.remove_parent(parent_id);
+        }
+        if with_perms {
+            for perm_id in group_val.perm_ids() {
+                if let Some(perm_val) = from.get_perm(perm_id) {
+                    to.set_perm(perm_id.to_string(), perm_val.clone());
+                }
+            }
+        }
+        to.set_group(id, group_val);
+    }
 }
 
+#[cfg(test)]
 mod tests {
     use crate::data::BasicData;
-    use crate::devices::Device;
+    use crate::devices::{Device, Error, PendingContact};
+    use crate::metadata::{PermType, PermissionSet};
     use std::collections::HashSet;
 
     #[test]
@@ -561,4 +779,191 @@ mod tests {
 
         assert_eq!(None, linked_members.get(&idkey_1));
     }
+
+    #[test]
+    fn test_remove_contact() {
+        let idkey_0 = String::from("0");
+        let device_0 = Device::<BasicData>::new(idkey_0.clone(), None, None);
+
+        let idkey_1 = String::from("1");
+        let device_1 = Device::<BasicData>::new(idkey_1.clone(), None, None);
+        let linked_name_1 = device_1.linked_name.read().clone();
+        let linked_members_1 =
+            device_1.meta_store.read().get_all_subgroups(&linked_name_1);
+
+        // simulate send and receive of AddContact message
+        device_0
+            .add_contact(linked_name_1.clone(), linked_members_1)
+            .unwrap();
+        assert_eq!(
+            device_0.get_contact_name(&idkey_1),
+            Some(linked_name_1.clone())
+        );
+        assert_eq!(device_0.get_contact_name(&idkey_0), None);
+
+        // share something with the contact
+        let perm_set = PermissionSet::new(None, None, None, None, None);
+        let perm_id = perm_set.perm_id().to_string();
+        device_0
+            .meta_store
+            .write()
+            .set_perm(perm_id.clone(), perm_set);
+        device_0
+            .meta_store
+            .write()
+            .add_permissions(
+                &perm_id,
+                None,
+                PermType::Readers(vec![linked_name_1.clone()]),
+            )
+            .unwrap();
+        let readers = device_0
+            .meta_store
+            .read()
+            .get_perm(&perm_id)
+            .unwrap()
+            .readers()
+            .clone()
+            .unwrap();
+        assert!(device_0
+            .meta_store
+            .read()
+            .is_group_member(&idkey_1, &readers, &None));
+
+        assert_eq!(
+            device_0.remove_contact(&linked_name_1),
+            Ok(HashSet::from([idkey_1.clone()]))
+        );
+        let meta_store = device_0.meta_store.read();
+        assert!(!meta_store.is_group_member(&idkey_1, &readers, &None));
+        assert_eq!(meta_store.get_group(&linked_name_1), None);
+        assert_eq!(meta_store.get_group(&idkey_1), None);
+        assert_eq!(
+            meta_store.get_group(&readers).unwrap().children(),
+            &Some(HashSet::new())
+        );
+        drop(meta_store);
+
+        assert_eq!(
+            device_0.remove_contact(&linked_name_1),
+            Err(Error::NotAContact(linked_name_1.clone()))
+        );
+    }
+
+    #[test]
+    fn test_pending_shares_and_contacts() {
+        let device = Device::<BasicData>::new(String::from("0"), None, None);
+        let perm_id = String::from("perm");
+        let sender = String::from("1");
+        device.hold_share_op(
+            perm_id.clone(),
+            sender.clone(),
+            None,
+            1,
+            String::from("op1"),
+        );
+        device.hold_share_op(
+            perm_id.clone(),
+            sender.clone(),
+            Some(String::from("data")),
+            2,
+            String::from("op2"),
+        );
+        assert!(device.is_share_pending(&perm_id));
+        let pending = device.get_pending_shares();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].data_ids.len(), 1);
+
+        let share = device.accept_share(&perm_id).unwrap();
+        assert_eq!(
+            share.ops,
+            vec![
+                (1, sender.clone(), String::from("op1")),
+                (2, sender.clone(), String::from("op2"))
+            ]
+        );
+        assert!(!device.is_share_pending(&perm_id));
+        assert!(device.is_share_accepted(&perm_id));
+
+        let other_perm_id = String::from("other_perm");
+        device.hold_share_op(
+            other_perm_id.clone(),
+            sender.clone(),
+            None,
+            3,
+            String::from("op3"),
+        );
+        assert!(device.decline_share(&other_perm_id).is_some());
+        assert!(device.is_share_declined(&other_perm_id));
+        assert!(device.get_pending_shares().is_empty());
+
+        let contact = PendingContact {
+            idkey: sender.clone(),
+            contact_name: String::from("contact"),
+            contact_devices: std::collections::HashMap::new(),
+        };
+        device.add_pending_contact(contact.clone());
+        assert_eq!(device.get_pending_contacts(), vec![contact.clone()]);
+        assert_eq!(
+            device.take_pending_contact(&contact.contact_name),
+            Some(contact)
+        );
+        assert!(device.get_pending_contacts().is_empty());
+    }
+
+    #[test]
+    fn test_namespace() {
+        let idkey_0 = String::from("0");
+        let device_0 = Device::<BasicData>::new(idkey_0.clone(), None, None);
+        let linked_name_0 = device_0.linked_name.read().clone();
+
+        let idkey_1 = String::from("1");
+        let device_1 = Device::<BasicData>::new(idkey_1.clone(), None, None);
+        let linked_name_1 = device_1.linked_name.read().clone();
+        let linked_members_1 =
+            device_1.meta_store.read().get_all_subgroups(&linked_name_1);
+        device_0
+            .add_contact(linked_name_1.clone(), linked_members_1)
+            .unwrap();
+
+        // data shared with the contact in the original namespace
+        let perm_set = PermissionSet::new(None, None, None, None, None);
+        let perm_id = perm_set.perm_id().to_string();
+        device_0
+            .meta_store
+            .write()
+            .set_perm(perm_id.clone(), perm_set);
+        device_0
+            .meta_store
+            .write()
+            .add_permissions(
+                &perm_id,
+                None,
+                PermType::Readers(vec![linked_name_1.clone()]),
+            )
+            .unwrap();
+
+        // same identity and linked devices, but no contacts or perms
+        let namespace = device_0.new_namespace();
+        assert_eq!(*namespace.linked_name.read(), linked_name_0);
+        assert_eq!(namespace.linked_devices(), HashSet::from([idkey_0.clone()]));
+        assert!(namespace.get_contacts().is_empty());
+        assert!(namespace.meta_store.read().get_perm(&perm_id).is_none());
+
+        assert_eq!(
+            namespace.copy_contact_from(&device_0, &idkey_0),
+            Err(Error::NotAContact(idkey_0.clone()))
+        );
+        namespace
+            .copy_contact_from(&device_0, &linked_name_1)
+            .unwrap();
+        assert_eq!(
+            namespace.get_contact_name(&idkey_1),
+            Some(linked_name_1.clone())
+        );
+        // without the other namespace's reader group as a parent
+        let meta_store = namespace.meta_store.read();
+        let contact_group = meta_store.get_group(&linked_name_1).unwrap();
+        assert!(contact_group.parents().is_empty());
+    }
 }
@@ -0,0 +1,338 @@
+use crate::data::{BasicData, ScubaData};
+use crate::devices::Device;
+use crate::metadata::{Group, PermissionSet};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use thiserror::Error;
+
+/*
+ * A device's full Tank state as a versioned JSON document, for backups and
+ * for moving data to another device. The version field is checked before
+ * anything else is parsed so that documents from newer versions of Tank are
+ * rejected instead of being misread.
+ */
+
+pub const STATE_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Error)]
+pub enum Error {
+    #[error("State export version {0} is not supported")]
+    UnsupportedVersion(u64),
+    #[error("Cannot convert state export to or from string: {0}")]
+    Serialization(String),
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct StateExport {
+    pub version: u32,
+    // identity of the exporting device
+    pub idkey: String,
+    pub linked_name: String,
+    pub linked_devices: BTreeSet<String>,
+    pub contacts: BTreeSet<String>,
+    pub groups: BTreeMap<String, Group>,
+    pub perms: BTreeMap<String, PermissionSet>,
+    pub data: BTreeMap<String, BasicData>,
+}
+
+impl StateExport {
+    pub fn from_device(idkey: String, device: &Device<BasicData>) -> StateExport {
+        let meta_store = device.meta_store.read();
+        let data_store = device.data_store.read();
+        StateExport {
+            version: STATE_VERSION,
+            idkey,
+            linked_name: device.linked_name.read().clone(),
+            linked_devices: device.linked_devices().into_iter().collect(),
+            contacts: device.get_contacts().into_iter().collect(),
+            groups: meta_store
+                .get_all_groups()
+                .iter()
+                .map(|(id, val)| (id.clone(), val.clone()))
+                .collect(),
+            perms: meta_store
+                .get_all_perms()
+                .iter()
+                .map(|(id, val)| (id.clone(), val.clone()))
+                .collect(),
+            data: data_store
+                .get_all_data()
+                .iter()
+                .map(|(id, val)| (id.clone(), val.clone()))
+                .collect(),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, Error> {
+        serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Serialization(e.to_string()))
+    }
+
+    pub fn from_json(json: &str) -> Result<StateExport, Error> {
+        let value: Value = serde_json::from_str(json)
+            .map_err(|e| Error::Serialization(e.to_string()))?;
+        match value.get("version").and_then(|version| version.as_u64()) {
+            Some(version) if version == STATE_VERSION as u64 => {}
+            Some(version) => return Err(Error::UnsupportedVersion(version)),
+            None => return Err(Error::Serialization("missing version".to_string())),
+        }
+        serde_json::from_value(value).map_err(|e| Error::Serialization(e.to_string()))
+    }
+
+    // Moves the exported state onto another linked group: the exported
+    // linked group and its devices are dropped, and everything that
+    // referenced the exported linked group (e.g. owner groups) references
+    // linked_name instead, so the importing devices take over ownership.
+    pub fn rebase(&mut self, linked_name: &String) {
+        if *linked_name == self.linked_name {
+            return;
+        }
+        let old_linked_name = self.linked_name.clone();
+        for device_id in self.linked_devices.iter() {
+            self.groups.remove(device_id);
+        }
+        self.groups.remove(&old_linked_name);
+        for group in self.groups.values_mut() {
+            if group.remove_parent(&old_linked_name) {
+                group.add_parent(linked_name.to_string());
+            }
+            if let Ok(true) = group.remove_child(&old_linked_name) {
+                let _ = group.add_child(linked_name.to_string());
+            }
+        }
+        self.linked_name = linked_name.to_string();
+        self.linked_devices = BTreeSet::new();
+    }
+
+    // Drops groups, permission sets and data that already exist on the
+    // importing device (as listed in the given ids): an import only adds
+    // state, and must not replace existing objects without the permission
+    // checks an update to them would go through. Returns (id, reason) for
+    // each permission set and data object dropped.
+    pub fn skip_existing(
+        &mut self,
+        group_ids: &HashSet<String>,
+        perm_ids: &HashSet<String>,
+        data_ids: &HashSet<String>,
+    ) -> Vec<(String, String)> {
+        let mut skipped = Vec::new();
+        self.groups
+            .retain(|group_id, _| !group_ids.contains(group_id));
+        self.perms.retain(|perm_id, _| {
+            let exists = perm_ids.contains(perm_id);
+            if exists {
+                skipped.push((perm_id.to_string(), "already exists".to_string()));
+            }
+            !exists
+        });
+        self.data.retain(|data_id, _| {
+            let exists = data_ids.contains(data_id);
+            if exists {
+                skipped.push((data_id.to_string(), "already exists".to_string()));
+            }
+            !exists
+        });
+        skipped
+    }
+
+    // Drops whatever does not hold together: references to groups that
+    // neither the export nor known_groups contain, permission sets that
+    // grant access to such groups, and data whose permission set is missing
+    // or whose id does not match its key. Returns (id, reason) for each
+    // permission set and data object dropped.
+    pub fn validate(&mut self, known_groups: &HashSet<String>) -> Vec<(String, String)> {
+        let mut skipped = Vec::new();
+        let group_ids: HashSet<String> = self
+            .groups
+            .keys()
+            .cloned()
+            .chain(known_groups.iter().cloned())
+            .collect();
+
+        for group in self.groups.values_mut() {
+            let dangling_parents: Vec<String> = group
+                .parents()
+                .iter()
+                .filter(|id| !group_ids.contains(*id))
+                .cloned()
+                .collect();
+            for parent_id in dangling_parents.iter() {
+                group.remove_parent(parent_id);
+            }
+            let dangling_children: Vec<String> =
+                group.children().as_ref().map_or(Vec::new(), |children| {
+                    children
+                        .iter()
+                        .filter(|id| !group_ids.contains(*id))
+                        .cloned()
+                        .collect()
+                });
+            for child_id in dangling_children.iter() {
+                let _ = group.remove_child(child_id);
+            }
+        }
+
+        self.perms.retain(|perm_id, perm_val| {
+            let role_groups = [
+                perm_val.owners(),
+                perm_val.writers(),
+                perm_val.readers(),
+                perm_val.do_readers(),
+            ];
+            match role_groups
+                .into_iter()
+                .flatten()
+                .find(|id| !group_ids.contains(*id))
+            {
+                Some(missing_id) => {
+                    skipped.push((
+                        perm_id.to_string(),
+                        format!("references missing group {}", missing_id),
+                    ));
+                    false
+                }
+                None => true,
+            }
+        });
+
+        let perms = &self.perms;
+        self.data.retain(|data_id, data_val| {
+            let reason = if data_id != data_val.data_id() {
+                Some(format!("stored under {}", data_val.data_id()))
+            } else if !perms.contains_key(data_val.perm_id()) {
+                Some(format!(
+                    "references missing permissions {}",
+                    data_val.perm_id()
+                ))
+            } else {
+                None
+            };
+            match reason {
+                Some(reason) => {
+                    skipped.push((data_id.to_string(), reason));
+                    false
+                }
+                None => true,
+            }
+        });
+
+        skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::BasicData;
+    use crate::devices::Device;
+    use crate::export::{Error, StateExport, STATE_VERSION};
+    use crate::metadata::{PermType, PermissionSet};
+    use std::collections::HashSet;
+
+    fn test_device() -> (Device<BasicData>, String) {
+        let device = Device::<BasicData>::new(String::from("0"), None, None);
+        let linked_name = device.linked_name.read().clone();
+        let perm = PermissionSet::new(None, None, None, None, None);
+        let perm_id = perm.perm_id().to_string();
+        let mut meta_store = device.meta_store.write();
+        meta_store.set_perm(perm_id.clone(), perm);
+        meta_store
+            .add_permissions(&perm_id, None, PermType::Owners(vec![linked_name.clone()]))
+            .unwrap();
+        drop(meta_store);
+        device.data_store.write().set_data(
+            String::from("data"),
+            BasicData::new(
+                String::from("data"),
+                String::from("type"),
+                String::from("val"),
+                perm_id.clone(),
+            ),
+        );
+        (device, perm_id)
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let (device, _) = test_device();
+        let export = StateExport::from_device(String::from("0"), &device);
+        let json = export.to_json().unwrap();
+        assert_eq!(StateExport::from_json(&json), Ok(export));
+
+        let newer = json.replacen(
+            &format!("\"version\": {}", STATE_VERSION),
+            &format!("\"version\": {}", STATE_VERSION + 1),
+            1,
+        );
+        assert_eq!(
+            StateExport::from_json(&newer),
+            Err(Error::UnsupportedVersion(STATE_VERSION as u64 + 1))
+        );
+    }
+
+    #[test]
+    fn test_rebase_and_validate() {
+        let (device, perm_id) = test_device();
+        let old_linked_name = device.linked_name.read().clone();
+        let mut export = StateExport::from_device(String::from("0"), &device);
+        let owners = export.perms[&perm_id].owners().clone().unwrap();
+
+        let new_linked_name = String::from("new_linked");
+        export.rebase(&new_linked_name);
+        assert!(!export.groups.contains_key(&old_linked_name));
+        assert!(!export.groups.contains_key(&String::from("0")));
+        assert!(export.groups[&owners]
+            .children()
+            .as_ref()
+            .unwrap()
+            .contains(&new_linked_name));
+
+        // a data object whose permissions did not make it into the export
+        let orphan = BasicData::new(
+            String::from("orphan"),
+            String::from("type"),
+            String::from("val"),
+            String::from("missing"),
+        );
+        export.data.insert(String::from("orphan"), orphan);
+
+        let skipped = export.validate(&HashSet::from([new_linked_name.clone()]));
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0, String::from("orphan"));
+        assert!(export.data.contains_key(&String::from("data")));
+        assert!(export.perms.contains_key(&perm_id));
+        // the owners group still points at the new linked group
+        assert!(export.groups[&owners]
+            .children()
+            .as_ref()
+            .unwrap()
+            .contains(&new_linked_name));
+    }
+
+    #[test]
+    fn test_skip_existing() {
+        let (device, perm_id) = test_device();
+        let mut export = StateExport::from_device(String::from("0"), &device);
+        let owners = export.perms[&perm_id].owners().clone().unwrap();
+
+        // the importing device already has the perm and its owners group,
+        // but not the data
+        let skipped = export.skip_existing(
+            &HashSet::from([owners.clone()]),
+            &HashSet::from([perm_id.clone()]),
+            &HashSet::new(),
+        );
+        assert_eq!(
+            skipped,
+            vec![(perm_id.clone(), String::from("already exists"))]
+        );
+        assert!(!export.groups.contains_key(&owners));
+        assert!(!export.perms.contains_key(&perm_id));
+
+        // data under a perm that was not imported is dropped as well
+        let skipped = export.validate(&HashSet::from([owners]));
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].0, String::from("data"));
+        assert!(export.data.is_empty());
+    }
+}
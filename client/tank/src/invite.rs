@@ -0,0 +1,273 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use uuid::Uuid;
+
+/*
+ * Invite codes let two users become contacts without copying idkeys around.
+ * An invite carries the inviter's idkey, a fingerprint of that idkey (so a
+ * mistyped or corrupted code is caught before anything is sent), a one-time
+ * secret that the inviter checks when the invite is used, and an expiry
+ * time. It can be rendered either as a short URL-safe code or as uppercase
+ * base32 text, which fits the QR alphanumeric mode. The unused invites a
+ * device has created can be persisted to a JSON file so they survive
+ * restarts.
+ */
+
+pub const DEFAULT_INVITE_TTL_SECS: u64 = 24 * 60 * 60;
+
+const CODE_PREFIX: &str = "scuba1.";
+const QR_PREFIX: &str = "SCUBA1:";
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Debug, PartialEq, Error)]
+pub enum Error {
+    #[error("Invite is malformed")]
+    Malformed,
+    #[error("Invite fingerprint does not match its idkey")]
+    FingerprintMismatch,
+    #[error("Invite expired at {0}")]
+    Expired(u64),
+    #[error("Cannot read or write invites file: {0}")]
+    Io(String),
+    #[error("Cannot convert invites to or from string: {0}")]
+    Serialization(String),
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Invite {
+    pub idkey: String,
+    pub fingerprint: String,
+    pub secret: String,
+    // seconds since the Unix epoch
+    pub expires_at: u64,
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+pub fn fingerprint(idkey: &String) -> String {
+    Sha256::digest(idkey.as_bytes())[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl Invite {
+    pub fn new(idkey: String, ttl_secs: u64) -> Invite {
+        Invite {
+            fingerprint: fingerprint(&idkey),
+            idkey,
+            secret: Uuid::new_v4().simple().to_string(),
+            expires_at: now_secs().saturating_add(ttl_secs),
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        bincode::DefaultOptions::new().serialize(self).unwrap()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Invite, Error> {
+        bincode::DefaultOptions::new()
+            .deserialize(bytes)
+            .map_err(|_| Error::Malformed)
+    }
+
+    pub fn code(&self) -> String {
+        format!("{}{}", CODE_PREFIX, URL_SAFE_NO_PAD.encode(self.to_bytes()))
+    }
+
+    pub fn qr_text(&self) -> String {
+        format!("{}{}", QR_PREFIX, base32_encode(&self.to_bytes()))
+    }
+
+    // accepts both code() and qr_text() output, and checks the fingerprint
+    // and expiry
+    pub fn parse(text: &str) -> Result<Invite, Error> {
+        let text = text.trim();
+        let bytes = if let Some(code) = text.strip_prefix(CODE_PREFIX) {
+            URL_SAFE_NO_PAD.decode(code).map_err(|_| Error::Malformed)?
+        } else if let Some(qr_text) = text.strip_prefix(QR_PREFIX) {
+            base32_decode(qr_text).ok_or(Error::Malformed)?
+        } else {
+            return Err(Error::Malformed);
+        };
+        let invite = Invite::from_bytes(&bytes)?;
+        invite.verify(now_secs())?;
+        Ok(invite)
+    }
+
+    pub fn verify(&self, now: u64) -> Result<(), Error> {
+        if fingerprint(&self.idkey) != self.fingerprint {
+            return Err(Error::FingerprintMismatch);
+        }
+        if now > self.expires_at {
+            return Err(Error::Expired(self.expires_at));
+        }
+        Ok(())
+    }
+}
+
+// Unused invites created by a device, by secret, with their expiry
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct IssuedInvites {
+    expiries: HashMap<String, u64>,
+}
+
+impl IssuedInvites {
+    pub fn new() -> IssuedInvites {
+        Self::default()
+    }
+
+    // a missing file is treated as no invites
+    pub fn load(path: &String) -> Result<IssuedInvites, Error> {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| Error::Serialization(e.to_string())),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(IssuedInvites::new()),
+            Err(err) => Err(Error::Io(err.to_string())),
+        }
+    }
+
+    pub fn save(&self, path: &String) -> Result<(), Error> {
+        let contents = serde_json::to_string(self)
+            .map_err(|e| Error::Serialization(e.to_string()))?;
+        fs::write(path, contents).map_err(|e| Error::Io(e.to_string()))
+    }
+
+    // also drops invites that expired before now
+    pub fn issue(&mut self, secret: String, expires_at: u64, now: u64) {
+        self.expiries.retain(|_, expires_at| *expires_at >= now);
+        self.expiries.insert(secret, expires_at);
+    }
+
+    pub fn is_valid(&self, secret: &String, now: u64) -> bool {
+        self.expiries
+            .get(secret)
+            .map_or(false, |expires_at| now <= *expires_at)
+    }
+
+    // invites are single-use; returns false if secret was not issued or has
+    // already been used
+    pub fn take(&mut self, secret: &String) -> bool {
+        self.expiries.remove(secret).is_some()
+    }
+
+    pub fn merge(&mut self, other: IssuedInvites) {
+        self.expiries.extend(other.expiries);
+    }
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buf: u32 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buf = (buf << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buf >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buf << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buf: u32 = 0;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let val = BASE32_ALPHABET.iter().position(|x| *x == c)? as u32;
+        buf = (buf << 5) | val;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buf >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::invite::{now_secs, Error, Invite, IssuedInvites};
+
+    #[test]
+    fn test_code_round_trip() {
+        let invite = Invite::new("idkey".to_string(), 60);
+        assert_eq!(Invite::parse(&invite.code()), Ok(invite.clone()));
+        assert_eq!(Invite::parse(&invite.qr_text()), Ok(invite.clone()));
+        assert!(invite
+            .qr_text()
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == ':'));
+    }
+
+    #[test]
+    fn test_fresh_secrets() {
+        let invite_1 = Invite::new("idkey".to_string(), 60);
+        let invite_2 = Invite::new("idkey".to_string(), 60);
+        assert_eq!(invite_1.fingerprint, invite_2.fingerprint);
+        assert_ne!(invite_1.secret, invite_2.secret);
+    }
+
+    #[test]
+    fn test_bad_invites() {
+        assert_eq!(Invite::parse("idkey"), Err(Error::Malformed));
+        assert_eq!(Invite::parse("scuba1.!!!"), Err(Error::Malformed));
+
+        let mut invite = Invite::new("idkey".to_string(), 60);
+        invite.idkey = "other_idkey".to_string();
+        assert_eq!(
+            Invite::parse(&invite.code()),
+            Err(Error::FingerprintMismatch)
+        );
+
+        let invite = Invite::new("idkey".to_string(), 0);
+        assert_eq!(
+            invite.verify(now_secs() + 1),
+            Err(Error::Expired(invite.expires_at))
+        );
+    }
+
+    #[test]
+    fn test_issued_invites_file() {
+        let path = std::env::temp_dir()
+            .join(format!("tank-invites-{}.json", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(IssuedInvites::load(&path), Ok(IssuedInvites::new()));
+
+        let mut issued = IssuedInvites::new();
+        issued.issue("expired".to_string(), 5, 0);
+        issued.issue("used".to_string(), 20, 0);
+        issued.issue("secret".to_string(), 20, 10);
+        assert!(!issued.is_valid(&"expired".to_string(), 10));
+        assert!(!issued.is_valid(&"secret".to_string(), 21));
+        assert!(issued.take(&"used".to_string()));
+        assert!(!issued.take(&"used".to_string()));
+        issued.save(&path).unwrap();
+
+        let loaded = IssuedInvites::load(&path).unwrap();
+        assert!(loaded.is_valid(&"secret".to_string(), 10));
+        assert!(!loaded.is_valid(&"used".to_string(), 10));
+        assert_eq!(loaded, issued);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -1,7 +1,16 @@
 #![feature(async_closure)]
 
 // TODO client -> driver, devices -> client
+pub mod blocklist;
+pub mod capability;
+pub mod causal;
 pub mod client;
+pub mod crdt;
 pub mod data;
 pub mod devices;
+pub mod export;
+pub mod invite;
 pub mod metadata;
+pub mod migration;
+pub mod snapshot;
+pub mod wire;
@@ -1,10 +1,19 @@
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
+use std::sync::Arc;
 use thiserror::Error;
 use uuid::Uuid;
 
+use crate::data::Expiry;
+
+// owner changes that can wait on approvals per permission set; owners can
+// propose changes without bound otherwise
+pub const MAX_OWNER_PROPOSALS_PER_PERM: usize = 32;
+
 #[derive(Debug, PartialEq, Error)]
 pub enum Error {
     #[error("Group {0} has no children")]
@@ -13,6 +22,18 @@ pub enum Error {
     GroupDoesNotExist(String),
     #[error("Permission Set {0} does not exist")]
     PermSetDoesNotExist(String),
+    #[error("Adding {1} as a child of {0} would create a cycle")]
+    GroupCycle(String, String),
+    #[error("Permission Set {0} has no owners")]
+    NoOwners(String),
+    #[error("{1} is not a member of Permission Set {0}")]
+    NotPermMember(String, String),
+    #[error("Owner change proposal {0} does not exist")]
+    ProposalDoesNotExist(String),
+    #[error("Owner change proposal id {0} is already in use")]
+    ProposalIdInUse(String),
+    #[error("Permission Set {0} has too many pending owner changes")]
+    TooManyProposals(String),
 }
 
 pub fn generate_uuid() -> String {
@@ -148,6 +169,15 @@ pub struct PermissionSet {
     writers: Option<String>,
     readers: Option<String>,
     do_readers: Option<String>,
+    // members whose reader/writer access lapses, and when
+    #[serde(default)]
+    reader_expiry: HashMap<String, Expiry>,
+    #[serde(default)]
+    writer_expiry: HashMap<String, Expiry>,
+    // number of owners (direct members of the owner group) that must approve
+    // an OwnerChange; None means any single owner can make one
+    #[serde(default)]
+    owner_quorum: Option<usize>,
 }
 
 impl fmt::Display for PermissionSet {
@@ -193,6 +223,9 @@ impl PermissionSet {
             writers,
             readers,
             do_readers,
+            reader_expiry: HashMap::new(),
+            writer_expiry: HashMap::new(),
+            owner_quorum: None,
         }
     }
 
@@ -239,16 +272,165 @@ impl PermissionSet {
         self.do_readers = Some(do_reader_group_id.to_string());
         old_do_readers
     }
+
+    pub fn reader_expiry(&self) -> &HashMap<String, Expiry> {
+        &self.reader_expiry
+    }
+
+    pub fn set_reader_expiry(&mut self, member_id: String, expiry: Expiry) {
+        self.reader_expiry.insert(member_id, expiry);
+    }
+
+    pub fn writer_expiry(&self) -> &HashMap<String, Expiry> {
+        &self.writer_expiry
+    }
+
+    pub fn set_writer_expiry(&mut self, member_id: String, expiry: Expiry) {
+        self.writer_expiry.insert(member_id, expiry);
+    }
+
+    // epoch at which the first of the reader and writer grants lapses
+    fn first_grant_expiry(&self) -> Option<u64> {
+        self.reader_expiry
+            .values()
+            .chain(self.writer_expiry.values())
+            .map(|expiry| expiry.epoch())
+            .min()
+    }
+
+    pub fn owner_quorum(&self) -> Option<usize> {
+        self.owner_quorum
+    }
+
+    pub fn set_owner_quorum(&mut self, quorum: Option<usize>) -> Option<usize> {
+        std::mem::replace(&mut self.owner_quorum, quorum)
+    }
+}
+
+// Changes to who governs a permission set. These take effect only once
+// enough owners have approved them (see PermissionSet::owner_quorum).
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum OwnerChange {
+    // replace the owner group's members with these groups, which must
+    // already be members of the permission set
+    Transfer(Vec<String>),
+    SetQuorum(Option<usize>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct OwnerProposal {
+    pub perm_id: String,
+    pub change: OwnerChange,
+    // devices that have approved the change so far
+    pub approvals: HashSet<String>,
 }
 
 // TODO maybe, in order to just have a single hashmap, Group vs
 // PermissionSet can be in an enum too -> but would this be helpful?
 // having two hashmaps is fine
 
+#[derive(Debug, PartialEq, Clone)]
+pub enum PermChange {
+    Set(PermissionSet),
+    AddMembers(Option<String>, PermType),
+    // an approved owner change
+    Owners(OwnerChange),
+}
+
+// Audit log entry for a change to a permission set, tagged with the device
+// that made it and the sequence number it was ordered at
+#[derive(Debug, PartialEq, Clone)]
+pub struct PermChangeEntry {
+    pub sender: String,
+    pub seq: u128,
+    pub perm_id: String,
+    pub change: PermChange,
+}
+
+// What a collect_garbage() pass removed
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Collected {
+    pub perm_ids: Vec<String>,
+    pub group_ids: Vec<String>,
+}
+
+// Everything reachable from a group through its children (including the
+// group itself), and the devices among them
+#[derive(Debug, PartialEq, Clone)]
+struct Closure {
+    members: HashSet<String>,
+    devices: HashSet<String>,
+}
+
+// Memoized closures, by group id. Filled in lazily by membership queries,
+// which only have &self, hence the Mutex. An entry is dropped whenever any
+// group it contains changes, so entries for unrelated groups survive.
+#[derive(Debug, Default)]
+struct MembershipCache {
+    enabled: bool,
+    closures: Mutex<HashMap<String, Arc<Closure>>>,
+}
+
+impl MembershipCache {
+    fn new() -> MembershipCache {
+        MembershipCache {
+            enabled: true,
+            closures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn get(&self, group_id: &String) -> Option<Arc<Closure>> {
+        self.closures.lock().get(group_id).cloned()
+    }
+
+    fn insert(&self, group_id: String, closure: Arc<Closure>) {
+        self.closures.lock().insert(group_id, closure);
+    }
+
+    fn invalidate(&self, group_id: &String) {
+        self.closures
+            .lock()
+            .retain(|_, closure| !closure.members.contains(group_id));
+    }
+
+    fn clear(&self) {
+        self.closures.lock().clear();
+    }
+}
+
+impl Clone for MembershipCache {
+    fn clone(&self) -> MembershipCache {
+        MembershipCache {
+            enabled: self.enabled,
+            closures: Mutex::new(self.closures.lock().clone()),
+        }
+    }
+}
+
+// the cache never changes what a store contains
+impl PartialEq for MembershipCache {
+    fn eq(&self, _: &MembershipCache) -> bool {
+        true
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct MetadataStore {
     group_store: HashMap<String, Group>,
     perm_store: HashMap<String, PermissionSet>,
+    // None unless logging is enabled
+    perm_log: Option<Vec<PermChangeEntry>>,
+    // owner changes still waiting on approvals, by proposal id
+    owner_proposals: HashMap<String, OwnerProposal>,
+    membership_cache: MembershipCache,
+    // perms that lost (some of) their data since the last collect_garbage()
+    orphaned_perms: HashSet<String>,
+    // groups deleted since the last collect_garbage(), whose ids may still
+    // be listed as children of groups that were not listed as their parents
+    deleted_groups: HashSet<String>,
+    // (epoch of the first grant to lapse, perm_id) of every perm with
+    // time-bounded grants, soonest first
+    grant_expiries: BTreeSet<(u64, String)>,
 }
 
 impl MetadataStore {
@@ -256,6 +438,88 @@ impl MetadataStore {
         Self {
             group_store: HashMap::<String, Group>::new(),
             perm_store: HashMap::<String, PermissionSet>::new(),
+            perm_log: None,
+            owner_proposals: HashMap::new(),
+            membership_cache: MembershipCache::new(),
+            orphaned_perms: HashSet::new(),
+            deleted_groups: HashSet::new(),
+            grant_expiries: BTreeSet::new(),
+        }
+    }
+
+    /*
+     * Membership cache methods
+     */
+
+    // The cache is on by default; turning it off makes is_group_member() and
+    // resolve_group_ids() walk the group graph on every call
+    pub fn enable_membership_cache(&mut self) {
+        self.membership_cache.enabled = true;
+    }
+
+    pub fn disable_membership_cache(&mut self) {
+        self.membership_cache.enabled = false;
+        self.membership_cache.clear();
+    }
+
+    fn closure(&self, group_id: &String) -> Arc<Closure> {
+        if let Some(closure) = self.membership_cache.get(group_id) {
+            return closure;
+        }
+        let mut members = HashSet::new();
+        let mut devices = HashSet::new();
+        let mut to_visit = vec![group_id];
+        while let Some(cur_id) = to_visit.pop() {
+            if !members.insert(cur_id.to_string()) {
+                continue;
+            }
+            match self.get_group(cur_id) {
+                Some(Group {
+                    children: Some(children),
+                    ..
+                }) => to_visit.extend(children.iter()),
+                Some(_) => {
+                    devices.insert(cur_id.to_string());
+                }
+                None => {}
+            }
+        }
+        let closure = Arc::new(Closure { members, devices });
+        if self.membership_cache.enabled {
+            self.membership_cache
+                .insert(group_id.to_string(), closure.clone());
+        }
+        closure
+    }
+
+    /*
+     * Permission change log methods
+     */
+
+    pub fn enable_perm_log(&mut self) {
+        if self.perm_log.is_none() {
+            self.perm_log = Some(Vec::new());
+        }
+    }
+
+    pub fn disable_perm_log(&mut self) {
+        self.perm_log = None;
+    }
+
+    pub fn log_perm_change(&mut self, entry: PermChangeEntry) {
+        if let Some(perm_log) = self.perm_log.as_mut() {
+            perm_log.push(entry);
+        }
+    }
+
+    // returns logged changes (oldest first), optionally only those to perm_id
+    pub fn get_perm_log(&self, perm_id: Option<&String>) -> Vec<&PermChangeEntry> {
+        match &self.perm_log {
+            Some(perm_log) => perm_log
+                .iter()
+                .filter(|entry| perm_id.map_or(true, |perm_id| entry.perm_id == *perm_id))
+                .collect(),
+            None => Vec::new(),
         }
     }
 
@@ -272,7 +536,19 @@ impl MetadataStore {
         perm_id: String,
         perm_val: PermissionSet,
     ) -> Option<PermissionSet> {
-        self.perm_store.insert(perm_id, perm_val)
+        if let Some(first_expiry) = perm_val.first_grant_expiry() {
+            self.grant_expiries.insert((first_expiry, perm_id.clone()));
+        }
+        let old_val = self.perm_store.insert(perm_id.clone(), perm_val);
+        if let Some(old_expiry) = old_val
+            .as_ref()
+            .and_then(|old_val| old_val.first_grant_expiry())
+        {
+            if self.get_perm(&perm_id).unwrap().first_grant_expiry() != Some(old_expiry) {
+                self.grant_expiries.remove(&(old_expiry, perm_id));
+            }
+        }
+        old_val
     }
 
     // TODO
@@ -290,6 +566,23 @@ impl MetadataStore {
 
         let mut perm_set = self.get_perm(perm_id).unwrap().clone();
 
+        // granting access again without an expiry replaces a time-bounded
+        // grant (a SetGrantExpiry sent along with the grant sets a new one)
+        let regranted = match &new_perm_members {
+            PermType::Readers(members) => members
+                .iter()
+                .filter(|member| perm_set.reader_expiry.remove(*member).is_some())
+                .count(),
+            PermType::Writers(members) => members
+                .iter()
+                .filter(|member| perm_set.writer_expiry.remove(*member).is_some())
+                .count(),
+            PermType::Owners(_) | PermType::DOReaders(_) => 0,
+        };
+        if regranted > 0 {
+            self.set_perm(perm_id.to_string(), perm_set.clone());
+        }
+
         let (existing_members, new_members) = match new_perm_members.clone() {
             PermType::Owners(new_owners) => (perm_set.owners(), new_owners),
             PermType::Writers(new_writers) => (perm_set.writers(), new_writers),
@@ -353,6 +646,45 @@ impl MetadataStore {
         &self.perm_store
     }
 
+    // Removes members whose reader/writer grant has lapsed from the
+    // corresponding group; returns (perm_id, member_id) for each
+    pub fn purge_expired_grants(&mut self, epoch: u64) -> Vec<(String, String)> {
+        let mut revoked = Vec::new();
+        while let Some((first_expiry, perm_id)) = self.grant_expiries.first().cloned() {
+            if first_expiry > epoch {
+                break;
+            }
+            self.grant_expiries.pop_first();
+            // the perm may have been collected since
+            let mut perm_set = match self.perm_store.get(&perm_id) {
+                Some(perm_set) => perm_set.clone(),
+                None => continue,
+            };
+            let mut lapsed = Vec::new();
+            for (group_id, expiries) in [
+                (perm_set.readers.clone(), &mut perm_set.reader_expiry),
+                (perm_set.writers.clone(), &mut perm_set.writer_expiry),
+            ] {
+                expiries.retain(|member_id, expiry| {
+                    if !expiry.has_expired(epoch) {
+                        return true;
+                    }
+                    if let Some(group_id) = &group_id {
+                        lapsed.push((group_id.to_string(), member_id.to_string()));
+                    }
+                    false
+                });
+            }
+            for (group_id, member_id) in lapsed {
+                // the member may already be gone (e.g. a removed contact)
+                let _ = self.unlink_groups(&group_id, &member_id);
+                revoked.push((perm_id.clone(), member_id));
+            }
+            self.set_perm(perm_id, perm_set);
+        }
+        revoked
+    }
+
     pub fn has_data_mod_permissions(&self, group_id: &String, perm_id: &String) -> bool {
         let perm_opt = self.get_perm(perm_id);
         match perm_opt {
@@ -411,6 +743,232 @@ impl MetadataStore {
         }
     }
 
+    /*
+     * Owner governance methods
+     */
+
+    pub fn get_owner_proposal(&self, proposal_id: &String) -> Option<&OwnerProposal> {
+        self.owner_proposals.get(proposal_id)
+    }
+
+    pub fn get_owner_proposals(&self) -> &HashMap<String, OwnerProposal> {
+        &self.owner_proposals
+    }
+
+    // number of owner approvals a change to perm_id needs; never more than
+    // there are owners, so that losing owners cannot lock everyone out
+    pub fn required_owner_approvals(&self, perm_id: &String) -> usize {
+        let perm_val = match self.get_perm(perm_id) {
+            Some(perm_val) => perm_val,
+            None => return 1,
+        };
+        let num_owners = perm_val
+            .owners()
+            .as_ref()
+            .and_then(|owner_group| self.get_group(owner_group))
+            .and_then(|owner_group| owner_group.children().as_ref())
+            .map_or(0, |children| children.len());
+        perm_val.owner_quorum().unwrap_or(1).min(num_owners).max(1)
+    }
+
+    // Owners count once each, no matter how many of their devices approved
+    fn num_owner_approvals(
+        &self,
+        perm_id: &String,
+        approvals: &HashSet<String>,
+    ) -> usize {
+        let owners = match self
+            .get_perm(perm_id)
+            .and_then(|perm_val| perm_val.owners().as_ref())
+            .and_then(|owner_group| self.get_group(owner_group))
+            .and_then(|owner_group| owner_group.children().as_ref())
+        {
+            Some(owners) => owners,
+            None => return 0,
+        };
+        owners
+            .iter()
+            .filter(|owner| {
+                approvals
+                    .iter()
+                    .any(|approver| self.is_group_member(approver, owner, &None))
+            })
+            .count()
+    }
+
+    // Whether a new owner change with this id can be proposed for perm_id.
+    // The id may become the id of a new owner group, so it cannot be that of
+    // an existing group either.
+    pub fn check_new_owner_proposal(
+        &self,
+        proposal_id: &String,
+        perm_id: &String,
+    ) -> Result<(), Error> {
+        if self.get_perm(perm_id).is_none() {
+            return Err(Error::PermSetDoesNotExist(perm_id.to_string()));
+        }
+        if self.owner_proposals.contains_key(proposal_id)
+            || self.get_group(proposal_id).is_some()
+        {
+            return Err(Error::ProposalIdInUse(proposal_id.to_string()));
+        }
+        let pending = self
+            .owner_proposals
+            .values()
+            .filter(|proposal| &proposal.perm_id == perm_id)
+            .count();
+        if pending >= MAX_OWNER_PROPOSALS_PER_PERM {
+            return Err(Error::TooManyProposals(perm_id.to_string()));
+        }
+        Ok(())
+    }
+
+    // Records a proposed owner change along with the proposer's approval.
+    // Returns true if that was enough for it to be applied.
+    pub fn propose_owner_change(
+        &mut self,
+        proposal_id: String,
+        perm_id: String,
+        change: OwnerChange,
+        proposer: String,
+    ) -> Result<bool, Error> {
+        self.check_new_owner_proposal(&proposal_id, &perm_id)?;
+        if let OwnerChange::Transfer(new_owners) = &change {
+            if new_owners.is_empty() {
+                return Err(Error::NoOwners(perm_id));
+            }
+            if let Some(non_member) = new_owners
+                .iter()
+                .find(|new_owner| !self.is_perm_member(new_owner, &perm_id))
+            {
+                return Err(Error::NotPermMember(perm_id, non_member.to_string()));
+            }
+        }
+        self.owner_proposals.insert(
+            proposal_id.clone(),
+            OwnerProposal {
+                perm_id,
+                change,
+                approvals: HashSet::from([proposer]),
+            },
+        );
+        self.apply_owner_change_if_approved(&proposal_id)
+    }
+
+    // Returns true if this approval was the last one needed and the change
+    // was applied
+    pub fn approve_owner_change(
+        &mut self,
+        proposal_id: &String,
+        approver: String,
+    ) -> Result<bool, Error> {
+        match self.owner_proposals.get_mut(proposal_id) {
+            Some(proposal) => {
+                proposal.approvals.insert(approver);
+            }
+            None => return Err(Error::ProposalDoesNotExist(proposal_id.to_string())),
+        }
+        self.apply_owner_change_if_approved(proposal_id)
+    }
+
+    fn apply_owner_change_if_approved(
+        &mut self,
+        proposal_id: &String,
+    ) -> Result<bool, Error> {
+        let proposal = self.owner_proposals.get(proposal_id).unwrap();
+        if self.num_owner_approvals(&proposal.perm_id, &proposal.approvals)
+            < self.required_owner_approvals(&proposal.perm_id)
+        {
+            return Ok(false);
+        }
+        let proposal = self.owner_proposals.remove(proposal_id).unwrap();
+        let mut perm_val = match self.get_perm(&proposal.perm_id) {
+            Some(perm_val) => perm_val.clone(),
+            None => return Err(Error::PermSetDoesNotExist(proposal.perm_id)),
+        };
+        match proposal.change {
+            OwnerChange::Transfer(new_owners) => match perm_val.owners().clone() {
+                Some(owner_group_id) => {
+                    let old_owners = self
+                        .get_group(&owner_group_id)
+                        .and_then(|group| group.children().clone())
+                        .unwrap_or_default();
+                    for old_owner in old_owners.iter() {
+                        if !new_owners.contains(old_owner) {
+                            self.unlink_groups(&owner_group_id, old_owner)?;
+                        }
+                    }
+                    for new_owner in new_owners.iter() {
+                        if !old_owners.contains(new_owner) {
+                            self.link_groups(&owner_group_id, new_owner)?;
+                        }
+                    }
+                }
+                // the proposal id doubles as the id of the new owner group
+                // so that every device creates the same one; a group created
+                // with that id since the proposal must not be taken over
+                None if self.get_group(proposal_id).is_some() => {
+                    return Err(Error::ProposalIdInUse(proposal_id.to_string()))
+                }
+                None => self.add_permissions(
+                    &proposal.perm_id,
+                    Some(proposal_id.to_string()),
+                    PermType::Owners(new_owners),
+                )?,
+            },
+            OwnerChange::SetQuorum(quorum) => {
+                perm_val.set_owner_quorum(quorum);
+                self.set_perm(proposal.perm_id, perm_val);
+            }
+        }
+        Ok(true)
+    }
+
+    // whether id is in any of perm_id's owner, writer, reader or data-only
+    // reader groups
+    pub fn is_perm_member(&self, id: &String, perm_id: &String) -> bool {
+        match self.get_perm(perm_id) {
+            Some(perm_val) => [
+                perm_val.owners(),
+                perm_val.writers(),
+                perm_val.readers(),
+                perm_val.do_readers(),
+            ]
+            .into_iter()
+            .flatten()
+            .any(|group_id| self.is_group_member(id, group_id, &None)),
+            None => false,
+        }
+    }
+
+    // Whether setting perm_id to perm_val would replace a different,
+    // existing permission set. Resending an unchanged one is allowed, since
+    // add_permissions sends the existing set to all members.
+    pub fn is_perm_overwrite(&self, perm_id: &String, perm_val: &PermissionSet) -> bool {
+        match self.get_perm(perm_id) {
+            Some(existing_val) => existing_val != perm_val,
+            None => false,
+        }
+    }
+
+    // Returns the perm whose owner quorum would be bypassed by setting
+    // group_id to group_val, i.e. if group_id is the (changed) owner group of
+    // a perm that needs more than one owner to approve owner changes
+    pub fn bypasses_owner_quorum(
+        &self,
+        group_id: &String,
+        group_val: &Group,
+    ) -> Option<String> {
+        if self.get_group(group_id) == Some(group_val) {
+            return None;
+        }
+        self.perm_store
+            .values()
+            .filter(|perm_val| perm_val.owners().as_ref() == Some(group_id))
+            .find(|perm_val| self.required_owner_approvals(perm_val.perm_id()) > 1)
+            .map(|perm_val| perm_val.perm_id().to_string())
+    }
+
     // TODO rename => exists_metadata_mod_permissions
     pub fn find_metadata_mod_permissions(
         &self,
@@ -440,11 +998,16 @@ impl MetadataStore {
         self.group_store.get(group_id)
     }
 
+    // every change to a group goes through one of get_group_mut(),
+    // set_group() or delete_group() (add_parent(), link_groups() etc. call
+    // set_group()), which keep the membership cache up to date
     pub fn get_group_mut(&mut self, group_id: &String) -> Option<&mut Group> {
+        self.membership_cache.invalidate(group_id);
         self.group_store.get_mut(group_id)
     }
 
     pub fn set_group(&mut self, group_id: String, group_val: Group) -> Option<Group> {
+        self.membership_cache.invalidate(&group_id);
         self.group_store.insert(group_id, group_val)
     }
 
@@ -467,6 +1030,13 @@ impl MetadataStore {
             return Err(Error::GroupDoesNotExist(to_parent_id.to_string()));
         }
 
+        if self.creates_cycle(to_parent_id, base_group_id) {
+            return Err(Error::GroupCycle(
+                to_parent_id.to_string(),
+                base_group_id.to_string(),
+            ));
+        }
+
         let mut base_group = self.get_group(base_group_id).unwrap().clone();
         base_group.add_parent(to_parent_id.to_string());
         self.set_group(base_group_id.to_string(), base_group);
@@ -507,6 +1077,13 @@ impl MetadataStore {
             return Err(Error::GroupDoesNotExist(to_child_id.to_string()));
         }
 
+        if self.creates_cycle(base_group_id, to_child_id) {
+            return Err(Error::GroupCycle(
+                base_group_id.to_string(),
+                to_child_id.to_string(),
+            ));
+        }
+
         let mut base_group = self.get_group(base_group_id).unwrap().clone();
         base_group
             .add_child(to_child_id.to_string())
@@ -553,6 +1130,13 @@ impl MetadataStore {
             return Err(Error::GroupDoesNotExist(to_child_id.to_string()));
         }
 
+        if self.creates_cycle(to_parent_id, to_child_id) {
+            return Err(Error::GroupCycle(
+                to_parent_id.to_string(),
+                to_child_id.to_string(),
+            ));
+        }
+
         // set child of to_parent group
         let mut to_parent_group = self.get_group(to_parent_id).unwrap().clone();
         if to_parent_group.children.is_none() {
@@ -569,6 +1153,14 @@ impl MetadataStore {
         Ok(())
     }
 
+    // an edge from to_parent_id down to to_child_id creates a cycle iff
+    // to_parent_id is already reachable from to_child_id (or is the same
+    // group)
+    fn creates_cycle(&self, to_parent_id: &String, to_child_id: &String) -> bool {
+        to_parent_id == to_child_id
+            || self.is_group_member(to_parent_id, to_child_id, &None)
+    }
+
     pub fn unlink_groups(
         &mut self,
         parent_id: &String,
@@ -605,22 +1197,26 @@ impl MetadataStore {
 
         let group_val = self.get_group(group_id).unwrap().clone();
 
-        // delete from all parents' children lists
+        // delete from all parents' children lists; groups received from
+        // peers (e.g. contacts) may reference parents that were never sent to
+        // this device, so skip those
         for parent_id in &group_val.parents {
-            let mut parent_group = self.get_group(&parent_id).unwrap().clone();
-            parent_group.remove_child(group_id);
-            self.set_group(parent_id.to_string(), parent_group);
+            if let Some(parent_group) = self.get_group_mut(&parent_id) {
+                let _ = parent_group.remove_child(group_id);
+            }
         }
 
         // delete from any childrens' parents lists
         if let Some(children) = group_val.children {
             for child_id in children {
-                let mut child_group = self.get_group(&child_id).unwrap().clone();
-                child_group.remove_parent(group_id);
-                self.set_group(child_id.to_string(), child_group);
+                if let Some(child_group) = self.get_group_mut(&child_id) {
+                    child_group.remove_parent(group_id);
+                }
             }
         }
 
+        self.membership_cache.invalidate(group_id);
+        self.deleted_groups.insert(group_id.to_string());
         self.group_store.remove(group_id)
     }
 
@@ -647,7 +1243,18 @@ impl MetadataStore {
         }
     }
 
+    // NOTE: the traversals below all keep a visited set, so that cyclic group
+    // state received from older peers (which did not reject cycles) still
+    // terminates
     pub fn resolve_group_ids<'a>(&'a self, ids: Vec<&'a String>) -> HashSet<String> {
+        if self.membership_cache.enabled {
+            let mut resolved_ids = HashSet::<String>::new();
+            for id in ids {
+                resolved_ids.extend(self.closure(id).devices.iter().cloned());
+            }
+            return resolved_ids;
+        }
+
         let mut resolved_ids = HashSet::<String>::new();
         let mut visited = HashSet::<&String>::new();
 
@@ -675,7 +1282,13 @@ impl MetadataStore {
             }
 
             visited.insert(cur_id);
-            if let Some(children) = &self.get_group(cur_id).unwrap().children {
+            // groups received from peers may reference ids that were never
+            // sent to this device; skip rather than panic
+            let cur_group = match self.get_group(cur_id) {
+                Some(cur_group) => cur_group,
+                None => continue,
+            };
+            if let Some(children) = &cur_group.children {
                 for child in children {
                     to_visit.push(&child);
                 }
@@ -689,6 +1302,26 @@ impl MetadataStore {
         &self.group_store
     }
 
+    // group_id's parents as they would be without group_id among their
+    // children, i.e. the group changes that take away group_id's access
+    // through them
+    pub fn parents_without_child(&self, group_id: &String) -> HashMap<String, Group> {
+        let mut parents = HashMap::new();
+        let group_val = match self.get_group(group_id) {
+            Some(group_val) => group_val,
+            None => return parents,
+        };
+        for parent_id in group_val.parents().iter() {
+            if let Some(parent_val) = self.get_group(parent_id) {
+                let mut parent_val = parent_val.clone();
+                if let Ok(true) = parent_val.remove_child(group_id) {
+                    parents.insert(parent_id.to_string(), parent_val);
+                }
+            }
+        }
+        parents
+    }
+
     pub fn get_all_subgroups<'a>(
         &'a self,
         group_id: &'a String,
@@ -706,7 +1339,10 @@ impl MetadataStore {
             }
             visited.insert(cur_id);
 
-            let cur_val = self.get_group(cur_id).unwrap();
+            let cur_val = match self.get_group(cur_id) {
+                Some(cur_val) => cur_val,
+                None => continue,
+            };
             subgroups.insert(cur_id.to_string(), cur_val.clone());
 
             if let Some(children) = &cur_val.children {
@@ -725,6 +1361,11 @@ impl MetadataStore {
         group_id: &'a String,
         new_group_opt: &Option<Group>,
     ) -> bool {
+        // a group that is about to be added is not in the cache
+        if self.membership_cache.enabled && new_group_opt.is_none() {
+            return self.closure(group_id).members.contains(is_member_id);
+        }
+
         let mut visited = HashSet::<&String>::new();
         let mut to_visit = Vec::<&String>::new();
         to_visit.push(group_id);
@@ -759,7 +1400,7 @@ impl MetadataStore {
                             }
                         }
                     }
-                    None => panic!("group does not exist"),
+                    None => {}
                 },
             }
         }
@@ -803,11 +1444,140 @@ impl MetadataStore {
         }
         false
     }
+
+    /*
+     * Garbage collection
+     */
+
+    // Records that data protected by perm_id was deleted, so the next
+    // collect_garbage() removes the perm if no other data still uses it.
+    // Only perms marked here are ever collected: a perm whose data simply has
+    // not arrived yet (SetPerm is sent ahead of UpdateData) must survive.
+    pub fn mark_perm_orphaned(&mut self, perm_id: &String) {
+        self.orphaned_perms.insert(perm_id.to_string());
+    }
+
+    // Removes orphaned perms that are not in live_perm_ids (those used by
+    // some data object), then every group that is neither reachable from a
+    // remaining perm, a contact or one of root_ids nor backs a remaining
+    // perm, and finally group ids left dangling in parents/children lists.
+    //
+    // Every replica that applies the same deletes reaches the same state, so
+    // this only ever changes local state and sends nothing.
+    pub fn collect_garbage(
+        &mut self,
+        live_perm_ids: &HashSet<String>,
+        root_ids: Vec<&String>,
+    ) -> Collected {
+        let mut collected = Collected::default();
+
+        for perm_id in std::mem::take(&mut self.orphaned_perms) {
+            if !live_perm_ids.contains(&perm_id)
+                && self.perm_store.remove(&perm_id).is_some()
+            {
+                collected.perm_ids.push(perm_id);
+            }
+        }
+        self.owner_proposals
+            .retain(|_, proposal| !collected.perm_ids.contains(&proposal.perm_id));
+
+        // mark
+        let mut to_visit: Vec<&String> = root_ids;
+        for perm_set in self.perm_store.values() {
+            for group_id in [
+                &perm_set.owners,
+                &perm_set.writers,
+                &perm_set.readers,
+                &perm_set.do_readers,
+            ] {
+                if let Some(group_id) = group_id {
+                    to_visit.push(group_id);
+                }
+            }
+        }
+        for (group_id, group_val) in self.group_store.iter() {
+            if group_val.is_contact_name {
+                to_visit.push(group_id);
+            }
+        }
+        let mut reachable = HashSet::<&String>::new();
+        while let Some(cur_id) = to_visit.pop() {
+            if !reachable.insert(cur_id) {
+                continue;
+            }
+            if let Some(Group {
+                children: Some(children),
+                ..
+            }) = self.get_group(cur_id)
+            {
+                to_visit.extend(children.iter());
+            }
+        }
+
+        // sweep
+        let unreachable: Vec<String> = self
+            .group_store
+            .iter()
+            .filter(|(group_id, group_val)| {
+                !reachable.contains(group_id)
+                    && group_val
+                        .perm_ids
+                        .iter()
+                        .all(|perm_id| !self.perm_store.contains_key(perm_id))
+            })
+            .map(|(group_id, _)| group_id.to_string())
+            .collect();
+        for group_id in unreachable {
+            self.delete_group(&group_id);
+            collected.group_ids.push(group_id);
+        }
+
+        // parents are only backpointers, so any missing one can go; a missing
+        // child may just not have been sent to this device yet, so only drop
+        // those known to have been deleted
+        let deleted_groups = std::mem::take(&mut self.deleted_groups);
+        let dangling: Vec<String> = self
+            .group_store
+            .iter()
+            .filter(|(_, group_val)| {
+                group_val
+                    .parents
+                    .iter()
+                    .any(|parent_id| !self.group_store.contains_key(parent_id))
+                    || group_val.children.as_ref().map_or(false, |children| {
+                        children.iter().any(|child_id| {
+                            deleted_groups.contains(child_id)
+                                && !self.group_store.contains_key(child_id)
+                        })
+                    })
+            })
+            .map(|(group_id, _)| group_id.to_string())
+            .collect();
+        for group_id in dangling {
+            let mut group_val = self.get_group(&group_id).unwrap().clone();
+            group_val
+                .parents
+                .retain(|parent_id| self.group_store.contains_key(parent_id));
+            if let Some(children) = group_val.children.as_mut() {
+                children.retain(|child_id| {
+                    !deleted_groups.contains(child_id)
+                        || self.group_store.contains_key(child_id)
+                });
+            }
+            self.set_group(group_id, group_val);
+        }
+
+        collected
+    }
 }
 
-/*
+#[cfg(test)]
 mod tests {
-    use crate::metadata::{Group, MetadataStore};
+    use crate::data::Expiry;
+    use crate::metadata::{
+        Collected, Error, Group, MetadataStore, OwnerChange, PermChange, PermChangeEntry,
+        PermType, PermissionSet, MAX_OWNER_PROPOSALS_PER_PERM,
+    };
     use std::collections::HashMap;
     use std::collections::HashSet;
 
@@ -821,7 +1591,7 @@ mod tests {
 
     #[test]
     fn test_set_get_group() {
-        let group = Group::new(None, true, false);
+        let group = Group::new(None, None, true, None);
         let mut meta_store = MetadataStore::new();
         meta_store.set_group(group.group_id.clone(), group.clone());
         assert_eq!(*meta_store.get_group(&group.group_id).unwrap(), group);
@@ -829,8 +1599,8 @@ mod tests {
 
     #[test]
     fn test_modify_group_parents() {
-        let mut group_0 = Group::new(None, true, false);
-        let group_1 = Group::new(None, true, true);
+        let mut group_0 = Group::new(None, None, true, None);
+        let group_1 = Group::new(None, None, true, Some(None));
 
         group_0.add_parent(group_1.group_id.clone());
         assert_eq!(group_0.parents, HashSet::from([group_1.group_id.clone()]));
@@ -841,8 +1611,8 @@ mod tests {
 
     #[test]
     fn test_modify_group_children() {
-        let mut group_0 = Group::new(None, true, true);
-        let group_1 = Group::new(None, true, false);
+        let mut group_0 = Group::new(None, None, true, Some(None));
+        let group_1 = Group::new(None, None, true, None);
 
         group_0.add_child(group_1.group_id.clone());
         assert_eq!(
@@ -856,8 +1626,8 @@ mod tests {
 
     #[test]
     fn test_link_groups() {
-        let group_0 = Group::new(None, true, true);
-        let group_1 = Group::new(None, true, false);
+        let group_0 = Group::new(None, None, true, Some(None));
+        let group_1 = Group::new(None, None, true, None);
 
         let mut meta_store = MetadataStore::new();
         meta_store.set_group(group_0.group_id.clone(), group_0.clone());
@@ -880,8 +1650,8 @@ mod tests {
 
     #[test]
     fn test_unlink_groups() {
-        let group_0 = Group::new(None, true, true);
-        let group_1 = Group::new(None, true, false);
+        let group_0 = Group::new(None, None, true, Some(None));
+        let group_1 = Group::new(None, None, true, None);
 
         let mut meta_store = MetadataStore::new();
         meta_store.set_group(group_0.group_id.clone(), group_0.clone());
@@ -894,9 +1664,38 @@ mod tests {
         assert_eq!(&group_1, meta_store.get_group(&group_1.group_id).unwrap());
     }
 
+    #[test]
+    fn test_parents_without_child() {
+        let group_0 = Group::new(None, None, true, Some(None));
+        let group_1 = Group::new(None, None, true, Some(None));
+        let group_2 = Group::new(None, None, true, None);
+
+        let mut meta_store = MetadataStore::new();
+        meta_store.set_group(group_0.group_id.clone(), group_0.clone());
+        meta_store.set_group(group_1.group_id.clone(), group_1.clone());
+        meta_store.set_group(group_2.group_id.clone(), group_2.clone());
+        meta_store
+            .link_groups(&group_0.group_id, &group_2.group_id)
+            .unwrap();
+        meta_store
+            .link_groups(&group_1.group_id, &group_2.group_id)
+            .unwrap();
+
+        let parents = meta_store.parents_without_child(&group_2.group_id);
+        assert_eq!(parents.len(), 2);
+        for parent_val in parents.values() {
+            assert_eq!(parent_val.children.as_ref().unwrap(), &HashSet::new());
+        }
+        // the store itself is unchanged
+        assert!(meta_store.is_group_member(&group_2.group_id, &group_0.group_id, &None));
+        assert!(meta_store
+            .parents_without_child(&group_0.group_id)
+            .is_empty());
+    }
+
     #[test]
     fn test_delete_group() {
-        let group = Group::new(None, true, false);
+        let group = Group::new(None, None, true, None);
         let mut meta_store = MetadataStore::new();
         meta_store.set_group(group.group_id.clone(), group.clone());
         meta_store.delete_group(&group.group_id);
@@ -905,8 +1704,8 @@ mod tests {
 
     #[test]
     fn test_delete_linked_group() {
-        let group_0 = Group::new(None, true, true);
-        let group_1 = Group::new(None, true, false);
+        let group_0 = Group::new(None, None, true, Some(None));
+        let group_1 = Group::new(None, None, true, None);
 
         let mut meta_store = MetadataStore::new();
         meta_store.set_group(group_0.group_id.clone(), group_0.clone());
@@ -921,10 +1720,10 @@ mod tests {
 
     #[test]
     fn test_add_members() {
-        let base_group = Group::new(None, true, true);
-        let group_0 = Group::new(None, true, false);
-        let group_1 = Group::new(None, true, false);
-        let group_2 = Group::new(None, true, false);
+        let base_group = Group::new(None, None, true, Some(None));
+        let group_0 = Group::new(None, None, true, None);
+        let group_1 = Group::new(None, None, true, None);
+        let group_2 = Group::new(None, None, true, None);
 
         let mut meta_store = MetadataStore::new();
 
@@ -938,8 +1737,7 @@ mod tests {
             vec![group_0.group_id(), group_1.group_id(), group_2.group_id()],
         );
 
-        let new_base_group =
-            meta_store.get_group(base_group.group_id()).unwrap();
+        let new_base_group = meta_store.get_group(base_group.group_id()).unwrap();
         assert_eq!(
             new_base_group.children.as_ref().unwrap(),
             &HashSet::from([
@@ -967,10 +1765,10 @@ mod tests {
 
     #[test]
     fn test_remove_members() {
-        let base_group = Group::new(None, true, true);
-        let group_0 = Group::new(None, true, false);
-        let group_1 = Group::new(None, true, false);
-        let group_2 = Group::new(None, true, false);
+        let base_group = Group::new(None, None, true, Some(None));
+        let group_0 = Group::new(None, None, true, None);
+        let group_1 = Group::new(None, None, true, None);
+        let group_2 = Group::new(None, None, true, None);
 
         let mut meta_store = MetadataStore::new();
 
@@ -989,8 +1787,7 @@ mod tests {
             vec![group_0.group_id(), group_2.group_id()],
         );
 
-        let new_base_group =
-            meta_store.get_group(base_group.group_id()).unwrap();
+        let new_base_group = meta_store.get_group(base_group.group_id()).unwrap();
         assert_eq!(
             new_base_group.children.as_ref().unwrap(),
             &HashSet::from([group_1.group_id.clone()]),
@@ -1014,13 +1811,13 @@ mod tests {
 
     #[test]
     fn test_resolve_group_ids() {
-        let base_group = Group::new(None, true, true);
-        let group_0 = Group::new(None, true, true);
-        let group_0a = Group::new(None, true, false);
-        let group_0b = Group::new(None, true, false);
-        let group_1 = Group::new(None, true, true);
-        let group_1a = Group::new(None, true, false);
-        let group_1b = Group::new(None, true, false);
+        let base_group = Group::new(None, None, true, Some(None));
+        let group_0 = Group::new(None, None, true, Some(None));
+        let group_0a = Group::new(None, None, true, None);
+        let group_0b = Group::new(None, None, true, None);
+        let group_1 = Group::new(None, None, true, Some(None));
+        let group_1a = Group::new(None, None, true, None);
+        let group_1b = Group::new(None, None, true, None);
 
         let mut meta_store = MetadataStore::new();
 
@@ -1060,18 +1857,527 @@ mod tests {
         );
 
         assert_eq!(
-            meta_store.resolve_group_ids(vec![
-                group_0.group_id(),
-                group_1.group_id()
-            ]),
+            meta_store.resolve_group_ids(vec![group_0.group_id(), group_1.group_id()]),
+            expected_ids
+        );
+    }
+
+    #[test]
+    fn test_resolve_group_ids_cycles() {
+        let group_0 = Group::new(None, None, true, Some(None));
+        let group_1 = Group::new(None, None, true, Some(None));
+        let group_1a = Group::new(None, None, true, None);
+
+        let mut meta_store = MetadataStore::new();
+
+        meta_store.set_group(group_0.group_id.clone(), group_0.clone());
+        meta_store.set_group(group_1.group_id.clone(), group_1.clone());
+        meta_store.set_group(group_1a.group_id.clone(), group_1a.clone());
+
+        assert_eq!(
+            meta_store.link_groups(group_0.group_id(), group_1.group_id()),
+            Ok(())
+        );
+        assert_eq!(
+            meta_store.link_groups(group_1.group_id(), group_1a.group_id()),
+            Ok(())
+        );
+
+        // closing the loop is rejected, as is a self-loop
+        assert_eq!(
+            meta_store.link_groups(group_1.group_id(), group_0.group_id()),
+            Err(Error::GroupCycle(
+                group_1.group_id().clone(),
+                group_0.group_id().clone()
+            ))
+        );
+        assert_eq!(
+            meta_store.add_child(group_0.group_id(), group_0.group_id()),
+            Err(Error::GroupCycle(
+                group_0.group_id().clone(),
+                group_0.group_id().clone()
+            ))
+        );
+        assert_eq!(
+            meta_store.add_parent(group_0.group_id(), group_1.group_id()),
+            Err(Error::GroupCycle(
+                group_1.group_id().clone(),
+                group_0.group_id().clone()
+            ))
+        );
+
+        // simulate cyclic state set directly by an older peer
+        let mut cyclic_group_1 =
+            meta_store.get_group(group_1.group_id()).unwrap().clone();
+        cyclic_group_1
+            .add_child(group_0.group_id().clone())
+            .unwrap();
+        meta_store.set_group(group_1.group_id().clone(), cyclic_group_1);
+
+        let expected_ids = HashSet::from([group_1a.group_id().clone()]);
+        assert_eq!(
+            meta_store.resolve_group_ids(vec![group_0.group_id()]),
+            expected_ids
+        );
+        assert_eq!(
+            meta_store.resolve_group_ids(vec![group_1.group_id()]),
             expected_ids
         );
+        assert_eq!(meta_store.get_all_subgroups(group_0.group_id()).len(), 3);
     }
 
     #[test]
-    fn test_resolve_group_ids_cycles() {}
+    fn test_is_member() {
+        let base_group = Group::new(None, None, true, Some(None));
+        let group_0 = Group::new(None, None, true, Some(None));
+        let group_0a = Group::new(None, None, true, None);
+        let group_1 = Group::new(None, None, true, None);
+
+        let mut meta_store = MetadataStore::new();
+
+        meta_store.set_group(base_group.group_id.clone(), base_group.clone());
+        meta_store.set_group(group_0.group_id.clone(), group_0.clone());
+        meta_store.set_group(group_0a.group_id.clone(), group_0a.clone());
+        meta_store.set_group(group_1.group_id.clone(), group_1.clone());
+
+        meta_store.add_members(base_group.group_id(), vec![group_0.group_id()]);
+        meta_store.add_members(group_0.group_id(), vec![group_0a.group_id()]);
+
+        assert!(meta_store.is_group_member(
+            group_0a.group_id(),
+            base_group.group_id(),
+            &None
+        ));
+        assert!(meta_store.is_group_member(
+            group_0.group_id(),
+            base_group.group_id(),
+            &None
+        ));
+        assert!(!meta_store.is_group_member(
+            group_1.group_id(),
+            base_group.group_id(),
+            &None
+        ));
+        assert!(!meta_store.is_group_member(
+            base_group.group_id(),
+            group_0a.group_id(),
+            &None
+        ));
+
+        // dangling child ids (e.g. from an older peer) are skipped
+        let mut dangling_group_0 =
+            meta_store.get_group(group_0.group_id()).unwrap().clone();
+        dangling_group_0
+            .add_child(String::from("dangling"))
+            .unwrap();
+        meta_store.set_group(group_0.group_id().clone(), dangling_group_0);
+        assert!(!meta_store.is_group_member(
+            group_1.group_id(),
+            base_group.group_id(),
+            &None
+        ));
+    }
 
     #[test]
-    fn test_is_member() {}
+    fn test_perm_log() {
+        let mut meta_store = MetadataStore::new();
+        let perm_0 = PermissionSet::new(None, None, None, None, None);
+        let perm_1 = PermissionSet::new(None, None, None, None, None);
+        let perm_0_id = perm_0.perm_id().to_string();
+        let entry = |seq: u128, perm_id: &String, change: PermChange| PermChangeEntry {
+            sender: String::from("sender"),
+            seq,
+            perm_id: perm_id.to_string(),
+            change,
+        };
+
+        // nothing is logged until enabled
+        meta_store.log_perm_change(entry(0, &perm_0_id, PermChange::Set(perm_0.clone())));
+        assert!(meta_store.get_perm_log(None).is_empty());
+
+        meta_store.enable_perm_log();
+        let set_0 = entry(1, &perm_0_id, PermChange::Set(perm_0.clone()));
+        let set_1 = entry(2, perm_1.perm_id(), PermChange::Set(perm_1.clone()));
+        let add_0 = entry(
+            3,
+            &perm_0_id,
+            PermChange::AddMembers(None, PermType::Readers(vec![String::from("r")])),
+        );
+        meta_store.log_perm_change(set_0.clone());
+        meta_store.log_perm_change(set_1.clone());
+        meta_store.log_perm_change(add_0.clone());
+
+        assert_eq!(meta_store.get_perm_log(None), vec![&set_0, &set_1, &add_0]);
+        assert_eq!(
+            meta_store.get_perm_log(Some(&perm_0_id)),
+            vec![&set_0, &add_0]
+        );
+
+        meta_store.disable_perm_log();
+        assert!(meta_store.get_perm_log(None).is_empty());
+    }
+
+    #[test]
+    fn test_purge_expired_grants() {
+        let mut meta_store = MetadataStore::new();
+        let reader_0 = String::from("reader_0");
+        let reader_1 = String::from("reader_1");
+        for reader in [&reader_0, &reader_1] {
+            meta_store.set_group(
+                reader.to_string(),
+                Group::new(Some(reader.to_string()), None, false, None),
+            );
+        }
+        let perm = PermissionSet::new(None, None, None, None, None);
+        let perm_id = perm.perm_id().to_string();
+        meta_store.set_perm(perm_id.clone(), perm);
+        meta_store
+            .add_permissions(
+                &perm_id,
+                None,
+                PermType::Readers(vec![reader_0.clone(), reader_1.clone()]),
+            )
+            .unwrap();
+
+        let mut perm = meta_store.get_perm(&perm_id).unwrap().clone();
+        let readers = perm.readers().clone().unwrap();
+        perm.set_reader_expiry(reader_0.clone(), Expiry::Epoch(3));
+        perm.set_reader_expiry(reader_1.clone(), Expiry::Epoch(3));
+        meta_store.set_perm(perm_id.clone(), perm);
+        // granting again without an expiry makes the grant permanent
+        meta_store
+            .add_permissions(&perm_id, None, PermType::Readers(vec![reader_1.clone()]))
+            .unwrap();
+
+        assert!(meta_store.purge_expired_grants(2).is_empty());
+        assert!(meta_store.is_group_member(&reader_0, &readers, &None));
+
+        assert_eq!(
+            meta_store.purge_expired_grants(3),
+            vec![(perm_id.clone(), reader_0.clone())]
+        );
+        assert!(!meta_store.is_group_member(&reader_0, &readers, &None));
+        assert!(meta_store.is_group_member(&reader_1, &readers, &None));
+        assert!(meta_store
+            .get_perm(&perm_id)
+            .unwrap()
+            .reader_expiry()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_owner_change_quorum() {
+        let mut meta_store = MetadataStore::new();
+        let owner_0 = String::from("owner_0");
+        let owner_1 = String::from("owner_1");
+        let reader = String::from("reader");
+        for id in [&owner_0, &owner_1, &reader] {
+            meta_store
+                .set_group(id.clone(), Group::new(Some(id.clone()), None, false, None));
+        }
+        let perm = PermissionSet::new(None, None, None, None, None);
+        let perm_id = perm.perm_id().to_string();
+        meta_store.set_perm(perm_id.clone(), perm);
+        meta_store
+            .add_permissions(
+                &perm_id,
+                None,
+                PermType::Owners(vec![owner_0.clone(), owner_1.clone()]),
+            )
+            .unwrap();
+        meta_store
+            .add_permissions(&perm_id, None, PermType::Readers(vec![reader.clone()]))
+            .unwrap();
+        let owners = meta_store
+            .get_perm(&perm_id)
+            .unwrap()
+            .owners()
+            .clone()
+            .unwrap();
+
+        // without a quorum, a single owner is enough
+        assert_eq!(
+            meta_store.propose_owner_change(
+                String::from("p0"),
+                perm_id.clone(),
+                OwnerChange::SetQuorum(Some(2)),
+                owner_0.clone(),
+            ),
+            Ok(true)
+        );
+        assert_eq!(meta_store.required_owner_approvals(&perm_id), 2);
+
+        // only existing members can become owners
+        assert_eq!(
+            meta_store.propose_owner_change(
+                String::from("p1"),
+                perm_id.clone(),
+                OwnerChange::Transfer(vec![String::from("stranger")]),
+                owner_0.clone(),
+            ),
+            Err(Error::NotPermMember(
+                perm_id.clone(),
+                String::from("stranger")
+            ))
+        );
+
+        let p2 = String::from("p2");
+        assert_eq!(
+            meta_store.propose_owner_change(
+                p2.clone(),
+                perm_id.clone(),
+                OwnerChange::Transfer(vec![reader.clone()]),
+                owner_0.clone(),
+            ),
+            Ok(false)
+        );
+        // approving twice as the same owner does not count
+        assert_eq!(
+            meta_store.approve_owner_change(&p2, owner_0.clone()),
+            Ok(false)
+        );
+        assert!(meta_store.is_group_member(&owner_0, &owners, &None));
+        assert_eq!(
+            meta_store.approve_owner_change(&p2, owner_1.clone()),
+            Ok(true)
+        );
+        assert!(meta_store.get_owner_proposal(&p2).is_none());
+        // an id can only be proposed once, and never that of a group
+        assert_eq!(
+            meta_store.propose_owner_change(
+                String::from("p3"),
+                perm_id.clone(),
+                OwnerChange::SetQuorum(Some(2)),
+                owner_0.clone(),
+            ),
+            Ok(false)
+        );
+        assert_eq!(
+            meta_store.propose_owner_change(
+                String::from("p3"),
+                perm_id.clone(),
+                OwnerChange::SetQuorum(None),
+                reader.clone(),
+            ),
+            Err(Error::ProposalIdInUse(String::from("p3")))
+        );
+        assert_eq!(
+            meta_store.propose_owner_change(
+                owners.clone(),
+                perm_id.clone(),
+                OwnerChange::SetQuorum(None),
+                reader.clone(),
+            ),
+            Err(Error::ProposalIdInUse(owners.clone()))
+        );
+        assert!(meta_store.is_group_member(&reader, &owners, &None));
+        assert!(!meta_store.is_group_member(&owner_0, &owners, &None));
+        assert!(!meta_store.is_group_member(&owner_1, &owners, &None));
+        // the quorum is capped at the number of remaining owners
+        assert_eq!(meta_store.required_owner_approvals(&perm_id), 1);
+
+        // pending changes are bounded per permission set (p3 is pending)
+        for i in 1..MAX_OWNER_PROPOSALS_PER_PERM {
+            assert_eq!(
+                meta_store.propose_owner_change(
+                    format!("q{}", i),
+                    perm_id.clone(),
+                    OwnerChange::SetQuorum(Some(2)),
+                    owner_0.clone(),
+                ),
+                Ok(false)
+            );
+        }
+        assert_eq!(
+            meta_store.propose_owner_change(
+                String::from("q0"),
+                perm_id.clone(),
+                OwnerChange::SetQuorum(Some(2)),
+                owner_0.clone(),
+            ),
+            Err(Error::TooManyProposals(perm_id.clone()))
+        );
+    }
+
+    #[test]
+    fn test_owner_quorum_blocks_direct_writes() {
+        let mut meta_store = MetadataStore::new();
+        let owner_0 = String::from("owner_0");
+        let owner_1 = String::from("owner_1");
+        for id in [&owner_0, &owner_1] {
+            meta_store
+                .set_group(id.clone(), Group::new(Some(id.clone()), None, false, None));
+        }
+        let perm = PermissionSet::new(None, None, None, None, None);
+        let perm_id = perm.perm_id().to_string();
+        meta_store.set_perm(perm_id.clone(), perm);
+        meta_store
+            .add_permissions(
+                &perm_id,
+                None,
+                PermType::Owners(vec![owner_0.clone(), owner_1.clone()]),
+            )
+            .unwrap();
+        meta_store
+            .propose_owner_change(
+                String::from("p0"),
+                perm_id.clone(),
+                OwnerChange::SetQuorum(Some(2)),
+                owner_0.clone(),
+            )
+            .unwrap();
+        let perm_val = meta_store.get_perm(&perm_id).unwrap().clone();
+        let owners = perm_val.owners().clone().unwrap();
+        let owners_val = meta_store.get_group(&owners).unwrap().clone();
+
+        // resending unchanged metadata is fine
+        assert!(!meta_store.is_perm_overwrite(&perm_id, &perm_val));
+        assert_eq!(meta_store.bypasses_owner_quorum(&owners, &owners_val), None);
+
+        // a lone owner cannot drop the quorum by replacing the perm...
+        let mut no_quorum = perm_val.clone();
+        no_quorum.set_owner_quorum(None);
+        assert!(meta_store.is_perm_overwrite(&perm_id, &no_quorum));
+
+        // ...or remove the other owner by replacing the owner group
+        let mut lone_owner = owners_val.clone();
+        lone_owner.remove_child(&owner_1).unwrap();
+        assert_eq!(
+            meta_store.bypasses_owner_quorum(&owners, &lone_owner),
+            Some(perm_id.clone())
+        );
+
+        // without a quorum, the owner group is guarded by the usual checks
+        assert_eq!(
+            meta_store.propose_owner_change(
+                String::from("p1"),
+                perm_id.clone(),
+                OwnerChange::SetQuorum(None),
+                owner_0.clone(),
+            ),
+            Ok(false)
+        );
+        assert_eq!(
+            meta_store.approve_owner_change(&String::from("p1"), owner_1.clone()),
+            Ok(true)
+        );
+        assert_eq!(meta_store.bypasses_owner_quorum(&owners, &lone_owner), None);
+    }
+
+    #[test]
+    fn test_membership_cache() {
+        let mut meta_store = MetadataStore::new();
+        let team = String::from("team");
+        let sub_team = String::from("sub_team");
+        let device_0 = String::from("0");
+        let device_1 = String::from("1");
+        for id in [&team, &sub_team] {
+            meta_store.set_group(
+                id.clone(),
+                Group::new(Some(id.clone()), None, false, Some(None)),
+            );
+        }
+        for id in [&device_0, &device_1] {
+            meta_store
+                .set_group(id.clone(), Group::new(Some(id.clone()), None, false, None));
+        }
+        meta_store.link_groups(&team, &sub_team).unwrap();
+        meta_store.link_groups(&sub_team, &device_0).unwrap();
+
+        assert!(meta_store.is_group_member(&device_0, &team, &None));
+        assert!(!meta_store.is_group_member(&device_1, &team, &None));
+        assert_eq!(
+            meta_store.resolve_group_ids(vec![&team]),
+            HashSet::from([device_0.clone()])
+        );
+
+        // changes below a cached group are picked up
+        meta_store.link_groups(&sub_team, &device_1).unwrap();
+        assert!(meta_store.is_group_member(&device_1, &team, &None));
+        meta_store.unlink_groups(&sub_team, &device_0).unwrap();
+        assert!(!meta_store.is_group_member(&device_0, &team, &None));
+        meta_store.delete_group(&sub_team);
+        assert!(meta_store.resolve_group_ids(vec![&team]).is_empty());
+
+        // and match the uncached traversal
+        let mut uncached = meta_store.clone();
+        uncached.disable_membership_cache();
+        assert_eq!(
+            uncached.resolve_group_ids(vec![&team]),
+            meta_store.resolve_group_ids(vec![&team])
+        );
+        assert_eq!(uncached, meta_store);
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let mut meta_store = MetadataStore::new();
+        let linked = String::from("linked");
+        let device = String::from("device");
+        meta_store.set_group(
+            linked.clone(),
+            Group::new(Some(linked.clone()), None, false, Some(None)),
+        );
+        meta_store.set_group(
+            device.clone(),
+            Group::new(Some(device.clone()), None, false, None),
+        );
+        meta_store.link_groups(&linked, &device).unwrap();
+
+        let mut perms = Vec::new();
+        for _ in 0..2 {
+            let perm = PermissionSet::new(None, None, None, None, None);
+            let perm_id = perm.perm_id().to_string();
+            meta_store.set_perm(perm_id.clone(), perm);
+            meta_store
+                .add_permissions(&perm_id, None, PermType::Owners(vec![linked.clone()]))
+                .unwrap();
+            perms.push(perm_id);
+        }
+        let owners = meta_store
+            .get_perm(&perms[0])
+            .unwrap()
+            .owners()
+            .clone()
+            .unwrap();
+        // a child id this device never received
+        meta_store
+            .get_group_mut(&owners)
+            .unwrap()
+            .add_child("unknown".to_string())
+            .unwrap();
+
+        // perms are only collected once their data has been deleted
+        let live = HashSet::from([perms[1].clone()]);
+        assert_eq!(
+            meta_store.collect_garbage(&live, vec![&linked]),
+            Collected::default()
+        );
+
+        meta_store.mark_perm_orphaned(&perms[0]);
+        meta_store.mark_perm_orphaned(&perms[1]);
+        let collected = meta_store.collect_garbage(&live, vec![&linked]);
+        assert_eq!(collected.perm_ids, vec![perms[0].clone()]);
+        assert_eq!(collected.group_ids, vec![owners.clone()]);
+        assert!(meta_store.get_perm(&perms[1]).is_some());
+        assert!(meta_store.get_group(&owners).is_none());
+        assert!(!meta_store
+            .get_group(&linked)
+            .unwrap()
+            .parents()
+            .contains(&owners));
+
+        // dangling parents are dropped
+        meta_store
+            .get_group_mut(&device)
+            .unwrap()
+            .add_parent("gone".to_string());
+        meta_store.collect_garbage(&live, vec![&linked]);
+        assert_eq!(
+            meta_store.get_group(&device).unwrap().parents(),
+            &HashSet::from([linked.clone()])
+        );
+        assert!(meta_store.get_group(&linked).is_some());
+        assert!(meta_store.get_group(&device).is_some());
+    }
 }
-*/
@@ -0,0 +1,196 @@
+use crate::data::{BasicData, ScubaData};
+use std::collections::{BTreeMap, HashMap};
+use thiserror::Error;
+
+/*
+ * Versioned data types. Every data object records the version of its
+ * data_type's format it was written in (0 for data from before versioning).
+ * Apps register a function that upgrades data_val from each version to the
+ * next, and the latest version of a type is the one after the highest
+ * version with an upgrade. Data is brought up to date either lazily, when it
+ * is read, or eagerly, as soon as an update arrives. A device cannot
+ * downgrade data written by a device that is on a newer version than itself,
+ * nor check it against its validators, so such updates are rejected. Data
+ * that still ends up stored in a newer version (e.g. from a snapshot) is kept
+ * as is, and reading it fails with NewerVersion instead of handing the app a
+ * value it cannot parse.
+ */
+
+// Called with data_val in one version; returns it in the next
+pub type UpgradeFn = Box<dyn Fn(&String) -> Result<String, String> + Send + Sync>;
+
+#[derive(Debug, PartialEq, Clone, Error)]
+pub enum Error {
+    #[error("No upgrade for {0} from version {1} is registered")]
+    MissingUpgrade(String, u32),
+    #[error("Upgrading {0} from version {1} failed: {2}")]
+    UpgradeFailed(String, u32, String),
+    #[error("{0} version {1} is newer than the latest known version {2}")]
+    NewerVersion(String, u32, u32),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MigrationMode {
+    // upgrade data when it is read
+    Lazy,
+    // upgrade data when it is received, and all stored data right away
+    Eager,
+}
+
+pub struct Migrations {
+    mode: MigrationMode,
+    // data_type -> upgrade functions by the version they upgrade from
+    upgrades: HashMap<String, BTreeMap<u32, UpgradeFn>>,
+}
+
+impl Migrations {
+    pub fn new() -> Migrations {
+        Migrations {
+            mode: MigrationMode::Lazy,
+            upgrades: HashMap::new(),
+        }
+    }
+
+    pub fn mode(&self) -> MigrationMode {
+        self.mode
+    }
+
+    pub fn set_mode(&mut self, mode: MigrationMode) {
+        self.mode = mode;
+    }
+
+    pub fn register_upgrade<F>(
+        &mut self,
+        data_type: String,
+        from_version: u32,
+        upgrade: F,
+    ) where
+        F: Fn(&String) -> Result<String, String> + Send + Sync + 'static,
+    {
+        self.upgrades
+            .entry(data_type)
+            .or_default()
+            .insert(from_version, Box::new(upgrade));
+    }
+
+    pub fn current_version(&self, data_type: &String) -> u32 {
+        self.upgrades
+            .get(data_type)
+            .and_then(|upgrades| upgrades.keys().next_back())
+            .map_or(0, |from_version| from_version + 1)
+    }
+
+    pub fn needs_upgrade(&self, data_val: &BasicData) -> bool {
+        data_val.type_version() != self.current_version(data_val.data_type())
+    }
+
+    // Returns data_val upgraded to the current version of its type
+    pub fn upgrade(&self, data_val: &BasicData) -> Result<BasicData, Error> {
+        let data_type = data_val.data_type();
+        let current_version = self.current_version(data_type);
+        let mut version = data_val.type_version();
+        if version > current_version {
+            return Err(Error::NewerVersion(
+                data_type.to_string(),
+                version,
+                current_version,
+            ));
+        }
+
+        let mut val = data_val.data_val().clone();
+        while version < current_version {
+            let upgrade = self
+                .upgrades
+                .get(data_type)
+                .and_then(|upgrades| upgrades.get(&version))
+                .ok_or(Error::MissingUpgrade(data_type.to_string(), version))?;
+            val = upgrade(&val).map_err(|err| {
+                Error::UpgradeFailed(data_type.to_string(), version, err)
+            })?;
+            version += 1;
+        }
+        Ok(data_val
+            .clone()
+            .with_data_val(val)
+            .with_type_version(version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{BasicData, ScubaData};
+    use crate::migration::{Error, Migrations};
+    use serde_json::{json, Value};
+
+    fn agent(data_val: &str, type_version: u32) -> BasicData {
+        BasicData::new(
+            "agent".to_string(),
+            "agent".to_string(),
+            data_val.to_string(),
+            "perm".to_string(),
+        )
+        .with_type_version(type_version)
+    }
+
+    fn test_migrations() -> Migrations {
+        let mut migrations = Migrations::new();
+        // v0 -> v1 splits name, v1 -> v2 adds a role
+        migrations.register_upgrade("agent".to_string(), 0, |val| {
+            let old: Value = serde_json::from_str(val).map_err(|e| e.to_string())?;
+            let name = old["name"].as_str().ok_or("missing name")?;
+            let (first, last) = name.split_once(' ').unwrap_or((name, ""));
+            Ok(json!({"first": first, "last": last}).to_string())
+        });
+        migrations.register_upgrade("agent".to_string(), 1, |val| {
+            let mut new: Value = serde_json::from_str(val).map_err(|e| e.to_string())?;
+            new["role"] = json!("member");
+            Ok(new.to_string())
+        });
+        migrations
+    }
+
+    #[test]
+    fn test_upgrade_chain() {
+        let migrations = test_migrations();
+        assert_eq!(migrations.current_version(&"agent".to_string()), 2);
+        assert_eq!(migrations.current_version(&"other".to_string()), 0);
+
+        let old = agent(r#"{"name": "Ada Lovelace"}"#, 0);
+        assert!(migrations.needs_upgrade(&old));
+        let upgraded = migrations.upgrade(&old).unwrap();
+        assert_eq!(upgraded.type_version(), 2);
+        assert!(!migrations.needs_upgrade(&upgraded));
+        assert_eq!(
+            serde_json::from_str::<Value>(upgraded.data_val()).unwrap(),
+            json!({"first": "Ada", "last": "Lovelace", "role": "member"})
+        );
+        assert_eq!(upgraded.data_id(), old.data_id());
+
+        // already current
+        assert_eq!(migrations.upgrade(&upgraded), Ok(upgraded.clone()));
+    }
+
+    #[test]
+    fn test_upgrade_failures() {
+        let migrations = test_migrations();
+        assert_eq!(
+            migrations.upgrade(&agent("{}", 3)),
+            Err(Error::NewerVersion("agent".to_string(), 3, 2))
+        );
+        assert_eq!(
+            migrations.upgrade(&agent("{}", 0)),
+            Err(Error::UpgradeFailed(
+                "agent".to_string(),
+                0,
+                "missing name".to_string()
+            ))
+        );
+
+        let mut gap = Migrations::new();
+        gap.register_upgrade("agent".to_string(), 1, |val| Ok(val.clone()));
+        assert_eq!(
+            gap.upgrade(&agent("{}", 0)),
+            Err(Error::MissingUpgrade("agent".to_string(), 0))
+        );
+    }
+}
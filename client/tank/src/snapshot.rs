@@ -0,0 +1,377 @@
+use crate::data::BasicData;
+use crate::metadata::Group;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use thiserror::Error;
+
+/*
+ * Chunked snapshots of a device's groups and data, used to bring a newly
+ * linked device up to date. The existing device splits its state into
+ * chunks of bounded size, each carrying its entries as serialized and a
+ * hash of exactly those bytes (groups hold sets, so re-serializing received
+ * entries need not reproduce them), and the
+ * whole snapshot is identified by a hash over all chunk hashes. The new
+ * device accepts chunks in any order, drops chunks whose hash does not
+ * match, and can ask for whichever chunks it is still missing, so a
+ * transfer that was interrupted can be resumed instead of restarted.
+ */
+
+pub const DEFAULT_CHUNK_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq, Error)]
+pub enum Error {
+    #[error("Chunk {0} does not match its hash")]
+    ChunkHashMismatch(usize),
+    #[error("Chunk {0} is out of range for a snapshot of {1} chunks")]
+    ChunkOutOfRange(usize, usize),
+    #[error("Snapshot does not match its hash")]
+    SnapshotHashMismatch,
+    #[error("Snapshot is missing chunks {0:?}")]
+    Incomplete(Vec<usize>),
+    #[error("Cannot convert snapshot entry to string: {0}")]
+    Serialization(String),
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub enum SnapshotEntry {
+    Group(String, Group),
+    Data(String, BasicData),
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SnapshotChunk {
+    pub index: usize,
+    // JSON-encoded Vec<SnapshotEntry>
+    pub entries: String,
+    pub hash: String,
+}
+
+impl SnapshotChunk {
+    fn new(index: usize, entries: Vec<SnapshotEntry>) -> Result<SnapshotChunk, Error> {
+        let entries = serde_json::to_string(&entries)
+            .map_err(|e| Error::Serialization(e.to_string()))?;
+        let hash = hex(&Sha256::digest(entries.as_bytes()));
+        Ok(SnapshotChunk {
+            index,
+            entries,
+            hash,
+        })
+    }
+
+    pub fn verify(&self) -> Result<(), Error> {
+        if hex(&Sha256::digest(self.entries.as_bytes())) != self.hash {
+            return Err(Error::ChunkHashMismatch(self.index));
+        }
+        Ok(())
+    }
+
+    pub fn entries(&self) -> Result<Vec<SnapshotEntry>, Error> {
+        serde_json::from_str(&self.entries)
+            .map_err(|e| Error::Serialization(e.to_string()))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash_chunk_hashes<'a>(hashes: impl Iterator<Item = &'a String>) -> String {
+    let mut hasher = Sha256::new();
+    for hash in hashes {
+        hasher.update(hash.as_bytes());
+    }
+    hex(&hasher.finalize())
+}
+
+// Snapshot held by the existing device until the new device confirms it has
+// everything
+#[derive(Debug, PartialEq, Clone)]
+pub struct OutgoingSnapshot {
+    pub linked_name: String,
+    pub chunks: Vec<SnapshotChunk>,
+    pub hash: String,
+}
+
+impl OutgoingSnapshot {
+    pub fn new(
+        linked_name: String,
+        groups: HashMap<String, Group>,
+        data: HashMap<String, BasicData>,
+        max_chunk_bytes: usize,
+    ) -> Result<OutgoingSnapshot, Error> {
+        // sort entries so the same state always produces the same snapshot
+        let entries = groups
+            .into_iter()
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .map(|(id, group)| SnapshotEntry::Group(id, group))
+            .chain(
+                data.into_iter()
+                    .collect::<BTreeMap<_, _>>()
+                    .into_iter()
+                    .map(|(id, data)| SnapshotEntry::Data(id, data)),
+            );
+
+        // greedily fill each chunk up to max_chunk_bytes; an entry larger
+        // than that gets a chunk of its own
+        let mut chunks = Vec::new();
+        let mut cur_entries = Vec::new();
+        let mut cur_bytes = 0;
+        for entry in entries {
+            let entry_bytes = serde_json::to_vec(&entry)
+                .map_err(|e| Error::Serialization(e.to_string()))?
+                .len();
+            if !cur_entries.is_empty() && cur_bytes + entry_bytes > max_chunk_bytes {
+                chunks.push(SnapshotChunk::new(chunks.len(), cur_entries)?);
+                cur_entries = Vec::new();
+                cur_bytes = 0;
+            }
+            cur_entries.push(entry);
+            cur_bytes += entry_bytes;
+        }
+        if !cur_entries.is_empty() || chunks.is_empty() {
+            chunks.push(SnapshotChunk::new(chunks.len(), cur_entries)?);
+        }
+
+        let hash = hash_chunk_hashes(chunks.iter().map(|chunk| &chunk.hash));
+        Ok(OutgoingSnapshot {
+            linked_name,
+            chunks,
+            hash,
+        })
+    }
+
+    pub fn num_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn get_chunk(&self, index: usize) -> Option<&SnapshotChunk> {
+        self.chunks.get(index)
+    }
+}
+
+// Chunks received so far by the new device
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct IncomingSnapshot {
+    header: Option<(String, usize, String)>,
+    chunks: BTreeMap<usize, SnapshotChunk>,
+}
+
+impl IncomingSnapshot {
+    pub fn new() -> IncomingSnapshot {
+        Self::default()
+    }
+
+    pub fn set_header(&mut self, linked_name: String, num_chunks: usize, hash: String) {
+        self.chunks.retain(|index, _| *index < num_chunks);
+        self.header = Some((linked_name, num_chunks, hash));
+    }
+
+    pub fn add_chunk(&mut self, chunk: SnapshotChunk) -> Result<(), Error> {
+        chunk.verify()?;
+        if let Some((_, num_chunks, _)) = &self.header {
+            if chunk.index >= *num_chunks {
+                return Err(Error::ChunkOutOfRange(chunk.index, *num_chunks));
+            }
+        }
+        self.chunks.insert(chunk.index, chunk);
+        Ok(())
+    }
+
+    // None if the header has not arrived yet, in which case every chunk is
+    // missing
+    pub fn missing_chunks(&self) -> Option<Vec<usize>> {
+        let (_, num_chunks, _) = self.header.as_ref()?;
+        Some(
+            (0..*num_chunks)
+                .filter(|index| !self.chunks.contains_key(index))
+                .collect(),
+        )
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.missing_chunks()
+            .map_or(false, |missing| missing.is_empty())
+    }
+
+    // returns (linked_name, groups, data)
+    pub fn assemble(
+        self,
+    ) -> Result<(String, HashMap<String, Group>, HashMap<String, BasicData>), Error> {
+        match self.missing_chunks() {
+            None => return Err(Error::Incomplete(Vec::new())),
+            Some(missing) if !missing.is_empty() => {
+                return Err(Error::Incomplete(missing))
+            }
+            _ => {}
+        }
+        let (linked_name, _, hash) = self.header.unwrap();
+        if hash_chunk_hashes(self.chunks.values().map(|chunk| &chunk.hash)) != hash {
+            return Err(Error::SnapshotHashMismatch);
+        }
+
+        let mut groups = HashMap::new();
+        let mut data = HashMap::new();
+        for (_, chunk) in self.chunks.into_iter() {
+            for entry in chunk.entries()?.into_iter() {
+                match entry {
+                    SnapshotEntry::Group(id, group) => {
+                        groups.insert(id, group);
+                    }
+                    SnapshotEntry::Data(id, data_val) => {
+                        data.insert(id, data_val);
+                    }
+                }
+            }
+        }
+        Ok((linked_name, groups, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::BasicData;
+    use crate::metadata::Group;
+    use crate::snapshot::{Error, IncomingSnapshot, OutgoingSnapshot, SnapshotChunk};
+    use std::collections::HashMap;
+
+    fn test_state(
+        num_data: usize,
+    ) -> (HashMap<String, Group>, HashMap<String, BasicData>) {
+        let mut groups = HashMap::new();
+        let group = Group::new(Some("linked".to_string()), None, false, Some(None));
+        groups.insert(group.group_id().to_string(), group);
+
+        let mut data = HashMap::new();
+        for i in 0..num_data {
+            let data_id = format!("data{}", i);
+            data.insert(
+                data_id.clone(),
+                BasicData::new(
+                    data_id,
+                    "type".to_string(),
+                    "x".repeat(100),
+                    "perm".to_string(),
+                ),
+            );
+        }
+        (groups, data)
+    }
+
+    #[test]
+    fn test_chunk_and_assemble() {
+        let (groups, data) = test_state(50);
+        let snapshot = OutgoingSnapshot::new(
+            "linked".to_string(),
+            groups.clone(),
+            data.clone(),
+            1024,
+        )
+        .unwrap();
+        assert!(snapshot.num_chunks() > 1);
+
+        let mut incoming = IncomingSnapshot::new();
+        // chunks may arrive before the header and out of order
+        for chunk in snapshot.chunks.iter().rev() {
+            incoming.add_chunk(chunk.clone()).unwrap();
+        }
+        assert_eq!(incoming.missing_chunks(), None);
+        incoming.set_header(
+            snapshot.linked_name.clone(),
+            snapshot.num_chunks(),
+            snapshot.hash.clone(),
+        );
+        assert!(incoming.is_complete());
+
+        let (linked_name, new_groups, new_data) = incoming.assemble().unwrap();
+        assert_eq!(linked_name, "linked".to_string());
+        assert_eq!(new_groups, groups);
+        assert_eq!(new_data, data);
+    }
+
+    #[test]
+    fn test_resume_missing_chunks() {
+        let (groups, data) = test_state(50);
+        let snapshot =
+            OutgoingSnapshot::new("linked".to_string(), groups, data, 1024).unwrap();
+
+        let mut incoming = IncomingSnapshot::new();
+        incoming.set_header(
+            snapshot.linked_name.clone(),
+            snapshot.num_chunks(),
+            snapshot.hash.clone(),
+        );
+        incoming.add_chunk(snapshot.chunks[0].clone()).unwrap();
+
+        // a tampered chunk is rejected and stays missing
+        let mut tampered = snapshot.chunks[1].clone();
+        tampered.entries.pop();
+        assert_eq!(
+            incoming.add_chunk(tampered),
+            Err(Error::ChunkHashMismatch(1))
+        );
+
+        let missing = incoming.missing_chunks().unwrap();
+        assert_eq!(missing, (1..snapshot.num_chunks()).collect::<Vec<_>>());
+        assert_eq!(
+            incoming.clone().assemble(),
+            Err(Error::Incomplete(missing.clone()))
+        );
+
+        for index in missing {
+            incoming
+                .add_chunk(snapshot.get_chunk(index).unwrap().clone())
+                .unwrap();
+        }
+        assert!(incoming.assemble().is_ok());
+    }
+
+    #[test]
+    fn test_chunk_survives_reserialization() {
+        // a group with several parents, whose order is not preserved when
+        // the chunk is decoded and encoded again
+        let mut group = Group::new(None, None, false, None);
+        for i in 0..16 {
+            group.add_parent(format!("parent{}", i));
+        }
+        let groups = HashMap::from([(group.group_id().to_string(), group)]);
+        let snapshot = OutgoingSnapshot::new(
+            "linked".to_string(),
+            groups.clone(),
+            HashMap::new(),
+            1024,
+        )
+        .unwrap();
+
+        let json = serde_json::to_string(&snapshot.chunks[0]).unwrap();
+        let chunk: SnapshotChunk = serde_json::from_str(&json).unwrap();
+        let json = serde_json::to_string(&chunk).unwrap();
+        let chunk: SnapshotChunk = serde_json::from_str(&json).unwrap();
+
+        let mut incoming = IncomingSnapshot::new();
+        incoming.set_header(snapshot.linked_name.clone(), 1, snapshot.hash.clone());
+        incoming.add_chunk(chunk).unwrap();
+        let (_, new_groups, _) = incoming.assemble().unwrap();
+        assert_eq!(new_groups, groups);
+    }
+
+    #[test]
+    fn test_empty_snapshot() {
+        let snapshot = OutgoingSnapshot::new(
+            "linked".to_string(),
+            HashMap::new(),
+            HashMap::new(),
+            1024,
+        )
+        .unwrap();
+        assert_eq!(snapshot.num_chunks(), 1);
+
+        let mut incoming = IncomingSnapshot::new();
+        incoming.set_header(snapshot.linked_name.clone(), 1, snapshot.hash.clone());
+        incoming.add_chunk(snapshot.chunks[0].clone()).unwrap();
+        let (_, groups, data) = incoming.assemble().unwrap();
+        assert!(groups.is_empty());
+        assert!(data.is_empty());
+    }
+}
@@ -0,0 +1,176 @@
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+
+/*
+ * Encoding of the messages devices send each other. Messages used to be
+ * plain JSON, in which every data value (often JSON itself) gets escaped a
+ * second time. Binary messages are CBOR instead, base64-encoded since the
+ * server relays strings, and prefixed with a marker and a format version:
+ * "!<version>:<base64>". No JSON document starts with '!', so decode() tells
+ * the two apart and still accepts JSON from older devices.
+ *
+ * Like JSON, CBOR identifies fields and enum variants by name, so fields
+ * added with #[serde(default)] still decode from messages sent before they
+ * existed, and variants can be added anywhere. Changes that older devices
+ * would misread (e.g. renaming fields or changing their types) must bump
+ * WIRE_VERSION; decoding a version it does not know fails rather than
+ * guessing.
+ */
+
+pub const WIRE_VERSION: u8 = 3;
+
+const BINARY_MARKER: char = '!';
+
+#[derive(Debug, PartialEq, Error)]
+pub enum Error {
+    #[error("Cannot encode or decode JSON message: {0}")]
+    Json(String),
+    #[error("Cannot encode or decode binary message: {0}")]
+    Binary(String),
+    #[error("Binary message is malformed")]
+    Malformed,
+    #[error("Binary message has unsupported version {0}")]
+    UnsupportedVersion(u8),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum WireFormat {
+    // understood by devices from before binary messages
+    Json,
+    Binary,
+}
+
+pub fn encode<T: Serialize>(value: &T, format: WireFormat) -> Result<String, Error> {
+    match format {
+        WireFormat::Json => {
+            serde_json::to_string(value).map_err(|e| Error::Json(e.to_string()))
+        }
+        WireFormat::Binary => {
+            let mut bytes = Vec::new();
+            ciborium::ser::into_writer(value, &mut bytes)
+                .map_err(|e| Error::Binary(e.to_string()))?;
+            Ok(format!(
+                "{}{}:{}",
+                BINARY_MARKER,
+                WIRE_VERSION,
+                STANDARD_NO_PAD.encode(bytes)
+            ))
+        }
+    }
+}
+
+pub fn decode<T: DeserializeOwned>(message: &str) -> Result<T, Error> {
+    let body = match message.strip_prefix(BINARY_MARKER) {
+        Some(body) => body,
+        None => {
+            return serde_json::from_str(message).map_err(|e| Error::Json(e.to_string()))
+        }
+    };
+    let (version, payload) = body.split_once(':').ok_or(Error::Malformed)?;
+    match version.parse::<u8>().map_err(|_| Error::Malformed)? {
+        WIRE_VERSION => {
+            let bytes = STANDARD_NO_PAD
+                .decode(payload)
+                .map_err(|_| Error::Malformed)?;
+            ciborium::de::from_reader(bytes.as_slice())
+                .map_err(|e| Error::Binary(e.to_string()))
+        }
+        version => Err(Error::UnsupportedVersion(version)),
+    }
+}
+
+// which format message was encoded in
+pub fn format_of(message: &str) -> WireFormat {
+    if message.starts_with(BINARY_MARKER) {
+        WireFormat::Binary
+    } else {
+        WireFormat::Json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{BasicData, BinaryVal, Expiry};
+    use crate::wire::{decode, encode, format_of, Error, WireFormat};
+    use serde::Serialize;
+
+    fn test_data() -> BasicData {
+        BasicData::new(
+            "data".to_string(),
+            "type".to_string(),
+            "{\"text\": \"hello \\\"world\\\"\"}".to_string(),
+            "perm".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let data = test_data();
+        for format in [WireFormat::Json, WireFormat::Binary] {
+            let message = encode(&data, format).unwrap();
+            assert_eq!(format_of(&message), format);
+            assert_eq!(decode::<BasicData>(&message), Ok(data.clone()));
+        }
+    }
+
+    // bytes take up about as much as their base64 encoding in either format,
+    // rather than the ~3.5 characters per byte of a JSON list of numbers
+    #[test]
+    fn test_bytes_are_compact() {
+        let bytes: Vec<u8> = (0..=255).cycle().take(4096).collect();
+        let data = test_data().with_bytes(Some(BinaryVal::new(bytes.clone(), false)));
+        for format in [WireFormat::Json, WireFormat::Binary] {
+            let message = encode(&data, format).unwrap();
+            assert!(message.len() < bytes.len() * 4 / 3 + 200);
+            assert_eq!(decode::<BasicData>(&message), Ok(data.clone()));
+        }
+    }
+
+    #[test]
+    fn test_bad_messages() {
+        assert_eq!(
+            decode::<BasicData>("!9:AAAA"),
+            Err(Error::UnsupportedVersion(9))
+        );
+        // packed CBOR, which identified variants by position
+        assert_eq!(
+            decode::<BasicData>("!2:AAAA"),
+            Err(Error::UnsupportedVersion(2))
+        );
+        assert_eq!(decode::<BasicData>("!3"), Err(Error::Malformed));
+        assert_eq!(decode::<BasicData>("!3:%%%"), Err(Error::Malformed));
+        assert!(matches!(decode::<BasicData>("{"), Err(Error::Json(_))));
+    }
+
+    // BasicData as sent before data types were versioned
+    #[derive(Serialize)]
+    struct UnversionedData {
+        data_id: String,
+        data_type: String,
+        data_val: String,
+        perm_id: String,
+        expiry: Option<Expiry>,
+        data_bytes: Option<BinaryVal>,
+    }
+
+    #[test]
+    fn test_decode_without_new_field() {
+        let old = UnversionedData {
+            data_id: "data".to_string(),
+            data_type: "type".to_string(),
+            data_val: "{\"text\": \"hello \\\"world\\\"\"}".to_string(),
+            perm_id: "perm".to_string(),
+            expiry: None,
+            data_bytes: None,
+        };
+        for format in [WireFormat::Json, WireFormat::Binary] {
+            let message = encode(&old, format).unwrap();
+            let data = decode::<BasicData>(&message).unwrap();
+            assert_eq!(data, test_data());
+            assert_eq!(data.type_version(), 0);
+        }
+    }
+}